@@ -1,30 +1,158 @@
 /// Image archive implementation
-use crate::shrx;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+mod layout;
+mod playback;
+mod retention;
+mod segments;
+pub use layout::{migrate, Layout, DEFAULT_LAYOUT};
+pub use playback::{
+    write_avi, write_avi_progress, ArchivedFrame, FrameReader, FrameRef, Playback, Range,
+};
+pub use retention::{parse_size, Retention};
+pub use segments::{Mode, SegmentWriter, DEFAULT_SEGMENT_MINUTES};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tinyjson::JsonValue;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+// Frames older than this are not written: the camera is disconnected or stalled
+const MAX_FRAME_AGE: u64 = 1000;
+
+/// Source of time for the archive thread, tests replace it with a fake one
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        crate::datetime::now_ms()
+    }
+}
+
 pub struct ImageArchive {
     imp: Arc<Mutex<Impl>>,
+    clock: Arc<dyn Clock>,
 
     thread: Vec<std::thread::JoinHandle<()>>,
 }
 
 struct Impl {
     path: String,
-    max_age: u32,
+    layout: Layout,
+    camera: String,
+    retention: Retention,
+    mode: Mode,
     fps: u32,
-    max_len: u32,
     stop: bool,
-    img: Vec<u8>,
+    // AVI segment being written, retention keeps it
+    segment: Option<PathBuf>,
+    // Retention deletions are logged
+    log: Option<crate::eventlog::EventLog>,
+    // Latest frame, shared so the thread doesn't hold the lock while writing it, and the time
+    // it was added at
+    img: Arc<Vec<u8>>,
+    img_time: u64,
+    // Results of writes since the last add_image call
+    written: Vec<String>,
+    errors: Vec<String>,
+}
+
+/// Frame file in the archive, name contains its timestamp: frame_<ms>.jpg
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameFile {
+    pub time: u64,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// List frames in the flat archive directory sorted by time
+pub fn list_frames(path: &Path) -> Result<Vec<FrameFile>> {
+    Layout::default().list(path, None)
+}
+
+/// Write file atomically: into temporary file first and then rename it
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_file_atomic_mode(path, data, 0o666)
 }
 
-fn now_ms() -> u64 {
-    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => n.as_millis() as u64,
-        Err(_) => 0,
+/// Write file atomically with the permissions (on Unix, limited by umask), the temporary file is
+/// created with them, so secrets are never readable by others
+pub fn write_file_atomic_mode(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    // The file left by a failed write keeps its permissions, so it is not reused
+    match std::fs::remove_file(&tmp) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(Box::new(err)),
+        _ => (),
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(&tmp)?.write_all(data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Frame times of the archive: fps time points evenly spread over every second
+struct FrameTimer {
+    time_points: Vec<u64>,
+    // Start of the second and index of the next time point in it
+    second: u64,
+    idx: usize,
+}
+
+impl FrameTimer {
+    fn new(fps: u32, now: u64) -> FrameTimer {
+        let mut time_points: Vec<u64> = vec![];
+        for i in 0..fps {
+            time_points.push((i as u64) * 1000 / (fps as u64));
+        }
+
+        let mut timer = FrameTimer {
+            time_points,
+            second: now - now % 1000,
+            idx: 0,
+        };
+        while timer.next() < now {
+            timer.advance();
+        }
+
+        timer
+    }
+
+    fn next(&self) -> u64 {
+        self.second + self.time_points[self.idx]
+    }
+
+    fn advance(&mut self) {
+        self.idx += 1;
+        if self.idx == self.time_points.len() {
+            self.idx = 0;
+            self.second += 1000;
+        }
+    }
+
+    /// Returns the time point to write the frame at if one is due. If several time points were
+    /// missed only the latest of them is returned.
+    fn poll(&mut self, now: u64) -> Option<u64> {
+        if now < self.next() {
+            return None;
+        }
+
+        let mut due = self.next();
+        while self.next() <= now {
+            due = self.next();
+            self.advance();
+        }
+
+        Some(due)
     }
 }
 
@@ -43,91 +171,203 @@ impl Impl {
         }
     }
 
-    fn next_frame_impl(&mut self, time_point: u64) -> Result<()> {
-        // We should save the frame:
-        let mut filename = std::path::PathBuf::new();
+    fn frame_path(&self, time_point: u64) -> PathBuf {
+        let mut filename = PathBuf::new();
         filename.push(&self.path);
-        filename.push(format!("frame_{}.jpg", time_point));
-
-        let mut f = std::fs::File::create(filename.as_path())?;
-        f.write(&self.img)?;
-
-        Ok(())
+        filename.push(self.layout.path(time_point, &self.camera));
+        filename
     }
 
-    fn next_frame(&mut self, time_point: u64) {
-        match self.next_frame_impl(time_point) {
-            Ok(()) => (),
-            Err(err) => println!("Can't save frame: {}", err),
+    /// Frame to write at the time point, None if there is no frame or it is too old
+    fn frame(&self, time_point: u64) -> Option<Arc<Vec<u8>>> {
+        if self.img.is_empty() || time_point.saturating_sub(self.img_time) > MAX_FRAME_AGE {
+            return None;
         }
+        Some(self.img.clone())
     }
 }
 
-fn run_thread(arch: Arc<Mutex<Impl>>) -> std::thread::JoinHandle<()> {
-    let mut time_points: Vec<u64> = vec![];
-
-    {
+fn write_frame(arch: &Mutex<Impl>, time_point: u64) {
+    // Take the frame and release the lock before writing it
+    let (path, img) = {
         let a = arch.lock().unwrap();
-
-        for i in 0..a.get_fps() {
-            time_points.push((i as u64) * 1000 / (a.get_fps() as u64));
+        match a.frame(time_point) {
+            Some(img) => (a.frame_path(time_point), img),
+            None => return,
         }
+    };
+
+    let res = match path.parent() {
+        Some(dir) => std::fs::create_dir_all(dir),
+        None => Ok(()),
+    };
+    let res = match res {
+        Ok(()) => write_file_atomic(&path, &img),
+        Err(err) => Err(err.into()),
+    };
+
+    let mut a = arch.lock().unwrap();
+    match res {
+        Ok(()) => a.written.push(path.to_string_lossy().into_owned()),
+        Err(err) => a
+            .errors
+            .push(format!("Can't save frame {}: {}", path.display(), err)),
     }
+}
 
-    std::thread::spawn(move || {
-        let now = now_ms();
-        let n_ms = now % 1000;
-        let mut tpidx = 0;
-        while tpidx < time_points.len() && time_points[tpidx] < n_ms {
-            tpidx += 1;
-        }
+fn write_segment_frame(arch: &Mutex<Impl>, segments: &mut SegmentWriter, time_point: u64) {
+    let img = match arch.lock().unwrap().frame(time_point) {
+        Some(img) => img,
+        None => return,
+    };
 
-        let mut next_frame = if tpidx == time_points.len() {
-            now - n_ms + time_points[0] + 1000
-        } else {
-            now - n_ms + time_points[tpidx]
+    let res = segments.add(time_point, &img);
+
+    let mut a = arch.lock().unwrap();
+    a.segment = segments.current().map(PathBuf::from);
+    match res {
+        Ok(Some(path)) => a.written.push(path.to_string_lossy().into_owned()),
+        Ok(None) => (),
+        Err(err) => a.errors.push(format!("Can't save frame: {}", err)),
+    }
+}
+
+fn run_thread(arch: Arc<Mutex<Impl>>, clock: Arc<dyn Clock>) -> std::thread::JoinHandle<()> {
+    let (fps, mut segments) = {
+        let a = arch.lock().unwrap();
+        let segments = match a.mode {
+            Mode::Files => None,
+            Mode::Avi(length) => Some(SegmentWriter::new(
+                Path::new(&a.path),
+                &a.layout,
+                &a.camera,
+                a.get_fps(),
+                length,
+            )),
         };
-        tpidx = (tpidx + 1) % time_points.len();
+        (a.get_fps(), segments)
+    };
+
+    std::thread::spawn(move || {
+        let mut timer = FrameTimer::new(fps, clock.now_ms());
 
         loop {
             {
                 let a = arch.lock().unwrap();
                 if a.stop {
-                    return ();
+                    break;
                 }
             }
 
-            let now = now_ms();
-            if now >= next_frame {
-                {
-                    let mut a = arch.lock().unwrap();
-                    a.next_frame(next_frame);
+            if let Some(time_point) = timer.poll(clock.now_ms()) {
+                match segments {
+                    Some(ref mut segments) => write_segment_frame(&arch, segments, time_point),
+                    None => write_frame(&arch, time_point),
                 }
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // The last segment must be finalized to be playable
+        if let Some(ref mut segments) = segments {
+            match segments.close() {
+                Ok(Some(path)) => println!("Closed segment {}", path.display()),
+                Ok(None) => (),
+                Err(err) => println!("Can't finalize segment: {}", err),
+            }
+            arch.lock().unwrap().segment = None;
+        }
+    })
+}
+
+fn run_retention_thread(
+    arch: Arc<Mutex<Impl>>,
+    clock: Arc<dyn Clock>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut next_check = clock.now_ms();
 
-                next_frame = now - now % 1000 + time_points[tpidx];
-                tpidx = (tpidx + 1) % time_points.len();
+        loop {
+            let (path, layout, camera, retention, segment, log) = {
+                let a = arch.lock().unwrap();
+                if a.stop {
+                    return;
+                }
+                let layout = match a.mode {
+                    Mode::Files => a.layout.clone(),
+                    Mode::Avi(_) => a.layout.segments(),
+                };
+                (
+                    PathBuf::from(&a.path),
+                    layout,
+                    a.camera.clone(),
+                    a.retention.clone(),
+                    a.segment.clone(),
+                    a.log.clone(),
+                )
+            };
+
+            let now = clock.now_ms();
+            if now >= next_check {
+                // Scanning could take a while, it is done without the lock
+                let keep = segment.as_deref();
+                match retention.enforce(&path, &layout, &camera, now, keep) {
+                    Ok(removed) => {
+                        if let (Some(log), Some(first), Some(last)) =
+                            (log, removed.first(), removed.last())
+                        {
+                            let bytes: u64 = removed.iter().map(|f| f.size).sum();
+                            log.log(
+                                "retention",
+                                crate::eventlog::data(&[
+                                    ("files", JsonValue::Number(removed.len() as f64)),
+                                    ("bytes", JsonValue::Number(bytes as f64)),
+                                    ("from", JsonValue::Number(first.time as f64)),
+                                    ("to", JsonValue::Number(last.time as f64)),
+                                ]),
+                            );
+                        }
+                    }
+                    Err(err) => {
+                        let mut a = arch.lock().unwrap();
+                        a.errors.push(format!("Retention failed: {}", err));
+                    }
+                }
+                next_check = now + retention.interval;
             }
 
-            std::thread::sleep(std::time::Duration::from_micros(2));
+            std::thread::sleep(std::time::Duration::from_millis(100));
         }
     })
 }
 
 impl ImageArchive {
     pub fn new(path: &str) -> Result<ImageArchive> {
-        shrx::Pattern::new("xxx");
+        ImageArchive::with_clock(path, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(path: &str, clock: Arc<dyn Clock>) -> Result<ImageArchive> {
         let imp = Arc::new(Mutex::new(Impl{
             path: String::from(path),
-            max_age: 86400 * 10,
+            layout: Layout::default(),
+            camera: String::from("camera"),
+            retention: Retention::default(),
+            mode: Mode::Files,
             fps: 1,
-            max_len: 3600,
             stop: false,
-            img: vec![],
+            segment: None,
+            log: None,
+            img: Arc::new(vec![]),
+            img_time: 0,
+            written: vec![],
+            errors: vec![],
         }));
 
         Ok(ImageArchive {
             thread: vec![],
             imp: imp.clone(),
+            clock,
         })
     }
 
@@ -150,9 +390,58 @@ impl ImageArchive {
         i.fps
     }
 
-    /// Run image archive in separate thread
+    /// Set layout of the archive directory and the camera name used in it
+    pub fn set_layout(&mut self, layout: Layout, camera: &str) {
+        let mut i = self.imp.lock().unwrap();
+        i.layout = layout;
+        i.camera = String::from(camera);
+    }
+
+    pub fn get_layout(&self) -> (Layout, String) {
+        let i = self.imp.lock().unwrap();
+        (i.layout.clone(), i.camera.clone())
+    }
+
+    /// Set limits of the archive, old frames are removed periodically when the archive runs
+    pub fn set_retention(&mut self, retention: Retention) {
+        let mut i = self.imp.lock().unwrap();
+        i.retention = retention;
+    }
+
+    pub fn get_retention(&self) -> Retention {
+        let i = self.imp.lock().unwrap();
+        i.retention.clone()
+    }
+
+    /// Reader of the archive frames, it could be used from other threads
+    pub fn playback(&self) -> Playback {
+        let i = self.imp.lock().unwrap();
+        Playback::new(Path::new(&i.path), &i.layout, &i.camera)
+    }
+
+    /// Log files removed by retention
+    pub fn set_event_log(&mut self, log: crate::eventlog::EventLog) {
+        let mut i = self.imp.lock().unwrap();
+        i.log = Some(log);
+    }
+
+    /// Set how frames are stored: JPEG files or AVI segments, it must be set before run
+    pub fn set_mode(&mut self, mode: Mode) {
+        let mut i = self.imp.lock().unwrap();
+        i.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> Mode {
+        let i = self.imp.lock().unwrap();
+        i.mode
+    }
+
+    /// Run image archive in separate threads: one writes frames, another removes old ones
     pub fn run(&mut self) -> Result<()> {
-        self.thread.push(run_thread(self.imp.clone()));
+        self.thread
+            .push(run_thread(self.imp.clone(), self.clock.clone()));
+        self.thread
+            .push(run_retention_thread(self.imp.clone(), self.clock.clone()));
         Ok(())
     }
 
@@ -167,13 +456,131 @@ impl ImageArchive {
             return Err(err("Not running"));
         }
 
-        let t = self.thread.pop();
-        t.expect("Internal error").join();
+        while let Some(t) = self.thread.pop() {
+            if t.join().is_err() {
+                return Err(err("Archive thread panicked"));
+            }
+        }
 
         Ok(())
     }
 
-    pub fn add_image(&self, buf: &[u8]) -> Result<String> {
-        Ok(String::from("xxx"))
+    /// Set the latest frame, the archive thread writes it at the next time points while it is
+    /// not older than a second. Returns paths of files written since the previous call or errors of writing them. In AVI
+    /// mode paths of finished segments are returned.
+    pub fn add_image(&self, buf: &[u8]) -> Result<Vec<String>> {
+        let img = Arc::new(Vec::<u8>::from(buf));
+        let now = self.clock.now_ms();
+
+        let mut i = self.imp.lock().unwrap();
+        i.img = img;
+        i.img_time = now;
+
+        if !i.errors.is_empty() {
+            let errors = std::mem::take(&mut i.errors);
+            i.written.clear();
+            return Err(err(&errors.join("; ")));
+        }
+
+        Ok(std::mem::take(&mut i.written))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    struct FakeClock(AtomicU64);
+
+    impl Clock for FakeClock {
+        fn now_ms(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn test_frame_timer() {
+        let mut timer = FrameTimer::new(4, 10_100);
+        assert_eq!(timer.poll(10_100), None);
+        assert_eq!(timer.poll(10_249), None);
+        assert_eq!(timer.poll(10_250), Some(10_250));
+        assert_eq!(timer.poll(10_260), None);
+        assert_eq!(timer.poll(10_500), Some(10_500));
+        // Missed time points are skipped
+        assert_eq!(timer.poll(11_300), Some(11_250));
+        assert_eq!(timer.poll(11_499), None);
+        assert_eq!(timer.poll(11_500), Some(11_500));
+
+        // Single time point: once a second
+        let mut timer = FrameTimer::new(1, 10_000);
+        assert_eq!(timer.poll(10_000), Some(10_000));
+        assert_eq!(timer.poll(10_999), None);
+        assert_eq!(timer.poll(11_000), Some(11_000));
+    }
+
+    fn wait_written(arch: &ImageArchive, img: &[u8]) -> Vec<String> {
+        for _ in 0..1000 {
+            let written = arch.add_image(img).unwrap();
+            if !written.is_empty() {
+                return written;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        panic!("Frame is not written");
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = crate::test_util::temp_path("archive");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let clock = Arc::new(FakeClock(AtomicU64::new(5_400)));
+        let mut arch = ImageArchive::with_clock(dir.to_str().unwrap(), clock.clone()).unwrap();
+        arch.set_fps(2).unwrap();
+        arch.run().unwrap();
+        assert!(arch.add_image(b"first").unwrap().is_empty());
+
+        clock.0.store(5_500, Ordering::SeqCst);
+        let written = wait_written(&arch, b"first");
+        assert_eq!(written, vec![dir.join("frame_5500.jpg").to_string_lossy()]);
+        assert_eq!(std::fs::read(&written[0]).unwrap(), b"first");
+
+        arch.add_image(b"second").unwrap();
+        clock.0.store(6_010, Ordering::SeqCst);
+        let written = wait_written(&arch, b"second");
+        assert_eq!(written, vec![dir.join("frame_6000.jpg").to_string_lossy()]);
+        assert_eq!(std::fs::read(&written[0]).unwrap(), b"second");
+        assert!(!dir.join("frame_6000.jpg.tmp").exists());
+
+        // Frame of the camera which stopped sending them is not written again after a second
+        clock.0.store(7_600, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(arch.add_image(b"third").unwrap().is_empty());
+        assert!(!dir.join("frame_7500.jpg").exists());
+        clock.0.store(8_000, Ordering::SeqCst);
+        let written = wait_written(&arch, b"third");
+        assert_eq!(written, vec![dir.join("frame_8000.jpg").to_string_lossy()]);
+
+        arch.stop().unwrap();
+
+        // Write errors are reported by add_image: archive path is a file
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::write(&dir, b"file").unwrap();
+        let clock = Arc::new(FakeClock(AtomicU64::new(1_000)));
+        let mut arch = ImageArchive::with_clock(dir.to_str().unwrap(), clock.clone()).unwrap();
+        arch.add_image(b"frame").unwrap();
+        arch.run().unwrap();
+        let mut failed = false;
+        for _ in 0..1000 {
+            if arch.add_image(b"frame").is_err() {
+                failed = true;
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(failed);
+        arch.stop().unwrap();
+        std::fs::remove_file(&dir).unwrap();
     }
 }
@@ -0,0 +1,398 @@
+// Layout of the archive directory: template of frame paths relative to the archive root.
+// Placeholders: {camera}, {YYYY}, {MM}, {DD}, {HH}, {mm}, {ss} (UTC) and {ms} - timestamp in ms,
+// which must be present because frames are found by it. Default layout is flat: frame_{ms}.jpg
+// AVI segments are stored in the directories of the layout and named by their start minute.
+
+use super::{FrameFile, Result};
+use crate::datetime::DateTime;
+use crate::shrx;
+use crate::shrx::CheckResult;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_LAYOUT: &str = "frame_{ms}.jpg";
+
+const PLACEHOLDERS: [&str; 8] = ["camera", "YYYY", "MM", "DD", "HH", "mm", "ss", "ms"];
+
+const SEGMENT_NAME: &str = "{YYYY}{MM}{DD}-{HH}{mm}.avi";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::parse(DEFAULT_LAYOUT).unwrap()
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+// Escape symbols which are special for shrx
+fn escape(s: &str) -> String {
+    let mut res = String::new();
+    for c in s.chars() {
+        if "*?[]\\".contains(c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+impl Layout {
+    pub fn parse(template: &str) -> Result<Layout> {
+        let layout = Layout::parse_parts(template)?;
+
+        let ms_count = layout
+            .parts
+            .iter()
+            .filter(|p| **p == Part::Placeholder("ms"))
+            .count();
+        if ms_count != 1 {
+            return Err(format!("Layout '{}' must contain {{ms}} once", template).into());
+        }
+
+        Ok(layout)
+    }
+
+    fn parse_parts(template: &str) -> Result<Layout> {
+        let mut parts: Vec<Part> = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(String::from(&rest[..start])));
+            }
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("Unclosed placeholder in '{}'", template).into()),
+            };
+            let name = &rest[start + 1..end];
+            match PLACEHOLDERS.iter().find(|p| **p == name) {
+                Some(p) => parts.push(Part::Placeholder(p)),
+                None => return Err(format!("Unknown placeholder {{{}}}", name).into()),
+            }
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(String::from(rest)));
+        }
+
+        if template.starts_with('/') || template.split('/').any(|c| c.is_empty() || c == "..") {
+            return Err(format!("Invalid path in layout '{}'", template).into());
+        }
+
+        Ok(Layout {
+            template: String::from(template),
+            parts,
+        })
+    }
+
+    /// Layout of AVI segments: YYYYMMDD-HHMM.avi in the directories of frames
+    pub fn segments(&self) -> Layout {
+        let template = match self.template.rfind('/') {
+            Some(idx) => format!("{}/{}", &self.template[..idx], SEGMENT_NAME),
+            None => String::from(SEGMENT_NAME),
+        };
+        // Directories were validated already
+        Layout::parse_parts(&template).unwrap()
+    }
+
+    pub fn is_flat(&self) -> bool {
+        !self.template.contains('/')
+    }
+
+    /// Path of the frame relative to the archive root
+    pub fn path(&self, time: u64, camera: &str) -> PathBuf {
+        let dt = DateTime::from_ms(time);
+        let mut res = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => res.push_str(s),
+                Part::Placeholder(p) => {
+                    let v = match *p {
+                        "camera" => String::from(camera),
+                        "YYYY" => format!("{:04}", dt.year),
+                        "MM" => format!("{:02}", dt.month),
+                        "DD" => format!("{:02}", dt.day),
+                        "HH" => format!("{:02}", dt.hour),
+                        "mm" => format!("{:02}", dt.minute),
+                        "ss" => format!("{:02}", dt.second),
+                        _ => format!("{}", time),
+                    };
+                    res.push_str(&v);
+                }
+            }
+        }
+
+        PathBuf::from(res)
+    }
+
+    /// Pattern matching relative frame paths and placeholders captured by its groups with the
+    /// number of groups of each. Calendar fields are matched digit by digit, so they could
+    /// follow each other. Frames of all cameras are matched if camera is None.
+    fn pattern(&self, camera: Option<&str>) -> Result<(shrx::Pattern, Vec<(&'static str, usize)>)> {
+        let mut rx = String::new();
+        let mut groups: Vec<(&'static str, usize)> = vec![];
+
+        for part in &self.parts {
+            match (part, camera) {
+                (Part::Literal(s), _) => rx.push_str(&escape(s)),
+                (Part::Placeholder("camera"), Some(camera)) => rx.push_str(&escape(camera)),
+                (Part::Placeholder(p), _) => {
+                    let digits = match *p {
+                        "camera" | "ms" => 0,
+                        "YYYY" => 4,
+                        _ => 2,
+                    };
+                    if digits == 0 {
+                        rx.push('*');
+                        groups.push((p, 1));
+                    } else {
+                        rx.push_str(&"[0-9]".repeat(digits));
+                        groups.push((p, digits));
+                    }
+                }
+            }
+        }
+
+        Ok((shrx::Pattern::new(&rx)?, groups))
+    }
+
+    /// Time of the matched path: {ms} if the layout has it, calendar fields otherwise
+    fn match_time(m: &CheckResult, groups: &[(&'static str, usize)]) -> Option<u64> {
+        let mut fields: Vec<(&'static str, String)> = vec![];
+        let mut idx = 0;
+        for (name, count) in groups {
+            let value: String = (idx..idx + count).map(|i| m.group(i).as_str()).collect();
+            fields.push((name, value));
+            idx += count;
+        }
+
+        // The file name is the most specific, so the last occurrence of a field is used
+        let field = |name: &str| {
+            fields
+                .iter()
+                .rev()
+                .find(|f| f.0 == name)
+                .map(|f| f.1.as_str())
+        };
+        if let Some(ms) = field("ms") {
+            return ms.parse::<u64>().ok();
+        }
+
+        let number = |name: &str| field(name).and_then(|v| v.parse::<u32>().ok());
+        let dt = DateTime {
+            year: number("YYYY")? as i32,
+            month: number("MM")?,
+            day: number("DD")?,
+            hour: number("HH")?,
+            minute: number("mm")?,
+            second: number("ss").unwrap_or(0),
+            ms: 0,
+        };
+        if dt.month == 0 || dt.month > 12 || dt.day == 0 || dt.day > 31 {
+            return None;
+        }
+
+        Some(dt.to_ms())
+    }
+
+    /// List frames under the archive root sorted by time
+    pub fn list(&self, root: &Path, camera: Option<&str>) -> Result<Vec<FrameFile>> {
+        let (pattern, groups) = self.pattern(camera)?;
+        let mut res: Vec<FrameFile> = vec![];
+        let mut dirs: Vec<(PathBuf, String)> = vec![(PathBuf::from(root), String::new())];
+
+        while let Some((dir, rel)) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name = match name.to_str() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let rel_path = if rel.is_empty() {
+                    String::from(name)
+                } else {
+                    format!("{}/{}", rel, name)
+                };
+
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    // Flat layout doesn't need to look into subdirectories
+                    if !self.is_flat() {
+                        dirs.push((entry.path(), rel_path));
+                    }
+                    continue;
+                }
+
+                if let Some(m) = pattern.check(&rel_path) {
+                    if let Some(time) = Layout::match_time(&m, &groups) {
+                        let size = match entry.metadata() {
+                            Ok(meta) => meta.len(),
+                            Err(_) => 0,
+                        };
+                        res.push(FrameFile {
+                            time,
+                            path: entry.path(),
+                            size,
+                        });
+                    }
+                }
+            }
+        }
+
+        res.sort_by_key(|f| f.time);
+        Ok(res)
+    }
+}
+
+/// Remove directories which became empty after the file was removed, up to the root
+pub fn prune_empty_dirs(root: &Path, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(d) = dir {
+        if d == root || !d.starts_with(root) {
+            break;
+        }
+        // Fails if the directory is not empty
+        if std::fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Move frames stored in the flat layout into the new layout.
+/// Returns number of moved frames.
+pub fn migrate(root: &Path, layout: &Layout, camera: &str) -> Result<usize> {
+    let frames = Layout::default().list(root, None)?;
+    let mut moved = 0;
+
+    for frame in frames {
+        let dest = root.join(layout.path(frame.time, camera));
+        if dest == frame.path {
+            continue;
+        }
+        if dest.exists() {
+            println!(
+                "Skipped {}: {} exists",
+                frame.path.display(),
+                dest.display()
+            );
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&frame.path, &dest)?;
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-01 12:34:56.789 UTC
+    const T: u64 = 1709296496789;
+
+    #[test]
+    fn test_layout_path() {
+        let layout = Layout::parse("{camera}/{YYYY}/{MM}/{DD}/{HH}/frame_{ms}.jpg").unwrap();
+        assert_eq!(
+            layout.path(T, "front"),
+            PathBuf::from("front/2024/03/01/12/frame_1709296496789.jpg")
+        );
+        assert!(!layout.is_flat());
+
+        let layout = Layout::default();
+        assert_eq!(
+            layout.path(T, "x"),
+            PathBuf::from("frame_1709296496789.jpg")
+        );
+        assert!(layout.is_flat());
+
+        assert!(Layout::parse("{YYYY}/frame.jpg").is_err());
+        assert!(Layout::parse("{ms}/{ms}.jpg").is_err());
+        assert!(Layout::parse("{day}/{ms}.jpg").is_err());
+        assert!(Layout::parse("../{ms}.jpg").is_err());
+        assert!(Layout::parse("/{ms}.jpg").is_err());
+        assert!(Layout::parse("{YYYY/{ms}.jpg").is_err());
+    }
+
+    #[test]
+    fn test_segments_layout() {
+        let layout = Layout::parse("{camera}/{YYYY}-{MM}/frame_{ms}.jpg").unwrap();
+        let segments = layout.segments();
+        assert_eq!(
+            segments.path(T, "front"),
+            PathBuf::from("front/2024-03/20240301-1234.avi")
+        );
+        assert_eq!(
+            Layout::default().segments().path(T, "front"),
+            PathBuf::from("20240301-1234.avi")
+        );
+
+        let root = crate::test_util::temp_path("segments");
+        let path = root.join(segments.path(T, "front"));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"avi").unwrap();
+        std::fs::write(path.with_file_name("2024030-11234.avi"), b"avi").unwrap();
+        std::fs::write(path.with_file_name("20241301-1234.avi"), b"avi").unwrap();
+
+        let list = segments.list(&root, Some("front")).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].time, T - T % 60000);
+        assert_eq!(list[0].path, path);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_migrate() {
+        let root = crate::test_util::temp_path("layout");
+        std::fs::create_dir_all(&root).unwrap();
+        for t in [T, T + 3600 * 1000, T + 1] {
+            std::fs::write(root.join(format!("frame_{}.jpg", t)), b"jpeg").unwrap();
+        }
+        std::fs::write(root.join("notes.txt"), b"text").unwrap();
+
+        let layout = Layout::parse("{camera}/{YYYY}-{MM}-{DD}/{HH}/frame_{ms}.jpg").unwrap();
+        assert_eq!(migrate(&root, &layout, "cam").unwrap(), 3);
+        assert!(root
+            .join("cam/2024-03-01/13/frame_1709300096789.jpg")
+            .exists());
+        assert!(root.join("notes.txt").exists());
+        assert!(Layout::default().list(&root, None).unwrap().is_empty());
+
+        let frames = layout.list(&root, Some("cam")).unwrap();
+        let times: Vec<u64> = frames.iter().map(|f| f.time).collect();
+        assert_eq!(times, vec![T, T + 1, T + 3600 * 1000]);
+        assert!(layout.list(&root, Some("other")).unwrap().is_empty());
+        assert_eq!(layout.list(&root, None).unwrap().len(), 3);
+
+        // Directory of the hour is removed with its last frame
+        std::fs::remove_file(&frames[2].path).unwrap();
+        prune_empty_dirs(&root, &frames[2].path);
+        assert!(!root.join("cam/2024-03-01/13").exists());
+        assert!(root.join("cam/2024-03-01/12").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+// Reading the archive back: frames are found by the timestamps in file names, JPEG files and AVI
+// segments could be mixed when the archive mode was changed. Frames of a segment are timed by
+// the start time stored in the segment (the name has minutes only) and its frame rate.
+
+use super::{FrameFile, Layout, Result};
+use crate::mjpeg::{jpeg_size, AviReader, AviWriter};
+use std::path::{Path, PathBuf};
+
+/// Where the frame is stored: JPEG file or frame index in AVI segment
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameRef {
+    File(PathBuf),
+    Segment(PathBuf, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedFrame {
+    pub time: u64,
+    pub frame: FrameRef,
+}
+
+/// Time range of continuous recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+    pub frames: usize,
+}
+
+/// Frame file or AVI segment starting at the time of the file
+struct Item {
+    file: FrameFile,
+    segment: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Playback {
+    root: PathBuf,
+    layout: Layout,
+    camera: String,
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    match path.to_str() {
+        Some(s) => Ok(s),
+        None => Err(format!("Invalid path {}", path.display()).into()),
+    }
+}
+
+/// Reads frames keeping the last AVI segment open, so frames of a segment are read quickly
+pub struct FrameReader {
+    segment: Option<(PathBuf, AviReader)>,
+}
+
+impl Default for FrameReader {
+    fn default() -> FrameReader {
+        FrameReader::new()
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader { segment: None }
+    }
+
+    pub fn read(&mut self, frame: &FrameRef) -> Result<Vec<u8>> {
+        let (path, idx) = match frame {
+            FrameRef::File(path) => return Ok(std::fs::read(path)?),
+            FrameRef::Segment(path, idx) => (path, *idx),
+        };
+
+        if self.segment.as_ref().map(|s| &s.0) != Some(path) {
+            self.segment = Some((path.clone(), AviReader::open(path_str(path)?)?));
+        }
+        match self.segment {
+            Some((_, ref mut reader)) => reader.frame(idx),
+            None => Err("Segment is not open".into()),
+        }
+    }
+}
+
+impl Playback {
+    pub fn new(root: &Path, layout: &Layout, camera: &str) -> Playback {
+        Playback {
+            root: PathBuf::from(root),
+            layout: layout.clone(),
+            camera: String::from(camera),
+        }
+    }
+
+    /// Archive directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn items(&self) -> Result<Vec<Item>> {
+        let mut items: Vec<Item> = vec![];
+        for file in self.layout.list(&self.root, Some(&self.camera))? {
+            items.push(Item {
+                file,
+                segment: false,
+            });
+        }
+        for file in self
+            .layout
+            .segments()
+            .list(&self.root, Some(&self.camera))?
+        {
+            items.push(Item {
+                file,
+                segment: true,
+            });
+        }
+
+        items.sort_by_key(|i| i.file.time);
+        Ok(items)
+    }
+
+    fn expand(&self, item: &Item) -> Result<Vec<ArchivedFrame>> {
+        let path = item.file.path.clone();
+        if !item.segment {
+            return Ok(vec![ArchivedFrame {
+                time: item.file.time,
+                frame: FrameRef::File(path),
+            }]);
+        }
+
+        let reader = AviReader::open(path_str(&path)?)?;
+        let start = reader.start_time().unwrap_or(item.file.time);
+        let fps = reader.fps() as u64;
+        Ok((0..reader.frame_count())
+            .map(|idx| ArchivedFrame {
+                time: start + idx as u64 * 1000 / fps,
+                frame: FrameRef::Segment(path.clone(), idx),
+            })
+            .collect())
+    }
+
+    /// Frames of [from, to] sorted by time
+    pub fn frames(&self, from: u64, to: u64) -> Result<Vec<ArchivedFrame>> {
+        let items = self.items()?;
+        let mut res: Vec<ArchivedFrame> = vec![];
+
+        for (idx, item) in items.iter().enumerate() {
+            if item.file.time > to {
+                break;
+            }
+            // Segment lasts till the next item at most, which starts within the minute of its name
+            let last = match items.get(idx + 1) {
+                Some(next) if item.segment => next.file.time + 60 * 1000,
+                Some(_) => item.file.time,
+                None if item.segment => u64::MAX,
+                None => item.file.time,
+            };
+            if last < from {
+                continue;
+            }
+
+            match self.expand(item) {
+                Ok(frames) => res.extend(
+                    frames
+                        .into_iter()
+                        .filter(|f| f.time >= from && f.time <= to),
+                ),
+                Err(err) => println!("Can't read {}: {}", item.file.path.display(), err),
+            }
+        }
+
+        // Segment names are moved to the next free minute, so the time stored in a segment may
+        // be before the time of the previous item, e.g. after the clock was set back
+        res.sort_by_key(|f| f.time);
+        Ok(res)
+    }
+
+    /// Time ranges of recording in [from, to]: frames further apart than gap ms start a new range
+    pub fn ranges(&self, from: u64, to: u64, gap: u64) -> Result<Vec<Range>> {
+        let mut res: Vec<Range> = vec![];
+
+        for frame in self.frames(from, to)? {
+            match res.last_mut() {
+                Some(range) if frame.time <= range.end.saturating_add(gap) => {
+                    range.end = frame.time;
+                    range.frames += 1;
+                }
+                _ => res.push(Range {
+                    start: frame.time,
+                    end: frame.time,
+                    frames: 1,
+                }),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Frame closest to the time
+    pub fn nearest(&self, time: u64) -> Result<Option<ArchivedFrame>> {
+        let items = self.items()?;
+        let idx = items.partition_point(|i| i.file.time <= time);
+        let mut candidates: Vec<ArchivedFrame> = vec![];
+
+        if idx > 0 {
+            candidates.extend(self.expand(&items[idx - 1])?);
+        }
+        if let Some(next) = items.get(idx) {
+            candidates.extend(self.expand(next)?.into_iter().take(1));
+        }
+
+        Ok(candidates.into_iter().min_by_key(|f| f.time.abs_diff(time)))
+    }
+}
+
+/// Write frames into AVI file played with the given frame rate. The video has the size of the
+/// first frame, frames of other sizes are skipped. Returns number of written frames.
+pub fn write_avi(frames: &[ArchivedFrame], path: &Path, fps: u32) -> Result<usize> {
+    write_avi_progress(frames, path, fps, &mut |_| true)
+}
+
+/// Write frames into AVI file reporting number of processed frames after each of them, writing
+/// is cancelled when progress returns false.
+pub fn write_avi_progress(
+    frames: &[ArchivedFrame],
+    path: &Path,
+    fps: u32,
+    progress: &mut dyn FnMut(usize) -> bool,
+) -> Result<usize> {
+    let mut reader = FrameReader::new();
+    let mut writer: Option<AviWriter> = None;
+    let mut count = 0;
+
+    for (idx, frame) in frames.iter().enumerate() {
+        if idx > 0 && !progress(idx) {
+            if let Some(mut w) = writer {
+                w.close()?;
+            }
+            return Err("Cancelled".into());
+        }
+
+        let jpeg = match reader.read(&frame.frame) {
+            Ok(jpeg) => jpeg,
+            Err(err) => {
+                println!("Can't read frame {}: {}", frame.time, err);
+                continue;
+            }
+        };
+        let size = match jpeg_size(&jpeg) {
+            Some(size) => size,
+            None => continue,
+        };
+
+        let w = match writer {
+            Some(ref mut w) => w,
+            None => writer.insert(AviWriter::with_start_time(
+                path_str(path)?,
+                size.0,
+                size.1,
+                fps,
+                frame.time,
+            )?),
+        };
+        if (w.width(), w.height()) != size {
+            continue;
+        }
+        if !w.fits(jpeg.len())? {
+            break;
+        }
+        w.add_frame(&jpeg)?;
+        count += 1;
+    }
+
+    match writer {
+        Some(mut w) => w.close()?,
+        None => return Err("No frames to write".into()),
+    }
+    progress(frames.len());
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SegmentWriter;
+    use super::*;
+    use crate::test_util::solid_jpeg;
+
+    #[test]
+    fn test_playback() {
+        let root = crate::test_util::temp_path("playback");
+        let layout = Layout::parse("{camera}/frame_{ms}.jpg").unwrap();
+
+        // JPEG files at 1 fps: 10:00:00 - 10:00:02, AVI segment at 2 fps from 10:01:05
+        let t0: u64 = 1709287200000;
+        for i in 0..3 {
+            let path = root.join(layout.path(t0 + i * 1000, "cam"));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, solid_jpeg(8, 8, i as u8)).unwrap();
+        }
+        let t1 = t0 + 65_000;
+        let mut segments = SegmentWriter::new(&root, &layout, "cam", 2, 600_000);
+        for i in 0..4 {
+            segments.add(t1 + i * 500, &solid_jpeg(8, 8, 100)).unwrap();
+        }
+        segments.close().unwrap();
+
+        let pb = Playback::new(&root, &layout, "cam");
+        let frames = pb.frames(0, u64::MAX).unwrap();
+        let times: Vec<u64> = frames.iter().map(|f| f.time).collect();
+        assert_eq!(
+            times,
+            vec![t0, t0 + 1000, t0 + 2000, t1, t1 + 500, t1 + 1000, t1 + 1500]
+        );
+        assert_eq!(pb.frames(t0 + 500, t1 + 600).unwrap().len(), 4);
+
+        assert_eq!(
+            pb.ranges(0, u64::MAX, 5000).unwrap(),
+            vec![
+                Range {
+                    start: t0,
+                    end: t0 + 2000,
+                    frames: 3
+                },
+                Range {
+                    start: t1,
+                    end: t1 + 1500,
+                    frames: 4
+                }
+            ]
+        );
+        assert_eq!(pb.ranges(0, u64::MAX, u64::MAX).unwrap().len(), 1);
+
+        let f = pb.nearest(t0 + 1400).unwrap().unwrap();
+        assert_eq!(f.time, t0 + 1000);
+        assert_eq!(
+            FrameReader::new().read(&f.frame).unwrap(),
+            solid_jpeg(8, 8, 1)
+        );
+        assert_eq!(pb.nearest(t1 - 1000).unwrap().unwrap().time, t1);
+        assert_eq!(pb.nearest(t0 + 61_000).unwrap().unwrap().time, t1);
+        assert_eq!(pb.nearest(t1 + 1300).unwrap().unwrap().time, t1 + 1500);
+        assert_eq!(pb.nearest(0).unwrap().unwrap().time, t0);
+
+        let avi = root.join("download.avi");
+        assert_eq!(write_avi(&frames, &avi, 5).unwrap(), 7);
+        let mut ar = AviReader::open(avi.to_str().unwrap()).unwrap();
+        assert_eq!((ar.fps(), ar.frame_count()), (5, 7));
+        assert_eq!(ar.frame(2).unwrap(), solid_jpeg(8, 8, 2));
+        assert!(write_avi(&[], &avi, 5).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(pb.frames(0, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_segment_before_its_name() {
+        let root = crate::test_util::temp_path("playback-order");
+        let layout = Layout::parse("{camera}/frame_{ms}.jpg").unwrap();
+
+        // Clock is set back by 30 s: the second segment starts before the first one, its name
+        // is moved to the next minute
+        let t: u64 = 1709287200000;
+        let mut segments = SegmentWriter::new(&root, &layout, "cam", 1, 600_000);
+        segments.add(t + 30_000, &solid_jpeg(8, 8, 1)).unwrap();
+        segments.close().unwrap();
+        segments.add(t, &solid_jpeg(8, 8, 2)).unwrap();
+        segments.add(t + 1000, &solid_jpeg(8, 8, 2)).unwrap();
+        assert_eq!(
+            segments.close().unwrap(),
+            Some(root.join("cam/20240301-1001.avi"))
+        );
+
+        let pb = Playback::new(&root, &layout, "cam");
+        let times: Vec<u64> = pb
+            .frames(0, u64::MAX)
+            .unwrap()
+            .iter()
+            .map(|f| f.time)
+            .collect();
+        assert_eq!(times, vec![t, t + 1000, t + 30_000]);
+        assert_eq!(pb.ranges(0, u64::MAX, 5000).unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+// Retention of the archive: old frames are removed when the archive grows beyond its limits
+
+use super::layout::prune_empty_dirs;
+use super::{FrameFile, Layout, Result};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retention {
+    /// Maximum age of frames in ms
+    pub max_age: Option<u64>,
+    /// Maximum number of frames
+    pub max_count: Option<usize>,
+    /// Maximum total size of frames in bytes
+    pub max_bytes: Option<u64>,
+    /// Minimum free space on the disk in bytes
+    pub min_free: Option<u64>,
+    /// How often the archive is checked in ms
+    pub interval: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            max_age: None,
+            max_count: None,
+            max_bytes: None,
+            min_free: None,
+            interval: 60 * 1000,
+        }
+    }
+}
+
+/// Parse size with optional suffix: 1024, 512K, 100M, 2G, 1T
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last() {
+        Some((idx, c)) if c.is_ascii_alphabetic() => {
+            let mult: u64 = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("Invalid size suffix in '{}'", s).into()),
+            };
+            (&s[..idx], mult)
+        }
+        _ => (s, 1),
+    };
+
+    match num.trim().parse::<u64>().map(|n| n.checked_mul(mult)) {
+        Ok(Some(n)) => Ok(n),
+        Ok(None) => Err(format!("Size '{}' is too large", s).into()),
+        Err(_) => Err(format!("Invalid size '{}'", s).into()),
+    }
+}
+
+/// Free space available to the user on the file system containing the path
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } != 0 {
+        return None;
+    }
+
+    Some(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none()
+            && self.max_count.is_none()
+            && self.max_bytes.is_none()
+            && self.min_free.is_none()
+    }
+
+    /// Select frames to remove: frames must be sorted oldest first.
+    /// Returns number of the oldest frames to remove and the reason for each of them.
+    pub fn plan(&self, frames: &[FrameFile], now: u64, free: Option<u64>) -> Vec<&'static str> {
+        let mut count = frames.len();
+        let mut bytes: u64 = frames.iter().map(|f| f.size).sum();
+        let mut freed: u64 = 0;
+        let mut res: Vec<&'static str> = vec![];
+
+        for frame in frames {
+            let reason = if self
+                .max_age
+                .is_some_and(|age| frame.time.saturating_add(age) < now)
+            {
+                "age"
+            } else if self.max_count.is_some_and(|max| count > max) {
+                "count"
+            } else if self.max_bytes.is_some_and(|max| bytes > max) {
+                "quota"
+            } else if let (Some(min), Some(free)) = (self.min_free, free) {
+                if free + freed < min {
+                    "free space"
+                } else {
+                    break;
+                }
+            } else {
+                break;
+            };
+
+            res.push(reason);
+            count -= 1;
+            bytes -= frame.size;
+            freed += frame.size;
+        }
+
+        res
+    }
+
+    /// Remove frames of the camera exceeding the limits, oldest first, and directories left
+    /// empty. The file being written (AVI segment) is kept. Returns removed frames.
+    pub fn enforce(
+        &self,
+        path: &Path,
+        layout: &Layout,
+        camera: &str,
+        now: u64,
+        keep: Option<&Path>,
+    ) -> Result<Vec<FrameFile>> {
+        if self.is_unlimited() {
+            return Ok(vec![]);
+        }
+
+        let mut frames = layout.list(path, Some(camera))?;
+        frames.retain(|f| Some(f.path.as_path()) != keep);
+        let free = match self.min_free {
+            Some(_) => free_space(path),
+            None => None,
+        };
+
+        let mut removed: Vec<FrameFile> = vec![];
+        for (frame, reason) in frames.iter().zip(self.plan(&frames, now, free)) {
+            match std::fs::remove_file(&frame.path) {
+                Ok(()) => {
+                    println!("Removed {} ({})", frame.path.display(), reason);
+                    prune_empty_dirs(path, &frame.path);
+                    removed.push(frame.clone());
+                }
+                Err(err) => println!("Can't remove {}: {}", frame.path.display(), err),
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::list_frames;
+    use super::*;
+    use std::path::PathBuf;
+
+    fn frames(times: &[u64], size: u64) -> Vec<FrameFile> {
+        times
+            .iter()
+            .map(|t| FrameFile {
+                time: *t,
+                path: PathBuf::from(format!("frame_{}.jpg", t)),
+                size,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let list = frames(&[1000, 2000, 3000, 4000, 5000], 100);
+
+        let r = Retention::default();
+        assert!(r.plan(&list, 10000, None).is_empty());
+
+        let r = Retention {
+            max_age: Some(2500),
+            ..Retention::default()
+        };
+        assert_eq!(r.plan(&list, 5000, None), vec!["age", "age"]);
+        let r = Retention {
+            max_age: Some(u64::MAX),
+            ..Retention::default()
+        };
+        assert!(r.plan(&list, 5000, None).is_empty());
+
+        let r = Retention {
+            max_count: Some(4),
+            max_bytes: Some(250),
+            ..Retention::default()
+        };
+        assert_eq!(r.plan(&list, 5000, None), vec!["count", "quota", "quota"]);
+
+        let r = Retention {
+            min_free: Some(1000),
+            ..Retention::default()
+        };
+        assert_eq!(r.plan(&list, 5000, Some(850)).len(), 2);
+        // Unknown free space doesn't remove anything
+        assert!(r.plan(&list, 5000, None).is_empty());
+    }
+
+    #[test]
+    fn test_enforce() {
+        let dir = crate::test_util::temp_path("retention");
+        std::fs::create_dir_all(&dir).unwrap();
+        for t in [1000, 2000, 3000] {
+            std::fs::write(dir.join(format!("frame_{}.jpg", t)), b"jpeg").unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), b"text").unwrap();
+
+        let r = Retention {
+            max_count: Some(1),
+            ..Retention::default()
+        };
+        let removed = r
+            .enforce(&dir, &Layout::default(), "cam", 3000, None)
+            .unwrap();
+        assert_eq!(removed.len(), 2);
+        let left = list_frames(&dir).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].time, 3000);
+        assert!(dir.join("notes.txt").exists());
+
+        // Partitioned layout: only frames of the camera are counted, empty directories are removed
+        let layout = Layout::parse("{camera}/{HH}/frame_{ms}.jpg").unwrap();
+        for (camera, t) in [("cam", 1000), ("cam", 3600 * 1000), ("other", 0)] {
+            let path = dir.join(layout.path(t, camera));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"jpeg").unwrap();
+        }
+        let removed = r.enforce(&dir, &layout, "cam", 3000, None).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!dir.join("cam/00").exists());
+        assert!(dir.join("cam/01").exists());
+        assert!(dir.join("other/00").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
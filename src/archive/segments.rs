@@ -0,0 +1,260 @@
+// Archive in AVI segments: frames are appended to MJPEG AVI files which are rotated every few
+// minutes, when the frame size changes or when the file approaches the AVI size limit. Frames of
+// a segment are timed by its start time and frame rate, so time points the archive thread was
+// late for are filled with the previous frame and a gap longer than a second starts a new segment.
+
+use super::{Layout, Result};
+use crate::mjpeg::{jpeg_size, AviWriter};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_SEGMENT_MINUTES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Every frame is written into its own JPEG file
+    Files,
+    /// Frames are appended to AVI segments of the given length in ms
+    Avi(u64),
+}
+
+impl Mode {
+    /// Parse mode name: files or avi
+    pub fn parse(name: &str, segment_minutes: u32) -> Result<Mode> {
+        match name {
+            "files" => Ok(Mode::Files),
+            "avi" if segment_minutes == 0 => Err("Segment length must be positive".into()),
+            "avi" => Ok(Mode::Avi(segment_minutes as u64 * 60 * 1000)),
+            _ => Err(format!("Unknown archive mode '{}'", name).into()),
+        }
+    }
+}
+
+struct Segment {
+    writer: AviWriter,
+    path: PathBuf,
+    // Time of the first frame and the time the segment is rotated at
+    start: u64,
+    end: u64,
+    // Previous frame, repeated for skipped time points
+    last: Vec<u8>,
+}
+
+impl Segment {
+    // Number of time points skipped before the frame of the time
+    fn missed(&self, time: u64, fps: u32) -> u64 {
+        let slot = (time.saturating_sub(self.start) * fps as u64 + 500) / 1000;
+        slot.saturating_sub(self.writer.frame_count() as u64)
+    }
+}
+
+/// Writer of AVI segments named YYYYMMDD-HHMM.avi by the minute of their first frame, exact
+/// time of the first frame is stored in the segment
+pub struct SegmentWriter {
+    root: PathBuf,
+    layout: Layout,
+    camera: String,
+    fps: u32,
+    length: u64,
+    current: Option<Segment>,
+}
+
+impl SegmentWriter {
+    pub fn new(root: &Path, layout: &Layout, camera: &str, fps: u32, length: u64) -> SegmentWriter {
+        SegmentWriter {
+            root: PathBuf::from(root),
+            layout: layout.segments(),
+            camera: String::from(camera),
+            fps,
+            length,
+            current: None,
+        }
+    }
+
+    /// Path of the segment being written
+    pub fn current(&self) -> Option<&Path> {
+        self.current.as_ref().map(|s| s.path.as_path())
+    }
+
+    fn open(&mut self, time: u64, width: u32, height: u32) -> Result<&mut Segment> {
+        // Names are unique: if the minute is taken already (segment was rotated because of its
+        // size or the archive was restarted) the next free minute is used
+        let mut name_time = time;
+        let mut path = self.root.join(self.layout.path(name_time, &self.camera));
+        while path.exists() {
+            name_time += 60 * 1000;
+            path = self.root.join(self.layout.path(name_time, &self.camera));
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let name = match path.to_str() {
+            Some(name) => name,
+            None => return Err(format!("Invalid segment path {}", path.display()).into()),
+        };
+        let writer = AviWriter::with_start_time(name, width, height, self.fps, time)?;
+
+        Ok(self.current.insert(Segment {
+            writer,
+            path,
+            start: time,
+            end: time - time % self.length + self.length,
+            last: vec![],
+        }))
+    }
+
+    /// Append the frame of the time point to the current segment, starting a new one if needed.
+    /// Skipped time points get the previous frame. Returns path of the segment finished before
+    /// the frame.
+    pub fn add(&mut self, time: u64, jpeg: &[u8]) -> Result<Option<PathBuf>> {
+        let (width, height) = match jpeg_size(jpeg) {
+            Some(size) => size,
+            None => return Err("Frame is not a JPEG image".into()),
+        };
+
+        let fps = self.fps;
+        let rotate = match self.current {
+            Some(ref mut s) => {
+                let missed = s.missed(time, fps);
+                time >= s.end
+                    || missed > fps as u64
+                    || (s.writer.width(), s.writer.height()) != (width, height)
+                    || !s
+                        .writer
+                        .fits(jpeg.len() + missed as usize * (s.last.len() + 24))?
+            }
+            None => false,
+        };
+        let finished = if rotate { self.close()? } else { None };
+
+        let segment = match self.current {
+            Some(ref mut s) => s,
+            None => self.open(time, width, height)?,
+        };
+        for _ in 0..segment.missed(time, fps) {
+            segment.writer.add_frame(&segment.last)?;
+        }
+        segment.writer.add_frame(jpeg)?;
+        segment.last = jpeg.to_vec();
+
+        Ok(finished)
+    }
+
+    /// Finalize the current segment. Returns its path if there was one.
+    pub fn close(&mut self) -> Result<Option<PathBuf>> {
+        match self.current.take() {
+            Some(mut s) => {
+                s.writer.close()?;
+                Ok(Some(s.path))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mjpeg::AviReader;
+    use crate::test_util::solid_jpeg;
+
+    // 2024-03-01 12:34:56.789 UTC
+    const T: u64 = 1709296496789;
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(Mode::parse("files", 0).unwrap(), Mode::Files);
+        assert_eq!(Mode::parse("avi", 5).unwrap(), Mode::Avi(300 * 1000));
+        assert!(Mode::parse("avi", 0).is_err());
+        assert!(Mode::parse("mkv", 5).is_err());
+    }
+
+    #[test]
+    fn test_segments() {
+        let root = crate::test_util::temp_path("avi");
+        let layout = Layout::parse("{camera}/frame_{ms}.jpg").unwrap();
+        let mut w = SegmentWriter::new(&root, &layout, "cam", 2, 10 * 60 * 1000);
+        let small = solid_jpeg(8, 8, 128);
+
+        assert_eq!(w.add(T, &small).unwrap(), None);
+        assert_eq!(w.add(T + 500, &small).unwrap(), None);
+        assert_eq!(
+            w.current(),
+            Some(root.join("cam/20240301-1234.avi").as_path())
+        );
+        assert!(w.add(T, b"not a jpeg").is_err());
+
+        // 12:40 starts the next segment
+        let t = T - T % 600_000 + 600_000;
+        let first = w.add(t, &small).unwrap().unwrap();
+        assert_eq!(first, root.join("cam/20240301-1234.avi"));
+        let mut ar = AviReader::open(first.to_str().unwrap()).unwrap();
+        assert_eq!((ar.fps(), ar.width(), ar.height()), (2, 8, 8));
+        assert_eq!(ar.frame_count(), 2);
+        assert_eq!(ar.frame(1).unwrap(), small);
+
+        // Frame size change rotates the segment, its name is moved to the next free minute
+        let second = w.add(t + 500, &solid_jpeg(16, 8, 128)).unwrap().unwrap();
+        assert_eq!(second, root.join("cam/20240301-1240.avi"));
+        assert_eq!(
+            w.current(),
+            Some(root.join("cam/20240301-1241.avi").as_path())
+        );
+        let third = w.close().unwrap().unwrap();
+        assert_eq!(
+            AviReader::open(third.to_str().unwrap()).unwrap().width(),
+            16
+        );
+        assert_eq!(w.close().unwrap(), None);
+
+        let list = layout.segments().list(&root, Some("cam")).unwrap();
+        let paths: Vec<PathBuf> = list.into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, vec![first, second, third]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_skipped_time_points() {
+        let root = crate::test_util::temp_path("avi-skip");
+        let layout = Layout::parse("{camera}/frame_{ms}.jpg").unwrap();
+        let mut w = SegmentWriter::new(&root, &layout, "cam", 4, 10 * 60 * 1000);
+        let (a, b, c) = (
+            solid_jpeg(8, 8, 0),
+            solid_jpeg(8, 8, 128),
+            solid_jpeg(8, 8, 255),
+        );
+
+        // 250 ms and 500 ms are skipped, the frame of 750 ms keeps its time
+        let t = T - T % 1000;
+        w.add(t, &a).unwrap();
+        w.add(t + 750, &b).unwrap();
+        w.add(t + 1000, &c).unwrap();
+        // Gap longer than a second starts a new segment at the time of its frame
+        let first = w.add(t + 2500, &a).unwrap().unwrap();
+        let second = w.close().unwrap().unwrap();
+
+        let mut ar = AviReader::open(first.to_str().unwrap()).unwrap();
+        assert_eq!(ar.start_time(), Some(t));
+        let frames: Vec<Vec<u8>> = (0..ar.frame_count())
+            .map(|i| ar.frame(i).unwrap())
+            .collect();
+        assert_eq!(frames, vec![a.clone(), a.clone(), a.clone(), b, c]);
+        let ar = AviReader::open(second.to_str().unwrap()).unwrap();
+        assert_eq!((ar.start_time(), ar.frame_count()), (Some(t + 2500), 1));
+
+        let pb = crate::archive::Playback::new(&root, &layout, "cam");
+        let times: Vec<u64> = pb
+            .frames(0, u64::MAX)
+            .unwrap()
+            .iter()
+            .map(|f| f.time)
+            .collect();
+        assert_eq!(
+            times,
+            vec![t, t + 250, t + 500, t + 750, t + 1000, t + 2500]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
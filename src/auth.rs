@@ -0,0 +1,607 @@
+/// Authentication of web clients.
+/// Users and API tokens are kept in a JSON file, only hashes of passwords and tokens are stored:
+///     {
+///         "users": [{"name": "alice", "role": "admin", "password": "pbkdf2-sha256$100000$<salt>$<hash>"}],
+///         "tokens": [{"name": "nvr", "role": "viewer", "hash": "sha256$<hash>"}]
+///     }
+/// Salts and hashes are base64. Browsers log in with HTTP Basic authentication, scripts send
+/// "Authorization: Bearer <token>". Viewers can watch the camera and the archive and call
+/// read-only API methods, admins can call all methods. Roles of the methods are registered with
+/// them, see web::MethodInfo.
+use crate::archive;
+use crate::datetime;
+use crate::web::{self, ApiError, ERR_INVALID_ARGS};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub const DEFAULT_FILE: &str = "httpcam-users.json";
+
+// Hashing is slow on purpose, tests use less iterations
+const ITERATIONS: u32 = if cfg!(test) { 1000 } else { 100_000 };
+const SALT_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+const TOKEN_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Result<Role> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(Box::new(ApiError::new(
+                ERR_INVALID_ARGS,
+                &format!("Unknown role '{}', expected viewer or admin", s),
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+fn random_bytes(size: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; size];
+    getrandom::getrandom(&mut buf).map_err(|err| format!("Can't get random bytes: {}", err))?;
+    Ok(buf)
+}
+
+// Comparison time doesn't depend on the position of the first difference
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn pbkdf2(password: &str, salt: &[u8], iterations: u32) -> [u8; HASH_SIZE] {
+    let mut hash = [0u8; HASH_SIZE];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+    hash
+}
+
+/// Hash the password with a random salt: pbkdf2-sha256$<iterations>$<salt>$<hash>
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = random_bytes(SALT_SIZE)?;
+    let hash = pbkdf2(password, &salt, ITERATIONS);
+    Ok(format!(
+        "pbkdf2-sha256${}${}${}",
+        ITERATIONS,
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    ))
+}
+
+/// Check the password against the hash made by hash_password
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    if parts.len() != 4 || parts[0] != "pbkdf2-sha256" {
+        return false;
+    }
+    let (iterations, salt, expected) = match (
+        parts[1].parse::<u32>(),
+        STANDARD_NO_PAD.decode(parts[2]),
+        STANDARD_NO_PAD.decode(parts[3]),
+    ) {
+        (Ok(iterations), Ok(salt), Ok(expected)) if iterations > 0 => (iterations, salt, expected),
+        _ => return false,
+    };
+    equal(&pbkdf2(password, &salt, iterations), &expected)
+}
+
+// Tokens are random, so a fast hash is enough
+fn hash_token(token: &str) -> String {
+    format!(
+        "sha256${}",
+        STANDARD_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+    )
+}
+
+/// New random API token: hex string
+pub fn generate_token() -> Result<String> {
+    Ok(random_bytes(TOKEN_SIZE)?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// Password hash
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub name: String,
+    pub role: Role,
+    /// Token hash
+    pub hash: String,
+}
+
+fn entry_json(name: &str, role: Role, key: &str, hash: &str) -> JsonValue {
+    let mut res = HashMap::<String, JsonValue>::new();
+    res.insert(String::from("name"), JsonValue::String(String::from(name)));
+    res.insert(
+        String::from("role"),
+        JsonValue::String(String::from(role.name())),
+    );
+    res.insert(String::from(key), JsonValue::String(String::from(hash)));
+    JsonValue::Object(res)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(':') || name.chars().any(char::is_control) {
+        return Err(Box::new(ApiError::new(
+            ERR_INVALID_ARGS,
+            &format!("Invalid name '{}'", name),
+        )));
+    }
+    Ok(())
+}
+
+/// Users and API tokens of the file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Users {
+    pub users: Vec<User>,
+    pub tokens: Vec<Token>,
+}
+
+impl Users {
+    /// Load the file, there are no users if it doesn't exist
+    pub fn load(path: &Path) -> Result<Users> {
+        if !path.exists() {
+            return Ok(Users::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        Users::from_json(&content.parse()?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = self.to_json().format()?;
+        archive::write_file_atomic_mode(path, content.as_bytes(), 0o600)
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<Users> {
+        let mut res = Users::default();
+        let list = |name: &str| -> Result<Vec<JsonValue>> {
+            match web::optional_arg(value, name) {
+                Some(JsonValue::Array(list)) => Ok(list.clone()),
+                Some(_) => Err(format!("{} must be an array", name).into()),
+                None => Ok(vec![]),
+            }
+        };
+
+        for user in list("users")? {
+            res.users.push(User {
+                name: web::string_arg(&user, "name")?.clone(),
+                role: Role::parse(web::string_arg(&user, "role")?)?,
+                password: web::string_arg(&user, "password")?.clone(),
+            });
+        }
+        for token in list("tokens")? {
+            res.tokens.push(Token {
+                name: web::string_arg(&token, "name")?.clone(),
+                role: Role::parse(web::string_arg(&token, "role")?)?,
+                hash: web::string_arg(&token, "hash")?.clone(),
+            });
+        }
+        Ok(res)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let users = self
+            .users
+            .iter()
+            .map(|u| entry_json(&u.name, u.role, "password", &u.password))
+            .collect();
+        let tokens = self
+            .tokens
+            .iter()
+            .map(|t| entry_json(&t.name, t.role, "hash", &t.hash))
+            .collect();
+
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("users"), JsonValue::Array(users));
+        res.insert(String::from("tokens"), JsonValue::Array(tokens));
+        JsonValue::Object(res)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.tokens.is_empty()
+    }
+
+    /// Add the user or change password and role of the existing one
+    pub fn add_user(&mut self, name: &str, role: Role, password: &str) -> Result<()> {
+        check_name(name)?;
+        if password.is_empty() {
+            return Err(Box::new(ApiError::new(
+                ERR_INVALID_ARGS,
+                "Password must not be empty",
+            )));
+        }
+
+        let user = User {
+            name: String::from(name),
+            role,
+            password: hash_password(password)?,
+        };
+        match self.users.iter_mut().find(|u| u.name == name) {
+            Some(u) => *u = user,
+            None => self.users.push(user),
+        }
+        Ok(())
+    }
+
+    /// Create a new token replacing the one of the same name, returns the token. It is shown
+    /// only once, just its hash is kept.
+    pub fn add_token(&mut self, name: &str, role: Role) -> Result<String> {
+        check_name(name)?;
+        let token = generate_token()?;
+        self.tokens.retain(|t| t.name != name);
+        self.tokens.push(Token {
+            name: String::from(name),
+            role,
+            hash: hash_token(&token),
+        });
+        Ok(token)
+    }
+
+    /// Remove the user and the token of the name, returns false if there were none
+    pub fn remove(&mut self, name: &str) -> bool {
+        let count = self.users.len() + self.tokens.len();
+        self.users.retain(|u| u.name != name);
+        self.tokens.retain(|t| t.name != name);
+        self.users.len() + self.tokens.len() != count
+    }
+
+    pub fn check_password(&self, name: &str, password: &str) -> Option<Role> {
+        self.users
+            .iter()
+            .find(|u| u.name == name && verify_password(password, &u.password))
+            .map(|u| u.role)
+    }
+
+    /// Find the token, returns its name and role
+    pub fn check_token(&self, token: &str) -> Option<(&str, Role)> {
+        let hash = hash_token(token);
+        self.tokens
+            .iter()
+            .find(|t| equal(t.hash.as_bytes(), hash.as_bytes()))
+            .map(|t| (t.name.as_str(), t.role))
+    }
+}
+
+/// Authenticated client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Authentication of the web server. The file is loaded again when it changes, so users added
+/// by `httpcam user` take effect without restart, requests are open while there are no users.
+/// Passwords are hashed without holding the lock, so slow hashing doesn't stop other requests.
+/// Failed credentials are remembered, and after a wrong password the user name is locked for a
+/// second for the client address, so guessing passwords can't keep all web workers hashing.
+/// Other users of the address and other clients of the user can still log in. Clients behind a
+/// reverse proxy share its address, so wrong passwords sent through the proxy delay new logins
+/// of that user through it, sessions verified before are not affected.
+pub struct Auth {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    modified: Option<SystemTime>,
+    users: Arc<Users>,
+    // Incremented when users are reloaded, results of checks of older users are dropped
+    generation: u64,
+    // Identities of Authorization headers verified before by their hashes, so passwords
+    // aren't hashed on every request
+    verified: HashMap<String, Identity>,
+    // Hashes of Authorization headers which failed
+    failed: HashSet<String>,
+    // Times of the last wrong passwords of user names by client addresses
+    locked: HashMap<(String, String), u64>,
+}
+
+// Time in ms a user name is locked for the client address after a wrong password
+const LOCK_TIME: u64 = 1000;
+// Remembered failed credentials and locked names, the oldest ones are dropped past the limit
+const MAX_FAILED: usize = 1000;
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl State {
+    fn reload(&mut self, path: &Path) {
+        let time = modified(path);
+        if time.is_none() || time == self.modified {
+            return;
+        }
+        match Users::load(path) {
+            Ok(users) => {
+                println!("Users reloaded from {}", path.display());
+                self.users = Arc::new(users);
+                self.generation += 1;
+                self.verified.clear();
+                self.failed.clear();
+                self.locked.clear();
+            }
+            Err(err) => println!("Can't reload {}: {}", path.display(), err),
+        }
+        self.modified = time;
+    }
+}
+
+/// Credentials of Authorization header
+enum Credentials<'a> {
+    Password(&'a str, &'a str),
+    Token(&'a str),
+}
+
+impl Auth {
+    pub fn open(path: &Path) -> Result<Auth> {
+        Ok(Auth {
+            path: PathBuf::from(path),
+            state: Mutex::new(State {
+                modified: modified(path),
+                users: Arc::new(Users::load(path)?),
+                generation: 0,
+                verified: HashMap::new(),
+                failed: HashSet::new(),
+                locked: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Current users, reloaded if the file is changed
+    pub fn users(&self) -> Arc<Users> {
+        let mut state = self.state.lock().unwrap();
+        state.reload(&self.path);
+        Arc::clone(&state.users)
+    }
+
+    /// Authentication is required when there are users or tokens
+    pub fn enabled(&self) -> bool {
+        !self.users().is_empty()
+    }
+
+    /// Identify the client of the address by the value of Authorization header: Basic or Bearer
+    pub fn authenticate(&self, authorization: &str, address: &str) -> Option<Identity> {
+        self.authenticate_at(authorization, address, datetime::now_ms())
+    }
+
+    fn authenticate_at(&self, authorization: &str, address: &str, now: u64) -> Option<Identity> {
+        let key = STANDARD_NO_PAD.encode(Sha256::digest(authorization.as_bytes()));
+        let (users, generation) = {
+            let mut state = self.state.lock().unwrap();
+            state.reload(&self.path);
+            if let Some(identity) = state.verified.get(&key) {
+                return Some(identity.clone());
+            }
+            if state.failed.contains(&key) {
+                return None;
+            }
+            (Arc::clone(&state.users), state.generation)
+        };
+
+        let (scheme, value) = authorization.trim().split_once(' ')?;
+        let decoded;
+        let credentials = if scheme.eq_ignore_ascii_case("basic") {
+            decoded = base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|d| String::from_utf8(d).ok())?;
+            let (name, password) = decoded.split_once(':')?;
+            Credentials::Password(name, password)
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            Credentials::Token(value.trim())
+        } else {
+            return None;
+        };
+
+        if let Credentials::Password(name, _) = credentials {
+            let state = self.state.lock().unwrap();
+            if state
+                .locked
+                .get(&(String::from(address), String::from(name)))
+                .is_some_and(|time| now < time + LOCK_TIME)
+            {
+                return None;
+            }
+        }
+
+        let identity = match credentials {
+            Credentials::Password(name, password) => {
+                users.check_password(name, password).map(|role| Identity {
+                    name: String::from(name),
+                    role,
+                })
+            }
+            Credentials::Token(token) => users.check_token(token).map(|(name, role)| Identity {
+                name: String::from(name),
+                role,
+            }),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return identity;
+        }
+        match identity {
+            Some(ref identity) => {
+                state.verified.insert(key, identity.clone());
+            }
+            None => {
+                if state.failed.len() >= MAX_FAILED {
+                    state.failed.clear();
+                }
+                state.failed.insert(key);
+                if let Credentials::Password(name, _) = credentials {
+                    if state.locked.len() >= MAX_FAILED {
+                        state.locked.retain(|_, time| now < *time + LOCK_TIME);
+                    }
+                    state
+                        .locked
+                        .insert((String::from(address), String::from(name)), now);
+                }
+            }
+        }
+        identity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "192.0.2.1";
+
+    fn basic(name: &str, password: &str) -> String {
+        let credentials = format!("{}:{}", name, password);
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    #[test]
+    fn test_password() {
+        let hash = hash_password("secret").unwrap();
+        assert!(hash.starts_with(&format!("pbkdf2-sha256${}$", ITERATIONS)));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("Secret", &hash));
+        assert!(!verify_password("secret", "sha256$xxx"));
+        // Salt is random
+        assert_ne!(hash, hash_password("secret").unwrap());
+    }
+
+    #[test]
+    fn test_users() {
+        let mut users = Users::default();
+        users.add_user("alice", Role::Admin, "secret").unwrap();
+        users.add_user("bob", Role::Viewer, "pass").unwrap();
+        let token = users.add_token("nvr", Role::Viewer).unwrap();
+        assert_eq!(token.len(), TOKEN_SIZE * 2);
+        assert!(users.add_user("a:b", Role::Viewer, "x").is_err());
+        assert!(users.add_user("carol", Role::Viewer, "").is_err());
+
+        let json = users.to_json().stringify().unwrap();
+        assert!(!json.contains("secret"));
+        assert!(!json.contains(&token));
+        let loaded = Users::from_json(&json.parse().unwrap()).unwrap();
+        assert_eq!(loaded, users);
+
+        assert_eq!(loaded.check_password("alice", "secret"), Some(Role::Admin));
+        assert_eq!(loaded.check_password("bob", "secret"), None);
+        assert_eq!(loaded.check_token(&token), Some(("nvr", Role::Viewer)));
+        assert_eq!(loaded.check_token("0123"), None);
+
+        users.add_user("bob", Role::Admin, "new").unwrap();
+        assert_eq!(users.check_password("bob", "new"), Some(Role::Admin));
+        assert!(users.remove("nvr"));
+        assert!(!users.remove("nvr"));
+        assert_eq!(users.check_token(&token), None);
+    }
+
+    #[test]
+    fn test_auth() {
+        let path = crate::test_util::temp_path("users.json");
+        let auth = Auth::open(&path).unwrap();
+        assert!(!auth.enabled());
+
+        let mut users = Users::default();
+        users.add_user("alice", Role::Admin, "secret").unwrap();
+        users.add_user("bob", Role::Viewer, "secret").unwrap();
+        let token = users.add_token("nvr", Role::Viewer).unwrap();
+        users.save(&path).unwrap();
+        // Users added to the file enable authentication of the running server
+        assert!(auth.enabled());
+
+        let alice = Identity {
+            name: String::from("alice"),
+            role: Role::Admin,
+        };
+        assert_eq!(
+            auth.authenticate(&basic("alice", "secret"), ADDRESS),
+            Some(alice.clone())
+        );
+        // Verified headers are cached
+        assert_eq!(
+            auth.authenticate(&basic("alice", "secret"), ADDRESS),
+            Some(alice.clone())
+        );
+        assert_eq!(
+            auth.authenticate(&format!("Bearer {}", token), ADDRESS)
+                .map(|i| i.role),
+            Some(Role::Viewer)
+        );
+        assert_eq!(auth.authenticate("Bearer 1234", ADDRESS), None);
+        assert_eq!(auth.authenticate("Digest xyz", ADDRESS), None);
+        assert_eq!(auth.authenticate("Basic !!!", ADDRESS), None);
+
+        // Wrong password is remembered and locks the name for the address for a while, verified
+        // headers and other users of the address still work
+        let now = datetime::now_ms();
+        let attacker = "192.0.2.2";
+        assert_eq!(
+            auth.authenticate_at(&basic("alice", "wrong"), attacker, now),
+            None
+        );
+        let other = Auth::open(&path).unwrap();
+        assert_eq!(
+            other.authenticate_at(&basic("alice", "wrong"), attacker, now),
+            None
+        );
+        assert_eq!(
+            other
+                .authenticate_at(&basic("bob", "secret"), attacker, now + 10)
+                .map(|i| i.role),
+            Some(Role::Viewer)
+        );
+        assert_eq!(
+            other.authenticate_at(&basic("alice", "secret"), attacker, now + 10),
+            None
+        );
+        assert_eq!(
+            other.authenticate_at(&basic("alice", "secret"), attacker, now + LOCK_TIME),
+            Some(alice.clone())
+        );
+        assert_eq!(
+            auth.authenticate_at(&basic("alice", "secret"), attacker, now),
+            Some(alice.clone())
+        );
+
+        // Wrong passwords of other clients don't lock out the user
+        let other = Auth::open(&path).unwrap();
+        assert_eq!(
+            other.authenticate_at(&basic("alice", "wrong"), attacker, now),
+            None
+        );
+        assert_eq!(
+            other.authenticate_at(&basic("alice", "secret"), ADDRESS, now + 10),
+            Some(alice)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+/// Cameras of one instance configured by a JSON file:
+///     {"cameras": [
+///         {"id": "door", "source": "camera:/dev/v4l/by-id/usb-Logitech_C920-video-index0",
+///          "resolution": "1280x720/30", "output": "/srv/archive/door", "preset": "day"},
+///         {"id": "yard", "source": "camera:HD Pro Webcam C920"}
+///     ]}
+/// Ids are stable names used in URLs (/cam/<id>/...), the archive layout and the event log.
+/// Sources are the ones of --source, cameras are better given by device paths or names which
+/// don't change when devices are replugged. Only id and source are required: the archive of a
+/// camera without output is written into <id> subdirectory of --output, presets, schedule and
+/// motion settings are kept in httpcam-<id>-presets.json etc. next to the executable.
+use crate::presets;
+use crate::web;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraConfig {
+    pub id: String,
+    /// Frame source specification, see source::create
+    pub source: String,
+    pub resolution: Option<String>,
+    /// Archive directory
+    pub output: Option<PathBuf>,
+    /// Preset applied on startup
+    pub preset: Option<String>,
+    pub presets: PathBuf,
+    pub schedule: PathBuf,
+    pub motion: PathBuf,
+}
+
+pub(crate) fn check_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!(
+            "Invalid camera id '{}', letters, digits, - and _ are allowed",
+            id
+        )
+        .into());
+    }
+    Ok(())
+}
+
+impl CameraConfig {
+    /// Camera of the configuration, output is the default archive root
+    fn from_json(value: &JsonValue, output: Option<&Path>) -> Result<CameraConfig> {
+        let id = web::string_arg(value, "id")?;
+        check_id(id)?;
+        let string = |name: &str| -> Result<Option<String>> {
+            match web::optional_arg(value, name) {
+                Some(_) => Ok(Some(web::string_arg(value, name)?.clone())),
+                None => Ok(None),
+            }
+        };
+        let path = |name: &str| -> Result<PathBuf> {
+            match string(name)? {
+                Some(path) => Ok(PathBuf::from(path)),
+                None => Ok(presets::default_path(&format!(
+                    "httpcam-{}-{}.json",
+                    id, name
+                ))),
+            }
+        };
+
+        Ok(CameraConfig {
+            id: id.clone(),
+            source: web::string_arg(value, "source")?.clone(),
+            resolution: string("resolution")?,
+            output: match string("output")? {
+                Some(path) => Some(PathBuf::from(path)),
+                None => output.map(|root| root.join(id)),
+            },
+            preset: string("preset")?,
+            presets: path("presets")?,
+            schedule: path("schedule")?,
+            motion: path("motion")?,
+        })
+    }
+}
+
+pub fn parse(value: &JsonValue, output: Option<&Path>) -> Result<Vec<CameraConfig>> {
+    let list = match web::optional_arg(value, "cameras") {
+        Some(JsonValue::Array(list)) if !list.is_empty() => list,
+        _ => return Err("List of cameras is required".into()),
+    };
+
+    let mut cameras: Vec<CameraConfig> = vec![];
+    for item in list {
+        let camera = CameraConfig::from_json(item, output)?;
+        if cameras.iter().any(|c| c.id == camera.id) {
+            return Err(format!("Camera id '{}' is not unique", camera.id).into());
+        }
+        cameras.push(camera);
+    }
+    Ok(cameras)
+}
+
+/// Load cameras of the file, output is the directory of archives of cameras without output
+pub fn load(path: &Path, output: Option<&Path>) -> Result<Vec<CameraConfig>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+    let value = content
+        .parse::<JsonValue>()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    parse(&value, output).map_err(|err| format!("{}: {}", path.display(), err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let value: JsonValue = r#"{"cameras": [
+            {"id": "door", "source": "camera:/dev/v4l/by-id/usb-cam-video-index0",
+             "resolution": "1280x720/30", "output": "/srv/door", "preset": "day",
+             "motion": "/etc/door-motion.json"},
+            {"id": "yard_2", "source": "test"}
+        ]}"#
+        .parse()
+        .unwrap();
+        let cameras = parse(&value, Some(Path::new("/srv/archive"))).unwrap();
+        assert_eq!(cameras.len(), 2);
+        assert_eq!(cameras[0].id, "door");
+        assert_eq!(cameras[0].resolution.as_deref(), Some("1280x720/30"));
+        assert_eq!(cameras[0].output, Some(PathBuf::from("/srv/door")));
+        assert_eq!(cameras[0].preset.as_deref(), Some("day"));
+        assert_eq!(cameras[0].motion, PathBuf::from("/etc/door-motion.json"));
+        assert!(cameras[0].presets.ends_with("httpcam-door-presets.json"));
+        assert_eq!(cameras[1].source, "test");
+        assert_eq!(
+            cameras[1].output,
+            Some(PathBuf::from("/srv/archive/yard_2"))
+        );
+        assert_eq!(parse(&value, None).unwrap()[1].output, None);
+
+        for invalid in [
+            r#"{"cameras": []}"#,
+            r#"{"cameras": [{"id": "a"}]}"#,
+            r#"{"cameras": [{"id": "a/b", "source": "test"}]}"#,
+            r#"{"cameras": [{"id": "a", "source": "test"}, {"id": "a", "source": "test"}]}"#,
+            r#"{"cameras": [{"id": "a", "source": "test", "output": 1}]}"#,
+        ] {
+            assert!(
+                parse(&invalid.parse().unwrap(), None).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,612 @@
+/// Camera controls: validation and conversion between nokhwa controls and JSON API values.
+use crate::source::FrameSource;
+use crate::web::{ApiError, ERR_INVALID_ARGS, ERR_NOT_FOUND, ERR_OUT_OF_RANGE, ERR_READ_ONLY};
+use nokhwa::utils::{
+    CameraControl, ControlValueDescription, ControlValueSetter, KnownCameraControl,
+    KnownCameraControlFlag,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn api_err(code: i32, msg: &str) -> Box<dyn Error> {
+    Box::new(ApiError::new(code, msg))
+}
+
+// Labels of V4L2 menu controls. nokhwa reports menus as integer ranges without item names.
+const MENU_LABELS: [(u128, &[&str]); 5] = [
+    // V4L2_CID_POWER_LINE_FREQUENCY
+    (0x00980918, &["Disabled", "50 Hz", "60 Hz", "Auto"]),
+    // V4L2_CID_COLORFX
+    (
+        0x0098091f,
+        &[
+            "None",
+            "Black & White",
+            "Sepia",
+            "Negative",
+            "Emboss",
+            "Sketch",
+            "Sky Blue",
+            "Grass Green",
+            "Skin Whiten",
+            "Vivid",
+        ],
+    ),
+    // V4L2_CID_EXPOSURE_AUTO
+    (
+        0x009a0901,
+        &[
+            "Auto Mode",
+            "Manual Mode",
+            "Shutter Priority Mode",
+            "Aperture Priority Mode",
+        ],
+    ),
+    // V4L2_CID_AUTO_N_PRESET_WHITE_BALANCE
+    (
+        0x009a0914,
+        &[
+            "Manual",
+            "Auto",
+            "Incandescent",
+            "Fluorescent",
+            "Fluorescent H",
+            "Horizon",
+            "Daylight",
+            "Flash",
+            "Cloudy",
+            "Shade",
+        ],
+    ),
+    // V4L2_CID_EXPOSURE_METERING
+    (
+        0x009a0919,
+        &["Average", "Center Weighted", "Spot", "Matrix"],
+    ),
+];
+
+fn menu_labels(control: &CameraControl) -> Option<&'static [&'static str]> {
+    if let KnownCameraControl::Other(id) = control.control() {
+        for (cid, labels) in MENU_LABELS {
+            if cid == id {
+                return Some(labels);
+            }
+        }
+    }
+    None
+}
+
+fn label(labels: Option<&[&str]>, value: i64) -> String {
+    match labels {
+        Some(labels) if value >= 0 && (value as usize) < labels.len() => {
+            String::from(labels[value as usize])
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Menu item values of the control if it is a menu
+fn options(control: &CameraControl) -> Option<Vec<i64>> {
+    match control.description() {
+        ControlValueDescription::Enum { possible, .. } => Some(possible.clone()),
+        ControlValueDescription::IntegerRange { min, max, step, .. } => {
+            match menu_labels(control) {
+                Some(_) => {
+                    let step = std::cmp::max(*step, 1) as usize;
+                    Some((*min..*max + 1).step_by(step).collect())
+                }
+                None => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn flag_to_string(flag: &KnownCameraControlFlag) -> &'static str {
+    match flag {
+        KnownCameraControlFlag::Automatic => "automatic",
+        KnownCameraControlFlag::Manual => "manual",
+        KnownCameraControlFlag::Continuous => "continuous",
+        KnownCameraControlFlag::ReadOnly => "readonly",
+        KnownCameraControlFlag::WriteOnly => "writeonly",
+        KnownCameraControlFlag::Volatile => "volatile",
+        KnownCameraControlFlag::Disabled => "disabled",
+    }
+}
+
+fn str_json(s: &str) -> JsonValue {
+    JsonValue::String(String::from(s))
+}
+
+fn num_json(v: f64) -> JsonValue {
+    JsonValue::Number(v)
+}
+
+fn array_json(v: &[f64]) -> JsonValue {
+    JsonValue::Array(v.iter().map(|x| JsonValue::Number(*x)).collect())
+}
+
+/// Describe control as JSON object: name, type, flags, current and default values and limits
+pub fn control_to_json(control: &CameraControl) -> JsonValue {
+    let mut ctrl = HashMap::<String, JsonValue>::new();
+
+    ctrl.insert(String::from("name"), str_json(control.name()));
+    ctrl.insert(String::from("id"), str_json(&control.control().to_string()));
+    // "flag" is kept for the old clients, it contains only the first flag
+    let flags: Vec<JsonValue> = control
+        .flag()
+        .iter()
+        .map(|f| str_json(flag_to_string(f)))
+        .collect();
+    ctrl.insert(
+        String::from("flag"),
+        match flags.first() {
+            Some(f) => f.clone(),
+            None => str_json("unknown"),
+        },
+    );
+    ctrl.insert(String::from("flags"), JsonValue::Array(flags));
+    ctrl.insert(String::from("active"), JsonValue::Boolean(control.active()));
+    ctrl.insert(String::from("value"), value_to_json(&control.value()));
+
+    let (tp, default) = match control.description() {
+        ControlValueDescription::None => ("none", JsonValue::Null),
+        ControlValueDescription::Integer { default, step, .. } => {
+            ctrl.insert(String::from("step"), num_json(*step as f64));
+            ("integer", num_json(*default as f64))
+        }
+        ControlValueDescription::IntegerRange {
+            min,
+            max,
+            step,
+            default,
+            ..
+        } => {
+            ctrl.insert(String::from("min"), num_json(*min as f64));
+            ctrl.insert(String::from("max"), num_json(*max as f64));
+            ctrl.insert(String::from("step"), num_json(*step as f64));
+            (
+                if menu_labels(control).is_some() {
+                    "enum"
+                } else {
+                    "integer"
+                },
+                num_json(*default as f64),
+            )
+        }
+        ControlValueDescription::Float { default, step, .. } => {
+            ctrl.insert(String::from("step"), num_json(*step));
+            ("number", num_json(*default))
+        }
+        ControlValueDescription::FloatRange {
+            min,
+            max,
+            step,
+            default,
+            ..
+        } => {
+            ctrl.insert(String::from("min"), num_json(*min));
+            ctrl.insert(String::from("max"), num_json(*max));
+            ctrl.insert(String::from("step"), num_json(*step));
+            ("number", num_json(*default))
+        }
+        ControlValueDescription::Boolean { default, .. } => {
+            ("boolean", JsonValue::Boolean(*default))
+        }
+        ControlValueDescription::String { default, .. } => (
+            "string",
+            match default {
+                Some(d) => str_json(d),
+                None => JsonValue::Null,
+            },
+        ),
+        ControlValueDescription::Bytes { default, .. } => (
+            "bytes",
+            value_to_json(&ControlValueSetter::Bytes(default.clone())),
+        ),
+        ControlValueDescription::KeyValuePair { default, .. } => (
+            "key_value",
+            value_to_json(&ControlValueSetter::KeyValue(default.0, default.1)),
+        ),
+        ControlValueDescription::Point { default, .. } => {
+            ("point", array_json(&[default.0, default.1]))
+        }
+        ControlValueDescription::Enum { default, .. } => ("enum", num_json(*default as f64)),
+        ControlValueDescription::RGB { max, default, .. } => {
+            ctrl.insert(String::from("max"), array_json(&[max.0, max.1, max.2]));
+            ("rgb", array_json(&[default.0, default.1, default.2]))
+        }
+    };
+
+    ctrl.insert(String::from("type"), str_json(tp));
+    ctrl.insert(String::from("default"), default);
+
+    if let Some(options) = options(control) {
+        let labels = menu_labels(control);
+        let options = options
+            .iter()
+            .map(|v| {
+                let mut opt = HashMap::<String, JsonValue>::new();
+                opt.insert(String::from("value"), num_json(*v as f64));
+                opt.insert(String::from("label"), str_json(&label(labels, *v)));
+                JsonValue::Object(opt)
+            })
+            .collect();
+        ctrl.insert(String::from("options"), JsonValue::Array(options));
+    }
+
+    JsonValue::Object(ctrl)
+}
+
+/// Find control by name, names are compared case insensitively
+pub fn find_control(cam: &dyn FrameSource, name: &str) -> Result<CameraControl> {
+    for control in cam.controls()? {
+        if control.name().eq_ignore_ascii_case(name) {
+            return Ok(control);
+        }
+    }
+
+    Err(api_err(
+        ERR_NOT_FOUND,
+        &format!("Unknown control: {}", name),
+    ))
+}
+
+fn integer_from_json(value: &JsonValue) -> Result<i64> {
+    match value.get::<f64>() {
+        Some(v) if v.fract() == 0.0 => Ok(*v as i64),
+        _ => Err(api_err(ERR_INVALID_ARGS, "Integer value expected")),
+    }
+}
+
+fn float_from_json(value: &JsonValue) -> Result<f64> {
+    match value.get::<f64>() {
+        Some(v) => Ok(*v),
+        None => Err(api_err(ERR_INVALID_ARGS, "Number value expected")),
+    }
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(v: T, min: T, max: T) -> Result<()> {
+    if v < min || v > max {
+        return Err(api_err(
+            ERR_OUT_OF_RANGE,
+            &format!("Value {} is out of range [{}, {}]", v, min, max),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> Box<dyn Error> {
+    api_err(ERR_INVALID_ARGS, msg)
+}
+
+fn numbers_from_json(value: &JsonValue, n: usize) -> Result<Vec<f64>> {
+    let items = match value.get::<Vec<JsonValue>>() {
+        Some(items) if items.len() == n => items,
+        _ => return Err(invalid(&format!("Array of {} numbers expected", n))),
+    };
+
+    let mut res: Vec<f64> = vec![];
+    for item in items {
+        match item.get::<f64>() {
+            Some(v) if v.is_finite() => res.push(*v),
+            _ => return Err(invalid(&format!("Array of {} numbers expected", n))),
+        }
+    }
+
+    Ok(res)
+}
+
+// Menu item can be selected by its value or by its label
+fn option_from_json(control: &CameraControl, value: &JsonValue) -> Result<i64> {
+    let v = match value.get::<String>() {
+        Some(s) => {
+            let labels = menu_labels(control);
+            let options = options(control).unwrap_or_default();
+            match options.iter().find(|v| label(labels, **v) == *s) {
+                Some(v) => *v,
+                None => return Err(api_err(ERR_OUT_OF_RANGE, &format!("Unknown option: {}", s))),
+            }
+        }
+        None => integer_from_json(value)?,
+    };
+
+    if let Some(options) = options(control) {
+        if !options.contains(&v) {
+            return Err(api_err(ERR_OUT_OF_RANGE, &format!("Invalid option: {}", v)));
+        }
+    }
+
+    Ok(v)
+}
+
+/// Convert JSON value into a setter for the control, validating it
+pub fn setter_from_json(control: &CameraControl, value: &JsonValue) -> Result<ControlValueSetter> {
+    match control.description() {
+        ControlValueDescription::None => Ok(ControlValueSetter::None),
+        ControlValueDescription::Integer { .. } => {
+            Ok(ControlValueSetter::Integer(integer_from_json(value)?))
+        }
+        ControlValueDescription::IntegerRange { min, max, step, .. } => {
+            let v = option_from_json(control, value)?;
+            check_range(v, *min, *max)?;
+            if *step > 0 && (v - min) % step != 0 {
+                return Err(api_err(
+                    ERR_OUT_OF_RANGE,
+                    &format!("Value {} doesn't match step {} from {}", v, step, min),
+                ));
+            }
+            Ok(ControlValueSetter::Integer(v))
+        }
+        ControlValueDescription::Float { .. } => {
+            Ok(ControlValueSetter::Float(float_from_json(value)?))
+        }
+        ControlValueDescription::FloatRange { min, max, step, .. } => {
+            let v = float_from_json(value)?;
+            check_range(v, *min, *max)?;
+            if *step > 0.0 {
+                let n = (v - min) / step;
+                if (n - n.round()).abs() > 1e-6 {
+                    return Err(api_err(
+                        ERR_OUT_OF_RANGE,
+                        &format!("Value {} doesn't match step {} from {}", v, step, min),
+                    ));
+                }
+            }
+            Ok(ControlValueSetter::Float(v))
+        }
+        ControlValueDescription::Boolean { .. } => match value.get::<bool>() {
+            Some(b) => Ok(ControlValueSetter::Boolean(*b)),
+            None => Err(invalid("Boolean value expected")),
+        },
+        ControlValueDescription::String { .. } => match value.get::<String>() {
+            Some(s) => Ok(ControlValueSetter::String(s.clone())),
+            None => Err(invalid("String value expected")),
+        },
+        ControlValueDescription::Bytes { .. } => {
+            let items = match value.get::<Vec<JsonValue>>() {
+                Some(items) => items,
+                None => return Err(invalid("Array of bytes expected")),
+            };
+            let mut res: Vec<u8> = vec![];
+            for item in items {
+                match item.get::<f64>() {
+                    Some(b) if *b >= 0.0 && *b <= 255.0 && b.fract() == 0.0 => res.push(*b as u8),
+                    _ => return Err(invalid("Array of bytes expected")),
+                }
+            }
+            Ok(ControlValueSetter::Bytes(res))
+        }
+        ControlValueDescription::KeyValuePair { .. } => {
+            let obj = match value.get::<HashMap<String, JsonValue>>() {
+                Some(obj) => obj,
+                None => return Err(invalid("Expected {\"key\": <key>, \"value\": <value>}")),
+            };
+            let key = match obj.get("key") {
+                Some(k) => integer_from_json(k)?,
+                None => return Err(invalid("Key is required")),
+            };
+            let v = match obj.get("value") {
+                Some(v) => integer_from_json(v)?,
+                None => return Err(invalid("Value is required")),
+            };
+            Ok(ControlValueSetter::KeyValue(key as i128, v as i128))
+        }
+        ControlValueDescription::Point { .. } => {
+            let p = numbers_from_json(value, 2)?;
+            Ok(ControlValueSetter::Point(p[0], p[1]))
+        }
+        ControlValueDescription::Enum { .. } => Ok(ControlValueSetter::EnumValue(
+            option_from_json(control, value)?,
+        )),
+        ControlValueDescription::RGB { max, .. } => {
+            let c = numbers_from_json(value, 3)?;
+            check_range(c[0], 0.0, max.0)?;
+            check_range(c[1], 0.0, max.1)?;
+            check_range(c[2], 0.0, max.2)?;
+            Ok(ControlValueSetter::RGB(c[0], c[1], c[2]))
+        }
+    }
+}
+
+/// Convert control value into JSON
+pub fn value_to_json(value: &ControlValueSetter) -> JsonValue {
+    match value {
+        ControlValueSetter::None => JsonValue::Null,
+        ControlValueSetter::Integer(v) => num_json(*v as f64),
+        ControlValueSetter::Float(v) => num_json(*v),
+        ControlValueSetter::Boolean(v) => JsonValue::Boolean(*v),
+        ControlValueSetter::String(v) => str_json(v),
+        ControlValueSetter::Bytes(v) => {
+            JsonValue::Array(v.iter().map(|b| num_json(*b as f64)).collect())
+        }
+        ControlValueSetter::KeyValue(k, v) => {
+            let mut obj = HashMap::<String, JsonValue>::new();
+            obj.insert(String::from("key"), num_json(*k as f64));
+            obj.insert(String::from("value"), num_json(*v as f64));
+            JsonValue::Object(obj)
+        }
+        ControlValueSetter::Point(x, y) => array_json(&[*x, *y]),
+        ControlValueSetter::EnumValue(v) => num_json(*v as f64),
+        ControlValueSetter::RGB(r, g, b) => array_json(&[*r, *g, *b]),
+    }
+}
+
+/// Validate and set control value. Returns the value the camera reports after the change.
+pub fn set_control(cam: &mut dyn FrameSource, name: &str, value: &JsonValue) -> Result<JsonValue> {
+    let control = find_control(cam, name)?;
+
+    for flag in control.flag() {
+        match flag {
+            KnownCameraControlFlag::ReadOnly => {
+                return Err(api_err(
+                    ERR_READ_ONLY,
+                    &format!("Control {} is read only", name),
+                ));
+            }
+            KnownCameraControlFlag::Disabled => {
+                return Err(api_err(
+                    ERR_READ_ONLY,
+                    &format!("Control {} is disabled", name),
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    let setter = setter_from_json(&control, value)?;
+    cam.set_control(control.control(), setter)?;
+
+    // Read the value back: the camera could adjust it
+    let control = find_control(cam, name)?;
+    Ok(value_to_json(&control.value()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::TestPatternSource;
+
+    fn error_code(res: Result<JsonValue>) -> i32 {
+        match res {
+            Ok(_) => 0,
+            Err(err) => err.downcast_ref::<ApiError>().map_or(-1, |e| e.code),
+        }
+    }
+
+    #[test]
+    fn test_set_control() {
+        let mut src = TestPatternSource::new(32, 24, 10).unwrap();
+
+        let res = set_control(&mut src, "brightness", &JsonValue::Number(10.0)).unwrap();
+        assert_eq!(res, JsonValue::Number(10.0));
+        let control = find_control(&src, "Brightness").unwrap();
+        assert_eq!(control.value(), ControlValueSetter::Integer(10));
+
+        let r = set_control(&mut src, "Brightness", &JsonValue::Number(100.0));
+        assert_eq!(error_code(r), ERR_OUT_OF_RANGE);
+        let r = set_control(&mut src, "Brightness", &JsonValue::Number(1.5));
+        assert_eq!(error_code(r), ERR_INVALID_ARGS);
+        let r = set_control(&mut src, "Brightness", &JsonValue::Boolean(true));
+        assert_eq!(error_code(r), ERR_INVALID_ARGS);
+        let r = set_control(&mut src, "Focus", &JsonValue::Number(1.0));
+        assert_eq!(error_code(r), ERR_NOT_FOUND);
+    }
+
+    fn control(id: KnownCameraControl, descr: ControlValueDescription) -> CameraControl {
+        CameraControl::new(id, String::from("Test"), descr, vec![], true)
+    }
+
+    #[test]
+    fn test_step() {
+        let c = control(
+            KnownCameraControl::Gain,
+            ControlValueDescription::IntegerRange {
+                min: 1,
+                max: 9,
+                value: 1,
+                step: 2,
+                default: 1,
+            },
+        );
+        assert!(setter_from_json(&c, &JsonValue::Number(5.0)).is_ok());
+        assert!(setter_from_json(&c, &JsonValue::Number(4.0)).is_err());
+
+        let c = control(
+            KnownCameraControl::Gain,
+            ControlValueDescription::FloatRange {
+                min: 0.0,
+                max: 1.0,
+                value: 0.5,
+                step: 0.1,
+                default: 0.5,
+            },
+        );
+        assert!(setter_from_json(&c, &JsonValue::Number(0.3)).is_ok());
+        assert!(setter_from_json(&c, &JsonValue::Number(0.35)).is_err());
+    }
+
+    #[test]
+    fn test_menu() {
+        // Power line frequency as V4L2 reports it
+        let c = control(
+            KnownCameraControl::Other(0x00980918),
+            ControlValueDescription::IntegerRange {
+                min: 0,
+                max: 2,
+                value: 1,
+                step: 1,
+                default: 1,
+            },
+        );
+        let json = control_to_json(&c);
+        assert_eq!(json["type"], str_json("enum"));
+        assert_eq!(json["options"][2]["label"], str_json("60 Hz"));
+
+        let s = setter_from_json(&c, &str_json("60 Hz")).unwrap();
+        assert_eq!(s, ControlValueSetter::Integer(2));
+        assert!(setter_from_json(&c, &str_json("Auto")).is_err());
+        assert!(setter_from_json(&c, &JsonValue::Number(3.0)).is_err());
+    }
+
+    #[test]
+    fn test_types() {
+        let c = control(
+            KnownCameraControl::WhiteBalance,
+            ControlValueDescription::Boolean {
+                value: false,
+                default: true,
+            },
+        );
+        let json = control_to_json(&c);
+        assert_eq!(json["type"], str_json("boolean"));
+        assert_eq!(json["default"], JsonValue::Boolean(true));
+        let s = setter_from_json(&c, &JsonValue::Boolean(true)).unwrap();
+        assert_eq!(s, ControlValueSetter::Boolean(true));
+        assert!(setter_from_json(&c, &JsonValue::Number(1.0)).is_err());
+
+        let c = control(
+            KnownCameraControl::Other(1),
+            ControlValueDescription::Enum {
+                value: 4,
+                possible: vec![4, 8],
+                default: 4,
+            },
+        );
+        assert_eq!(control_to_json(&c)["options"][1]["label"], str_json("8"));
+        let s = setter_from_json(&c, &JsonValue::Number(8.0)).unwrap();
+        assert_eq!(s, ControlValueSetter::EnumValue(8));
+        assert!(setter_from_json(&c, &JsonValue::Number(5.0)).is_err());
+
+        let c = control(
+            KnownCameraControl::Other(2),
+            ControlValueDescription::RGB {
+                value: (1.0, 1.0, 1.0),
+                max: (2.0, 2.0, 2.0),
+                default: (1.0, 1.0, 1.0),
+            },
+        );
+        let v: JsonValue = "[0.5, 2, 1]".parse().unwrap();
+        let s = setter_from_json(&c, &v).unwrap();
+        assert_eq!(s, ControlValueSetter::RGB(0.5, 2.0, 1.0));
+        assert_eq!(value_to_json(&s), v);
+        let v: JsonValue = "[0.5, 3, 1]".parse().unwrap();
+        assert!(setter_from_json(&c, &v).is_err());
+
+        let c = control(
+            KnownCameraControl::Other(3),
+            ControlValueDescription::KeyValuePair {
+                key: 1,
+                value: 2,
+                default: (0, 0),
+            },
+        );
+        let v: JsonValue = "{\"key\": 5, \"value\": 6}".parse().unwrap();
+        let s = setter_from_json(&c, &v).unwrap();
+        assert_eq!(s, ControlValueSetter::KeyValue(5, 6));
+        assert_eq!(value_to_json(&s), v);
+    }
+}
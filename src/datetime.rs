@@ -0,0 +1,171 @@
+/// Minimal UTC calendar arithmetic.
+/// We only need to split millisecond timestamps into calendar fields (and back), so there is
+/// no reason to pull a full date/time library in.
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub ms: u32,
+}
+
+pub fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400) as i32 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year } as i64;
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+impl DateTime {
+    pub fn from_ms(ms: u64) -> DateTime {
+        let secs = (ms / 1000) as i64;
+        let (year, month, day) = civil_from_days(secs / 86400);
+        let sod = (secs % 86400) as u32;
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: sod / 3600,
+            minute: sod / 60 % 60,
+            second: sod % 60,
+            ms: (ms % 1000) as u32,
+        }
+    }
+
+    pub fn to_ms(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+
+        (secs as u64) * 1000 + self.ms as u64
+    }
+}
+
+impl std::str::FromStr for DateTime {
+    type Err = String;
+
+    /// Parse UTC date and optional time: YYYY-MM-DD[( |T)HH:MM[:SS[.mmm]]]
+    fn from_str(s: &str) -> Result<DateTime, String> {
+        let invalid = || format!("Invalid date '{}', expected YYYY-MM-DD HH:MM:SS", s);
+        let number = |v: &str| v.parse::<u32>().map_err(|_| invalid());
+
+        let (date, time) = match s.trim().split_once([' ', 'T']) {
+            Some((date, time)) => (date, time),
+            None => (s.trim(), "00:00"),
+        };
+
+        let d: Vec<&str> = date.split('-').collect();
+        let (hms, ms) = match time.split_once('.') {
+            Some((hms, ms)) => (hms, ms),
+            None => (time, "0"),
+        };
+        let t: Vec<&str> = hms.split(':').collect();
+        if d.len() != 3 || t.len() < 2 || t.len() > 3 || ms.len() > 3 {
+            return Err(invalid());
+        }
+
+        let dt = DateTime {
+            year: number(d[0])? as i32,
+            month: number(d[1])?,
+            day: number(d[2])?,
+            hour: number(t[0])?,
+            minute: number(t[1])?,
+            second: if t.len() == 3 { number(t[2])? } else { 0 },
+            ms: number(&format!("{:0<3}", ms))?,
+        };
+        if dt.month == 0
+            || dt.month > 12
+            || dt.day == 0
+            || dt.day > 31
+            || dt.hour > 23
+            || dt.minute > 59
+            || dt.second > 59
+        {
+            return Err(invalid());
+        }
+
+        Ok(dt)
+    }
+}
+
+impl std::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.ms
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_ms() {
+        let dt = DateTime::from_ms(0);
+        assert_eq!(dt.to_string(), "1970-01-01 00:00:00.000");
+
+        // 2024-02-29 23:59:59.999 UTC
+        let dt = DateTime::from_ms(1709251199999);
+        assert_eq!(dt.to_string(), "2024-02-29 23:59:59.999");
+
+        let dt = DateTime::from_ms(1709251200000);
+        assert_eq!((dt.year, dt.month, dt.day), (2024, 3, 1));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for ms in [0, 951782400000, 1709251199999, 4102444800123] {
+            assert_eq!(DateTime::from_ms(ms).to_ms(), ms);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let dt: DateTime = "2024-02-29 23:59:59.999".parse().unwrap();
+        assert_eq!(dt.to_ms(), 1709251199999);
+        let dt: DateTime = "2024-03-01T10:20".parse().unwrap();
+        assert_eq!(dt.to_string(), "2024-03-01 10:20:00.000");
+        let dt: DateTime = "2024-03-01".parse().unwrap();
+        assert_eq!(dt.to_ms(), 1709251200000);
+        assert_eq!("2024-03-01 10:20:30.5".parse::<DateTime>().unwrap().ms, 500);
+
+        assert!("2024-13-01".parse::<DateTime>().is_err());
+        assert!("2024-03-01 24:00".parse::<DateTime>().is_err());
+        assert!("yesterday".parse::<DateTime>().is_err());
+    }
+}
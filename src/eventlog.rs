@@ -0,0 +1,566 @@
+/// Append-only log of what happened: camera state, control changes, motion, recorded events,
+/// retention deletions and client connections. Entries are JSON lines in log/events.jsonl of the
+/// archive directory:
+///     {"id": 12, "time": <ms>, "type": "motion", "data": {"state": "started", "score": 3.5}}
+/// The file is rotated when it grows over the maximum size: events.jsonl is renamed to
+/// events.1.jsonl, older files are shifted and the oldest one is removed. Without the archive
+/// directory the log is kept in memory only. Recent entries are kept in memory for clients
+/// waiting for new ones. Live entries (frame statistics, archive state) are only sent to waiting
+/// clients and are not written: they are kept apart from the recent ones and numbered on their
+/// own from 1 on every start, their JSON has "live": true.
+use crate::datetime;
+use crate::web::{self, ApiError, ERR_INVALID_ARGS};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Directory of the archive with log files
+pub const LOG_DIR: &str = "log";
+const LOG_NAME: &str = "events";
+pub const DEFAULT_MAX_SIZE: u64 = 10 << 20;
+pub const DEFAULT_FILES: usize = 5;
+// Entries kept in memory
+const RECENT: usize = 1000;
+const LIVE: usize = 100;
+/// Longest wait for new entries
+pub const MAX_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub id: u64,
+    pub time: u64,
+    pub kind: String,
+    pub data: JsonValue,
+    /// Entry is not written into the log, its id is the one of live entries
+    pub live: bool,
+}
+
+impl Entry {
+    fn from_json(value: &JsonValue) -> Result<Entry> {
+        Ok(Entry {
+            id: web::number_arg(value, "id")?.max(0.0) as u64,
+            time: web::number_arg(value, "time")?.max(0.0) as u64,
+            kind: web::string_arg(value, "type")?.clone(),
+            data: web::optional_arg(value, "data")
+                .cloned()
+                .unwrap_or(JsonValue::Null),
+            live: false,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("id"), JsonValue::Number(self.id as f64));
+        res.insert(String::from("time"), JsonValue::Number(self.time as f64));
+        res.insert(String::from("type"), JsonValue::String(self.kind.clone()));
+        res.insert(String::from("data"), self.data.clone());
+        if self.live {
+            res.insert(String::from("live"), JsonValue::Boolean(true));
+        }
+        JsonValue::Object(res)
+    }
+}
+
+/// Build JSON object of the entry data from pairs
+pub fn data(fields: &[(&str, JsonValue)]) -> JsonValue {
+    let mut res = HashMap::<String, JsonValue>::new();
+    for (name, value) in fields {
+        res.insert(String::from(*name), value.clone());
+    }
+    JsonValue::Object(res)
+}
+
+/// Query of entries, all conditions are optional
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub from: u64,
+    pub to: u64,
+    /// Entry types, all types if empty
+    pub types: Vec<String>,
+    /// Only entries with greater ids
+    pub after: u64,
+    /// The latest limit entries are returned
+    pub limit: usize,
+    /// Include live entries
+    pub live: bool,
+    /// Only live entries with greater ids
+    pub after_live: u64,
+    /// Entries of the camera and the ones of no camera
+    pub camera: Option<String>,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            from: 0,
+            to: u64::MAX,
+            types: vec![],
+            after: 0,
+            limit: usize::MAX,
+            live: false,
+            after_live: 0,
+            camera: None,
+        }
+    }
+}
+
+impl Filter {
+    /// Filter of the API call: {"from": <ms>, "to": <ms>, "type": <type> or [<type>, ...],
+    /// "after": <id>, "limit": <n>}
+    pub fn from_json(args: &JsonValue) -> Result<Filter> {
+        let number = |name: &str, default: u64| -> Result<u64> {
+            match web::optional_arg(args, name) {
+                Some(_) => Ok(web::number_arg(args, name)?.max(0.0) as u64),
+                None => Ok(default),
+            }
+        };
+        let types = match web::optional_arg(args, "type") {
+            None => vec![],
+            Some(JsonValue::String(t)) => vec![t.clone()],
+            Some(JsonValue::Array(list)) => list
+                .iter()
+                .filter_map(|t| t.get::<String>().cloned())
+                .collect(),
+            Some(_) => {
+                return Err(Box::new(ApiError::new(
+                    ERR_INVALID_ARGS,
+                    "Type must be a string or an array of strings",
+                )))
+            }
+        };
+
+        Ok(Filter {
+            from: number("from", 0)?,
+            to: number("to", u64::MAX)?,
+            types,
+            after: number("after", 0)?,
+            limit: number("limit", u64::MAX)?.try_into().unwrap_or(usize::MAX),
+            live: false,
+            after_live: 0,
+            camera: None,
+        })
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let after = match entry.live {
+            false => entry.id > self.after,
+            true => self.live && entry.id > self.after_live,
+        };
+        after
+            && entry.time >= self.from
+            && entry.time <= self.to
+            && (self.types.is_empty() || self.types.contains(&entry.kind))
+            && self.camera.as_ref().is_none_or(|camera| {
+                web::optional_arg(&entry.data, "camera")
+                    .and_then(|c| c.get::<String>())
+                    .is_none_or(|c| c == camera)
+            })
+    }
+
+    /// Skip entries up to the given one
+    pub fn advance(&mut self, entry: &Entry) {
+        match entry.live {
+            false => self.after = entry.id,
+            true => self.after_live = entry.id,
+        }
+    }
+}
+
+fn log_path(dir: &Path, idx: usize) -> PathBuf {
+    match idx {
+        0 => dir.join(format!("{}.jsonl", LOG_NAME)),
+        idx => dir.join(format!("{}.{}.jsonl", LOG_NAME, idx)),
+    }
+}
+
+/// Read entries of all files, oldest first
+fn read_files(dir: &Path, files: usize, filter: &Filter) -> Result<Vec<Entry>> {
+    let mut res: Vec<Entry> = vec![];
+    for idx in (0..=files).rev() {
+        let path = log_path(dir, idx);
+        if !path.exists() {
+            continue;
+        }
+        for line in std::fs::read_to_string(&path)?.lines() {
+            let entry = match line.parse::<JsonValue>() {
+                Ok(json) => Entry::from_json(&json),
+                Err(err) => Err(err.into()),
+            };
+            match entry {
+                Ok(entry) if filter.matches(&entry) => res.push(entry),
+                Ok(_) => (),
+                Err(err) => println!("{}: {}", path.display(), err),
+            }
+        }
+    }
+    Ok(res)
+}
+
+struct Impl {
+    dir: Option<PathBuf>,
+    max_size: u64,
+    files: usize,
+    size: u64,
+    next_id: u64,
+    recent: VecDeque<Entry>,
+    next_live: u64,
+    live: VecDeque<Entry>,
+    // Files are read without the lock of the log, they aren't rotated while they are read
+    rotation: Arc<RwLock<()>>,
+}
+
+impl Impl {
+    fn path(&self, idx: usize) -> Option<PathBuf> {
+        Some(log_path(self.dir.as_ref()?, idx))
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let rotation = Arc::clone(&self.rotation);
+        let _rotating = rotation.write().unwrap();
+        for idx in (0..=self.files).rev() {
+            if let (Some(from), Some(to)) = (self.path(idx), self.path(idx + 1)) {
+                if !from.exists() {
+                    continue;
+                }
+                if idx == self.files {
+                    std::fs::remove_file(&from)?;
+                } else {
+                    std::fs::rename(&from, &to)?;
+                }
+            }
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let path = match self.path(0) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let line = format!("{}\n", entry.to_json().stringify()?);
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Shared handle of the log, clones write into the same log
+#[derive(Clone)]
+pub struct EventLog {
+    imp: Arc<(Mutex<Impl>, Condvar)>,
+    // Added to the data of entries as "camera"
+    camera: Option<String>,
+}
+
+impl EventLog {
+    /// Log in memory only
+    pub fn memory() -> EventLog {
+        EventLog::new(None, DEFAULT_MAX_SIZE, DEFAULT_FILES)
+    }
+
+    fn new(dir: Option<&Path>, max_size: u64, files: usize) -> EventLog {
+        EventLog {
+            imp: Arc::new((
+                Mutex::new(Impl {
+                    dir: dir.map(PathBuf::from),
+                    max_size,
+                    files,
+                    size: 0,
+                    next_id: 1,
+                    recent: VecDeque::new(),
+                    next_live: 1,
+                    live: VecDeque::new(),
+                    rotation: Arc::new(RwLock::new(())),
+                }),
+                Condvar::new(),
+            )),
+            camera: None,
+        }
+    }
+
+    /// Open the log in the directory, files of max_size bytes at most are rotated and the given
+    /// number of rotated ones is kept.
+    /// Numbering of entries continues from the last logged one.
+    pub fn open(dir: &Path, max_size: u64, files: usize) -> Result<EventLog> {
+        std::fs::create_dir_all(dir)?;
+        let log = EventLog::new(Some(dir), max_size, files);
+        {
+            let mut imp = log.imp.0.lock().unwrap();
+            let entries = read_files(dir, files, &Filter::default())?;
+            imp.next_id = entries.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+            let skip = entries.len().saturating_sub(RECENT);
+            imp.recent = entries.into_iter().skip(skip).collect();
+            if let Some(path) = imp.path(0) {
+                imp.size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            }
+        }
+        Ok(log)
+    }
+
+    /// Handle of the same log which marks entries as the ones of the camera
+    pub fn for_camera(&self, id: &str) -> EventLog {
+        EventLog {
+            imp: Arc::clone(&self.imp),
+            camera: Some(String::from(id)),
+        }
+    }
+
+    /// Add entry, errors of writing it are printed only
+    pub fn log(&self, kind: &str, data: JsonValue) -> Entry {
+        self.log_at(datetime::now_ms(), kind, data)
+    }
+
+    pub fn log_at(&self, time: u64, kind: &str, data: JsonValue) -> Entry {
+        self.add(time, kind, data, false)
+    }
+
+    /// Send live entry to waiting clients
+    pub fn notify(&self, kind: &str, data: JsonValue) -> Entry {
+        self.add(datetime::now_ms(), kind, data, true)
+    }
+
+    fn add(&self, time: u64, kind: &str, mut data: JsonValue, live: bool) -> Entry {
+        if let (Some(camera), JsonValue::Object(obj)) = (&self.camera, &mut data) {
+            obj.insert(String::from("camera"), JsonValue::String(camera.clone()));
+        }
+        let (lock, cond) = &*self.imp;
+        let mut imp = lock.lock().unwrap();
+        let entry = Entry {
+            id: if live { imp.next_live } else { imp.next_id },
+            time,
+            kind: String::from(kind),
+            data,
+            live,
+        };
+
+        if live {
+            imp.next_live += 1;
+            imp.live.push_back(entry.clone());
+            if imp.live.len() > LIVE {
+                imp.live.pop_front();
+            }
+        } else {
+            imp.next_id += 1;
+            if let Err(err) = imp.append(&entry) {
+                println!("Can't write event log: {}", err);
+            }
+            imp.recent.push_back(entry.clone());
+            if imp.recent.len() > RECENT {
+                imp.recent.pop_front();
+            }
+        }
+        cond.notify_all();
+
+        entry
+    }
+
+    /// Entries matching the filter, oldest first
+    pub fn query(&self, filter: &Filter) -> Result<Vec<Entry>> {
+        let imp = self.imp.0.lock().unwrap();
+        let live: Vec<Entry> = match filter.live {
+            true => imp
+                .live
+                .iter()
+                .filter(|e| filter.matches(e))
+                .cloned()
+                .collect(),
+            false => vec![],
+        };
+        // Recent entries are enough if the log has no files or they contain the whole query
+        let files = match imp.dir {
+            Some(ref dir)
+                if !imp.recent.front().is_some_and(|e| {
+                    e.id <= filter.after.saturating_add(1) || e.time < filter.from
+                }) =>
+            {
+                Some((dir.clone(), imp.files, Arc::clone(&imp.rotation)))
+            }
+            _ => None,
+        };
+        let mut res: Vec<Entry> = match files {
+            Some((dir, files, rotation)) => {
+                // Reading all files takes a while, the log is written meanwhile
+                drop(imp);
+                let _reading = rotation.read().unwrap();
+                read_files(&dir, files, filter)?
+            }
+            None => imp
+                .recent
+                .iter()
+                .filter(|e| filter.matches(e))
+                .cloned()
+                .collect(),
+        };
+        if !live.is_empty() {
+            res.extend(live);
+            res.sort_by_key(|e| e.time);
+        }
+
+        let skip = res.len().saturating_sub(filter.limit);
+        res.drain(..skip);
+        Ok(res)
+    }
+
+    /// Query entries waiting up to timeout for new ones if there are none
+    pub fn wait(&self, filter: &Filter, timeout: Duration) -> Result<Vec<Entry>> {
+        let deadline = Instant::now() + std::cmp::min(timeout, MAX_WAIT);
+        let mut filter = filter.clone();
+        loop {
+            let res = self.query(&filter)?;
+            let now = Instant::now();
+            if !res.is_empty() || now >= deadline {
+                return Ok(res);
+            }
+
+            // Only entries logged from now on could match
+            let (lock, cond) = &*self.imp;
+            let imp = lock.lock().unwrap();
+            filter.after = std::cmp::max(filter.after, imp.next_id - 1);
+            filter.after_live = std::cmp::max(filter.after_live, imp.next_live - 1);
+            let last = (imp.next_id, imp.next_live);
+            let _ = cond
+                .wait_timeout_while(imp, deadline - now, |imp| {
+                    (imp.next_id, imp.next_live) == last
+                })
+                .unwrap();
+        }
+    }
+
+    /// Id of the last entry
+    pub fn last_id(&self) -> u64 {
+        self.imp.0.lock().unwrap().next_id - 1
+    }
+
+    /// Id of the last live entry
+    pub fn last_live_id(&self) -> u64 {
+        self.imp.0.lock().unwrap().next_live - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn test_log() {
+        let dir = crate::test_util::temp_path("log");
+        let log = EventLog::open(&dir, 500, 3).unwrap();
+        for i in 0..40 {
+            let kind = if i % 2 == 0 { "motion" } else { "control" };
+            log.log_at(
+                1000 + i * 10,
+                kind,
+                data(&[("n", JsonValue::Number(i as f64))]),
+            );
+        }
+        assert_eq!(log.last_id(), 40);
+
+        // Lines are about 60 bytes: the current file and 3 rotated ones are kept
+        let files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(files.len(), 4);
+        assert!(!dir.join("events.4.jsonl").exists());
+        assert!(std::fs::metadata(dir.join("events.jsonl")).unwrap().len() <= 500);
+
+        let filter = Filter {
+            types: vec![String::from("motion")],
+            from: 1100,
+            to: 1200,
+            ..Filter::default()
+        };
+        assert_eq!(
+            ids(&log.query(&filter).unwrap()),
+            vec![11, 13, 15, 17, 19, 21]
+        );
+        let filter = Filter { limit: 2, ..filter };
+        assert_eq!(ids(&log.query(&filter).unwrap()), vec![19, 21]);
+        let filter = Filter {
+            after: u64::MAX,
+            ..Filter::default()
+        };
+        assert!(log.query(&filter).unwrap().is_empty());
+
+        // Reopened log continues numbering, old entries are read from the files
+        let log = EventLog::open(&dir, 500, 3).unwrap();
+        let all = log.query(&Filter::default()).unwrap();
+        assert_eq!(all.last().unwrap().id, 40);
+        assert!(all.len() < 40);
+        assert_eq!(log.log("camera", JsonValue::Null).id, 41);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wait() {
+        let log = EventLog::memory();
+        log.log("motion", JsonValue::Null);
+
+        let filter = Filter {
+            after: 1,
+            types: vec![String::from("control")],
+            ..Filter::default()
+        };
+        let start = Instant::now();
+        assert!(log
+            .wait(&filter, Duration::from_millis(50))
+            .unwrap()
+            .is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let l = log.clone();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            l.log("motion", JsonValue::Null);
+            l.log("control", JsonValue::Null);
+        });
+        let res = log.wait(&filter, Duration::from_secs(5)).unwrap();
+        t.join().unwrap();
+        assert_eq!(ids(&res), vec![3]);
+
+        let filter =
+            Filter::from_json(&r#"{"type": ["motion", "camera"], "after": 1}"#.parse().unwrap())
+                .unwrap();
+        assert_eq!(ids(&log.query(&filter).unwrap()), vec![2]);
+
+        // Live entries are seen by clients asking for them only, they don't push out logged ones
+        for _ in 0..RECENT {
+            log.notify("stats", JsonValue::Null);
+        }
+        assert_eq!(log.last_id(), 3);
+        assert_eq!(log.last_live_id(), RECENT as u64);
+        assert_eq!(ids(&log.query(&Filter::default()).unwrap()), vec![1, 2, 3]);
+        let mut filter = Filter {
+            after: 3,
+            live: true,
+            after_live: RECENT as u64 - 1,
+            ..Filter::default()
+        };
+        let res = log.wait(&filter, Duration::ZERO).unwrap();
+        assert_eq!(ids(&res), vec![RECENT as u64]);
+        assert!(res[0].live);
+        filter.advance(&res[0]);
+        assert!(log.query(&filter).unwrap().is_empty());
+        assert!(Filter::from_json(&r#"{"type": 1}"#.parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+/// JPEG encoding of captured frames.
+/// Web interface, archive and AVI files all expect JPEG data, but cameras deliver JPEG only in
+/// MJPEG mode. Frames in other formats are decoded to RGB and encoded again.
+use nokhwa::pixel_format::RgbFormat;
+use nokhwa::utils::{FrameFormat, Resolution};
+use nokhwa::Buffer;
+use std::borrow::Cow;
+use std::error::Error;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub const DEFAULT_QUALITY: u8 = 85;
+
+/// Encode 8 bit RGB image
+pub fn encode_rgb(rgb: &[u8], width: u32, height: u32, quality: u8) -> Result<Vec<u8>> {
+    encode(rgb, width, height, jpeg_encoder::ColorType::Rgb, quality)
+}
+
+fn encode(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: jpeg_encoder::ColorType,
+    quality: u8,
+) -> Result<Vec<u8>> {
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(Box::<dyn Error>::from("Image is too large for JPEG"));
+    }
+
+    let mut jpeg: Vec<u8> = vec![];
+    let encoder = jpeg_encoder::Encoder::new(&mut jpeg, quality);
+    encoder.encode(data, width as u16, height as u16, color)?;
+
+    Ok(jpeg)
+}
+
+/// Get JPEG image from the frame: MJPEG frames are returned untouched, others are encoded with
+/// the given quality.
+pub fn frame_to_jpeg(frame: &Buffer, quality: u8) -> Result<Cow<'_, [u8]>> {
+    let res = frame.resolution();
+
+    match frame.source_frame_format() {
+        FrameFormat::MJPEG => Ok(Cow::Borrowed(frame.buffer())),
+        FrameFormat::GRAY => {
+            let expected = (res.width() * res.height()) as usize;
+            if frame.buffer().len() < expected {
+                return Err(Box::<dyn Error>::from("Frame is too short"));
+            }
+            let jpeg = encode(
+                &frame.buffer()[..expected],
+                res.width(),
+                res.height(),
+                jpeg_encoder::ColorType::Luma,
+                quality,
+            )?;
+            Ok(Cow::Owned(jpeg))
+        }
+        _ => {
+            let rgb = frame.decode_image::<RgbFormat>()?;
+            let jpeg = encode_rgb(rgb.as_raw(), rgb.width(), rgb.height(), quality)?;
+            Ok(Cow::Owned(jpeg))
+        }
+    }
+}
+
+/// Decode JPEG image into 8 bit RGB, returns the pixels with the image size
+pub fn decode_rgb(jpeg: &[u8]) -> Result<(Vec<u8>, u32, u32)> {
+    let (width, height) = match crate::mjpeg::jpeg_size(jpeg) {
+        Some(size) => size,
+        None => return Err(Box::<dyn Error>::from("Not a JPEG image")),
+    };
+    let frame = Buffer::new(Resolution::new(width, height), jpeg, FrameFormat::MJPEG);
+    let rgb = frame.decode_image::<RgbFormat>()?;
+
+    Ok((rgb.into_raw(), width, height))
+}
+
+/// Scale JPEG image down to the width keeping the aspect ratio. Every pixel of the result is
+/// the average of the source pixels it covers.
+pub fn thumbnail(jpeg: &[u8], width: u32, quality: u8) -> Result<Vec<u8>> {
+    let (rgb, src_w, src_h) = decode_rgb(jpeg)?;
+    if width == 0 || width >= src_w {
+        return encode_rgb(&rgb, src_w, src_h, quality);
+    }
+
+    let height = std::cmp::max(src_h * width / src_w, 1);
+    let mut res: Vec<u8> = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        let (y0, y1) = (y * src_h / height, (y + 1) * src_h / height);
+        for x in 0..width {
+            let (x0, x1) = (x * src_w / width, (x + 1) * src_w / width);
+            let mut sum = [0u32; 3];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let idx = ((sy * src_w + sx) * 3) as usize;
+                    for c in 0..3 {
+                        sum[c] += rgb[idx + c] as u32;
+                    }
+                }
+            }
+            let count = (y1 - y0) * (x1 - x0);
+            for value in sum {
+                res.push((value / count) as u8);
+            }
+        }
+    }
+
+    encode_rgb(&res, width, height, quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mjpeg::jpeg_size;
+
+    #[test]
+    fn test_frame_to_jpeg() {
+        let (w, h) = (32, 16);
+
+        let rgb: Vec<u8> = (0..w * h * 3).map(|i| (i % 251) as u8).collect();
+        let frame = Buffer::new(Resolution::new(w, h), &rgb, FrameFormat::RAWRGB);
+        let jpeg = frame_to_jpeg(&frame, 90).unwrap();
+        assert_eq!(jpeg_size(&jpeg), Some((w, h)));
+
+        // YUYV: 2 bytes per pixel
+        let yuyv: Vec<u8> = (0..w * h * 2).map(|i| (i % 200) as u8).collect();
+        let frame = Buffer::new(Resolution::new(w, h), &yuyv, FrameFormat::YUYV);
+        let jpeg = frame_to_jpeg(&frame, 90).unwrap();
+        assert_eq!(jpeg_size(&jpeg), Some((w, h)));
+
+        let gray: Vec<u8> = (0..w * h).map(|i| i as u8).collect();
+        let frame = Buffer::new(Resolution::new(w, h), &gray, FrameFormat::GRAY);
+        let jpeg = frame_to_jpeg(&frame, 90).unwrap();
+        assert_eq!(jpeg_size(&jpeg), Some((w, h)));
+
+        // MJPEG frame is passed through
+        let frame = Buffer::new(Resolution::new(w, h), &jpeg, FrameFormat::MJPEG);
+        assert!(matches!(
+            frame_to_jpeg(&frame, 50).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(frame_to_jpeg(&frame, 50).unwrap(), jpeg);
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let (w, h) = (64, 32);
+        let rgb: Vec<u8> = (0..w * h * 3).map(|i| (i % 3 * 100) as u8).collect();
+        let jpeg = encode_rgb(&rgb, w, h, 90).unwrap();
+
+        let (decoded, dw, dh) = decode_rgb(&jpeg).unwrap();
+        assert_eq!((dw, dh, decoded.len()), (w, h, rgb.len()));
+
+        let thumb = thumbnail(&jpeg, 16, 80).unwrap();
+        assert_eq!(jpeg_size(&thumb), Some((16, 8)));
+        // Colors are averaged, not mixed between channels
+        let (pixels, _, _) = decode_rgb(&thumb).unwrap();
+        assert!(pixels[0] < 30 && pixels[1].abs_diff(100) < 30 && pixels[2] > 170);
+
+        // Larger width keeps the size
+        assert_eq!(jpeg_size(&thumbnail(&jpeg, 100, 80).unwrap()), Some((w, h)));
+        assert!(thumbnail(b"text", 16, 80).is_err());
+    }
+}
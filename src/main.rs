@@ -1,87 +1,316 @@
 use nokhwa::pixel_format::RgbFormat;
-use nokhwa::utils::{CameraIndex, RequestedFormat, RequestedFormatType};
+use nokhwa::utils::{RequestedFormat, RequestedFormatType};
 use nokhwa::{nokhwa_initialize, Camera};
 use std::error::Error;
 use tinyjson::JsonValue;
 
 use argh::FromArgs;
-use std::fs::File;
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub mod archive;
+pub mod auth;
+pub mod cameras;
+pub mod controls;
+pub mod datetime;
+pub mod eventlog;
+pub mod jpeg;
 pub mod mjpeg;
+pub mod motion;
+pub mod presets;
+pub mod recorder;
+pub mod schedule;
 pub mod shrx;
+pub mod source;
+#[cfg(test)]
+mod test_util;
+pub mod timelapse;
 pub mod web;
 
-type Result<T> = std::result::Result<T, Box<dyn Error>>;
+use auth::Role;
+use source::FrameSource;
+use web::{MethodInfo, Param, ParamKind};
 
-fn save_file(name: &str, data: &[u8]) -> Result<()> {
-    let path = Path::new(name);
-    let mut f = File::create(path)?;
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-    f.write_all(data)?;
-
-    Ok(())
-}
+const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_TLS_CERT: &str = "httpcam-cert.pem";
+const DEFAULT_TLS_KEY: &str = "httpcam-key.pem";
+// Delay before reopening a failed source, it is doubled after every failed attempt up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
 #[derive(FromArgs)]
 /// Simple HTTP webcam interface
 struct CmdLine {
-    /// listen address in the form addr:port
-    #[argh(option, short = 'a', default = "String::from(\"0.0.0.0:8080\")")]
-    address: String,
+    /// listen address: addr:port, [ipv6]:port, https:addr:port or unix:<path>, could be repeated or comma separated (default: 0.0.0.0:8080)
+    #[argh(option, short = 'a')]
+    address: Vec<String>,
+
+    /// number of web worker threads
+    #[argh(option, default = "4")]
+    workers: usize,
 
     /// index of a camera to use
     #[argh(option, short = 'c', default = "0")]
     camera: u32,
 
-    /// get <fps> frames per second
+    /// frame source: camera:<index>|<path>|<name>, test[:<w>x<h>/<fps>] or replay:<path>[,fps=<n>][,loop] (overrides --camera)
+    #[argh(option, short = 's')]
+    source: Option<String>,
+
+    /// cameras file: {{"cameras": [{{"id": <id>, "source": <source>, ...}}]}}, cameras are served at /cam/<id>/, the first one also at /
+    #[argh(option)]
+    cameras: Option<String>,
+
+    /// write <fps> frames per second into the archive
     #[argh(option, default = "4")]
     fps: u32,
 
+    /// quality (1-100) of JPEG images encoded from frames which are not MJPEG
+    #[argh(option, default = "jpeg::DEFAULT_QUALITY")]
+    jpeg_quality: u8,
+
     /// write images archive into directory
     #[argh(option, short = 'o')]
     output: Option<String>,
 
-    /// maximum image age in archive in hours
+    /// archive layout: path template with {{camera}}, {{YYYY}}, {{MM}}, {{DD}}, {{HH}}, {{mm}}, {{ss}} and {{ms}}, e.g. {{camera}}/{{YYYY}}/{{MM}}/{{DD}}/{{HH}}/frame_{{ms}}.jpg
+    #[argh(option, default = "String::from(archive::DEFAULT_LAYOUT)")]
+    archive_layout: String,
+
+    /// archive mode: files (JPEG file per frame) or avi (MJPEG AVI segments in the layout directories)
+    #[argh(option, default = "String::from(\"files\")")]
+    archive_mode: String,
+
+    /// length of AVI segments in minutes
+    #[argh(option, default = "archive::DEFAULT_SEGMENT_MINUTES")]
+    segment_minutes: u32,
+
+    /// recording: continuous (archive), events (clips of motion and triggered events only) or both
+    #[argh(option, default = "String::from(\"continuous\")")]
+    record: String,
+
+    /// seconds recorded before an event
+    #[argh(option, default = "5")]
+    pre_roll: u32,
+
+    /// seconds recorded after an event
+    #[argh(option, default = "5")]
+    post_roll: u32,
+
+    /// maximum length of an event clip in seconds, longer events are split
+    #[argh(option, default = "300")]
+    max_event: u32,
+
+    /// frame rate of event clips
+    #[argh(option, default = "10")]
+    event_fps: u32,
+
+    /// size of the event log file in the archive at which it is rotated, e.g. 10M
+    #[argh(option, default = "String::from(\"10M\")")]
+    log_size: String,
+
+    /// number of rotated event log files to keep
+    #[argh(option, default = "eventlog::DEFAULT_FILES")]
+    log_files: usize,
+
+    /// camera name used in the archive layout and /cam/<name>/ routes
+    #[argh(option, default = "String::from(\"camera\")")]
+    name: String,
+
+    /// maximum image age in archive in hours, 0 means unlimited
     #[argh(option, default = "24")]
     max_age: u32,
 
+    /// maximum number of images (or AVI segments) in archive
+    #[argh(option)]
+    max_frames: Option<usize>,
+
+    /// maximum total size of archive, e.g. 500M or 20G
+    #[argh(option)]
+    max_size: Option<String>,
+
+    /// minimum free disk space to keep, e.g. 1G
+    #[argh(option)]
+    min_free: Option<String>,
+
     /// select resolution
     #[argh(option)]
     resolution: Option<String>,
 
+    /// control presets file (default: httpcam-presets.json next to the executable)
+    #[argh(option)]
+    presets: Option<String>,
+
+    /// apply named control preset on startup
+    #[argh(option)]
+    preset: Option<String>,
+
+    /// control profile schedule file (default: httpcam-schedule.json next to the executable)
+    #[argh(option)]
+    schedule: Option<String>,
+
+    /// motion detection settings file (default: httpcam-motion.json next to the executable)
+    #[argh(option)]
+    motion: Option<String>,
+
+    /// users and API tokens file, authentication is required when it exists (default: httpcam-users.json next to the executable)
+    #[argh(option)]
+    users: Option<String>,
+
+    /// PEM certificate chain of https listeners (default: httpcam-cert.pem next to the executable)
+    #[argh(option)]
+    tls_cert: Option<String>,
+
+    /// PEM private key of https listeners (default: httpcam-key.pem next to the executable)
+    #[argh(option)]
+    tls_key: Option<String>,
+
+    /// generate self-signed certificate for https listeners if the certificate files don't exist
+    #[argh(switch)]
+    self_signed: bool,
+
+    /// redirect plain HTTP requests to the first https listener
+    #[argh(switch)]
+    https_redirect: bool,
+
     /// list all devices and known resolutions
     #[argh(switch, short = 'l')]
     list: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
 }
 
-fn camera_format_string(fmt: &nokhwa::utils::CameraFormat) -> String {
-    let mut res = String::new();
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Migrate(MigrateCmd),
+    Timelapse(TimelapseCmd),
+    User(UserCmd),
+}
+
+#[derive(FromArgs)]
+/// Move frames of a flat archive in --output directory into --archive-layout and exit
+#[argh(subcommand, name = "migrate")]
+struct MigrateCmd {}
+
+#[derive(FromArgs)]
+/// Build timelapse AVI from the archive in --output directory and exit
+#[argh(subcommand, name = "timelapse")]
+struct TimelapseCmd {
+    /// start: YYYY-MM-DD[ HH:MM[:SS]] (UTC) or ms since the epoch
+    #[argh(option)]
+    from: String,
 
-    res += &fmt.width().to_string();
-    res += "x";
-    res += &fmt.height().to_string();
-    res += "/";
-    res += &fmt.frame_rate().to_string();
+    /// end: YYYY-MM-DD[ HH:MM[:SS]] (UTC) or ms since the epoch
+    #[argh(option)]
+    to: String,
+
+    /// interval between frames, e.g. 30s, 10m, 1h (default: 10m)
+    #[argh(option, default = "String::from(timelapse::DEFAULT_INTERVAL)")]
+    interval: String,
+
+    /// frame rate of the video
+    #[argh(option, default = "timelapse::DEFAULT_FPS")]
+    fps: u32,
 
-    res
+    /// take frames in daylight only, at the location of the schedule unless --latitude and --longitude are given
+    #[argh(switch)]
+    daylight: bool,
+
+    /// latitude for --daylight
+    #[argh(option)]
+    latitude: Option<f64>,
+
+    /// longitude for --daylight
+    #[argh(option)]
+    longitude: Option<f64>,
+
+    /// output AVI file
+    #[argh(positional)]
+    file: String,
+}
+
+#[derive(FromArgs)]
+/// Manage users and API tokens of the --users file and exit
+#[argh(subcommand, name = "user")]
+struct UserCmd {
+    #[argh(subcommand)]
+    action: UserAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum UserAction {
+    Add(UserAddCmd),
+    Token(UserTokenCmd),
+    Remove(UserRemoveCmd),
+    List(UserListCmd),
+}
+
+#[derive(FromArgs)]
+/// Add a user or change password and role of the existing one
+#[argh(subcommand, name = "add")]
+struct UserAddCmd {
+    /// role: viewer or admin
+    #[argh(option, default = "String::from(\"viewer\")")]
+    role: String,
+
+    /// password, read from the standard input if not given
+    #[argh(option)]
+    password: Option<String>,
+
+    /// user name
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs)]
+/// Create an API token and print it, a token of the same name is replaced
+#[argh(subcommand, name = "token")]
+struct UserTokenCmd {
+    /// role: viewer or admin
+    #[argh(option, default = "String::from(\"viewer\")")]
+    role: String,
+
+    /// token name
+    #[argh(positional)]
+    name: String,
 }
 
+#[derive(FromArgs)]
+/// Remove a user or an API token
+#[argh(subcommand, name = "remove")]
+struct UserRemoveCmd {
+    /// user or token name
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs)]
+/// List users and API tokens
+#[argh(subcommand, name = "list")]
+struct UserListCmd {}
+
 fn list_cameras(cameras: &Vec<nokhwa::utils::CameraInfo>) -> Result<()> {
     for info in cameras {
         println!("{}", info);
         let requested =
             RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestResolution);
-        let mut camera = Camera::new(info.index().clone(), requested);
+        let camera = Camera::new(info.index().clone(), requested);
         match camera {
             Ok(mut camera) => {
                 let formats = camera.compatible_camera_formats();
                 match formats {
                     Ok(formats) => {
                         for fmt in &formats {
-                            println!("    {} ({})", camera_format_string(&fmt), fmt.format());
+                            println!("    {} ({})", source::format_string(fmt), fmt.format());
                         }
                     }
                     Err(_) => (),
@@ -94,9 +323,9 @@ fn list_cameras(cameras: &Vec<nokhwa::utils::CameraInfo>) -> Result<()> {
     Ok(())
 }
 
-fn api_error(msg: &str) -> JsonValue {
+fn api_error(err: &web::ApiError) -> JsonValue {
     let mut res = std::collections::HashMap::<String, JsonValue>::new();
-    res.insert(String::from("error"), JsonValue::String(msg.to_string()));
+    res.insert(String::from("error"), err.to_json());
     JsonValue::Object(res)
 }
 
@@ -104,9 +333,9 @@ fn api_ping(req: &JsonValue) -> Result<JsonValue> {
     Ok(req.clone())
 }
 
-fn api_list_resolutions(cam: &mut Camera, _req: &JsonValue) -> Result<JsonValue> {
+fn api_list_resolutions(cam: &mut dyn FrameSource, _req: &JsonValue) -> Result<JsonValue> {
     let mut res: Vec<JsonValue> = vec![];
-    let formats = cam.compatible_camera_formats()?;
+    let formats = cam.formats()?;
     for fmt in &formats {
         let mut f = std::collections::HashMap::<String, JsonValue>::new();
         f.insert(String::from("width"), JsonValue::Number(fmt.width() as f64));
@@ -129,86 +358,1263 @@ fn api_list_resolutions(cam: &mut Camera, _req: &JsonValue) -> Result<JsonValue>
     Ok(JsonValue::Array(res))
 }
 
-fn flag_to_string(f: &[nokhwa::utils::KnownCameraControlFlag]) -> String {
-    for flag in f {
-        return String::from(match flag {
-            nokhwa::utils::KnownCameraControlFlag::Automatic => "automatic",
-            nokhwa::utils::KnownCameraControlFlag::Manual => "manual",
-            nokhwa::utils::KnownCameraControlFlag::Continuous => "continuous",
-            nokhwa::utils::KnownCameraControlFlag::ReadOnly => "readonly",
-            nokhwa::utils::KnownCameraControlFlag::WriteOnly => "writeonly",
-            nokhwa::utils::KnownCameraControlFlag::Volatile => "volatile",
-            nokhwa::utils::KnownCameraControlFlag::Disabled => "disabled",
-        });
+fn api_list_controls(cam: &mut dyn FrameSource, _req: &JsonValue) -> Result<JsonValue> {
+    let mut res: Vec<JsonValue> = vec![];
+    for control in cam.controls()? {
+        res.push(controls::control_to_json(&control));
+    }
+
+    Ok(JsonValue::Array(res))
+}
+
+fn set_control_item(cam: &mut dyn FrameSource, item: &JsonValue) -> Result<JsonValue> {
+    let obj: &std::collections::HashMap<String, JsonValue> = match item.get() {
+        Some(obj) => obj,
+        None => {
+            return Err(Box::new(web::ApiError::new(
+                web::ERR_INVALID_ARGS,
+                "Expected {\"name\": <name>, \"value\": <value>}",
+            )))
+        }
+    };
+
+    let name = web::string_arg(item, "name")?;
+
+    let value = match obj.get("value") {
+        Some(value) => value,
+        None => {
+            return Err(Box::new(web::ApiError::new(
+                web::ERR_INVALID_ARGS,
+                "Control value is required",
+            )))
+        }
+    };
+
+    let applied = controls::set_control(cam, name, value)?;
+    let mut res = std::collections::HashMap::<String, JsonValue>::new();
+    res.insert(String::from("name"), JsonValue::String(name.clone()));
+    res.insert(String::from("value"), applied);
+
+    Ok(JsonValue::Object(res))
+}
+
+/// Set one control: {"name": <name>, "value": <value>}
+/// or several: {"controls": [{"name": <name>, "value": <value>}, ...]}
+/// In the batch form every control is reported separately with its value or error.
+fn api_set_control(cam: &mut dyn FrameSource, req: &JsonValue) -> Result<JsonValue> {
+    let items = match req {
+        JsonValue::Array(items) => items,
+        JsonValue::Object(obj) => match obj.get("controls") {
+            Some(JsonValue::Array(items)) => items,
+            _ => return set_control_item(cam, req),
+        },
+        _ => {
+            return Err(Box::new(web::ApiError::new(
+                web::ERR_INVALID_ARGS,
+                "Control name and value are required",
+            )))
+        }
+    };
+
+    let mut res: Vec<JsonValue> = vec![];
+    for item in items {
+        let r = match set_control_item(cam, item) {
+            Ok(r) => r,
+            Err(err) => {
+                let mut r = std::collections::HashMap::<String, JsonValue>::new();
+                if let JsonValue::Object(obj) = item {
+                    if let Some(name) = obj.get("name") {
+                        r.insert(String::from("name"), name.clone());
+                    }
+                }
+                r.insert(
+                    String::from("error"),
+                    web::ApiError::from_error(err.as_ref()).to_json(),
+                );
+                JsonValue::Object(r)
+            }
+        };
+        res.push(r);
+    }
+
+    Ok(JsonValue::Array(res))
+}
+
+fn api_list_presets(presets: &presets::Presets, _req: &JsonValue) -> Result<JsonValue> {
+    let mut res = std::collections::HashMap::<String, JsonValue>::new();
+    for name in presets.names() {
+        res.insert(name.clone(), presets.get(&name)?.to_json());
+    }
+
+    Ok(JsonValue::Object(res))
+}
+
+/// Save current controls and resolution: {"name": <name>}
+fn api_save_preset(
+    cam: &mut dyn FrameSource,
+    presets: &mut presets::Presets,
+    req: &JsonValue,
+) -> Result<JsonValue> {
+    let name = web::string_arg(req, "name")?;
+    let preset = presets::Preset::capture(cam)?;
+    let res = preset.to_json();
+    presets.set(name, preset)?;
+
+    Ok(res)
+}
+
+/// Apply preset: {"name": <name>}, returns the list of applied and failed controls
+fn api_apply_preset(
+    cam: &mut dyn FrameSource,
+    presets: &presets::Presets,
+    req: &JsonValue,
+) -> Result<JsonValue> {
+    let name = web::string_arg(req, "name")?;
+    Ok(presets.get(name)?.apply(cam))
+}
+
+fn api_delete_preset(presets: &mut presets::Presets, req: &JsonValue) -> Result<JsonValue> {
+    let name = web::string_arg(req, "name")?;
+    presets.remove(name)?;
+
+    Ok(JsonValue::Boolean(true))
+}
+
+/// Current state: source, resolution, active scheduled profile and motion
+fn api_status(
+    cam: &mut dyn FrameSource,
+    schedule: &schedule::Schedule,
+    motion: &motion::Detector,
+    recorder: &Option<recorder::Recorder>,
+    _req: &JsonValue,
+) -> Result<JsonValue> {
+    let mut res = std::collections::HashMap::<String, JsonValue>::new();
+    res.insert(String::from("source"), JsonValue::String(cam.name()));
+    res.insert(
+        String::from("resolution"),
+        JsonValue::String(source::format_string(&cam.format())),
+    );
+    res.insert(String::from("profile"), schedule.status_json());
+    res.insert(String::from("motion"), motion.status_json());
+    let recording = match recorder {
+        Some(r) => r
+            .current()
+            .map_or(JsonValue::Null, |e| e.api_json(r.camera())),
+        None => JsonValue::Null,
+    };
+    res.insert(String::from("recording"), recording);
+
+    Ok(JsonValue::Object(res))
+}
+
+fn api_get_schedule(schedule: &schedule::Schedule, _req: &JsonValue) -> Result<JsonValue> {
+    let mut res = match schedule.to_json() {
+        JsonValue::Object(obj) => obj,
+        _ => std::collections::HashMap::new(),
+    };
+    res.insert(String::from("active"), schedule.status_json());
+    res.insert(String::from("sun"), schedule.sun_json(datetime::now_ms()));
+
+    Ok(JsonValue::Object(res))
+}
+
+/// Replace the schedule, the profile active by the new schedule is applied immediately
+fn api_set_schedule(schedule: &mut schedule::Schedule, req: &JsonValue) -> Result<JsonValue> {
+    schedule.set(req)?;
+    Ok(schedule.to_json())
+}
+
+/// Motion detection settings with the current state in "state"
+fn api_get_motion(motion: &motion::Detector, _req: &JsonValue) -> Result<JsonValue> {
+    let mut res = match motion.config().to_json() {
+        JsonValue::Object(obj) => obj,
+        _ => std::collections::HashMap::new(),
+    };
+    res.insert(String::from("state"), motion.status_json());
+
+    Ok(JsonValue::Object(res))
+}
+
+/// Change motion detection settings, the ones not given are kept
+fn api_set_motion(motion: &mut motion::Detector, req: &JsonValue) -> Result<JsonValue> {
+    motion.set(req)?;
+    Ok(motion.config().to_json())
+}
+
+fn recording_disabled() -> Box<dyn Error> {
+    Box::new(web::ApiError::new(
+        web::ERR_NOT_FOUND,
+        "Event recording is not enabled",
+    ))
+}
+
+/// Start or extend an event: {"source": <name>, "duration": <ms>}, source is "api" by default
+fn api_trigger_event(
+    recorder: &mut Option<recorder::Recorder>,
+    req: &JsonValue,
+) -> Result<JsonValue> {
+    let source = match web::optional_arg(req, "source") {
+        Some(_) => web::string_arg(req, "source")?.as_str(),
+        None => "api",
+    };
+    if source.is_empty() {
+        return Err(Box::new(web::ApiError::new(
+            web::ERR_INVALID_ARGS,
+            "Event source must not be empty",
+        )));
+    }
+    let duration = time_arg(req, "duration", 0)?;
+
+    let recorder = match recorder {
+        Some(r) => r,
+        None => return Err(recording_disabled()),
+    };
+    let event = recorder
+        .trigger(source, datetime::now_ms(), duration)?
+        .clone();
+    Ok(event.api_json(recorder.camera()))
+}
+
+/// Recorded events: {"from": <ms>, "to": <ms>, "source": <name>, "limit": <n>}
+fn api_list_events(recorder: &Option<recorder::Recorder>, req: &JsonValue) -> Result<JsonValue> {
+    match recorder {
+        Some(r) => recorder::events_json(r, req),
+        None => Err(recording_disabled()),
+    }
+}
+
+fn archive_disabled() -> Box<dyn Error> {
+    Box::new(web::ApiError::new(
+        web::ERR_NOT_FOUND,
+        "Archive is not enabled",
+    ))
+}
+
+fn archive_playback(archive: &Option<archive::ImageArchive>) -> Result<archive::Playback> {
+    match archive {
+        Some(a) => Ok(a.playback()),
+        None => Err(archive_disabled()),
+    }
+}
+
+/// Optional time argument in ms since the epoch
+fn time_arg(req: &JsonValue, name: &str, default: u64) -> Result<u64> {
+    match web::optional_arg(req, name) {
+        Some(_) => Ok(web::number_arg(req, name)?.max(0.0) as u64),
+        None => Ok(default),
     }
-    String::from("unknown")
 }
 
-fn api_list_controls(cam: &mut Camera, _req: &JsonValue) -> Result<JsonValue> {
+/// Recorded time ranges: {"from": <ms>, "to": <ms>, "gap": <ms>}, all arguments are optional.
+/// Frames further apart than gap (10 s by default) belong to different ranges.
+fn api_archive_ranges(playback: Option<&archive::Playback>, req: &JsonValue) -> Result<JsonValue> {
+    let playback = playback.ok_or_else(archive_disabled)?;
+    let from = time_arg(req, "from", 0)?;
+    let to = time_arg(req, "to", u64::MAX)?;
+    let gap = time_arg(req, "gap", 10 * 1000)?;
+
     let mut res: Vec<JsonValue> = vec![];
-    for control in cam.camera_controls()? {
-        let mut ctrl = std::collections::HashMap::<String, JsonValue>::new();
-        let descr = control.description();
+    for range in playback.ranges(from, to, gap)? {
+        let mut r = std::collections::HashMap::<String, JsonValue>::new();
+        r.insert(String::from("start"), JsonValue::Number(range.start as f64));
+        r.insert(String::from("end"), JsonValue::Number(range.end as f64));
+        r.insert(
+            String::from("frames"),
+            JsonValue::Number(range.frames as f64),
+        );
+        res.push(JsonValue::Object(r));
+    }
 
-        ctrl.insert(
-            String::from("name"),
-            JsonValue::String(control.name().to_string()),
+    Ok(JsonValue::Array(res))
+}
+
+/// Frame closest to the time: {"time": <ms>}, returns its time and URLs of the image and thumbnail
+/// in the archive of the camera
+fn api_archive_frame(
+    playback: Option<&archive::Playback>,
+    camera: &str,
+    req: &JsonValue,
+) -> Result<JsonValue> {
+    let playback = playback.ok_or_else(archive_disabled)?;
+    let time = web::number_arg(req, "time")?.max(0.0) as u64;
+
+    let frame = match playback.nearest(time)? {
+        Some(frame) => frame,
+        None => {
+            return Err(Box::new(web::ApiError::new(
+                web::ERR_NOT_FOUND,
+                "Archive is empty",
+            )))
+        }
+    };
+
+    let mut res = std::collections::HashMap::<String, JsonValue>::new();
+    res.insert(String::from("time"), JsonValue::Number(frame.time as f64));
+    res.insert(
+        String::from("image"),
+        JsonValue::String(web::archive_url(
+            camera,
+            &format!("frame.jpg?t={}", frame.time),
+        )),
+    );
+    res.insert(
+        String::from("thumbnail"),
+        JsonValue::String(web::archive_url(
+            camera,
+            &format!("thumb.jpg?t={}", frame.time),
+        )),
+    );
+
+    Ok(JsonValue::Object(res))
+}
+
+/// Build timelapse in the background: {"from": <date>, "to": <date>, "interval": "10m",
+/// "fps": 25, "daylight": true, "name": <file name>}, see timelapse::Options.
+/// Returns the job, its file is served at /cam/<id>/archive/timelapse/<name> when it is done.
+fn api_timelapse(
+    archive: &Option<archive::ImageArchive>,
+    schedule: &schedule::Schedule,
+    jobs: &mut timelapse::Jobs,
+    req: &JsonValue,
+) -> Result<JsonValue> {
+    let playback = archive_playback(archive)?;
+    let options = timelapse::Options::from_json(req, schedule.location())?;
+    let name = match web::optional_arg(req, "name") {
+        Some(_) => Some(web::string_arg(req, "name")?.as_str()),
+        None => None,
+    };
+
+    Ok(jobs.start(playback, options, name)?.to_json())
+}
+
+/// Cancel timelapse job: {"id": <id>}
+fn api_timelapse_cancel(jobs: &timelapse::Jobs, req: &JsonValue) -> Result<JsonValue> {
+    let id = web::number_arg(req, "id")?.max(0.0) as u32;
+    jobs.cancel(id)?;
+
+    Ok(jobs.get(id)?.to_json())
+}
+
+/// Frame statistics and archive state sent to live event clients once a second
+#[derive(Default)]
+struct Stats {
+    since: u64,
+    frames: u64,
+    bytes: usize,
+    width: u32,
+    height: u32,
+    written: usize,
+    last_written: Option<String>,
+    errors: usize,
+}
+
+impl Stats {
+    fn report(&mut self, log: &eventlog::EventLog, now: u64, archive: bool, recording: bool) {
+        if self.since == 0 {
+            self.since = now;
+        }
+        let elapsed = now.saturating_sub(self.since);
+        if elapsed < 1000 {
+            return;
+        }
+
+        let number = |n: f64| JsonValue::Number(n);
+        let frames = self.frames.max(1) as f64;
+        log.notify(
+            "stats",
+            eventlog::data(&[
+                ("fps", number(self.frames as f64 * 1000.0 / elapsed as f64)),
+                ("width", number(self.width as f64)),
+                ("height", number(self.height as f64)),
+                ("bytes", number((self.bytes as f64 / frames).round())),
+            ]),
         );
-        ctrl.insert(
-            String::from("flag"),
-            JsonValue::String(flag_to_string(control.flag())),
+        let last = match self.last_written.take() {
+            Some(path) => JsonValue::String(path),
+            None => JsonValue::Null,
+        };
+        log.notify(
+            "archive",
+            eventlog::data(&[
+                ("enabled", JsonValue::Boolean(archive)),
+                ("recording", JsonValue::Boolean(recording)),
+                ("written", number(self.written as f64)),
+                ("last", last),
+                ("errors", number(self.errors as f64)),
+            ]),
         );
 
-        // We can set only known types of values
-        let add = match descr {
-            nokhwa::utils::ControlValueDescription::IntegerRange {
-                min, max, value, ..
-            } => {
-                ctrl.insert(
-                    String::from("type"),
-                    JsonValue::String(String::from("number")),
-                );
-                ctrl.insert(String::from("min"), JsonValue::Number(*min as f64));
-                ctrl.insert(String::from("max"), JsonValue::Number(*max as f64));
-                ctrl.insert(String::from("value"), JsonValue::Number(*value as f64));
-                true
+        *self = Stats {
+            since: now,
+            ..Stats::default()
+        };
+    }
+}
+
+const NAME: Param = Param::required("name", ParamKind::String, "Name of the preset");
+const FROM: Param = Param::optional(
+    "from",
+    ParamKind::Number,
+    "Start time in ms since the epoch",
+);
+const TO: Param = Param::optional("to", ParamKind::Number, "End time in ms since the epoch");
+
+/// Method of the capture loop, it gets the state of the camera
+type LoopHandler = fn(&mut App, &JsonValue) -> Result<JsonValue>;
+
+enum Handler {
+    /// Method handled by web workers, it doesn't need the source
+    Worker(web::APICallback),
+    Loop(LoopHandler),
+}
+
+/// Methods of the camera: each one is registered with its description and handler in one call,
+/// the camera gets the descriptions and handlers of web workers, the capture loop the others
+struct Registry<'a> {
+    cam: &'a web::Camera,
+    handlers: HashMap<&'static str, LoopHandler>,
+}
+
+impl Registry<'_> {
+    fn register(&mut self, info: MethodInfo, handler: Handler) {
+        match handler {
+            Handler::Worker(handler) => self.cam.register(info, Some(handler)),
+            Handler::Loop(handler) => {
+                self.handlers.insert(info.name, handler);
+                self.cam.register(info, None);
             }
-            nokhwa::utils::ControlValueDescription::FloatRange {
-                min, max, value, ..
-            } => {
-                ctrl.insert(
-                    String::from("type"),
-                    JsonValue::String(String::from("number")),
+        }
+    }
+}
+
+/// Methods of the frame source
+fn register_source_methods(registry: &mut Registry) {
+    registry.register(
+        MethodInfo {
+            name: "ping",
+            description: "Return the arguments",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Worker(Box::new(api_ping)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "status",
+            description: "Source, resolution, active profile, motion and recorded event",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, req| {
+            api_status(
+                app.source.as_mut(),
+                &app.schedule,
+                &app.motion,
+                &app.recorder,
+                req,
+            )
+        }),
+    );
+    registry.register(
+        MethodInfo {
+            name: "list_controls",
+            description: "Controls of the source with their ranges and values",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, req| api_list_controls(app.source.as_mut(), req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "list_resolution",
+            description: "Resolutions, frame rates and formats of the source",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, req| api_list_resolutions(app.source.as_mut(), req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "set_control",
+            description: "Set a control or several ones given in controls, returns applied values",
+            role: Role::Admin,
+            params: const {
+                &[
+                    Param::optional("name", ParamKind::String, "Name of the control"),
+                    Param::optional("value", ParamKind::Any, "Value of the control"),
+                    Param::optional("controls", ParamKind::Array, "List of {name, value}"),
+                ]
+            },
+        },
+        Handler::Loop(|app, req| api_set_control(app.source.as_mut(), req)),
+    );
+}
+
+/// Methods of control presets
+fn register_preset_methods(registry: &mut Registry) {
+    registry.register(
+        MethodInfo {
+            name: "list_presets",
+            description: "Saved presets by name",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, req| api_list_presets(&app.presets, req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "save_preset",
+            description: "Save current controls and resolution",
+            role: Role::Admin,
+            params: &[NAME],
+        },
+        Handler::Loop(|app, req| api_save_preset(app.source.as_mut(), &mut app.presets, req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "apply_preset",
+            description: "Apply the preset, returns applied and failed controls",
+            role: Role::Admin,
+            params: &[NAME],
+        },
+        Handler::Loop(|app, req| api_apply_preset(app.source.as_mut(), &app.presets, req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "delete_preset",
+            description: "Delete the preset",
+            role: Role::Admin,
+            params: &[NAME],
+        },
+        Handler::Loop(|app, req| api_delete_preset(&mut app.presets, req)),
+    );
+}
+
+/// Methods of the profile schedule
+fn register_schedule_methods(registry: &mut Registry) {
+    registry.register(
+        MethodInfo {
+            name: "get_schedule",
+            description: "Schedule of profiles with the active one and sun times",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, req| api_get_schedule(&app.schedule, req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "set_schedule",
+            description: "Replace the schedule, the active profile is applied immediately",
+            role: Role::Admin,
+            params: const {
+                &[
+                    Param::optional("latitude", ParamKind::Number, "Latitude for sun times"),
+                    Param::optional("longitude", ParamKind::Number, "Longitude for sun times"),
+                    Param::optional(
+                        "utc_offset",
+                        ParamKind::Number,
+                        "Offset of local time in minutes",
+                    ),
+                    Param::optional("entries", ParamKind::Array, "Profiles with their times"),
+                ]
+            },
+        },
+        Handler::Loop(|app, req| api_set_schedule(&mut app.schedule, req)),
+    );
+}
+
+/// Methods of motion detection
+fn register_motion_methods(registry: &mut Registry) {
+    registry.register(
+        MethodInfo {
+            name: "get_motion",
+            description: "Motion detection settings and state",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, req| api_get_motion(&app.motion, req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "set_motion",
+            description: "Change motion detection settings, the ones not given are kept",
+            role: Role::Admin,
+            params: const {
+                &[
+                    Param::optional("enabled", ParamKind::Boolean, "Detect motion"),
+                    Param::optional("sensitivity", ParamKind::Number, "Sensitivity 1-100"),
+                    Param::optional("min_area", ParamKind::Number, "Changed area in percent"),
+                    Param::optional(
+                        "hold",
+                        ParamKind::Number,
+                        "Time in ms motion is kept active",
+                    ),
+                    Param::optional("zones", ParamKind::Array, "Included and excluded zones"),
+                ]
+            },
+        },
+        Handler::Loop(|app, req| api_set_motion(&mut app.motion, req)),
+    );
+}
+
+/// Methods of the archive, they read it on web workers
+fn register_archive_methods(registry: &mut Registry, playback: Option<archive::Playback>) {
+    let camera = String::from(registry.cam.id());
+    registry.register(
+        MethodInfo {
+            name: "archive_ranges",
+            description: "Recorded time ranges, frames further apart than gap start a new range",
+            role: Role::Viewer,
+            params: const {
+                &[
+                    FROM,
+                    TO,
+                    Param::optional("gap", ParamKind::Number, "Gap in ms, 10 s by default"),
+                ]
+            },
+        },
+        {
+            let playback = playback.clone();
+            Handler::Worker(Box::new(move |req: &JsonValue| {
+                api_archive_ranges(playback.as_ref(), req)
+            }))
+        },
+    );
+    registry.register(
+        MethodInfo {
+            name: "archive_frame",
+            description: "Archived frame closest to the time with URLs of the image and thumbnail",
+            role: Role::Viewer,
+            params: const {
+                &[Param::required(
+                    "time",
+                    ParamKind::Number,
+                    "Time in ms since the epoch",
+                )]
+            },
+        },
+        Handler::Worker(Box::new(move |req: &JsonValue| {
+            api_archive_frame(playback.as_ref(), &camera, req)
+        })),
+    );
+}
+
+/// Methods of event recording
+fn register_recorder_methods(registry: &mut Registry) {
+    registry.register(
+        MethodInfo {
+            name: "trigger_event",
+            description: "Start or extend a recorded event",
+            role: Role::Admin,
+            params: const {
+                &[
+                    Param::optional(
+                        "source",
+                        ParamKind::String,
+                        "Source of the event, api by default",
+                    ),
+                    Param::optional("duration", ParamKind::Number, "Duration in ms"),
+                ]
+            },
+        },
+        Handler::Loop(|app, req| api_trigger_event(&mut app.recorder, req)),
+    );
+    registry.register(
+        MethodInfo {
+            name: "list_events",
+            description: "Recorded events, the latest limit ones",
+            role: Role::Viewer,
+            params: const {
+                &[
+                    FROM,
+                    TO,
+                    Param::optional("source", ParamKind::String, "Source of the events"),
+                    Param::optional("limit", ParamKind::Number, "Maximum number of events"),
+                ]
+            },
+        },
+        Handler::Loop(|app, req| api_list_events(&app.recorder, req)),
+    );
+}
+
+/// Methods of timelapse jobs
+fn register_timelapse_methods(registry: &mut Registry) {
+    registry.register(
+        MethodInfo {
+            name: "timelapse",
+            description: "Build timelapse of the archive in the background, returns the job",
+            role: Role::Admin,
+            params: const {
+                &[
+                    Param::required("from", ParamKind::Any, "Start date or time in ms"),
+                    Param::required("to", ParamKind::Any, "End date or time in ms"),
+                    Param::optional(
+                        "interval",
+                        ParamKind::Any,
+                        "Interval between frames: 10m or ms",
+                    ),
+                    Param::optional("fps", ParamKind::Number, "Frame rate of the video"),
+                    Param::optional(
+                        "daylight",
+                        ParamKind::Boolean,
+                        "Only frames between sun times",
+                    ),
+                    Param::optional("latitude", ParamKind::Number, "Latitude for sun times"),
+                    Param::optional("longitude", ParamKind::Number, "Longitude for sun times"),
+                    Param::optional("name", ParamKind::String, "File name of the video"),
+                ]
+            },
+        },
+        Handler::Loop(|app, req| {
+            api_timelapse(&app.archive, &app.schedule, &mut app.timelapse, req)
+        }),
+    );
+    registry.register(
+        MethodInfo {
+            name: "timelapse_jobs",
+            description: "Timelapse jobs with their progress",
+            role: Role::Viewer,
+            params: &[],
+        },
+        Handler::Loop(|app, _| Ok(app.timelapse.to_json())),
+    );
+    registry.register(
+        MethodInfo {
+            name: "timelapse_cancel",
+            description: "Cancel the timelapse job",
+            role: Role::Admin,
+            params: const { &[Param::required("id", ParamKind::Number, "Job id")] },
+        },
+        Handler::Loop(|app, req| api_timelapse_cancel(&app.timelapse, req)),
+    );
+}
+
+/// Register the API methods of the camera, returns handlers of the capture loop
+fn register_methods(
+    cam: &web::Camera,
+    playback: Option<archive::Playback>,
+) -> HashMap<&'static str, LoopHandler> {
+    let mut registry = Registry {
+        cam,
+        handlers: HashMap::new(),
+    };
+    register_source_methods(&mut registry);
+    register_preset_methods(&mut registry);
+    register_schedule_methods(&mut registry);
+    register_motion_methods(&mut registry);
+    register_archive_methods(&mut registry, playback);
+    register_recorder_methods(&mut registry);
+    register_timelapse_methods(&mut registry);
+    registry.handlers
+}
+
+/// State of the main loop: frame source and everything working with it
+struct App {
+    source: Box<dyn FrameSource>,
+    presets: presets::Presets,
+    schedule: schedule::Schedule,
+    motion: motion::Detector,
+    archive: Option<archive::ImageArchive>,
+    recorder: Option<recorder::Recorder>,
+    timelapse: timelapse::Jobs,
+    log: eventlog::EventLog,
+    stats: Stats,
+    jpeg_quality: u8,
+    // External triggers seen by the camera
+    triggers: u64,
+    methods: HashMap<&'static str, LoopHandler>,
+}
+
+impl App {
+    /// Call the method of the capture loop
+    fn handle_request(&mut self, method: &str, args: &JsonValue) -> Result<JsonValue> {
+        match self.methods.get(method).copied() {
+            Some(handler) => handler(self, args),
+            None => Err(Box::new(web::ApiError::new(
+                web::ERR_UNKNOWN_METHOD,
+                &format!("Unknown method '{}'", method),
+            ))),
+        }
+    }
+
+    /// Log successful changes of controls
+    fn log_request(&self, method: &str, args: &JsonValue, res: &JsonValue) {
+        if method != "set_control" && method != "apply_preset" {
+            return;
+        }
+        if let JsonValue::Object(obj) = res {
+            if obj.contains_key("error") {
+                return;
+            }
+        }
+
+        self.log.log(
+            "control",
+            eventlog::data(&[
+                ("source", JsonValue::String(format!("api {}", method))),
+                ("request", args.clone()),
+                ("result", res.clone()),
+            ]),
+        );
+    }
+
+    /// Process pending API request, run the schedule and publish the next frame
+    fn step(&mut self, cam: &web::Camera) -> Result<()> {
+        if let Some(req) = cam.json_request() {
+            let res = match self.handle_request(&req.method, &req.args) {
+                Ok(res) => res,
+                Err(err) => api_error(&web::ApiError::from_error(err.as_ref())),
+            };
+            self.log_request(&req.method, &req.args, &res);
+            req.result_sender.send(res)?;
+        }
+
+        if let Some(report) = self.schedule.run(self.source.as_mut(), datetime::now_ms()) {
+            if let Some(profile) = self.schedule.active() {
+                println!("Profile {}: {}", profile.name, report.stringify()?);
+                let source = format!("profile {}", profile.name);
+                self.log.log(
+                    "control",
+                    eventlog::data(&[("source", JsonValue::String(source)), ("result", report)]),
                 );
-                ctrl.insert(String::from("min"), JsonValue::Number(*min));
-                ctrl.insert(String::from("max"), JsonValue::Number(*max));
-                ctrl.insert(String::from("value"), JsonValue::Number(*value));
-                true
             }
+        }
 
-            _ => false,
+        let frame = match self.source.frame() {
+            Ok(frame) => frame,
+            Err(err) => {
+                self.log.log(
+                    "camera",
+                    eventlog::data(&[
+                        ("state", JsonValue::String(String::from("disconnected"))),
+                        ("error", JsonValue::String(err.to_string())),
+                    ]),
+                );
+                return Err(err);
+            }
         };
+        println!(
+            "Frame: {} {}",
+            frame.resolution(),
+            frame.source_frame_format()
+        );
+        let image = jpeg::frame_to_jpeg(&frame, self.jpeg_quality)?;
+        cam.update_image(&image)?;
+
+        let resolution = frame.resolution();
+        self.stats.frames += 1;
+        self.stats.bytes += image.len();
+        self.stats.width = resolution.width();
+        self.stats.height = resolution.height();
+
+        if self.motion.enabled() {
+            match motion::Gray::from_jpeg(&image) {
+                Ok(gray) => {
+                    let state = match self.motion.process(&gray, datetime::now_ms()) {
+                        Some(motion::Event::Started) => "started",
+                        Some(motion::Event::Ended) => "ended",
+                        None => "",
+                    };
+                    if !state.is_empty() {
+                        println!("Motion {}: {:.1}%", state, self.motion.score());
+                        self.log.log(
+                            "motion",
+                            eventlog::data(&[
+                                ("state", JsonValue::String(String::from(state))),
+                                ("score", JsonValue::Number(self.motion.score())),
+                            ]),
+                        );
+                    }
+                }
+                Err(err) => println!("Motion detection error: {}", err),
+            }
+        }
 
-        if add {
-            res.push(JsonValue::Object(ctrl));
+        if let Some(ref mut a) = self.archive {
+            match a.add_image(&image) {
+                Ok(written) => {
+                    self.stats.written += written.len();
+                    for path in written {
+                        println!("Written {}", path);
+                        self.stats.last_written = Some(path);
+                    }
+                }
+                Err(err) => {
+                    println!("Archive error: {}", err);
+                    self.stats.errors += 1;
+                }
+            }
         }
+
+        if let Some(ref mut r) = self.recorder {
+            let now = datetime::now_ms();
+            match r.add_frame(now, &image) {
+                Ok(Some(event)) => println!("Event {} recorded: {}", event.id, event.clip),
+                Ok(None) => (),
+                Err(err) => println!("Recording error: {}", err),
+            }
+
+            let mut triggers: Vec<&str> = vec![];
+            if self.motion.active() {
+                triggers.push("motion");
+            }
+            if recorder::external_triggered(&mut self.triggers) {
+                triggers.push("external");
+            }
+            for source in triggers {
+                match r.trigger(source, now, 0) {
+                    Ok(event) if event.start == now && event.source == source => {
+                        println!("Event {} started: {}", event.id, source)
+                    }
+                    Ok(_) => (),
+                    Err(err) => println!("Recording error: {}", err),
+                }
+            }
+        }
+
+        let recording = self
+            .recorder
+            .as_ref()
+            .is_some_and(|r| r.current().is_some());
+        self.stats.report(
+            &self.log,
+            datetime::now_ms(),
+            self.archive.is_some(),
+            recording,
+        );
+
+        Ok(())
     }
 
-    Ok(JsonValue::Array(res))
+    /// Reopen the source after an error until it is opened or stop is set, waiting longer after
+    /// every failed attempt. Calls of methods of the capture loop get ERR_BUSY meanwhile.
+    fn reconnect(&mut self, config: &cameras::CameraConfig, cam: &web::Camera, stop: &AtomicBool) {
+        if let Err(err) = self.source.close() {
+            println!("Error: can't close source: {}", err);
+        }
+
+        let mut delay = RECONNECT_DELAY;
+        let mut next = Instant::now() + delay;
+        while !stop.load(Ordering::SeqCst) {
+            if let Some(req) = cam.json_request() {
+                let err = web::ApiError::new(
+                    web::ERR_BUSY,
+                    &format!("Camera {} is disconnected", config.id),
+                );
+                req.result_sender.send(api_error(&err)).ok();
+            }
+            if Instant::now() < next {
+                continue;
+            }
+
+            match open_source(config, &self.presets) {
+                Ok(source) => {
+                    self.source = source;
+                    cam.set_source(&self.source.name());
+                    log_connected(config, self.source.as_ref(), &self.log);
+                    return;
+                }
+                Err(err) => {
+                    println!("Camera {}: can't reopen source: {}", config.id, err);
+                    delay = std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                    next = Instant::now() + delay;
+                }
+            }
+        }
+    }
+
+    /// Stop the camera stream, timelapse jobs, event recording and the archive
+    fn shutdown(&mut self) {
+        if let Err(err) = self.source.close() {
+            println!("Error: can't close source: {}", err);
+        }
+
+        self.timelapse.shutdown();
+
+        if let Some(ref mut r) = self.recorder {
+            match r.finish() {
+                Ok(Some(event)) => println!("Event {} recorded: {}", event.id, event.clip),
+                Ok(None) => (),
+                Err(err) => println!("Error: can't finish event: {}", err),
+            }
+        }
+
+        if let Some(ref mut a) = self.archive {
+            if let Err(err) = a.stop() {
+                println!("Error: can't stop archive: {}", err);
+            }
+        }
+    }
 }
 
-fn api_set_control(cam: &mut Camera, req: &JsonValue) -> Result<JsonValue> {
-    Ok(JsonValue::Boolean(true))
+/// User command: change the users file
+fn manage_users(path: &Path, cmd: &UserCmd) -> Result<()> {
+    let mut users = auth::Users::load(path)?;
+    match cmd.action {
+        UserAction::Add(ref add) => {
+            let role = auth::Role::parse(&add.role)?;
+            let password = match add.password {
+                Some(ref password) => password.clone(),
+                None => {
+                    print!("Password for {}: ", add.name);
+                    std::io::stdout().flush()?;
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)?;
+                    String::from(line.trim_end_matches(['\r', '\n']))
+                }
+            };
+            users.add_user(&add.name, role, &password)?;
+            users.save(path)?;
+            println!(
+                "User {} ({}) saved into {}",
+                add.name,
+                role.name(),
+                path.display()
+            );
+        }
+        UserAction::Token(ref token) => {
+            let role = auth::Role::parse(&token.role)?;
+            let value = users.add_token(&token.name, role)?;
+            users.save(path)?;
+            println!("Token {} ({}): {}", token.name, role.name(), value);
+        }
+        UserAction::Remove(ref remove) => {
+            if !users.remove(&remove.name) {
+                return Err(format!("No user or token {}", remove.name).into());
+            }
+            users.save(path)?;
+            println!("Removed {}", remove.name);
+        }
+        UserAction::List(_) => {
+            for user in &users.users {
+                println!("user {} {}", user.name, user.role.name());
+            }
+            for token in &users.tokens {
+                println!("token {} {}", token.name, token.role.name());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Migrate command: move frames of the flat archive into the layout
+fn migrate_archive(args: &CmdLine, layout: &archive::Layout) -> Result<()> {
+    let path = match args.output {
+        Some(ref path) => std::path::PathBuf::from(path),
+        None => return Err(Box::<dyn Error>::from("Archive directory is required")),
+    };
+    let moved = archive::migrate(&path, layout, &args.name)?;
+    println!("Moved {} frames into {}", moved, layout);
+
+    Ok(())
+}
+
+/// Timelapse command: build the video printing progress every 10%
+fn build_timelapse(args: &CmdLine, cmd: &TimelapseCmd, layout: &archive::Layout) -> Result<()> {
+    let root = match args.output {
+        Some(ref path) => std::path::PathBuf::from(path),
+        None => return Err(Box::<dyn Error>::from("Archive directory is required")),
+    };
+
+    let daylight = match (cmd.daylight, cmd.latitude, cmd.longitude) {
+        (false, _, _) => None,
+        (true, Some(lat), Some(lon)) => Some((lat, lon)),
+        (true, _, _) => {
+            let schedule_path = match args.schedule {
+                Some(ref path) => std::path::PathBuf::from(path),
+                None => presets::default_path(schedule::DEFAULT_FILE),
+            };
+            match schedule::Schedule::load(&schedule_path)?.location() {
+                Some(location) => Some(location),
+                None => {
+                    return Err(Box::<dyn Error>::from(
+                        "Location is required for --daylight: set --latitude and --longitude",
+                    ))
+                }
+            }
+        }
+    };
+    let options = timelapse::Options {
+        from: timelapse::parse_time(&cmd.from)?,
+        to: timelapse::parse_time(&cmd.to)?,
+        interval: timelapse::parse_interval(&cmd.interval)?,
+        fps: cmd.fps,
+        daylight,
+    };
+
+    let playback = archive::Playback::new(&root, layout, &args.name);
+    let mut reported = 0;
+    let count = timelapse::build(
+        &playback,
+        &options,
+        Path::new(&cmd.file),
+        &mut |done, total| {
+            let percent = done * 100 / total;
+            if percent >= reported + 10 || done == total {
+                reported = percent;
+                println!("Timelapse: {}/{} frames ({}%)", done, total, percent);
+            }
+            true
+        },
+    )?;
+    println!("Written {} frames into {}", count, cmd.file);
+
+    Ok(())
+}
+
+/// Options of the archive and recording shared by all cameras
+#[derive(Clone)]
+struct Options {
+    layout: archive::Layout,
+    mode: archive::Mode,
+    fps: u32,
+    retention: archive::Retention,
+    continuous: bool,
+    // Event recording options, None if events are not recorded
+    recorder: Option<recorder::Options>,
+    jpeg_quality: u8,
+}
+
+/// Create the source of the camera, select its resolution, apply the preset and open it
+fn open_source(
+    config: &cameras::CameraConfig,
+    presets: &presets::Presets,
+) -> Result<Box<dyn FrameSource>> {
+    let mut source = source::create(&config.source)?;
+    println!("Camera {}: {}", config.id, source.name());
+
+    if let Some(ref r) = config.resolution {
+        source::select_format(source.as_mut(), r)?;
+    }
+
+    if let Some(ref name) = config.preset {
+        let report = presets.get(name)?.apply(source.as_mut());
+        println!("Preset {}: {}", name, report.stringify()?);
+    }
+
+    source.open()?;
+
+    Ok(source)
+}
+
+/// Report the opened source of the camera
+fn log_connected(
+    config: &cameras::CameraConfig,
+    source: &dyn FrameSource,
+    log: &eventlog::EventLog,
+) {
+    let resolution = format!(
+        "{}/{}",
+        source.format().resolution(),
+        source.format().frame_rate()
+    );
+    println!("Camera {}: resolution {}", config.id, resolution);
+    log.log(
+        "camera",
+        eventlog::data(&[
+            ("state", JsonValue::String(String::from("connected"))),
+            ("source", JsonValue::String(source.name())),
+            ("resolution", JsonValue::String(resolution)),
+        ]),
+    );
+}
+
+/// Open the source of the camera with its archive, recorder, presets, schedule and motion
+/// detection and register methods of the camera
+fn start_camera(
+    config: &cameras::CameraConfig,
+    options: &Options,
+    log: eventlog::EventLog,
+    cam: &web::Camera,
+) -> Result<App> {
+    let recorder = match (&options.recorder, &config.output) {
+        (Some(opts), Some(path)) => {
+            let dir = path.join(recorder::EVENTS_DIR);
+            let mut r = recorder::Recorder::open(&dir, &config.id, opts.clone())?;
+            r.set_event_log(log.clone());
+            Some(r)
+        }
+        (Some(_), None) => {
+            return Err(Box::<dyn Error>::from(
+                "Archive directory is required for event recording",
+            ))
+        }
+        _ => None,
+    };
+    if let Some(ref path) = config.output {
+        // Archives of cameras are subdirectories of --output which may not exist yet
+        std::fs::create_dir_all(path)?;
+        cam.set_archive(archive::Playback::new(path, &options.layout, &config.id));
+    }
+
+    let archive = match config.output {
+        Some(ref path) if options.continuous => {
+            let mut arch = archive::ImageArchive::new(&path.to_string_lossy())?;
+            arch.set_layout(options.layout.clone(), &config.id);
+            arch.set_mode(options.mode);
+            arch.set_fps(options.fps)?;
+            arch.set_event_log(log.clone());
+            arch.set_retention(options.retention.clone());
+            arch.run()?;
+            Some(arch)
+        }
+        _ => None,
+    };
+    let methods = register_methods(cam, archive.as_ref().map(|a| a.playback()));
+
+    let presets = presets::Presets::load(&config.presets)?;
+    let schedule = schedule::Schedule::load(&config.schedule)?;
+    let motion = motion::Detector::load(&config.motion)?;
+
+    let source = open_source(config, &presets)?;
+    cam.set_source(&source.name());
+    log_connected(config, source.as_ref(), &log);
+
+    Ok(App {
+        source,
+        presets,
+        schedule,
+        motion,
+        archive,
+        recorder,
+        timelapse: timelapse::Jobs::new(&config.id),
+        log,
+        stats: Stats::default(),
+        jpeg_quality: options.jpeg_quality,
+        triggers: 0,
+        methods,
+    })
 }
 
-fn api<F>(mut cb: F, req: &JsonValue) -> JsonValue
-where
-    F: FnMut(&JsonValue) -> Result<JsonValue>,
-{
-    match cb(req) {
-        Ok(res) => res,
-        Err(err) => api_error(&err.to_string()),
+/// Capture thread of the camera: start the camera, report it by ready and run its loop until
+/// stop. The source is reopened after errors, so other cameras keep working.
+fn run_camera(
+    config: &cameras::CameraConfig,
+    options: &Options,
+    log: eventlog::EventLog,
+    cam: web::Camera,
+    ready: std::sync::mpsc::Sender<std::result::Result<(), String>>,
+    stop: &AtomicBool,
+) -> std::result::Result<(), String> {
+    let mut app = match start_camera(config, options, log, &cam) {
+        Ok(app) => app,
+        Err(err) => {
+            let err = format!("Camera {}: {}", config.id, err);
+            ready.send(Err(err.clone())).ok();
+            return Err(err);
+        }
+    };
+    ready.send(Ok(())).ok();
+
+    while !stop.load(Ordering::SeqCst) {
+        if let Err(err) = app.step(&cam) {
+            println!("Camera {}: {}", config.id, err);
+            app.reconnect(config, &cam, stop);
+        }
     }
+    app.shutdown();
+
+    Ok(())
 }
 
 fn main_err() -> Result<()> {
@@ -218,119 +1624,279 @@ fn main_err() -> Result<()> {
         println!("Result: {}", r);
     });
 
-    let cameras = nokhwa::query(nokhwa::utils::ApiBackend::Auto)?;
-
     if args.list {
+        let cameras = nokhwa::query(nokhwa::utils::ApiBackend::Auto)?;
         list_cameras(&cameras)?;
         return Ok(());
     }
 
-    for x in cameras {
-        println!("{}", x);
+    let layout = archive::Layout::parse(&args.archive_layout)?;
+    let mode = archive::Mode::parse(&args.archive_mode, args.segment_minutes)?;
+    cameras::check_id(&args.name)?;
+    let users_path = match args.users {
+        Some(ref path) => std::path::PathBuf::from(path),
+        None => presets::default_path(auth::DEFAULT_FILE),
+    };
+    match args.command {
+        Some(Command::Migrate(_)) => return migrate_archive(&args, &layout),
+        Some(Command::Timelapse(ref cmd)) => return build_timelapse(&args, cmd, &layout),
+        Some(Command::User(ref cmd)) => return manage_users(&users_path, cmd),
+        None => (),
     }
 
-    let srv = web::Server::new("127.0.0.1:8080")?;
+    if args.jpeg_quality == 0 || args.jpeg_quality > 100 {
+        return Err(Box::<dyn Error>::from(
+            "JPEG quality must be in range 1-100",
+        ));
+    }
 
-    let requested =
-        RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestResolution);
-    let index = CameraIndex::Index(args.camera);
-    let mut camera = Camera::new(index, requested)?;
-    let mut archive: Option<archive::ImageArchive> = match args.output {
-        Some(path) => Some(archive::ImageArchive::new(&path)?),
-        None => None,
+    if args.workers == 0 {
+        return Err(Box::<dyn Error>::from(
+            "At least one web worker is required",
+        ));
+    }
+
+    let output = args.output.as_ref().map(std::path::PathBuf::from);
+    let configs = match args.cameras {
+        Some(ref path) => {
+            if args.source.is_some()
+                || args.resolution.is_some()
+                || args.preset.is_some()
+                || args.presets.is_some()
+                || args.schedule.is_some()
+                || args.motion.is_some()
+            {
+                return Err(Box::<dyn Error>::from(
+                    "Source, resolution, preset, presets, schedule and motion of cameras are set in --cameras file",
+                ));
+            }
+            cameras::load(Path::new(path), output.as_deref())?
+        }
+        None => vec![cameras::CameraConfig {
+            id: args.name.clone(),
+            source: match args.source {
+                Some(ref spec) => spec.clone(),
+                None => format!("camera:{}", args.camera),
+            },
+            resolution: args.resolution.clone(),
+            output: output.clone(),
+            preset: args.preset.clone(),
+            presets: match args.presets {
+                Some(ref path) => std::path::PathBuf::from(path),
+                None => presets::default_path(presets::DEFAULT_FILE),
+            },
+            schedule: match args.schedule {
+                Some(ref path) => std::path::PathBuf::from(path),
+                None => presets::default_path(schedule::DEFAULT_FILE),
+            },
+            motion: match args.motion {
+                Some(ref path) => std::path::PathBuf::from(path),
+                None => presets::default_path(motion::DEFAULT_FILE),
+            },
+        }],
     };
 
-    match archive {
-        Some(ref mut arch) => {
-            // TODO: set archive parameters
-            arch.run();
+    let (continuous, events) = match args.record.as_str() {
+        "continuous" => (true, false),
+        "events" => (false, true),
+        "both" => (true, true),
+        other => {
+            return Err(Box::<dyn Error>::from(format!(
+                "Unknown recording '{}', expected continuous, events or both",
+                other
+            )))
+        }
+    };
+    if events && configs.iter().any(|c| c.output.is_none()) {
+        return Err(Box::<dyn Error>::from(
+            "Archive directory is required for event recording",
+        ));
+    }
+    let options = Options {
+        layout,
+        mode,
+        fps: args.fps,
+        retention: archive::Retention {
+            max_age: match args.max_age {
+                0 => None,
+                hours => Some(hours as u64 * 3600 * 1000),
+            },
+            max_count: args.max_frames,
+            max_bytes: args
+                .max_size
+                .as_deref()
+                .map(archive::parse_size)
+                .transpose()?,
+            min_free: args
+                .min_free
+                .as_deref()
+                .map(archive::parse_size)
+                .transpose()?,
+            ..archive::Retention::default()
         },
-        None => (),
+        continuous,
+        recorder: match events {
+            true => Some(recorder::Options {
+                pre_roll: args.pre_roll as u64 * 1000,
+                post_roll: args.post_roll as u64 * 1000,
+                max_length: args.max_event as u64 * 1000,
+                fps: args.event_fps,
+            }),
+            false => None,
+        },
+        jpeg_quality: args.jpeg_quality,
     };
 
-    match args.resolution {
-        Some(r) => {
-            let formats = camera.compatible_camera_formats()?;
-            let mut found = false;
-            for fmt in &formats {
-                if camera_format_string(fmt) == r {
-                    camera.set_resolution(fmt.resolution())?;
-                    camera.set_frame_rate(fmt.frame_rate())?;
-                    camera.set_frame_format(fmt.format())?;
-                    found = true;
-                    break;
-                }
-            }
+    let mut listen: Vec<web::Listen> = vec![];
+    for addr in args.address.iter().flat_map(|a| a.split(',')) {
+        listen.push(web::Listen::parse(addr.trim())?);
+    }
+    if listen.is_empty() {
+        listen.push(web::Listen::parse(DEFAULT_ADDRESS)?);
+    }
 
-            if !found {
-                return Err(<Box<dyn Error>>::from(String::from(
-                    "Camera format is not found!",
-                )));
-            }
+    let https: Vec<&str> = listen
+        .iter()
+        .filter_map(|l| match l {
+            web::Listen::Https(addr) => Some(addr.as_str()),
+            _ => None,
+        })
+        .collect();
+    let tls = if !https.is_empty() {
+        let tls = web::Tls {
+            cert: match args.tls_cert {
+                Some(ref path) => std::path::PathBuf::from(path),
+                None => presets::default_path(DEFAULT_TLS_CERT),
+            },
+            key: match args.tls_key {
+                Some(ref path) => std::path::PathBuf::from(path),
+                None => presets::default_path(DEFAULT_TLS_KEY),
+            },
+            redirect: args.https_redirect,
+        };
+        // Certificate is valid for the addresses of https listeners
+        let names: Vec<String> = https
+            .iter()
+            .filter_map(|addr| addr.rsplit_once(':'))
+            .map(|(host, _)| host.trim_matches(['[', ']']))
+            .filter(|host| {
+                !host
+                    .parse::<std::net::IpAddr>()
+                    .is_ok_and(|ip| ip.is_unspecified())
+            })
+            .map(String::from)
+            .collect();
+        if args.self_signed && tls.generate_self_signed(&names)? {
+            println!("Generated self-signed certificate {}", tls.cert.display());
         }
-        None => (),
+        Some(tls)
+    } else if args.tls_cert.is_some()
+        || args.tls_key.is_some()
+        || args.self_signed
+        || args.https_redirect
+    {
+        return Err(Box::<dyn Error>::from(
+            "Certificate options require https listeners",
+        ));
+    } else {
+        None
     };
 
-    camera.open_stream()?;
+    let reload_tls = tls.is_some();
+    let auth = auth::Auth::open(&users_path)?;
+    let users = auth.users();
+    if users.is_empty() {
+        println!(
+            "Authentication: disabled until users are added to {}",
+            users_path.display()
+        );
+    } else {
+        println!(
+            "Authentication: {} users, {} tokens",
+            users.users.len(),
+            users.tokens.len()
+        );
+    }
+    let srv = web::Server::new(&listen, args.workers, tls, auth)?;
 
-    println!(
-        "Resolution: {}/{}",
-        camera.resolution(),
-        camera.frame_rate()
-    );
+    // Events of all cameras are kept in one log, entries are tagged by ids of cameras
+    let log = match output {
+        Some(ref path) => eventlog::EventLog::open(
+            &path.join(eventlog::LOG_DIR),
+            archive::parse_size(&args.log_size)?,
+            args.log_files,
+        )?,
+        None => eventlog::EventLog::memory(),
+    };
+    srv.set_event_log(log.clone());
+    if events {
+        recorder::install_trigger_signal();
+    }
 
-    loop {
-        let req = srv.json_request();
-        match req {
-            Some(req) => {
-                let res = if req.method == "ping" {
-                    api(api_ping, &req.args)
-                } else if req.method == "list_controls" {
-                    api(
-                        |req: &JsonValue| -> Result<JsonValue> {
-                            api_list_controls(&mut camera, req)
-                        },
-                        &req.args,
-                    )
-                } else if req.method == "list_resolution" {
-                    api(
-                        |req: &JsonValue| -> Result<JsonValue> {
-                            api_list_resolutions(&mut camera, req)
-                        },
-                        &req.args,
-                    )
-                } else if req.method == "set_control" {
-                    api(
-                        |req: &JsonValue| -> Result<JsonValue> {
-                            api_set_control(&mut camera, req)
-                        },
-                        &req.args,
-                    )
-                } else {
-                    api_error("Unknown method")
-                };
-                req.result_sender.send(res)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = Arc::clone(&stop);
+        ctrlc::set_handler(move || {
+            if stop.swap(true, Ordering::SeqCst) {
+                println!("Terminated");
+                std::process::exit(1);
             }
-            None => (),
-        }
+            println!("Stopping...");
+        })?;
+    }
+    if reload_tls {
+        web::install_reload_signal();
+    }
 
-        let frame = camera.frame()?;
-        println!(
-            "Frame: {} {}",
-            frame.resolution(),
-            frame.source_frame_format()
-        );
-        srv.update_image(frame.buffer())?;
+    // Every camera is captured by its own thread, sources can't be moved between threads, so
+    // they are opened there
+    let (ready_sender, ready) = std::sync::mpsc::channel::<std::result::Result<(), String>>();
+    let mut threads = vec![];
+    for config in configs {
+        let cam = srv.add_camera(&config.id)?;
+        let options = options.clone();
+        let log = log.for_camera(&config.id);
+        let ready_sender = ready_sender.clone();
+        let stop = Arc::clone(&stop);
+        threads.push(std::thread::spawn(move || {
+            run_camera(&config, &options, log, cam, ready_sender, &stop)
+        }));
+    }
+    drop(ready_sender);
 
-        match archive {
-            Some(ref mut a) => {
-                println!("Written image {}", a.add_image(frame.buffer())?);
+    let mut res: std::result::Result<(), String> = Ok(());
+    for _ in 0..threads.len() {
+        match ready.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                res = Err(err);
+                break;
             }
-            None => (),
-        };
+            Err(_) => {
+                res = Err(String::from("Camera thread is stopped"));
+                break;
+            }
+        }
+    }
+    if res.is_err() {
+        stop.store(true, Ordering::SeqCst);
+    }
+
+    while !stop.load(Ordering::SeqCst) {
+        std::thread::sleep(core::time::Duration::from_millis(100));
+    }
+
+    for thread in threads {
+        let r = thread
+            .join()
+            .unwrap_or_else(|_| Err(String::from("Camera thread panicked")));
+        if res.is_ok() {
+            res = r;
+        }
     }
+    srv.destroy();
 
-    // save_file("test.jpg", frame.buffer());
+    res.map_err(Box::<dyn Error>::from)
 }
 
 fn main() {
@@ -40,8 +40,9 @@ Example to add an image.Image as a frame to the video:
 
     checkErr(aw.Close())
 */
+use crate::datetime::DateTime;
 use std::fs::File;
-use std::io::{Seek, Write};
+use std::io::{Read, Seek, Write};
 
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
@@ -52,14 +53,10 @@ fn err(s: &str) -> Box<dyn Error> {
 /// AviWriter is an *.avi video writer.
 /// The video codec is MJPEG.
 pub struct AviWriter {
-    // aviFile is the name of the file to write the result to
-    avi_file: String,
     // width is the width of the video
     width: u32,
     // height is the height of the video
     height: u32,
-    // fps is the frames/second (the "speed") of the video
-    fps: u32,
 
     // avif is the avi file descriptor
     avif: File,
@@ -80,21 +77,44 @@ pub struct AviWriter {
 
     // frames is the number of frames written to the AVI file
     frames: u32,
+    // finalized is set once the headers and the index are written
+    finalized: bool,
 }
 
 impl AviWriter {
     // New returns a new AviWriter.
     // The Close() method of the AviWriter must be called to finalize the video file.
     pub fn new(avi_file: &str, width: u32, height: u32, fps: u32) -> Result<AviWriter> {
+        AviWriter::create(avi_file, width, height, fps, None)
+    }
+
+    /// with_start_time creates the video with the recording time of its first frame (ms since
+    /// the epoch) stored in the IDIT chunk.
+    pub fn with_start_time(
+        avi_file: &str,
+        width: u32,
+        height: u32,
+        fps: u32,
+        start: u64,
+    ) -> Result<AviWriter> {
+        AviWriter::create(avi_file, width, height, fps, Some(start))
+    }
+
+    fn create(
+        avi_file: &str,
+        width: u32,
+        height: u32,
+        fps: u32,
+        start: Option<u64>,
+    ) -> Result<AviWriter> {
         let mut aw = AviWriter {
-            avi_file: String::from(avi_file),
             width: width,
             height: height,
-            fps: fps,
             idx: vec![],
             length_fields: vec![],
             avif: File::create(avi_file)?,
             frames: 0,
+            finalized: false,
             movi_pos: 0,
             frames_count_field_pos: 0,
             frames_count_field_pos2: 0,
@@ -168,13 +188,20 @@ impl AviWriter {
         let mut name = String::from("Created with https://github.com/icza/mjpeg"); // TODO: + " at " + time.Now().Format("2006-01-02 15:04:05 MST")
                                                                                    // Name must be 0-terminated and stream name length (the length of the chunk) must be even
         if name.len() & 0x01 == 0 {
-            name = name + " \000" // padding space plus terminating 0
+            name += " \0" // padding space plus terminating 0
         } else {
-            name = name + "\000" // terminating 0
+            name += "\0" // terminating 0
         }
         aw.write_u32(name.len() as u32)?; // Length of the strn sub-CHUNK (must be even)
         aw.write_str(&name)?;
         aw.finalize_length_field()?; // LIST 'strl' finished (nesting level 2)
+
+        if let Some(start) = start {
+            aw.write_str("IDIT")?; // Digitization date and time: UTC with ms, 0-terminated
+            let date = format!("{}\0", DateTime::from_ms(start)); // 24 bytes, length is even
+            aw.write_u32(date.len() as u32)?;
+            aw.write_str(&date)?;
+        }
         aw.finalize_length_field()?; // LIST 'hdrl' finished (nesting level 1)
 
         aw.write_str("LIST")?; // The second LIST chunk, which contains the actual data
@@ -187,19 +214,13 @@ impl AviWriter {
 
     // write_str writes a string to the file.
     fn write_str(&mut self, s: &str) -> Result<()> {
-        self.avif.write(s.as_bytes())?;
+        self.avif.write_all(s.as_bytes())?;
         Ok(())
     }
 
-    // writeInt32 writes a 32-bit int value to the file.
+    // writeInt32 writes a 32-bit int value to the file (AVI is little endian).
     fn write_u32(&mut self, n: u32) -> Result<()> {
-        let mut buf: [u8; 4] = [0, 0, 0, 0];
-        buf[3] = (n & 0xff) as u8;
-        buf[2] = ((n >> 8) & 0xff) as u8;
-        buf[1] = ((n >> 16) & 0xff) as u8;
-        buf[0] = ((n >> 24) & 0xff) as u8;
-
-        self.avif.write(&buf)?;
+        self.avif.write_all(&n.to_le_bytes())?;
 
         Ok(())
     }
@@ -211,13 +232,9 @@ impl AviWriter {
         self.idx.push(((n >> 24) & 0xff) as u8);
     }
 
-    // write_u16 writes a 16-bit int value to the index file.
+    // write_u16 writes a 16-bit int value to the file.
     fn write_u16(&mut self, n: u16) -> Result<()> {
-        let mut buf: [u8; 2] = [0, 0];
-        buf[1] = (n & 0xff) as u8;
-        buf[0] = ((n >> 8) & 0xff) as u8;
-
-        self.avif.write(&buf)?;
+        self.avif.write_all(&n.to_le_bytes())?;
 
         Ok(())
     }
@@ -246,7 +263,7 @@ impl AviWriter {
         self.write_u32((pos - len_pos - 4) as u32)?;
         self.avif.seek(std::io::SeekFrom::Start(pos))?;
         if pos % 2 == 1 {
-            self.avif.write(&[0])?;
+            self.avif.write_all(&[0])?;
         }
         Ok(())
     }
@@ -255,22 +272,44 @@ impl AviWriter {
         Ok(self.avif.seek(std::io::SeekFrom::Current(0))?)
     }
 
-    /// add_frame adds new frame to MJpeg stream
-    pub fn add_frame(&mut self, jpeg_data: &[u8]) -> Result<()> {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frames
+    }
+
+    /// fits checks if a frame of the given size could be added without exceeding the AVI limits
+    pub fn fits(&mut self, len: usize) -> Result<bool> {
         let frame_pos = self.tell()?;
 
         // Pointers in AVI are 32 bit. Do not write beyond that else the whole AVI file will be corrupted (not playable).
-        // Index entry size: 16 bytes (for each frame)
-        if frame_pos + jpeg_data.len() as u64 + (self.frames * 16) as u64 > 4200000000 {
-            // 2^32 = 4 294 967 296
+        // Index entry size: 16 bytes (for each frame) plus 8 bytes of the chunk header
+        // 2^32 = 4 294 967 296
+        Ok(frame_pos + len as u64 + 8 + (self.frames as u64 + 1) * 16 <= 4200000000)
+    }
+
+    /// add_frame adds new frame to MJpeg stream
+    pub fn add_frame(&mut self, jpeg_data: &[u8]) -> Result<()> {
+        if self.finalized {
+            return Err(err("File is finalized"));
+        }
+        if !self.fits(jpeg_data.len())? {
             return Err(err("File is too large"));
         }
 
+        let frame_pos = self.tell()?;
+
         self.frames += 1;
 
         self.write_u32(0x63643030)?; // "00dc" compressed frame
         self.write_length_field()?; // Chunk length (nesting level 2)
-        self.avif.write(jpeg_data)?;
+        self.avif.write_all(jpeg_data)?;
         self.finalize_length_field()?; // "00dc" chunk finished (nesting level 2)
 
         // Write index data
@@ -283,6 +322,11 @@ impl AviWriter {
     }
 
     fn finalize(&mut self) -> Result<()> {
+        if self.finalized {
+            return Ok(());
+        }
+        self.finalized = true;
+
         self.finalize_length_field()?; // LIST 'movi' finished (nesting level 1)
 
         // Write index
@@ -290,7 +334,7 @@ impl AviWriter {
         let idx_len = self.idx.len();
         self.write_u32(idx_len as u32)?; // Chunk length (we know its size, no need to use writeLengthField() and finalizeLengthField() pair)
                                          // Copy temporary index data
-        self.avif.write(&self.idx)?;
+        self.avif.write_all(&self.idx)?;
 
         let pos = self.tell()?;
         self.avif
@@ -302,10 +346,16 @@ impl AviWriter {
         self.avif.seek(std::io::SeekFrom::Start(pos))?;
 
         self.finalize_length_field()?; // 'RIFF' File finished (nesting level 0)
+        self.avif.flush()?;
 
         Ok(())
     }
 
+    /// close finalizes the video file, frames couldn't be added after it
+    pub fn close(&mut self) -> Result<()> {
+        self.finalize()
+    }
+
     pub fn destroy(&mut self) {
         let r = self.finalize();
 
@@ -317,3 +367,249 @@ impl AviWriter {
         }
     }
 }
+
+/// jpeg_size returns (width, height) of a JPEG image reading it from the SOF marker.
+pub fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() < 4 || data[0] != 0xff || data[1] != 0xd8 {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xff {
+            // Fill byte
+            pos += 1;
+            continue;
+        }
+        let len = ((data[pos + 2] as usize) << 8) | data[pos + 3] as usize;
+        // SOF0..SOF15 except DHT, JPG and DAC
+        if (0xc0..=0xcf).contains(&marker) && marker != 0xc4 && marker != 0xc8 && marker != 0xcc {
+            if pos + 9 > data.len() {
+                return None;
+            }
+            let height = ((data[pos + 5] as u32) << 8) | data[pos + 6] as u32;
+            let width = ((data[pos + 7] as u32) << 8) | data[pos + 8] as u32;
+            return Some((width, height));
+        }
+        pos += 2 + len;
+    }
+
+    None
+}
+
+// Deepest nesting of lists which is read, AviWriter writes two levels
+const MAX_LIST_DEPTH: usize = 8;
+
+/// AviReader reads MJPEG frames back from *.avi files written by AviWriter.
+/// Files which are not finalized yet (being written or left after a crash) are read up to the
+/// last complete frame.
+pub struct AviReader {
+    avif: File,
+    // us_per_frame is the frame delay from the main AVI header
+    us_per_frame: u32,
+    width: u32,
+    height: u32,
+    // start_time is the recording time of the first frame from the IDIT chunk
+    start_time: Option<u64>,
+    // frames contains offsets and lengths of the frame chunks
+    frames: Vec<(u64, u32)>,
+}
+
+impl AviReader {
+    pub fn open(avi_file: &str) -> Result<AviReader> {
+        let mut ar = AviReader {
+            avif: File::open(avi_file)?,
+            us_per_frame: 0,
+            width: 0,
+            height: 0,
+            start_time: None,
+            frames: vec![],
+        };
+
+        if ar.read_fourcc()? != *b"RIFF" {
+            return Err(err("Not a RIFF file"));
+        }
+        let len = ar.read_u32()? as u64;
+        if ar.read_fourcc()? != *b"AVI " {
+            return Err(err("Not an AVI file"));
+        }
+
+        let file_len = ar.avif.metadata()?.len();
+        // Length of an unfinished file is not written yet
+        let end = if len == 0 {
+            file_len
+        } else {
+            std::cmp::min(len + 8, file_len)
+        };
+        ar.scan(12, end, 0)?;
+
+        if ar.us_per_frame == 0 {
+            return Err(err("AVI header is not found"));
+        }
+
+        Ok(ar)
+    }
+
+    fn read_fourcc(&mut self) -> Result<[u8; 4]> {
+        let mut buf: [u8; 4] = [0; 4];
+        self.avif.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_fourcc()?))
+    }
+
+    // scan walks through the chunks in [start, end) looking for the headers and the frames,
+    // depth is the number of lists containing them.
+    fn scan(&mut self, start: u64, end: u64, depth: usize) -> Result<()> {
+        if depth > MAX_LIST_DEPTH {
+            return Err(err("AVI lists are nested too deeply"));
+        }
+        let mut pos = start;
+        while pos + 8 <= end {
+            self.avif.seek(std::io::SeekFrom::Start(pos))?;
+            let id = self.read_fourcc()?;
+            let len = self.read_u32()?;
+            let data = pos + 8;
+
+            if id == *b"LIST" && len == 0 {
+                // Unfinished 'movi' list lasts till the end of the file
+                self.read_fourcc()?;
+                return self.scan(data + 4, end, depth + 1);
+            } else if id == *b"LIST" {
+                self.read_fourcc()?;
+                self.scan(data + 4, std::cmp::min(data + len as u64, end), depth + 1)?;
+            } else if data + len as u64 > end || (id[2..4] == *b"dc" && len == 0) {
+                // Frame which is being written
+                break;
+            } else if id == *b"avih" {
+                self.us_per_frame = self.read_u32()?;
+                self.avif.seek(std::io::SeekFrom::Start(data + 32))?;
+                self.width = self.read_u32()?;
+                self.height = self.read_u32()?;
+            } else if id == *b"IDIT" {
+                let mut date = vec![0; len as usize];
+                self.avif.read_exact(&mut date)?;
+                self.start_time = String::from_utf8_lossy(&date)
+                    .trim_end_matches('\0')
+                    .parse::<DateTime>()
+                    .ok()
+                    .map(|dt| dt.to_ms());
+            } else if id[2..4] == *b"dc" || id[2..4] == *b"db" {
+                self.frames.push((data, len));
+            }
+
+            pos = data + len as u64 + (len & 1) as u64;
+        }
+
+        Ok(())
+    }
+
+    /// fps returns the frame rate of the video rounded to the nearest integer
+    pub fn fps(&self) -> u32 {
+        std::cmp::max((1000000 + self.us_per_frame / 2) / self.us_per_frame, 1)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// start_time returns the recording time of the first frame if the file has it
+    pub fn start_time(&self) -> Option<u64> {
+        self.start_time
+    }
+
+    /// frame reads JPEG data of the frame with index idx
+    pub fn frame(&mut self, idx: usize) -> Result<Vec<u8>> {
+        let (pos, len) = match self.frames.get(idx) {
+            Some(f) => *f,
+            None => return Err(err("Frame index is out of range")),
+        };
+
+        let mut buf = vec![0; len as usize];
+        self.avif.seek(std::io::SeekFrom::Start(pos))?;
+        self.avif.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Smallest valid JPEG header we need: SOI, APP0 and SOF0 with 3x2 size
+    const FAKE_JPEG: [u8; 29] = [
+        0xff, 0xd8, 0xff, 0xe0, 0x00, 0x06, 0x4a, 0x46, 0x49, 0x46, 0xff, 0xc0, 0x00, 0x11, 0x08,
+        0x00, 0x02, 0x00, 0x03, 0x03, 0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0xff, 0xd9,
+    ];
+
+    #[test]
+    fn test_jpeg_size() {
+        assert_eq!(jpeg_size(&FAKE_JPEG), Some((3, 2)));
+        assert_eq!(jpeg_size(&FAKE_JPEG[0..12]), None);
+        assert_eq!(jpeg_size(&[0, 1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_avi_round_trip() {
+        let path = crate::test_util::temp_path("mjpeg.avi");
+        let path = path.to_str().unwrap();
+
+        let mut aw = AviWriter::new(path, 3, 2, 5).unwrap();
+        aw.add_frame(&FAKE_JPEG).unwrap();
+        aw.add_frame(&FAKE_JPEG[0..27]).unwrap();
+        aw.destroy();
+
+        let mut ar = AviReader::open(path).unwrap();
+        assert_eq!(ar.fps(), 5);
+        assert_eq!((ar.width(), ar.height()), (3, 2));
+        assert_eq!(ar.frame_count(), 2);
+        assert_eq!(ar.frame(0).unwrap(), FAKE_JPEG.to_vec());
+        assert_eq!(ar.frame(1).unwrap(), FAKE_JPEG[0..27].to_vec());
+        assert!(ar.frame(2).is_err());
+
+        assert_eq!(ar.start_time(), None);
+
+        // Second close is ignored and the file stays valid
+        let mut aw = AviWriter::with_start_time(path, 3, 2, 5, 1709296496789).unwrap();
+        assert!(aw.fits(FAKE_JPEG.len()).unwrap());
+        assert!(!aw.fits(4200000000).unwrap());
+        aw.add_frame(&FAKE_JPEG).unwrap();
+        // Frames of the file being written are readable
+        assert_eq!(AviReader::open(path).unwrap().frame_count(), 1);
+        aw.close().unwrap();
+        aw.close().unwrap();
+        assert!(aw.add_frame(&FAKE_JPEG).is_err());
+        let ar = AviReader::open(path).unwrap();
+        assert_eq!(ar.frame_count(), 1);
+        assert_eq!(ar.start_time(), Some(1709296496789));
+
+        // Lists nested without limit are rejected
+        let mut data = b"RIFF\0\0\0\0AVI ".to_vec();
+        for _ in 0..100000 {
+            data.extend_from_slice(b"LIST\0\0\0\0movi");
+        }
+        std::fs::write(path, data).unwrap();
+        let res = AviReader::open(path);
+        assert_eq!(
+            res.err().map(|e| e.to_string()).as_deref(),
+            Some("AVI lists are nested too deeply")
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,618 @@
+/// Motion detection by differencing downscaled luma of frames with a background model.
+/// Settings are stored in a JSON file:
+///     {
+///         "enabled": true, "sensitivity": 50, "min_area": 1.0, "hold": 2000,
+///         "zones": [
+///             {"name": "yard", "type": "include", "points": [[0, 0.4], [1, 0.4], [1, 1], [0, 1]]},
+///             {"name": "tree", "type": "exclude", "points": [[0.7, 0.4], [0.9, 0.4], [0.8, 0.7]]}
+///         ]
+///     }
+/// Zone points are fractions of the frame width and height. Pixels inside include zones (the
+/// whole frame if there are none) and outside of exclude zones are watched. A pixel is changed
+/// when it differs from the background by the threshold given by sensitivity (1-100), the frame
+/// has motion when changed pixels cover min_area percent of the watched area. Motion is active
+/// until hold ms pass without motion.
+use crate::archive;
+use crate::web::{self, ApiError, ERR_INVALID_ARGS};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub const DEFAULT_FILE: &str = "httpcam-motion.json";
+
+/// Frames are analyzed at this width
+const ANALYSIS_WIDTH: u32 = 160;
+/// Part of the frame mixed into the background after every frame
+const LEARNING_RATE: f32 = 0.05;
+
+fn invalid(msg: &str) -> Box<dyn Error> {
+    Box::new(ApiError::new(ERR_INVALID_ARGS, msg))
+}
+
+/// 8 bit grayscale image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gray {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Gray {
+    pub fn from_rgb(rgb: &[u8], width: u32, height: u32) -> Gray {
+        let data = rgb
+            .chunks_exact(3)
+            .take((width * height) as usize)
+            .map(|p| ((77 * p[0] as u32 + 150 * p[1] as u32 + 29 * p[2] as u32) >> 8) as u8)
+            .collect();
+        Gray {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn from_jpeg(jpeg: &[u8]) -> Result<Gray> {
+        let (rgb, width, height) = crate::jpeg::decode_rgb(jpeg)?;
+        Ok(Gray::from_rgb(&rgb, width, height))
+    }
+
+    /// Scale down to the width keeping the aspect ratio, pixels are averaged
+    pub fn scale(&self, width: u32) -> Gray {
+        if width == 0 || width >= self.width {
+            return self.clone();
+        }
+
+        let height = std::cmp::max(self.height * width / self.width, 1);
+        let mut data: Vec<u8> = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let (y0, y1) = (y * self.height / height, (y + 1) * self.height / height);
+            for x in 0..width {
+                let (x0, x1) = (x * self.width / width, (x + 1) * self.width / width);
+                let mut sum = 0u32;
+                for sy in y0..y1 {
+                    let row = (sy * self.width) as usize;
+                    for sx in x0..x1 {
+                        sum += self.data[row + sx as usize] as u32;
+                    }
+                }
+                data.push((sum / ((y1 - y0) * (x1 - x0))) as u8);
+            }
+        }
+
+        Gray {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub name: String,
+    pub exclude: bool,
+    /// Polygon vertices, fractions of the frame size
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Zone {
+    fn from_json(value: &JsonValue) -> Result<Zone> {
+        let name = web::string_arg(value, "name")?.clone();
+        let exclude = match web::optional_arg(value, "type") {
+            None => false,
+            Some(JsonValue::String(t)) if t == "include" => false,
+            Some(JsonValue::String(t)) if t == "exclude" => true,
+            Some(_) => return Err(invalid("Zone type must be include or exclude")),
+        };
+
+        let list: &Vec<JsonValue> = match web::optional_arg(value, "points").and_then(|v| v.get()) {
+            Some(list) => list,
+            None => return Err(invalid(&format!("Zone '{}' requires points", name))),
+        };
+        let mut points: Vec<(f64, f64)> = vec![];
+        for p in list {
+            let xy: Vec<f64> = match p.get::<Vec<JsonValue>>() {
+                Some(xy) => xy.iter().filter_map(|v| v.get::<f64>().copied()).collect(),
+                None => vec![],
+            };
+            match xy[..] {
+                [x, y] if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) => {
+                    points.push((x, y))
+                }
+                _ => {
+                    return Err(invalid(&format!(
+                        "Points of zone '{}' must be [x, y] in range 0-1",
+                        name
+                    )))
+                }
+            }
+        }
+        if points.len() < 3 {
+            return Err(invalid(&format!("Zone '{}' needs at least 3 points", name)));
+        }
+
+        Ok(Zone {
+            name,
+            exclude,
+            points,
+        })
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("name"), JsonValue::String(self.name.clone()));
+        let t = if self.exclude { "exclude" } else { "include" };
+        res.insert(String::from("type"), JsonValue::String(String::from(t)));
+        let points = self
+            .points
+            .iter()
+            .map(|(x, y)| JsonValue::Array(vec![JsonValue::Number(*x), JsonValue::Number(*y)]))
+            .collect();
+        res.insert(String::from("points"), JsonValue::Array(points));
+        JsonValue::Object(res)
+    }
+
+    /// Even-odd rule
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        let mut inside = false;
+        let mut j = self.points.len() - 1;
+        for (i, &(xi, yi)) in self.points.iter().enumerate() {
+            let (xj, yj) = self.points[j];
+            if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                inside = !inside;
+            }
+            j = i;
+        }
+        inside
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub enabled: bool,
+    /// 1-100, higher sensitivity detects smaller brightness changes
+    pub sensitivity: u32,
+    /// Percent of the watched area which must change
+    pub min_area: f64,
+    /// Time in ms motion stays active after the last frame with motion
+    pub hold: u64,
+    pub zones: Vec<Zone>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            enabled: false,
+            sensitivity: 50,
+            min_area: 1.0,
+            hold: 2000,
+            zones: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Luma difference of a changed pixel: 64 at sensitivity 1, 5 at 100
+    pub fn threshold(&self) -> f32 {
+        5.0 + (100 - self.sensitivity.clamp(1, 100)) as f32 * 0.6
+    }
+
+    /// Update settings given in the JSON object, others are kept
+    fn update(&mut self, value: &JsonValue) -> Result<()> {
+        let mut config = self.clone();
+        let number = |name: &str, min: f64, max: f64| -> Result<Option<f64>> {
+            match web::optional_arg(value, name) {
+                None => Ok(None),
+                Some(_) => {
+                    let v = web::number_arg(value, name)?;
+                    if v < min || v > max {
+                        return Err(invalid(&format!("{} must be in [{}, {}]", name, min, max)));
+                    }
+                    Ok(Some(v))
+                }
+            }
+        };
+
+        match web::optional_arg(value, "enabled") {
+            Some(JsonValue::Boolean(b)) => config.enabled = *b,
+            Some(_) => return Err(invalid("enabled must be boolean")),
+            None => (),
+        }
+        if let Some(v) = number("sensitivity", 1.0, 100.0)? {
+            config.sensitivity = v as u32;
+        }
+        if let Some(v) = number("min_area", 0.0, 100.0)? {
+            config.min_area = v;
+        }
+        if let Some(v) = number("hold", 0.0, 3600.0 * 1000.0)? {
+            config.hold = v as u64;
+        }
+        if let Some(list) = web::optional_arg(value, "zones") {
+            let list: &Vec<JsonValue> = match list.get() {
+                Some(list) => list,
+                None => return Err(invalid("Zones must be an array")),
+            };
+            config.zones = list.iter().map(Zone::from_json).collect::<Result<_>>()?;
+        }
+
+        *self = config;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("enabled"), JsonValue::Boolean(self.enabled));
+        res.insert(
+            String::from("sensitivity"),
+            JsonValue::Number(self.sensitivity as f64),
+        );
+        res.insert(String::from("min_area"), JsonValue::Number(self.min_area));
+        res.insert(String::from("hold"), JsonValue::Number(self.hold as f64));
+        res.insert(
+            String::from("zones"),
+            JsonValue::Array(self.zones.iter().map(|z| z.to_json()).collect()),
+        );
+        JsonValue::Object(res)
+    }
+}
+
+/// Change of the motion state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Started,
+    Ended,
+}
+
+pub struct Detector {
+    path: PathBuf,
+    config: Config,
+    width: u32,
+    height: u32,
+    background: Vec<f32>,
+    // Watched pixels of the analyzed frame
+    mask: Vec<bool>,
+    watched: usize,
+    score: f64,
+    active: bool,
+    since: Option<u64>,
+    last_motion: Option<u64>,
+}
+
+impl Detector {
+    pub fn new(path: &Path, config: Config) -> Detector {
+        Detector {
+            path: PathBuf::from(path),
+            config,
+            width: 0,
+            height: 0,
+            background: vec![],
+            mask: vec![],
+            watched: 0,
+            score: 0.0,
+            active: false,
+            since: None,
+            last_motion: None,
+        }
+    }
+
+    /// Load settings from the file, missing file means disabled detection
+    pub fn load(path: &Path) -> Result<Detector> {
+        let mut config = Config::default();
+        if path.exists() {
+            let content = std::fs::read_to_string(path)?;
+            let json: JsonValue = content.parse()?;
+            config.update(&json)?;
+        }
+
+        Ok(Detector::new(path, config))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Update settings given in the JSON object and save them into the file. Background is
+    /// learned again.
+    pub fn set(&mut self, value: &JsonValue) -> Result<()> {
+        self.config.update(value)?;
+        self.reset();
+        let content = self.config.to_json().format()?;
+        archive::write_file_atomic(&self.path, content.as_bytes())
+    }
+
+    fn reset(&mut self) {
+        self.width = 0;
+        self.height = 0;
+        self.background.clear();
+        self.score = 0.0;
+        if !self.config.enabled {
+            self.active = false;
+        }
+    }
+
+    fn build_mask(&mut self) {
+        let includes = self.config.zones.iter().any(|z| !z.exclude);
+        self.mask = Vec::with_capacity((self.width * self.height) as usize);
+        for y in 0..self.height {
+            let fy = (y as f64 + 0.5) / self.height as f64;
+            for x in 0..self.width {
+                let fx = (x as f64 + 0.5) / self.width as f64;
+                let mut watched = !includes;
+                for zone in &self.config.zones {
+                    if zone.contains(fx, fy) {
+                        if zone.exclude {
+                            watched = false;
+                            break;
+                        }
+                        watched = true;
+                    }
+                }
+                self.mask.push(watched);
+            }
+        }
+        self.watched = self.mask.iter().filter(|m| **m).count();
+    }
+
+    /// Compare the frame with the background and update the motion state. The first frame and
+    /// the first frame of a new size only initialize the background.
+    pub fn process(&mut self, frame: &Gray, time: u64) -> Option<Event> {
+        let frame = frame.scale(ANALYSIS_WIDTH);
+        if (frame.width, frame.height) != (self.width, self.height) || self.background.is_empty() {
+            self.width = frame.width;
+            self.height = frame.height;
+            self.background = frame.data.iter().map(|v| *v as f32).collect();
+            self.build_mask();
+            self.score = 0.0;
+            return self.update(false, time);
+        }
+
+        let threshold = self.config.threshold();
+        let mut changed = 0;
+        for ((bg, value), watched) in self
+            .background
+            .iter_mut()
+            .zip(frame.data.iter())
+            .zip(self.mask.iter())
+        {
+            let value = *value as f32;
+            if *watched && (value - *bg).abs() > threshold {
+                changed += 1;
+            }
+            *bg += (value - *bg) * LEARNING_RATE;
+        }
+
+        self.score = match self.watched {
+            0 => 0.0,
+            watched => changed as f64 * 100.0 / watched as f64,
+        };
+        let moving = changed > 0 && self.score >= self.config.min_area;
+        self.update(moving, time)
+    }
+
+    fn update(&mut self, moving: bool, time: u64) -> Option<Event> {
+        if moving {
+            self.last_motion = Some(time);
+            if !self.active {
+                self.active = true;
+                self.since = Some(time);
+                return Some(Event::Started);
+            }
+        } else if self.active
+            && self
+                .last_motion
+                .is_none_or(|t| time >= t + self.config.hold)
+        {
+            self.active = false;
+            self.since = Some(time);
+            return Some(Event::Ended);
+        }
+        None
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Percent of the watched area changed in the last frame
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    /// Motion state: {"active": <bool>, "score": <percent>, "since": <ms>, "last_motion": <ms>}
+    pub fn status_json(&self) -> JsonValue {
+        let time = |t: Option<u64>| t.map_or(JsonValue::Null, |t| JsonValue::Number(t as f64));
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("enabled"), JsonValue::Boolean(self.enabled()));
+        res.insert(String::from("active"), JsonValue::Boolean(self.active));
+        res.insert(String::from("score"), JsonValue::Number(self.score));
+        res.insert(String::from("since"), time(self.since));
+        res.insert(String::from("last_motion"), time(self.last_motion));
+        JsonValue::Object(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: u32 = 80;
+    const H: u32 = 60;
+
+    /// Gray frame with a bright square at (x, y)
+    fn frame(square: Option<(u32, u32)>, size: u32) -> Gray {
+        let mut data = vec![0u8; (W * H) as usize];
+        for y in 0..H {
+            for x in 0..W {
+                // Static gradient background
+                data[(y * W + x) as usize] = (40 + x / 2) as u8;
+            }
+        }
+        if let Some((sx, sy)) = square {
+            for y in sy..sy + size {
+                for x in sx..sx + size {
+                    data[(y * W + x) as usize] = 220;
+                }
+            }
+        }
+        Gray {
+            width: W,
+            height: H,
+            data,
+        }
+    }
+
+    fn detector(config: &str) -> Detector {
+        let mut d = Detector::new(Path::new("unused.json"), Config::default());
+        d.config.update(&config.parse().unwrap()).unwrap();
+        d
+    }
+
+    /// Run frames at 100 ms, returns events with their frame indexes
+    fn run(d: &mut Detector, frames: &[Gray]) -> Vec<(usize, Event)> {
+        let mut res = vec![];
+        for (idx, f) in frames.iter().enumerate() {
+            if let Some(ev) = d.process(f, idx as u64 * 100) {
+                res.push((idx, ev));
+            }
+        }
+        res
+    }
+
+    fn sequence() -> Vec<Gray> {
+        // Static scene, a square moving along the bottom part, static scene again
+        let mut frames: Vec<Gray> = (0..5).map(|_| frame(None, 0)).collect();
+        frames.extend((0..5).map(|i| frame(Some((10 + i * 10, 40)), 10)));
+        frames.extend((0..30).map(|_| frame(None, 0)));
+        frames
+    }
+
+    #[test]
+    fn test_gray() {
+        let g = Gray::from_rgb(&[255, 255, 255, 255, 0, 0, 0, 0, 255, 0, 0, 0], 2, 2);
+        assert_eq!(g.data, vec![255, 76, 28, 0]);
+        let g = g.scale(1);
+        assert_eq!((g.width, g.height, g.data), (1, 1, vec![89]));
+
+        let jpeg = crate::test_util::solid_jpeg(32, 16, 100);
+        let g = Gray::from_jpeg(&jpeg).unwrap();
+        assert_eq!((g.width, g.height), (32, 16));
+        assert!(g.data.iter().all(|v| v.abs_diff(100) <= 2));
+    }
+
+    #[test]
+    fn test_zones() {
+        let zone = Zone::from_json(
+            &r#"{"name": "z", "points": [[0, 0], [1, 0], [0, 1]]}"#
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(!zone.exclude);
+        assert!(zone.contains(0.2, 0.2));
+        assert!(!zone.contains(0.8, 0.8));
+
+        for bad in [
+            r#"{"name": "z", "points": [[0, 0], [1, 0]]}"#,
+            r#"{"name": "z", "points": [[0, 0], [1, 0], [2, 1]]}"#,
+            r#"{"name": "z", "type": "other", "points": [[0, 0], [1, 0], [0, 1]]}"#,
+        ] {
+            assert!(Zone::from_json(&bad.parse().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_detection() {
+        let mut d = detector(r#"{"enabled": true, "hold": 500}"#);
+        let events = run(&mut d, &sequence());
+        assert_eq!(events, vec![(5, Event::Started), (14, Event::Ended)]);
+        assert!(!d.active());
+        assert_eq!(d.score(), 0.0);
+
+        // Motion score of the square: 100 px of 4800
+        let mut d = detector(r#"{"enabled": true}"#);
+        d.process(&frame(None, 0), 0);
+        assert_eq!(
+            d.process(&frame(Some((10, 10)), 10), 100),
+            Some(Event::Started)
+        );
+        assert!((d.score() - 100.0 * 100.0 / 4800.0).abs() < 0.01);
+        let status = d.status_json().stringify().unwrap();
+        assert!(status.contains(r#""active":true"#));
+        assert!(status.contains(r#""since":100"#));
+
+        // Too small area
+        let mut d = detector(r#"{"enabled": true, "min_area": 5}"#);
+        assert!(run(&mut d, &sequence()).is_empty());
+
+        // Small brightness change is seen by sensitive detector only
+        let dim: Vec<Gray> = (0..4)
+            .map(|i| {
+                let mut f = frame(None, 0);
+                if i >= 2 {
+                    f.data.iter_mut().for_each(|v| *v += 20);
+                }
+                f
+            })
+            .collect();
+        assert!(run(&mut detector(r#"{"sensitivity": 20}"#), &dim).is_empty());
+        assert_eq!(
+            run(&mut detector(r#"{"sensitivity": 90}"#), &dim),
+            vec![(2, Event::Started)]
+        );
+    }
+
+    #[test]
+    fn test_detection_zones() {
+        // Motion in the bottom half is excluded
+        let mut d = detector(
+            r#"{"zones": [{"name": "bottom", "type": "exclude",
+                "points": [[0, 0.5], [1, 0.5], [1, 1], [0, 1]]}]}"#,
+        );
+        assert!(run(&mut d, &sequence()).is_empty());
+
+        // Only the top half is watched
+        let mut d = detector(
+            r#"{"zones": [{"name": "top", "points": [[0, 0], [1, 0], [1, 0.5], [0, 0.5]]}]}"#,
+        );
+        assert!(run(&mut d, &sequence()).is_empty());
+
+        // Only the bottom right quarter is watched: the square enters it in the frame 8
+        let mut d = detector(
+            r#"{"zones": [{"name": "corner", "points": [[0.5, 0.5], [1, 0.5], [1, 1], [0.5, 1]]}]}"#,
+        );
+        assert_eq!(run(&mut d, &sequence())[0], (8, Event::Started));
+        assert!((d.watched as u32) < W * H / 4 + W);
+    }
+
+    #[test]
+    fn test_settings() {
+        let path = crate::test_util::temp_path("motion.json");
+        let mut d = Detector::load(&path).unwrap();
+        assert!(!d.enabled());
+
+        d.set(&r#"{"enabled": true, "sensitivity": 80}"#.parse().unwrap())
+            .unwrap();
+        assert!(d.set(&r#"{"sensitivity": 0}"#.parse().unwrap()).is_err());
+        assert!(d.set(&r#"{"zones": {}}"#.parse().unwrap()).is_err());
+        d.set(&r#"{"min_area": 2.5}"#.parse().unwrap()).unwrap();
+
+        let d = Detector::load(&path).unwrap();
+        assert_eq!(
+            d.config(),
+            &Config {
+                enabled: true,
+                sensitivity: 80,
+                min_area: 2.5,
+                ..Config::default()
+            }
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
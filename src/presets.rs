@@ -0,0 +1,268 @@
+/// Named camera control presets persisted in a JSON file:
+///     {"<name>": {"resolution": "640x480/15", "controls": [<control>, ...]}, ...}
+/// Controls are stored as list_controls reports them, only names and values are used to apply them.
+use crate::archive;
+use crate::controls;
+use crate::source::{self, FrameSource};
+use crate::web::{self, ApiError, ERR_INVALID_ARGS, ERR_NOT_FOUND};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub const DEFAULT_FILE: &str = "httpcam-presets.json";
+
+#[derive(Clone)]
+pub struct Preset {
+    pub resolution: Option<String>,
+    pub controls: Vec<JsonValue>,
+}
+
+pub struct Presets {
+    path: PathBuf,
+    presets: BTreeMap<String, Preset>,
+}
+
+/// Default location of configuration files: next to the executable
+pub fn default_path(file: &str) -> PathBuf {
+    match std::env::current_exe() {
+        Ok(exe) => exe.with_file_name(file),
+        Err(_) => PathBuf::from(file),
+    }
+}
+
+impl Preset {
+    /// Capture current resolution and values of all controls
+    pub fn capture(cam: &mut dyn FrameSource) -> Result<Preset> {
+        let mut ctrls: Vec<JsonValue> = vec![];
+        for control in cam.controls()? {
+            ctrls.push(controls::control_to_json(&control));
+        }
+
+        Ok(Preset {
+            resolution: Some(source::format_string(&cam.format())),
+            controls: ctrls,
+        })
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<Preset> {
+        let resolution = web::optional_arg(value, "resolution")
+            .and_then(|r| r.get::<String>())
+            .cloned();
+        let ctrls =
+            match web::optional_arg(value, "controls").and_then(|c| c.get::<Vec<JsonValue>>()) {
+                Some(c) => c.clone(),
+                None => vec![],
+            };
+
+        Ok(Preset {
+            resolution,
+            controls: ctrls,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        if let Some(r) = &self.resolution {
+            res.insert(String::from("resolution"), JsonValue::String(r.clone()));
+        }
+        res.insert(
+            String::from("controls"),
+            JsonValue::Array(self.controls.clone()),
+        );
+        JsonValue::Object(res)
+    }
+
+    fn control_name(control: &JsonValue) -> Option<&String> {
+        match control {
+            JsonValue::Object(obj) => obj.get("name").and_then(|n| n.get::<String>()),
+            _ => None,
+        }
+    }
+
+    fn should_apply(control: &JsonValue) -> bool {
+        // Read only controls are stored for information only
+        if let JsonValue::Object(obj) = control {
+            if let Some(JsonValue::Array(flags)) = obj.get("flags") {
+                let ro = JsonValue::String(String::from("readonly"));
+                return !flags.contains(&ro);
+            }
+        }
+        true
+    }
+
+    /// Apply the preset. Controls could depend on each other (e.g. exposure can be set only in
+    /// manual exposure mode) so failed controls are retried once after all others are set.
+    /// Returns report: {"applied": [<name>, ...], "failed": [{"name": <name>, "error": <error>}]}
+    pub fn apply(&self, cam: &mut dyn FrameSource) -> JsonValue {
+        let mut applied: Vec<JsonValue> = vec![];
+        let mut failed: Vec<JsonValue> = vec![];
+
+        if let Some(r) = &self.resolution {
+            match source::select_format(cam, r) {
+                Ok(()) => applied.push(JsonValue::String(String::from("resolution"))),
+                Err(err) => failed.push(failure("resolution", err.as_ref())),
+            }
+        }
+
+        let mut pending: Vec<&JsonValue> = self
+            .controls
+            .iter()
+            .filter(|c| Preset::should_apply(c))
+            .collect();
+        for attempt in 0..2 {
+            let mut retry: Vec<&JsonValue> = vec![];
+            for control in pending {
+                let name = match Preset::control_name(control) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let value = match control {
+                    JsonValue::Object(obj) => obj.get("value").unwrap_or(&JsonValue::Null),
+                    _ => &JsonValue::Null,
+                };
+
+                match controls::set_control(cam, name, value) {
+                    Ok(_) => applied.push(JsonValue::String(name.clone())),
+                    Err(err) => {
+                        if attempt == 0 {
+                            retry.push(control);
+                        } else {
+                            failed.push(failure(name, err.as_ref()));
+                        }
+                    }
+                }
+            }
+            pending = retry;
+        }
+
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("applied"), JsonValue::Array(applied));
+        res.insert(String::from("failed"), JsonValue::Array(failed));
+        JsonValue::Object(res)
+    }
+}
+
+fn failure(name: &str, err: &(dyn Error + 'static)) -> JsonValue {
+    let mut res = HashMap::<String, JsonValue>::new();
+    res.insert(String::from("name"), JsonValue::String(String::from(name)));
+    res.insert(String::from("error"), ApiError::from_error(err).to_json());
+    JsonValue::Object(res)
+}
+
+impl Presets {
+    /// Load presets from the file, missing file means no presets
+    pub fn load(path: &Path) -> Result<Presets> {
+        let mut presets = BTreeMap::<String, Preset>::new();
+
+        if path.exists() {
+            let content = std::fs::read_to_string(path)?;
+            let json: JsonValue = content.parse()?;
+            let obj: &HashMap<String, JsonValue> = match json.get() {
+                Some(obj) => obj,
+                None => return Err(Box::<dyn Error>::from("Invalid presets file")),
+            };
+            for (name, preset) in obj {
+                presets.insert(name.clone(), Preset::from_json(preset)?);
+            }
+        }
+
+        Ok(Presets {
+            path: PathBuf::from(path),
+            presets,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut obj = HashMap::<String, JsonValue>::new();
+        for (name, preset) in &self.presets {
+            obj.insert(name.clone(), preset.to_json());
+        }
+
+        let content = JsonValue::Object(obj).format()?;
+        archive::write_file_atomic(&self.path, content.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Preset> {
+        match self.presets.get(name) {
+            Some(p) => Ok(p),
+            None => Err(Box::new(ApiError::new(
+                ERR_NOT_FOUND,
+                &format!("Preset {} is not found", name),
+            ))),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.presets.keys().cloned().collect()
+    }
+
+    /// Store preset and save the file
+    pub fn set(&mut self, name: &str, preset: Preset) -> Result<()> {
+        if name.is_empty() {
+            return Err(Box::new(ApiError::new(
+                ERR_INVALID_ARGS,
+                "Preset name can't be empty",
+            )));
+        }
+        self.presets.insert(String::from(name), preset);
+        self.save()
+    }
+
+    /// Remove preset and save the file
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.get(name)?;
+        self.presets.remove(name);
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::TestPatternSource;
+
+    #[test]
+    fn test_presets() {
+        let path = crate::test_util::temp_path("presets.json");
+        let mut cam = TestPatternSource::new(320, 240, 5).unwrap();
+
+        let mut presets = Presets::load(&path).unwrap();
+        assert!(presets.names().is_empty());
+
+        controls::set_control(&mut cam, "Brightness", &JsonValue::Number(20.0)).unwrap();
+        presets
+            .set("day", Preset::capture(&mut cam).unwrap())
+            .unwrap();
+
+        controls::set_control(&mut cam, "Brightness", &JsonValue::Number(-20.0)).unwrap();
+        cam.open().unwrap();
+        source::select_format(&mut cam, "640x480/5").unwrap();
+
+        let presets = Presets::load(&path).unwrap();
+        assert_eq!(presets.names(), vec![String::from("day")]);
+        let report = presets.get("day").unwrap().apply(&mut cam);
+        assert_eq!(report["failed"], JsonValue::Array(vec![]));
+        assert_eq!(source::format_string(&cam.format()), "320x240/5");
+        let brightness = controls::find_control(&cam, "Brightness").unwrap();
+        assert_eq!(
+            controls::value_to_json(&brightness.value()),
+            JsonValue::Number(20.0)
+        );
+
+        // Unknown control is reported as failed, the others are applied
+        let mut preset = presets.get("day").unwrap().clone();
+        preset
+            .controls
+            .push("{\"name\": \"Zoom\", \"value\": 1}".parse().unwrap());
+        let report = preset.apply(&mut cam);
+        assert_eq!(
+            report["failed"][0]["name"],
+            JsonValue::String(String::from("Zoom"))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,582 @@
+/// Event-triggered recording.
+/// The last pre-roll ms of frames are kept in memory. When an event is triggered (by motion, by
+/// the API or by SIGUSR1) they are written into an AVI clip followed by the frames of the event
+/// and post-roll ms after it. Triggers during the recording extend the event, clips are split
+/// at the maximum length. Finished events are appended to index.jsonl in the events directory:
+///     {"id": 1, "source": "motion", "start": <ms>, "end": <ms>, "clip": "event-1-...avi", "frames": 120}
+use crate::datetime::DateTime;
+use crate::eventlog::{self, EventLog};
+use crate::mjpeg::{jpeg_size, AviWriter};
+use crate::web::{self, ApiError, ERR_INVALID_ARGS};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Directory of the archive with event clips and the index
+pub const EVENTS_DIR: &str = "events";
+const INDEX_FILE: &str = "index.jsonl";
+
+// Number of external triggers, every camera compares it with the number it has seen
+static EXTERNAL_TRIGGERS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(unix)]
+extern "C" fn on_trigger_signal(_: libc::c_int) {
+    EXTERNAL_TRIGGERS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Trigger events by SIGUSR1, e.g. kill -USR1 <pid> from a doorbell script
+pub fn install_trigger_signal() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGUSR1,
+            on_trigger_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Check if the external trigger fired since the previous check, seen is the number of triggers
+/// of the caller
+pub fn external_triggered(seen: &mut u64) -> bool {
+    let count = EXTERNAL_TRIGGERS.load(Ordering::SeqCst);
+    let triggered = count != *seen;
+    *seen = count;
+    triggered
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Time in ms recorded before the trigger
+    pub pre_roll: u64,
+    /// Time in ms recorded after the event
+    pub post_roll: u64,
+    /// Maximum length of a clip in ms, longer events are split
+    pub max_length: u64,
+    /// Frame rate of clips
+    pub fps: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            pre_roll: 5000,
+            post_roll: 5000,
+            max_length: 5 * 60 * 1000,
+            fps: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: u64,
+    /// What triggered the event: motion, api, external or a name given through the API
+    pub source: String,
+    pub start: u64,
+    pub end: u64,
+    /// Clip file in the events directory, empty if no frames were recorded
+    pub clip: String,
+    pub frames: usize,
+}
+
+impl Event {
+    pub fn from_json(value: &JsonValue) -> Result<Event> {
+        let number =
+            |name: &str| -> Result<u64> { Ok(web::number_arg(value, name)?.max(0.0) as u64) };
+        Ok(Event {
+            id: number("id")?,
+            source: web::string_arg(value, "source")?.clone(),
+            start: number("start")?,
+            end: number("end")?,
+            clip: web::string_arg(value, "clip")?.clone(),
+            frames: number("frames")? as usize,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("id"), JsonValue::Number(self.id as f64));
+        res.insert(
+            String::from("source"),
+            JsonValue::String(self.source.clone()),
+        );
+        res.insert(String::from("start"), JsonValue::Number(self.start as f64));
+        res.insert(String::from("end"), JsonValue::Number(self.end as f64));
+        res.insert(String::from("clip"), JsonValue::String(self.clip.clone()));
+        res.insert(
+            String::from("frames"),
+            JsonValue::Number(self.frames as f64),
+        );
+        JsonValue::Object(res)
+    }
+
+    /// Event with URL of its clip in the archive of the camera for API clients
+    pub fn api_json(&self, camera: &str) -> JsonValue {
+        let mut res = match self.to_json() {
+            JsonValue::Object(obj) => obj,
+            _ => HashMap::new(),
+        };
+        let url = match self.clip.as_str() {
+            "" => JsonValue::Null,
+            clip => JsonValue::String(web::archive_url(
+                camera,
+                &format!("{}/{}", EVENTS_DIR, clip),
+            )),
+        };
+        res.insert(String::from("url"), url);
+        JsonValue::Object(res)
+    }
+}
+
+struct Recording {
+    event: Event,
+    writer: Option<AviWriter>,
+    // Frames are recorded until this time unless the event is extended
+    stop_at: u64,
+    // Frame interval slot and data of the last written frame
+    last: Option<(u64, Vec<u8>)>,
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    // Camera whose archive serves the clips
+    camera: String,
+    options: Options,
+    // Pre-roll frames
+    buffer: VecDeque<(u64, Vec<u8>)>,
+    // Frame interval slot of the last taken frame
+    last_slot: Option<u64>,
+    current: Option<Recording>,
+    events: Vec<Event>,
+    log: Option<EventLog>,
+}
+
+impl Recorder {
+    /// Open the events directory of the camera, events of the index are loaded
+    pub fn open(dir: &Path, camera: &str, options: Options) -> Result<Recorder> {
+        if options.fps == 0 || options.fps > 1000 {
+            return Err("Clip frame rate must be in range 1-1000".into());
+        }
+        std::fs::create_dir_all(dir)?;
+
+        let mut events: Vec<Event> = vec![];
+        let index = dir.join(INDEX_FILE);
+        if index.exists() {
+            for (no, line) in std::fs::read_to_string(&index)?.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match line.parse::<JsonValue>() {
+                    Ok(json) => match Event::from_json(&json) {
+                        Ok(event) => events.push(event),
+                        Err(err) => println!("{}:{}: {}", index.display(), no + 1, err),
+                    },
+                    Err(err) => println!("{}:{}: {}", index.display(), no + 1, err),
+                }
+            }
+        }
+
+        Ok(Recorder {
+            dir: PathBuf::from(dir),
+            camera: String::from(camera),
+            options,
+            buffer: VecDeque::new(),
+            last_slot: None,
+            current: None,
+            events,
+            log: None,
+        })
+    }
+
+    /// Log start and end of events
+    pub fn set_event_log(&mut self, log: EventLog) {
+        self.log = Some(log);
+    }
+
+    fn log(&self, state: &str, event: &Event) {
+        if let Some(ref log) = self.log {
+            log.log(
+                "recording",
+                eventlog::data(&[
+                    ("state", JsonValue::String(String::from(state))),
+                    ("event", event.api_json(&self.camera)),
+                ]),
+            );
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn camera(&self) -> &str {
+        &self.camera
+    }
+
+    /// Event being recorded
+    pub fn current(&self) -> Option<&Event> {
+        self.current.as_ref().map(|r| &r.event)
+    }
+
+    /// Events overlapping [from, to] including the one being recorded, optionally of the given
+    /// source only
+    pub fn events(&self, from: u64, to: u64, source: Option<&str>) -> Vec<&Event> {
+        self.events
+            .iter()
+            .chain(self.current())
+            .filter(|e| e.end >= from && e.start <= to)
+            .filter(|e| source.is_none_or(|s| e.source == s))
+            .collect()
+    }
+
+    /// Start the event or extend the current one, it lasts duration ms from the time. The frame
+    /// of the time should be added before, so it is recorded as a pre-roll frame.
+    pub fn trigger(&mut self, source: &str, time: u64, duration: u64) -> Result<&Event> {
+        // Durations of API calls may be anything up to u64::MAX
+        let end = time.saturating_add(duration);
+        let stop_at = end.saturating_add(self.options.post_roll);
+
+        if self.current.is_none() {
+            let id = self.events.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+            let dt = DateTime::from_ms(time);
+            let clip = format!(
+                "event-{}-{:04}{:02}{:02}-{:02}{:02}{:02}.avi",
+                id, dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+            );
+            self.current = Some(Recording {
+                event: Event {
+                    id,
+                    source: String::from(source),
+                    start: time,
+                    end,
+                    clip,
+                    frames: 0,
+                },
+                writer: None,
+                stop_at,
+                last: None,
+            });
+
+            if let Some(ref rec) = self.current {
+                self.log("started", &rec.event);
+            }
+            for (t, jpeg) in std::mem::take(&mut self.buffer) {
+                if t + self.options.pre_roll >= time {
+                    self.write(t, &jpeg)?;
+                }
+            }
+        }
+
+        match self.current {
+            Some(ref mut rec) => {
+                rec.event.end = std::cmp::max(rec.event.end, end);
+                rec.stop_at = std::cmp::max(rec.stop_at, stop_at);
+                Ok(&rec.event)
+            }
+            None => Err("Recording is not started".into()),
+        }
+    }
+
+    fn write(&mut self, time: u64, jpeg: &[u8]) -> Result<()> {
+        let rec = match self.current {
+            Some(ref mut rec) => rec,
+            None => return Ok(()),
+        };
+        let (width, height) = match jpeg_size(jpeg) {
+            Some(size) => size,
+            None => return Err("Frame is not a JPEG image".into()),
+        };
+
+        let writer = match rec.writer {
+            Some(ref mut w) => w,
+            None => {
+                let path = self.dir.join(&rec.event.clip);
+                let name = match path.to_str() {
+                    Some(name) => name,
+                    None => return Err(format!("Invalid clip path {}", path.display()).into()),
+                };
+                rec.writer.insert(AviWriter::with_start_time(
+                    name,
+                    width,
+                    height,
+                    self.options.fps,
+                    time,
+                )?)
+            }
+        };
+
+        // Clip keeps the size of its first frame
+        if (writer.width(), writer.height()) != (width, height) {
+            return Ok(());
+        }
+        // Slots the camera had no frame for get the previous one, so the clip keeps its frame rate
+        let slot = time * self.options.fps as u64 / 1000;
+        if let Some((last_slot, ref last)) = rec.last {
+            for _ in last_slot + 1..slot {
+                if !writer.fits(last.len())? {
+                    return Ok(());
+                }
+                writer.add_frame(last)?;
+                rec.event.frames += 1;
+            }
+        }
+        if writer.fits(jpeg.len())? {
+            writer.add_frame(jpeg)?;
+            rec.event.frames += 1;
+            rec.last = Some((slot, jpeg.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Add the frame of the time point: it is written into the clip or kept for pre-roll.
+    /// Frames are taken at the clip frame rate, the previous frame is repeated if the camera is
+    /// slower. Returns the event finished before the frame.
+    pub fn add_frame(&mut self, time: u64, jpeg: &[u8]) -> Result<Option<Event>> {
+        let slot = time * self.options.fps as u64 / 1000;
+        if self.last_slot.is_some_and(|s| slot <= s) {
+            return Ok(None);
+        }
+        self.last_slot = Some(slot);
+
+        let mut finished: Option<Event> = None;
+        if let Some(ref rec) = self.current {
+            if time >= rec.stop_at || time >= rec.event.start + self.options.max_length {
+                finished = self.finish()?;
+            }
+        }
+
+        if self.current.is_some() {
+            if let Err(err) = self.write(time, jpeg) {
+                println!("Can't record frame: {}", err);
+                return self.finish();
+            }
+        } else {
+            self.buffer.push_back((time, jpeg.to_vec()));
+            while self
+                .buffer
+                .front()
+                .is_some_and(|(t, _)| *t + self.options.pre_roll < time)
+            {
+                self.buffer.pop_front();
+            }
+        }
+
+        Ok(finished)
+    }
+
+    /// Finish the current event: the clip is finalized and the event is added to the index
+    pub fn finish(&mut self) -> Result<Option<Event>> {
+        let mut rec = match self.current.take() {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+
+        match rec.writer {
+            Some(ref mut w) => w.close()?,
+            None => rec.event.clip.clear(),
+        }
+
+        let mut index = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX_FILE))?;
+        writeln!(index, "{}", rec.event.to_json().stringify()?)?;
+
+        self.log("finished", &rec.event);
+        self.events.push(rec.event.clone());
+        Ok(Some(rec.event))
+    }
+}
+
+/// Events query of the API: {"from": <ms>, "to": <ms>, "source": <source>, "limit": <n>}, all
+/// arguments are optional. The latest limit events are returned.
+pub fn events_json(recorder: &Recorder, args: &JsonValue) -> Result<JsonValue> {
+    let time = |name: &str, default: u64| -> Result<u64> {
+        match web::optional_arg(args, name) {
+            Some(_) => Ok(web::number_arg(args, name)?.max(0.0) as u64),
+            None => Ok(default),
+        }
+    };
+    let source = match web::optional_arg(args, "source") {
+        Some(_) => Some(web::string_arg(args, "source")?.as_str()),
+        None => None,
+    };
+    let limit = time("limit", u64::MAX)? as usize;
+    if limit == 0 {
+        return Err(Box::new(ApiError::new(
+            ERR_INVALID_ARGS,
+            "Limit must be positive",
+        )));
+    }
+
+    let events = recorder.events(time("from", 0)?, time("to", u64::MAX)?, source);
+    let skip = events.len().saturating_sub(limit);
+    Ok(JsonValue::Array(
+        events
+            .iter()
+            .skip(skip)
+            .map(|e| e.api_json(&recorder.camera))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mjpeg::AviReader;
+    use crate::test_util::solid_jpeg;
+
+    // 2024-03-01 12:00:00 UTC
+    const T: u64 = 1709294400000;
+
+    fn options() -> Options {
+        Options {
+            pre_roll: 1000,
+            post_roll: 500,
+            max_length: 3000,
+            fps: 10,
+        }
+    }
+
+    #[test]
+    fn test_recording() {
+        let dir = crate::test_util::temp_path("events-rec");
+        let mut r = Recorder::open(&dir, "front", options()).unwrap();
+        let log = EventLog::memory();
+        r.set_event_log(log.clone());
+
+        // Camera at 20 fps, clips are recorded at 10 fps
+        let mut finished: Vec<Event> = vec![];
+        for i in 0..100 {
+            let time = T + i * 50;
+            if let Some(event) = r.add_frame(time, &solid_jpeg(8, 8, i as u8)).unwrap() {
+                finished.push(event);
+            }
+            if i == 40 {
+                let event = r.trigger("api", time, 200).unwrap();
+                assert_eq!((event.id, event.start), (1, T + 2000));
+            }
+        }
+
+        // Pre-roll from 1:000, event till 2:200, post-roll till 2:700
+        assert_eq!(finished.len(), 1);
+        let event = &finished[0];
+        assert_eq!(event.source, "api");
+        assert_eq!((event.start, event.end), (T + 2000, T + 2200));
+        assert_eq!(event.clip, "event-1-20240301-120002.avi");
+        assert_eq!(event.frames, 17);
+        let mut ar = AviReader::open(dir.join(&event.clip).to_str().unwrap()).unwrap();
+        assert_eq!((ar.fps(), ar.frame_count()), (10, 17));
+        assert_eq!(ar.start_time(), Some(T + 1000));
+        assert_eq!(ar.frame(0).unwrap(), solid_jpeg(8, 8, 20));
+        assert_eq!(ar.frame(10).unwrap(), solid_jpeg(8, 8, 40));
+
+        let logged = log.query(&eventlog::Filter::default()).unwrap();
+        let states: Vec<String> = logged
+            .iter()
+            .map(|e| {
+                format!(
+                    "{} {}",
+                    e.kind,
+                    e.data.stringify().unwrap().contains("finished")
+                )
+            })
+            .collect();
+        assert_eq!(states, vec!["recording false", "recording true"]);
+
+        // Index is reloaded
+        let r = Recorder::open(&dir, "front", options()).unwrap();
+        assert_eq!(r.events(0, u64::MAX, None), vec![event]);
+        assert!(r.events(0, u64::MAX, Some("motion")).is_empty());
+        assert!(r.events(T + 2300, u64::MAX, None).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_slow_camera() {
+        let dir = crate::test_util::temp_path("events-slow");
+        let mut r = Recorder::open(&dir, "front", options()).unwrap();
+        for fps in [0, 1001] {
+            let options = Options { fps, ..options() };
+            assert!(Recorder::open(&dir, "front", options).is_err());
+        }
+
+        // Camera at 5 fps, every frame fills two slots of the 10 fps clip
+        let mut finished: Vec<Event> = vec![];
+        for i in 0..20 {
+            let time = T + i * 200;
+            if let Some(event) = r.add_frame(time, &solid_jpeg(8, 8, i as u8)).unwrap() {
+                finished.push(event);
+            }
+            if i == 10 {
+                r.trigger("api", time, 200).unwrap();
+            }
+        }
+
+        // Frames of 1:000 - 2:600 fill the clip from 1:000 to 2:600 at 10 fps
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].frames, 17);
+        let mut ar = AviReader::open(dir.join(&finished[0].clip).to_str().unwrap()).unwrap();
+        assert_eq!((ar.fps(), ar.frame_count()), (10, 17));
+        assert_eq!(ar.start_time(), Some(T + 1000));
+        for idx in [0, 1] {
+            assert_eq!(ar.frame(idx).unwrap(), solid_jpeg(8, 8, 5));
+        }
+        assert_eq!(ar.frame(2).unwrap(), solid_jpeg(8, 8, 6));
+        assert_eq!(ar.frame(16).unwrap(), solid_jpeg(8, 8, 13));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extend_and_split() {
+        let dir = crate::test_util::temp_path("events-split");
+        let mut r = Recorder::open(&dir, "front", options()).unwrap();
+
+        // Motion for 5 s: split at 3 s, the next event starts at the next trigger
+        let mut finished: Vec<Event> = vec![];
+        for i in 0..80 {
+            let time = T + i * 100;
+            if let Some(event) = r.add_frame(time, &solid_jpeg(8, 8, 1)).unwrap() {
+                finished.push(event);
+            }
+            if i < 50 {
+                r.trigger("motion", time, 0).unwrap();
+            }
+        }
+        assert_eq!(r.current(), None);
+
+        let spans: Vec<(u64, u64, u64)> = finished
+            .iter()
+            .map(|e| (e.id, e.start - T, e.end - T))
+            .collect();
+        assert_eq!(spans, vec![(1, 0, 2900), (2, 3000, 4900)]);
+        assert_eq!(finished[0].frames, 30);
+        assert_eq!(finished[1].frames, 24);
+
+        let json = events_json(&r, &r#"{"limit": 1}"#.parse().unwrap()).unwrap();
+        let json = json.stringify().unwrap();
+        assert!(json.contains(r#""id":2"#));
+        assert!(!json.contains(r#""id":1"#));
+        assert!(json.contains("/cam/front/archive/events/event-2-20240301-120003.avi"));
+        assert!(events_json(&r, &r#"{"limit": 0}"#.parse().unwrap()).is_err());
+
+        // Event without frames has no clip
+        r.trigger("external", T + 10000, 0).unwrap();
+        let event = r.finish().unwrap().unwrap();
+        assert_eq!((event.id, event.frames), (3, 0));
+        assert_eq!(event.clip, "");
+
+        let event = r.trigger("api", T + 20000, u64::MAX).unwrap();
+        assert_eq!(event.end, u64::MAX);
+        r.finish().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
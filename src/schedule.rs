@@ -0,0 +1,621 @@
+/// Switching of camera control profiles by time of day.
+/// Schedule is stored in a JSON file:
+///     {
+///         "latitude": 52.52, "longitude": 13.4, "utc_offset": 60,
+///         "entries": [
+///             {"name": "day", "at": "sunrise+30m", "resolution": "1280x720/10", "controls": [...]},
+///             {"name": "night", "at": "0 22 * * *", "controls": [{"name": "Gain", "value": 80}]}
+///         ]
+///     }
+/// "at" is either a cron expression (minute hour day month weekday) in local time given by
+/// utc_offset in minutes or sunrise/sunset with optional offset up to 24h computed from the
+/// coordinates.
+/// The active profile is the entry which was triggered last.
+use crate::archive;
+use crate::datetime::DateTime;
+use crate::presets::Preset;
+use crate::source::FrameSource;
+use crate::web::{self, ApiError, ERR_INVALID_ARGS};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub const DEFAULT_FILE: &str = "httpcam-schedule.json";
+
+const MINUTE_MS: u64 = 60 * 1000;
+const DAY_MS: u64 = 24 * 60 * MINUTE_MS;
+// Largest offset of sunrise/sunset triggers in minutes
+const MAX_SUN_OFFSET: i64 = 24 * 60;
+// Sun center is 50' below horizon at sunrise/sunset because of refraction and its radius
+const ZENITH: f64 = 90.833;
+
+fn invalid(msg: &str) -> Box<dyn Error> {
+    Box::new(ApiError::new(ERR_INVALID_ARGS, msg))
+}
+
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+enum Trigger {
+    Cron(Cron),
+    Sun { rising: bool, offset: i64 },
+}
+
+pub struct Entry {
+    pub name: String,
+    pub at: String,
+    trigger: Trigger,
+    pub preset: Preset,
+}
+
+pub struct Schedule {
+    path: PathBuf,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    utc_offset: i64,
+    entries: Vec<Entry>,
+    active: Option<usize>,
+    triggered_at: u64,
+    checked: Option<u64>,
+}
+
+fn parse_number<T: std::str::FromStr>(v: &str, s: &str) -> Result<T> {
+    match v.trim().parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(invalid(&format!("Invalid number '{}' in '{}'", v, s))),
+    }
+}
+
+/// Parse one cron field: *, 5, 1-5, */15, 0-30/10 or comma separated list of them
+fn parse_field(s: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0u64;
+
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_number::<u32>(step, s)?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid(&format!("Invalid step in '{}'", s)));
+        }
+
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((lo, hi)) = range.split_once('-') {
+            (parse_number::<u32>(lo, s)?, parse_number::<u32>(hi, s)?)
+        } else {
+            let v = parse_number::<u32>(range, s)?;
+            (v, if step > 1 { max } else { v })
+        };
+        if lo < min || hi > max || lo > hi {
+            return Err(invalid(&format!("Value out of range in '{}'", s)));
+        }
+
+        for v in (lo..=hi).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+
+    Ok(mask)
+}
+
+fn bit(mask: u64, v: u32) -> bool {
+    mask & (1 << v) != 0
+}
+
+impl Cron {
+    fn parse(s: &str) -> Result<Cron> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid(&format!(
+                "Expected 'minute hour day month weekday' in '{}'",
+                s
+            )));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if bit(weekdays, 7) {
+            weekdays |= 1;
+        }
+
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Check the date, like in cron if both day and weekday are restricted either matches
+    fn matches_day(&self, dt: &DateTime, weekday: u32) -> bool {
+        if !bit(self.months, dt.month) {
+            return false;
+        }
+
+        let day = bit(self.days, dt.day);
+        let wday = bit(self.weekdays, weekday);
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => wday,
+            (false, true) => day,
+            (false, false) => day || wday,
+        }
+    }
+
+    /// Last time (ms since epoch) not later than now when the expression matched
+    fn last_match(&self, now: u64, utc_offset: i64) -> Option<u64> {
+        let offset = utc_offset * MINUTE_MS as i64;
+        let local = (now as i64 + offset) as u64 / MINUTE_MS;
+        let today = local / (24 * 60);
+
+        // A year is enough to find any matching date
+        for d in 0..=366 {
+            if d > today {
+                break;
+            }
+            let day = today - d;
+            let dt = DateTime::from_ms(day * DAY_MS);
+            // 1970-01-01 was Thursday
+            let weekday = ((day + 4) % 7) as u32;
+            if !self.matches_day(&dt, weekday) {
+                continue;
+            }
+
+            let last = if d == 0 {
+                local % (24 * 60)
+            } else {
+                24 * 60 - 1
+            };
+            for m in (0..=last).rev() {
+                if bit(self.hours, (m / 60) as u32) && bit(self.minutes, (m % 60) as u32) {
+                    let t = ((day * 24 * 60 + m) * MINUTE_MS) as i64 - offset;
+                    return Some(t as u64);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn sin_deg(x: f64) -> f64 {
+    x.to_radians().sin()
+}
+
+fn cos_deg(x: f64) -> f64 {
+    x.to_radians().cos()
+}
+
+/// Time of sunrise or sunset (ms since epoch) on the UTC day (days since 1970-01-01).
+/// None if the sun doesn't rise or set this day.
+/// The algorithm is from Almanac for Computers (1990), it is precise to a couple of minutes.
+pub fn sun_event(day: u64, latitude: f64, longitude: f64, rising: bool) -> Option<u64> {
+    let dt = DateTime::from_ms(day * DAY_MS);
+    let jan1 = DateTime {
+        month: 1,
+        day: 1,
+        ..dt
+    };
+    let n = (day - jan1.to_ms() / DAY_MS + 1) as f64;
+
+    let lng_hour = longitude / 15.0;
+    let t = n + (if rising { 6.0 } else { 18.0 } - lng_hour) / 24.0;
+    // Sun's mean anomaly and true longitude
+    let m = 0.9856 * t - 3.289;
+    let l = (m + 1.916 * sin_deg(m) + 0.020 * sin_deg(2.0 * m) + 282.634).rem_euclid(360.0);
+    // Right ascension in the same quadrant as the longitude
+    let ra = (0.91764 * l.to_radians().tan())
+        .atan()
+        .to_degrees()
+        .rem_euclid(360.0);
+    let ra = (ra + (l / 90.0).floor() * 90.0 - (ra / 90.0).floor() * 90.0) / 15.0;
+
+    let sin_dec = 0.39782 * sin_deg(l);
+    let cos_dec = sin_dec.asin().cos();
+    let cos_h = (cos_deg(ZENITH) - sin_dec * sin_deg(latitude)) / (cos_dec * cos_deg(latitude));
+    if !(-1.0..=1.0).contains(&cos_h) {
+        return None;
+    }
+
+    let h = cos_h.acos().to_degrees();
+    let h = if rising { 360.0 - h } else { h } / 15.0;
+    let ut = (h + ra - 0.06571 * t - 6.622 - lng_hour).rem_euclid(24.0);
+
+    Some(day * DAY_MS + (ut * 3600.0 * 1000.0) as u64)
+}
+
+/// Parse sunrise/sunset time: "sunrise", "sunset-1h", "sunrise+45m"
+fn parse_sun(s: &str) -> Result<Option<Trigger>> {
+    let (rising, rest) = if let Some(rest) = s.strip_prefix("sunrise") {
+        (true, rest)
+    } else if let Some(rest) = s.strip_prefix("sunset") {
+        (false, rest)
+    } else {
+        return Ok(None);
+    };
+
+    let rest = rest.trim();
+    let offset = if rest.is_empty() {
+        0
+    } else {
+        let (sign, value) = match rest.split_at(1) {
+            ("+", v) => (1, v.trim()),
+            ("-", v) => (-1, v.trim()),
+            _ => return Err(invalid(&format!("Invalid offset in '{}'", s))),
+        };
+        let minutes = if let Some(h) = value.strip_suffix('h') {
+            parse_number::<u32>(h, s)? as i64 * 60
+        } else {
+            parse_number::<u32>(value.strip_suffix('m').unwrap_or(value), s)? as i64
+        };
+        if minutes > MAX_SUN_OFFSET {
+            return Err(invalid(&format!("Offset in '{}' exceeds 24h", s)));
+        }
+        sign * minutes
+    };
+
+    Ok(Some(Trigger::Sun { rising, offset }))
+}
+
+impl Entry {
+    fn from_json(value: &JsonValue) -> Result<Entry> {
+        let name = web::string_arg(value, "name")?;
+        let at = web::string_arg(value, "at")?;
+        let trigger = match parse_sun(at.trim())? {
+            Some(trigger) => trigger,
+            None => Trigger::Cron(Cron::parse(at)?),
+        };
+
+        let preset = Preset::from_json(value)?;
+        for control in &preset.controls {
+            web::string_arg(control, "name")?;
+            if web::optional_arg(control, "value").is_none() {
+                return Err(invalid("Control value is required"));
+            }
+        }
+
+        Ok(Entry {
+            name: name.clone(),
+            at: at.clone(),
+            trigger,
+            preset,
+        })
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut res = match self.preset.to_json() {
+            JsonValue::Object(obj) => obj,
+            _ => HashMap::new(),
+        };
+        res.insert(String::from("name"), JsonValue::String(self.name.clone()));
+        res.insert(String::from("at"), JsonValue::String(self.at.clone()));
+        JsonValue::Object(res)
+    }
+}
+
+fn time_json(ms: u64) -> JsonValue {
+    JsonValue::String(DateTime::from_ms(ms).to_string())
+}
+
+impl Schedule {
+    /// Load schedule from the file, missing file means empty schedule
+    pub fn load(path: &Path) -> Result<Schedule> {
+        let mut schedule = Schedule {
+            path: PathBuf::from(path),
+            latitude: None,
+            longitude: None,
+            utc_offset: 0,
+            entries: vec![],
+            active: None,
+            triggered_at: 0,
+            checked: None,
+        };
+
+        if path.exists() {
+            let content = std::fs::read_to_string(path)?;
+            let json: JsonValue = content.parse()?;
+            schedule.update(&json)?;
+        }
+
+        Ok(schedule)
+    }
+
+    fn update(&mut self, value: &JsonValue) -> Result<()> {
+        let number = |name: &str, min: f64, max: f64| -> Result<Option<f64>> {
+            match web::optional_arg(value, name) {
+                None => Ok(None),
+                Some(_) => {
+                    let v = web::number_arg(value, name)?;
+                    if v < min || v > max {
+                        return Err(invalid(&format!("{} must be in [{}, {}]", name, min, max)));
+                    }
+                    Ok(Some(v))
+                }
+            }
+        };
+        let latitude = number("latitude", -90.0, 90.0)?;
+        let longitude = number("longitude", -180.0, 180.0)?;
+        let utc_offset = number("utc_offset", -14.0 * 60.0, 14.0 * 60.0)?.unwrap_or(0.0) as i64;
+
+        let mut entries: Vec<Entry> = vec![];
+        if let Some(list) = web::optional_arg(value, "entries") {
+            let list: &Vec<JsonValue> = match list.get() {
+                Some(list) => list,
+                None => return Err(invalid("Schedule entries must be an array")),
+            };
+            for item in list {
+                let entry = Entry::from_json(item)?;
+                if let Trigger::Sun { .. } = entry.trigger {
+                    if latitude.is_none() || longitude.is_none() {
+                        return Err(invalid(&format!(
+                            "Latitude and longitude are required for '{}'",
+                            entry.at
+                        )));
+                    }
+                }
+                entries.push(entry);
+            }
+        }
+
+        self.latitude = latitude;
+        self.longitude = longitude;
+        self.utc_offset = utc_offset;
+        self.entries = entries;
+        // Re-evaluate the schedule on the next tick
+        self.active = None;
+        self.triggered_at = 0;
+        self.checked = None;
+
+        Ok(())
+    }
+
+    /// Replace the schedule and save it into the file
+    pub fn set(&mut self, value: &JsonValue) -> Result<()> {
+        self.update(value)?;
+        let content = self.to_json().format()?;
+        archive::write_file_atomic(&self.path, content.as_bytes())
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        if let Some(lat) = self.latitude {
+            res.insert(String::from("latitude"), JsonValue::Number(lat));
+        }
+        if let Some(lon) = self.longitude {
+            res.insert(String::from("longitude"), JsonValue::Number(lon));
+        }
+        res.insert(
+            String::from("utc_offset"),
+            JsonValue::Number(self.utc_offset as f64),
+        );
+        res.insert(
+            String::from("entries"),
+            JsonValue::Array(self.entries.iter().map(|e| e.to_json()).collect()),
+        );
+        JsonValue::Object(res)
+    }
+
+    fn last_trigger(&self, entry: &Entry, now: u64) -> Option<u64> {
+        match &entry.trigger {
+            Trigger::Cron(cron) => cron.last_match(now, self.utc_offset),
+            Trigger::Sun { rising, offset } => {
+                let (lat, lon) = (self.latitude?, self.longitude?);
+                let today = now / DAY_MS;
+                let mut last: Option<u64> = None;
+                // Offset could move the event to the neighbour day
+                for day in today.saturating_sub(2)..=today + 1 {
+                    let t = sun_event(day, lat, lon, *rising)
+                        .and_then(|t| t.checked_add_signed(offset.checked_mul(MINUTE_MS as i64)?));
+                    if let Some(t) = t {
+                        if t <= now && last.is_none_or(|l| t > l) {
+                            last = Some(t);
+                        }
+                    }
+                }
+                last
+            }
+        }
+    }
+
+    /// Check the schedule, returns the entry to apply if the active profile was triggered.
+    /// The schedule is checked once a minute.
+    pub fn tick(&mut self, now: u64) -> Option<&Entry> {
+        let minute = now / MINUTE_MS;
+        if self.checked == Some(minute) {
+            return None;
+        }
+        self.checked = Some(minute);
+
+        let mut best: Option<(u64, usize)> = None;
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if let Some(t) = self.last_trigger(entry, now) {
+                // Later entry wins if triggered at the same time
+                if best.is_none_or(|(b, _)| t >= b) {
+                    best = Some((t, idx));
+                }
+            }
+        }
+
+        match best {
+            Some((t, idx)) if self.active != Some(idx) || self.triggered_at != t => {
+                self.active = Some(idx);
+                self.triggered_at = t;
+                Some(&self.entries[idx])
+            }
+            _ => None,
+        }
+    }
+
+    /// Apply profile if it was triggered
+    pub fn run(&mut self, cam: &mut dyn FrameSource, now: u64) -> Option<JsonValue> {
+        self.tick(now).map(|entry| entry.preset.apply(cam))
+    }
+
+    pub fn active(&self) -> Option<&Entry> {
+        self.active.map(|idx| &self.entries[idx])
+    }
+
+    /// Active profile: {"name": <name>, "at": <time spec>, "since": <trigger time>} or null
+    pub fn status_json(&self) -> JsonValue {
+        match self.active() {
+            Some(entry) => {
+                let mut res = HashMap::<String, JsonValue>::new();
+                res.insert(String::from("name"), JsonValue::String(entry.name.clone()));
+                res.insert(String::from("at"), JsonValue::String(entry.at.clone()));
+                res.insert(String::from("since"), time_json(self.triggered_at));
+                JsonValue::Object(res)
+            }
+            None => JsonValue::Null,
+        }
+    }
+
+    /// Configured coordinates: latitude and longitude
+    pub fn location(&self) -> Option<(f64, f64)> {
+        Some((self.latitude?, self.longitude?))
+    }
+
+    /// Today's sunrise and sunset (UTC) for the configured coordinates
+    pub fn sun_json(&self, now: u64) -> JsonValue {
+        let mut res = HashMap::<String, JsonValue>::new();
+        if let (Some(lat), Some(lon)) = (self.latitude, self.longitude) {
+            for (name, rising) in [("sunrise", true), ("sunset", false)] {
+                let t = sun_event(now / DAY_MS, lat, lon, rising);
+                res.insert(String::from(name), t.map_or(JsonValue::Null, time_json));
+            }
+        }
+        JsonValue::Object(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(s: &str) -> u64 {
+        // "YYYY-MM-DD HH:MM"
+        let n = |a: usize, b: usize| s[a..b].parse::<u32>().unwrap();
+        DateTime {
+            year: n(0, 4) as i32,
+            month: n(5, 7),
+            day: n(8, 10),
+            hour: n(11, 13),
+            minute: n(14, 16),
+            second: 0,
+            ms: 0,
+        }
+        .to_ms()
+    }
+
+    #[test]
+    fn test_cron() {
+        let cron = Cron::parse("*/15 8-18 * * 1-5").unwrap();
+        // 2024-06-21 was Friday
+        let t = cron.last_match(ms("2024-06-21 12:07"), 0).unwrap();
+        assert_eq!(DateTime::from_ms(t).to_string(), "2024-06-21 12:00:00.000");
+        // Saturday morning: last match is Friday evening
+        let t = cron.last_match(ms("2024-06-22 09:00"), 0).unwrap();
+        assert_eq!(DateTime::from_ms(t).to_string(), "2024-06-21 18:45:00.000");
+        // Local time is UTC+2
+        let t = cron.last_match(ms("2024-06-21 12:07"), 120).unwrap();
+        assert_eq!(DateTime::from_ms(t).to_string(), "2024-06-21 12:00:00.000");
+
+        let cron = Cron::parse("30 22 1 * 7").unwrap();
+        let t = cron.last_match(ms("2024-06-21 12:07"), 0).unwrap();
+        assert_eq!(DateTime::from_ms(t).to_string(), "2024-06-16 22:30:00.000");
+
+        assert!(Cron::parse("0 24 * * *").is_err());
+        assert!(Cron::parse("0 12 * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+    }
+
+    #[test]
+    fn test_sun() {
+        // Berlin, 2024-06-21: sunrise 02:43 UTC, sunset 19:33 UTC
+        let day = ms("2024-06-21 00:00") / DAY_MS;
+        let sunrise = sun_event(day, 52.52, 13.405, true).unwrap();
+        let sunset = sun_event(day, 52.52, 13.405, false).unwrap();
+        assert!(sunrise.abs_diff(ms("2024-06-21 02:43")) < 3 * MINUTE_MS);
+        assert!(sunset.abs_diff(ms("2024-06-21 19:33")) < 3 * MINUTE_MS);
+
+        // Polar day in Longyearbyen
+        assert!(sun_event(day, 78.22, 15.65, true).is_none());
+    }
+
+    #[test]
+    fn test_parse_sun() {
+        let offset = |s: &str| match parse_sun(s).unwrap() {
+            Some(Trigger::Sun { offset, .. }) => offset,
+            _ => panic!("{} is not a sun trigger", s),
+        };
+        assert_eq!(offset("sunrise"), 0);
+        assert_eq!(offset("sunrise+45m"), 45);
+        assert_eq!(offset("sunset-1h"), -60);
+        assert_eq!(offset("sunset+24h"), MAX_SUN_OFFSET);
+        assert!(parse_sun("0 12 * * *").unwrap().is_none());
+
+        assert!(parse_sun("sunset+25h").is_err());
+        assert!(parse_sun("sunrise-1441m").is_err());
+        assert!(parse_sun("sunrise+99999999999999999h").is_err());
+        assert!(parse_sun("sunrise+-5m").is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let path = crate::test_util::temp_path("schedule.json");
+        let mut schedule = Schedule::load(&path).unwrap();
+        assert!(schedule.tick(ms("2024-06-21 12:00")).is_none());
+
+        let config: JsonValue = r#"{
+            "latitude": 52.52, "longitude": 13.405,
+            "entries": [
+                {"name": "day", "at": "sunrise+30m", "controls": [{"name": "Brightness", "value": 0}]},
+                {"name": "night", "at": "sunset", "controls": [{"name": "Brightness", "value": 40}]}
+            ]
+        }"#
+        .parse()
+        .unwrap();
+        schedule.set(&config).unwrap();
+
+        let schedule = &mut Schedule::load(&path).unwrap();
+        let name = |s: &mut Schedule, t: &str| s.tick(ms(t)).map(|e| e.name.clone());
+        assert_eq!(
+            name(schedule, "2024-06-21 02:00"),
+            Some(String::from("night"))
+        );
+        assert_eq!(name(schedule, "2024-06-21 02:30"), None);
+        assert_eq!(
+            name(schedule, "2024-06-21 03:20"),
+            Some(String::from("day"))
+        );
+        assert_eq!(schedule.active().unwrap().name, "day");
+        assert_eq!(
+            name(schedule, "2024-06-21 19:40"),
+            Some(String::from("night"))
+        );
+        // Next night triggers the profile again
+        assert_eq!(name(schedule, "2024-06-22 01:00"), None);
+        assert_eq!(
+            name(schedule, "2024-06-22 19:40"),
+            Some(String::from("night"))
+        );
+
+        let config: JsonValue = r#"{"entries": [{"name": "x", "at": "sunset"}]}"#.parse().unwrap();
+        assert!(schedule.set(&config).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
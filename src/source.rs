@@ -0,0 +1,265 @@
+/// Frame sources: anything that can produce video frames for the main loop.
+///
+/// Sources are selected with a specification string:
+///     camera:<index>          - nokhwa camera with the given index
+///     camera:<path>|<name>    - camera by its device path (e.g. /dev/v4l/by-id/<device>) or name,
+///                               they don't change when devices are replugged
+///     test[:<w>x<h>[/<fps>]]  - synthetic test pattern, 640x480/15 by default
+///     replay:<path>[,fps=<n>][,loop][,layout=<template>]
+///                             - replay frame_<ms>.jpg files from the directory (or files stored in
+///                               the archive layout) or an MJPEG AVI file with the original timing
+///                               or with fixed frame rate
+use crate::archive::Layout;
+use nokhwa::utils::{CameraControl, CameraFormat, ControlValueSetter, KnownCameraControl};
+use nokhwa::Buffer;
+use std::error::Error;
+
+mod camera;
+mod replay;
+mod synthetic;
+
+pub use camera::{find_camera, CameraSource};
+pub use replay::ReplaySource;
+pub use synthetic::TestPatternSource;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn err(s: &str) -> Box<dyn Error> {
+    Box::<dyn Error>::from(String::from(s))
+}
+
+pub trait FrameSource {
+    /// Human readable description of the source
+    fn name(&self) -> String;
+
+    /// Start streaming. Format can't be changed after this call.
+    fn open(&mut self) -> Result<()>;
+
+    /// Check if the source is streaming
+    fn is_open(&self) -> bool;
+
+    /// Wait for the next frame and return it
+    fn frame(&mut self) -> Result<Buffer>;
+
+    /// List all formats supported by the source
+    fn formats(&mut self) -> Result<Vec<CameraFormat>>;
+
+    /// Currently selected format
+    fn format(&self) -> CameraFormat;
+
+    /// Select one of the formats returned by formats()
+    fn set_format(&mut self, fmt: &CameraFormat) -> Result<()>;
+
+    /// List source controls and their current values
+    fn controls(&self) -> Result<Vec<CameraControl>>;
+
+    /// Set control value. The value is not validated here, use controls::set_control for that.
+    fn set_control(&mut self, control: KnownCameraControl, value: ControlValueSetter)
+        -> Result<()>;
+
+    /// Stop streaming
+    fn close(&mut self) -> Result<()>;
+}
+
+/// Parse format in the form <width>x<height>[/<fps>]
+pub fn parse_format(s: &str, default_fps: u32) -> Result<(u32, u32, u32)> {
+    let (res, fps) = match s.split_once('/') {
+        Some((res, fps)) => (res, fps.parse::<u32>()?),
+        None => (s, default_fps),
+    };
+
+    let (w, h) = match res.split_once('x') {
+        Some((w, h)) => (w.parse::<u32>()?, h.parse::<u32>()?),
+        None => return Err(err("Invalid format, expected <width>x<height>[/<fps>]")),
+    };
+
+    if w == 0 || h == 0 || fps == 0 {
+        return Err(err("Invalid format: zero size or frame rate"));
+    }
+
+    Ok((w, h, fps))
+}
+
+/// Format as <width>x<height>/<fps>
+pub fn format_string(fmt: &CameraFormat) -> String {
+    format!("{}x{}/{}", fmt.width(), fmt.height(), fmt.frame_rate())
+}
+
+/// Select format by its string representation, the stream is restarted if it is open. The source
+/// keeps the previous format if the new one can't be set or opened.
+pub fn select_format(src: &mut dyn FrameSource, s: &str) -> Result<()> {
+    if format_string(&src.format()) == s {
+        return Ok(());
+    }
+
+    let formats = src.formats()?;
+    let fmt = match formats.iter().find(|f| format_string(f) == s) {
+        Some(fmt) => fmt,
+        None => return Err(err(&format!("Camera format {} is not found", s))),
+    };
+
+    let previous = src.format();
+    let was_open = src.is_open();
+    if was_open {
+        src.close()?;
+    }
+    let reopen = |src: &mut dyn FrameSource| match was_open {
+        true => src.open(),
+        false => Ok(()),
+    };
+    let error = match src.set_format(fmt).and_then(|_| reopen(src)) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+
+    if src.is_open() {
+        src.close()?;
+    }
+    match src.set_format(&previous).and_then(|_| reopen(src)) {
+        Ok(()) => Err(error),
+        Err(e) => Err(err(&format!(
+            "{}, the previous format is not restored: {}",
+            error, e
+        ))),
+    }
+}
+
+/// Create a frame source from the specification string
+pub fn create(spec: &str) -> Result<Box<dyn FrameSource>> {
+    let (kind, params) = match spec.split_once(':') {
+        Some((kind, params)) => (kind, params),
+        None => (spec, ""),
+    };
+
+    match kind {
+        "camera" => {
+            let index = if params.is_empty() {
+                0
+            } else {
+                find_camera(params)?
+            };
+            Ok(Box::new(CameraSource::new(index)?))
+        }
+        "test" => {
+            let (w, h, fps) = if params.is_empty() {
+                (640, 480, 15)
+            } else {
+                parse_format(params, 15)?
+            };
+            Ok(Box::new(TestPatternSource::new(w, h, fps)?))
+        }
+        "replay" => {
+            let mut parts = params.split(',');
+            let path = parts.next().unwrap_or("");
+            let mut fps: Option<u32> = None;
+            let mut looping = false;
+            let mut layout = Layout::default();
+            for opt in parts {
+                if opt == "loop" {
+                    looping = true;
+                } else if let Some(v) = opt.strip_prefix("fps=") {
+                    fps = Some(v.parse::<u32>()?);
+                } else if let Some(v) = opt.strip_prefix("layout=") {
+                    layout = Layout::parse(v)?;
+                } else {
+                    return Err(err(&format!("Unknown replay option: {}", opt)));
+                }
+            }
+            Ok(Box::new(ReplaySource::new(path, &layout, fps, looping)?))
+        }
+        _ => Err(err(&format!("Unknown source type: {}", kind))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!(parse_format("640x480/15", 1).unwrap(), (640, 480, 15));
+        assert_eq!(parse_format("320x240", 7).unwrap(), (320, 240, 7));
+        assert!(parse_format("320", 7).is_err());
+        assert!(parse_format("0x240/1", 7).is_err());
+        assert!(parse_format("axb/1", 7).is_err());
+    }
+
+    #[test]
+    fn test_create() {
+        let src = create("test:320x240/5").unwrap();
+        let fmt = src.format();
+        assert_eq!((fmt.width(), fmt.height(), fmt.frame_rate()), (320, 240, 5));
+        assert!(create("unknown:1").is_err());
+        assert!(create("replay:/nonexistent,fps=5,loop").is_err());
+        assert!(create("replay:/nonexistent,speed=5").is_err());
+    }
+
+    #[test]
+    fn test_select_format() {
+        let mut src = create("test:320x240/5").unwrap();
+        src.open().unwrap();
+        select_format(src.as_mut(), "640x480/5").unwrap();
+        assert!(src.is_open());
+        assert_eq!(format_string(&src.format()), "640x480/5");
+        assert!(select_format(src.as_mut(), "641x480/5").is_err());
+
+        // Sources which fail to switch the format are reopened with the previous one
+        let mut src = Failing(TestPatternSource::new(320, 240, 5).unwrap());
+        src.open().unwrap();
+        assert!(select_format(&mut src, "1920x1080/5").is_err());
+        assert!(src.is_open());
+        assert_eq!(format_string(&src.format()), "320x240/5");
+    }
+
+    // Test pattern which can't be set to 1920x1080
+    struct Failing(TestPatternSource);
+
+    impl FrameSource for Failing {
+        fn name(&self) -> String {
+            self.0.name()
+        }
+
+        fn open(&mut self) -> Result<()> {
+            self.0.open()
+        }
+
+        fn is_open(&self) -> bool {
+            self.0.is_open()
+        }
+
+        fn frame(&mut self) -> Result<Buffer> {
+            self.0.frame()
+        }
+
+        fn formats(&mut self) -> Result<Vec<CameraFormat>> {
+            self.0.formats()
+        }
+
+        fn format(&self) -> CameraFormat {
+            self.0.format()
+        }
+
+        fn set_format(&mut self, fmt: &CameraFormat) -> Result<()> {
+            if fmt.width() == 1920 {
+                return Err(err("Format is not supported"));
+            }
+            self.0.set_format(fmt)
+        }
+
+        fn controls(&self) -> Result<Vec<CameraControl>> {
+            self.0.controls()
+        }
+
+        fn set_control(
+            &mut self,
+            control: KnownCameraControl,
+            value: ControlValueSetter,
+        ) -> Result<()> {
+            self.0.set_control(control, value)
+        }
+
+        fn close(&mut self) -> Result<()> {
+            self.0.close()
+        }
+    }
+}
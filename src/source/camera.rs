@@ -0,0 +1,124 @@
+// Frame source reading from a real camera using nokhwa
+
+use super::{FrameSource, Result};
+use nokhwa::pixel_format::RgbFormat;
+use nokhwa::utils::{
+    CameraControl, CameraFormat, CameraIndex, ControlValueSetter, KnownCameraControl,
+    RequestedFormat, RequestedFormatType,
+};
+use nokhwa::{Buffer, Camera};
+use std::path::Path;
+
+pub struct CameraSource {
+    camera: Camera,
+}
+
+impl CameraSource {
+    pub fn new(index: u32) -> Result<CameraSource> {
+        let requested =
+            RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestResolution);
+        let camera = Camera::new(CameraIndex::Index(index), requested)?;
+
+        Ok(CameraSource { camera })
+    }
+}
+
+/// Find index of the camera given by its index, device path or name. Indices change when
+/// devices are replugged, links like /dev/v4l/by-id/<device> and names don't. The first camera
+/// is taken if several ones have the name.
+pub fn find_camera(spec: &str) -> Result<u32> {
+    if let Ok(index) = spec.parse::<u32>() {
+        return Ok(index);
+    }
+
+    if spec.starts_with('/') {
+        let path =
+            std::fs::canonicalize(spec).map_err(|err| format!("Camera {}: {}", spec, err))?;
+        return match device_index(&path) {
+            Some(index) => Ok(index),
+            None => Err(format!("{} is not a video device", path.display()).into()),
+        };
+    }
+
+    let cameras = nokhwa::query(nokhwa::utils::ApiBackend::Auto)?;
+    match cameras.iter().find(|info| info.human_name() == spec) {
+        Some(info) => Ok(info.index().as_index()?),
+        None => Err(format!("Camera '{}' is not found", spec).into()),
+    }
+}
+
+/// Index of /dev/video<index>
+fn device_index(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("video")?
+        .parse()
+        .ok()
+}
+
+impl FrameSource for CameraSource {
+    fn name(&self) -> String {
+        self.camera.info().to_string()
+    }
+
+    fn open(&mut self) -> Result<()> {
+        self.camera.open_stream()?;
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.camera.is_stream_open()
+    }
+
+    fn frame(&mut self) -> Result<Buffer> {
+        Ok(self.camera.frame()?)
+    }
+
+    fn formats(&mut self) -> Result<Vec<CameraFormat>> {
+        Ok(self.camera.compatible_camera_formats()?)
+    }
+
+    fn format(&self) -> CameraFormat {
+        self.camera.camera_format()
+    }
+
+    fn set_format(&mut self, fmt: &CameraFormat) -> Result<()> {
+        self.camera.set_resolution(fmt.resolution())?;
+        self.camera.set_frame_rate(fmt.frame_rate())?;
+        self.camera.set_frame_format(fmt.format())?;
+        Ok(())
+    }
+
+    fn controls(&self) -> Result<Vec<CameraControl>> {
+        Ok(self.camera.camera_controls()?)
+    }
+
+    fn set_control(
+        &mut self,
+        control: KnownCameraControl,
+        value: ControlValueSetter,
+    ) -> Result<()> {
+        self.camera.set_camera_control(control, value)?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if self.camera.is_stream_open() {
+            self.camera.stop_stream()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_camera() {
+        assert_eq!(find_camera("2").unwrap(), 2);
+        assert_eq!(device_index(Path::new("/dev/video10")), Some(10));
+        assert_eq!(device_index(Path::new("/dev/media0")), None);
+        assert!(find_camera("/nonexistent/video0").is_err());
+    }
+}
@@ -0,0 +1,267 @@
+// Frame source replaying recorded footage: a directory with frame_<ms>.jpg files written by
+// the ImageArchive or an MJPEG AVI file written by mjpeg::AviWriter. Replay which doesn't loop
+// repeats the last frame at the end of the recording.
+
+use super::{err, FrameSource, Result};
+use crate::archive::{FrameFile, Layout};
+use crate::datetime::now_ms;
+use crate::mjpeg;
+use nokhwa::utils::{
+    CameraControl, CameraFormat, ControlValueSetter, FrameFormat, KnownCameraControl, Resolution,
+};
+use nokhwa::Buffer;
+use std::path::Path;
+
+enum Recording {
+    Files(Vec<FrameFile>),
+    Avi(mjpeg::AviReader),
+}
+
+pub struct ReplaySource {
+    path: String,
+    recording: Recording,
+    // Fixed playback rate, None means original timing
+    fps: Option<u32>,
+    looping: bool,
+    width: u32,
+    height: u32,
+    pos: usize,
+    // Wall clock time and recording time of the playback start
+    start: u64,
+    start_ts: u64,
+    opened: bool,
+}
+
+impl Recording {
+    fn len(&self) -> usize {
+        match self {
+            Recording::Files(files) => files.len(),
+            Recording::Avi(avi) => avi.frame_count(),
+        }
+    }
+
+    fn frame(&mut self, idx: usize) -> Result<Vec<u8>> {
+        match self {
+            Recording::Files(files) => Ok(std::fs::read(&files[idx].path)?),
+            Recording::Avi(avi) => avi.frame(idx),
+        }
+    }
+
+    // Timestamp of the frame in ms using the original timing
+    fn timestamp(&self, idx: usize) -> u64 {
+        match self {
+            Recording::Files(files) => files[idx].time,
+            Recording::Avi(avi) => idx as u64 * 1000 / avi.fps() as u64,
+        }
+    }
+
+    // Average frame rate of the recording
+    fn fps(&self) -> u32 {
+        match self {
+            Recording::Files(files) => {
+                let duration = self.timestamp(files.len() - 1) - self.timestamp(0);
+                match ((files.len() as u64 - 1) * 1000).checked_div(duration) {
+                    Some(fps) => std::cmp::max(fps as u32, 1),
+                    None => 1,
+                }
+            }
+            Recording::Avi(avi) => avi.fps(),
+        }
+    }
+}
+
+impl ReplaySource {
+    /// Replay the archive directory with frames stored in the layout or the AVI file
+    pub fn new(
+        path: &str,
+        layout: &Layout,
+        fps: Option<u32>,
+        looping: bool,
+    ) -> Result<ReplaySource> {
+        if fps == Some(0) {
+            return Err(err("Invalid frame rate"));
+        }
+
+        let p = Path::new(path);
+        let mut recording = if p.is_dir() {
+            Recording::Files(layout.list(p, None)?)
+        } else {
+            Recording::Avi(mjpeg::AviReader::open(path)?)
+        };
+
+        if recording.len() == 0 {
+            return Err(err(&format!("No frames found in {}", path)));
+        }
+
+        let first = recording.frame(0)?;
+        let (width, height) = match mjpeg::jpeg_size(&first) {
+            Some(size) => size,
+            None => return Err(err("The first frame is not a valid JPEG image")),
+        };
+
+        Ok(ReplaySource {
+            path: String::from(path),
+            recording,
+            fps,
+            looping,
+            width,
+            height,
+            pos: 0,
+            start: 0,
+            start_ts: 0,
+            opened: false,
+        })
+    }
+
+    // Playback time of the frame relative to the start of the recording
+    fn frame_time(&self, idx: usize) -> u64 {
+        match self.fps {
+            Some(fps) => idx as u64 * 1000 / fps as u64,
+            None => self.recording.timestamp(idx) - self.recording.timestamp(0),
+        }
+    }
+}
+
+impl FrameSource for ReplaySource {
+    fn name(&self) -> String {
+        format!("Replay of {} ({} frames)", self.path, self.recording.len())
+    }
+
+    fn open(&mut self) -> Result<()> {
+        self.opened = true;
+        self.pos = 0;
+        self.start = now_ms();
+        self.start_ts = 0;
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.opened
+    }
+
+    fn frame(&mut self) -> Result<Buffer> {
+        if !self.opened {
+            return Err(err("Source is not opened"));
+        }
+
+        let period = 1000 / self.format().frame_rate() as u64;
+        if self.pos >= self.recording.len() && self.looping {
+            // Start the next round one frame period after the last frame
+            let last = self.frame_time(self.recording.len() - 1);
+            self.start_ts += last + period;
+            self.pos = 0;
+        }
+
+        let idx = if self.pos < self.recording.len() {
+            let due = self.start + self.start_ts + self.frame_time(self.pos);
+            let now = now_ms();
+            if now < due {
+                std::thread::sleep(std::time::Duration::from_millis(due - now));
+            }
+            self.pos += 1;
+            self.pos - 1
+        } else {
+            std::thread::sleep(std::time::Duration::from_millis(period));
+            self.recording.len() - 1
+        };
+        let data = self.recording.frame(idx)?;
+
+        Ok(Buffer::new(
+            Resolution::new(self.width, self.height),
+            &data,
+            FrameFormat::MJPEG,
+        ))
+    }
+
+    fn formats(&mut self) -> Result<Vec<CameraFormat>> {
+        Ok(vec![self.format()])
+    }
+
+    fn format(&self) -> CameraFormat {
+        let fps = match self.fps {
+            Some(fps) => fps,
+            None => self.recording.fps(),
+        };
+        CameraFormat::new_from(self.width, self.height, FrameFormat::MJPEG, fps)
+    }
+
+    fn set_format(&mut self, fmt: &CameraFormat) -> Result<()> {
+        if *fmt != self.format() {
+            return Err(err("Replay source can't change format"));
+        }
+        Ok(())
+    }
+
+    fn controls(&self) -> Result<Vec<CameraControl>> {
+        Ok(vec![])
+    }
+
+    fn set_control(
+        &mut self,
+        _control: KnownCameraControl,
+        _value: ControlValueSetter,
+    ) -> Result<()> {
+        Err(err("Replay source has no controls"))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.opened = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::TestPatternSource;
+    use super::*;
+
+    fn write_frames(dir: &Path, timestamps: &[u64]) {
+        let mut src = TestPatternSource::new(32, 24, 1000).unwrap();
+        src.open().unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        for ts in timestamps {
+            let frame = src.frame().unwrap();
+            std::fs::write(dir.join(format!("frame_{}.jpg", ts)), frame.buffer()).unwrap();
+        }
+        std::fs::write(dir.join("frame_x.jpg"), b"garbage").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"garbage").unwrap();
+    }
+
+    #[test]
+    fn test_replay_directory() {
+        let dir = crate::test_util::temp_path("replay");
+        write_frames(&dir, &[1000, 1020, 1010]);
+
+        let frames = crate::archive::list_frames(&dir).unwrap();
+        let ts: Vec<u64> = frames.iter().map(|f| f.time).collect();
+        assert_eq!(ts, vec![1000, 1010, 1020]);
+
+        let mut src =
+            ReplaySource::new(dir.to_str().unwrap(), &Layout::default(), None, true).unwrap();
+        let fmt = src.format();
+        assert_eq!((fmt.width(), fmt.height(), fmt.frame_rate()), (32, 24, 100));
+
+        src.open().unwrap();
+        for i in 0..4 {
+            let frame = src.frame().unwrap();
+            assert_eq!(
+                frame.buffer(),
+                &std::fs::read(&frames[i % 3].path).unwrap()[..]
+            );
+        }
+
+        let mut src =
+            ReplaySource::new(dir.to_str().unwrap(), &Layout::default(), Some(200), false).unwrap();
+        assert_eq!(src.format().frame_rate(), 200);
+        src.open().unwrap();
+        for _ in 0..3 {
+            src.frame().unwrap();
+        }
+        assert_eq!(
+            src.frame().unwrap().buffer(),
+            &std::fs::read(&frames[2].path).unwrap()[..]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
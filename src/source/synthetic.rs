@@ -0,0 +1,315 @@
+// Synthetic frame source: moving color bars with a frame counter and a timestamp.
+// It is used for testing httpcam without a real camera.
+
+use super::{err, FrameSource, Result};
+use crate::datetime::{now_ms, DateTime};
+use crate::jpeg;
+use nokhwa::utils::{
+    CameraControl, CameraFormat, ControlValueDescription, ControlValueSetter, FrameFormat,
+    KnownCameraControl, KnownCameraControlFlag, Resolution,
+};
+use nokhwa::Buffer;
+
+const BARS: [[u8; 3]; 8] = [
+    [235, 235, 235],
+    [235, 235, 16],
+    [16, 235, 235],
+    [16, 235, 16],
+    [235, 16, 235],
+    [235, 16, 16],
+    [16, 16, 235],
+    [16, 16, 16],
+];
+
+// 5x7 font, one byte per row, the highest bit is the left column
+const FONT_CHARS: &str = "0123456789-:.#";
+const FONT: [[u8; 7]; 14] = [
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+    [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+    [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a],
+];
+
+pub struct TestPatternSource {
+    width: u32,
+    height: u32,
+    fps: u32,
+    brightness: i64,
+    counter: u64,
+    next_frame: u64,
+    opened: bool,
+}
+
+struct Canvas {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32) -> Canvas {
+        Canvas {
+            width: width as usize,
+            height: height as usize,
+            data: vec![0; width as usize * height as usize * 3],
+        }
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: &[u8; 3]) {
+        for row in y..std::cmp::min(y + h, self.height) {
+            for col in x..std::cmp::min(x + w, self.width) {
+                let idx = (row * self.width + col) * 3;
+                self.data[idx..idx + 3].copy_from_slice(color);
+            }
+        }
+    }
+
+    fn draw_text(&mut self, x: usize, y: usize, scale: usize, text: &str) {
+        let mut pos = x;
+        for c in text.chars() {
+            if let Some(idx) = FONT_CHARS.find(c) {
+                for (row, bits) in FONT[idx].iter().enumerate() {
+                    for col in 0..5 {
+                        if bits & (0x10 >> col) != 0 {
+                            self.fill_rect(
+                                pos + col * scale,
+                                y + row * scale,
+                                scale,
+                                scale,
+                                &[255, 255, 255],
+                            );
+                        }
+                    }
+                }
+            }
+            pos += 6 * scale;
+        }
+    }
+}
+
+impl TestPatternSource {
+    pub fn new(width: u32, height: u32, fps: u32) -> Result<TestPatternSource> {
+        if width == 0 || height == 0 || width > 65535 || height > 65535 {
+            return Err(err("Invalid test pattern size"));
+        }
+        if fps == 0 {
+            return Err(err("Invalid frame rate"));
+        }
+
+        Ok(TestPatternSource {
+            width,
+            height,
+            fps,
+            brightness: 0,
+            counter: 0,
+            next_frame: 0,
+            opened: false,
+        })
+    }
+
+    /// Render frame number `counter` taken at `time_ms` into an RGB buffer
+    fn render(&self, counter: u64, time_ms: u64) -> Vec<u8> {
+        let mut canvas = Canvas::new(self.width, self.height);
+        let w = canvas.width;
+        let h = canvas.height;
+
+        // Color bars moving to the right
+        let bar_width = w.div_ceil(BARS.len());
+        let shift = (counter as usize * 4) % w;
+        for (i, color) in BARS.iter().enumerate() {
+            let x = (i * bar_width + shift) % w;
+            canvas.fill_rect(x, 0, bar_width, h, color);
+            if x + bar_width > w {
+                canvas.fill_rect(0, 0, x + bar_width - w, h, color);
+            }
+        }
+
+        // Square bouncing between top and bottom
+        let size = std::cmp::max(h / 8, 1);
+        let span = (h - size) as u64;
+        let y = if span == 0 {
+            0
+        } else {
+            let p = (counter * 4) % (2 * span);
+            if p < span {
+                p
+            } else {
+                2 * span - p
+            }
+        } as usize;
+        canvas.fill_rect((w - size) / 2, y, size, size, &[128, 128, 128]);
+
+        // Frame counter and timestamp
+        let scale = std::cmp::max(h / 240, 1);
+        let line = 9 * scale;
+        let counter_text = format!("#{:08}", counter);
+        let time_text = DateTime::from_ms(time_ms).to_string();
+        canvas.fill_rect(
+            0,
+            0,
+            (time_text.len() * 6 + 3) * scale,
+            2 * line + scale,
+            &[0, 0, 0],
+        );
+        canvas.draw_text(2 * scale, 2 * scale, scale, &counter_text);
+        canvas.draw_text(2 * scale, 2 * scale + line, scale, &time_text);
+
+        if self.brightness != 0 {
+            for v in canvas.data.iter_mut() {
+                *v = (*v as i64 + self.brightness).clamp(0, 255) as u8;
+            }
+        }
+
+        canvas.data
+    }
+}
+
+impl FrameSource for TestPatternSource {
+    fn name(&self) -> String {
+        format!("Test pattern {}x{}/{}", self.width, self.height, self.fps)
+    }
+
+    fn open(&mut self) -> Result<()> {
+        self.opened = true;
+        self.next_frame = now_ms();
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.opened
+    }
+
+    fn frame(&mut self) -> Result<Buffer> {
+        if !self.opened {
+            return Err(err("Source is not opened"));
+        }
+
+        let now = now_ms();
+        if now < self.next_frame {
+            std::thread::sleep(std::time::Duration::from_millis(self.next_frame - now));
+        }
+
+        let period = 1000 / self.fps as u64;
+        let now = now_ms();
+        self.next_frame = if now > self.next_frame + period {
+            // We are late, don't try to catch up
+            now + period
+        } else {
+            self.next_frame + period
+        };
+
+        let rgb = self.render(self.counter, now);
+        self.counter += 1;
+
+        let jpeg = jpeg::encode_rgb(&rgb, self.width, self.height, jpeg::DEFAULT_QUALITY)?;
+
+        Ok(Buffer::new(
+            Resolution::new(self.width, self.height),
+            &jpeg,
+            FrameFormat::MJPEG,
+        ))
+    }
+
+    fn formats(&mut self) -> Result<Vec<CameraFormat>> {
+        let mut res: Vec<CameraFormat> = vec![];
+        for (w, h) in [(320, 240), (640, 480), (1280, 720), (1920, 1080)] {
+            res.push(CameraFormat::new_from(w, h, FrameFormat::MJPEG, self.fps));
+        }
+
+        let current = self.format();
+        if !res.contains(&current) {
+            res.push(current);
+        }
+
+        Ok(res)
+    }
+
+    fn format(&self) -> CameraFormat {
+        CameraFormat::new_from(self.width, self.height, FrameFormat::MJPEG, self.fps)
+    }
+
+    fn set_format(&mut self, fmt: &CameraFormat) -> Result<()> {
+        if self.opened {
+            return Err(err("Can't change format of opened source"));
+        }
+
+        let src = TestPatternSource::new(fmt.width(), fmt.height(), fmt.frame_rate())?;
+        self.width = src.width;
+        self.height = src.height;
+        self.fps = src.fps;
+        Ok(())
+    }
+
+    fn controls(&self) -> Result<Vec<CameraControl>> {
+        Ok(vec![CameraControl::new(
+            KnownCameraControl::Brightness,
+            String::from("Brightness"),
+            ControlValueDescription::IntegerRange {
+                min: -64,
+                max: 64,
+                value: self.brightness,
+                step: 1,
+                default: 0,
+            },
+            vec![KnownCameraControlFlag::Manual],
+            true,
+        )])
+    }
+
+    fn set_control(
+        &mut self,
+        control: KnownCameraControl,
+        value: ControlValueSetter,
+    ) -> Result<()> {
+        match (control, value) {
+            (KnownCameraControl::Brightness, ControlValueSetter::Integer(v)) => {
+                self.brightness = v.clamp(-64, 64);
+                Ok(())
+            }
+            _ => Err(err("Unsupported control")),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.opened = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut src = TestPatternSource::new(64, 48, 100).unwrap();
+        assert!(src.frame().is_err());
+        src.open().unwrap();
+
+        let f1 = src.frame().unwrap();
+        let f2 = src.frame().unwrap();
+        assert_eq!(f1.resolution(), Resolution::new(64, 48));
+        assert_eq!(f1.source_frame_format(), FrameFormat::MJPEG);
+        // JPEG SOI marker
+        assert_eq!(&f1.buffer()[0..2], &[0xff, 0xd8]);
+        assert_ne!(f1.buffer(), f2.buffer());
+    }
+
+    #[test]
+    fn test_render_is_deterministic() {
+        let src = TestPatternSource::new(32, 24, 10).unwrap();
+        assert_eq!(src.render(5, 1000), src.render(5, 1000));
+        assert_ne!(src.render(5, 1000), src.render(6, 1000));
+        assert_eq!(src.render(0, 0).len(), 32 * 24 * 3);
+    }
+}
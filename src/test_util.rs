@@ -0,0 +1,12 @@
+// Helpers shared by tests of several modules
+
+/// JPEG of one color
+pub fn solid_jpeg(width: u32, height: u32, value: u8) -> Vec<u8> {
+    let rgb = vec![value; (width * height * 3) as usize];
+    crate::jpeg::encode_rgb(&rgb, width, height, 50).unwrap()
+}
+
+/// Path of a file or directory in the temporary directory, unique for the process
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("httpcam-{}-{}", std::process::id(), name))
+}
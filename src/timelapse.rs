@@ -0,0 +1,569 @@
+/// Timelapse videos built from the archive.
+/// One frame is taken per interval between two dates, optionally in daylight only, and the frames
+/// are written into an AVI file played with the given frame rate. Timelapses requested through
+/// the API are built by background jobs in the timelapse directory of the archive, their
+/// progress is reported by timelapse_jobs. A few jobs run at once, more ones are refused.
+use crate::archive::{self, ArchivedFrame, Playback};
+use crate::datetime::DateTime;
+use crate::schedule;
+use crate::web::{self, ApiError, ERR_BUSY, ERR_INVALID_ARGS, ERR_NOT_FOUND};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tinyjson::JsonValue;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Directory of the archive where timelapses built through the API are stored
+pub const TIMELAPSE_DIR: &str = "timelapse";
+pub const DEFAULT_INTERVAL: &str = "10m";
+pub const DEFAULT_FPS: u32 = 25;
+// Most jobs running at once
+const MAX_RUNNING: usize = 4;
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+fn invalid(msg: &str) -> Box<dyn Error> {
+    Box::new(ApiError::new(ERR_INVALID_ARGS, msg))
+}
+
+/// Parse interval: number with s, m, h or d suffix, e.g. 30s, 10m, 1h
+pub fn parse_interval(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last() {
+        Some((idx, 's')) => (&s[..idx], 1000),
+        Some((idx, 'm')) => (&s[..idx], 60 * 1000),
+        Some((idx, 'h')) => (&s[..idx], 3600 * 1000),
+        Some((idx, 'd')) => (&s[..idx], DAY_MS),
+        _ => {
+            return Err(invalid(&format!(
+                "Interval '{}' needs s, m, h or d suffix",
+                s
+            )))
+        }
+    };
+
+    match num.trim().parse::<u64>().map(|n| n.checked_mul(mult)) {
+        Ok(Some(n)) if n > 0 => Ok(n),
+        Ok(None) => Err(invalid(&format!("Interval '{}' is too long", s))),
+        _ => Err(invalid(&format!("Invalid interval '{}'", s))),
+    }
+}
+
+/// Parse time: ms since the epoch or UTC date YYYY-MM-DD[ HH:MM[:SS]]
+pub fn parse_time(s: &str) -> Result<u64> {
+    if let Ok(ms) = s.trim().parse::<u64>() {
+        return Ok(ms);
+    }
+    match s.parse::<DateTime>() {
+        Ok(dt) => Ok(dt.to_ms()),
+        Err(err) => Err(invalid(&err)),
+    }
+}
+
+/// Check if the sun is up at the time
+pub fn is_daylight(time: u64, latitude: f64, longitude: f64) -> bool {
+    let day = time / DAY_MS;
+    let sunrise = schedule::sun_event(day, latitude, longitude, true);
+    let sunset = schedule::sun_event(day, latitude, longitude, false);
+
+    match (sunrise, sunset) {
+        (Some(rise), Some(set)) if rise < set => time >= rise && time < set,
+        // Far from Greenwich sunset of the UTC day could be earlier than sunrise
+        (Some(rise), Some(set)) => time >= rise || time < set,
+        // Polar day or night, frames are kept
+        _ => true,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub from: u64,
+    pub to: u64,
+    /// Interval between frames in ms
+    pub interval: u64,
+    /// Frame rate of the video
+    pub fps: u32,
+    /// Coordinates (latitude, longitude) to take frames in daylight only
+    pub daylight: Option<(f64, f64)>,
+}
+
+fn time_arg(args: &JsonValue, name: &str) -> Result<u64> {
+    match web::optional_arg(args, name) {
+        Some(JsonValue::Number(n)) if *n >= 0.0 => Ok(*n as u64),
+        Some(JsonValue::String(s)) => parse_time(s),
+        _ => Err(invalid(&format!(
+            "Argument '{}' must be a date or time in ms",
+            name
+        ))),
+    }
+}
+
+impl Options {
+    pub fn validate(&self) -> Result<()> {
+        if self.from >= self.to {
+            return Err(invalid("Start of the timelapse must be before its end"));
+        }
+        if self.interval == 0 {
+            return Err(invalid("Interval must be positive"));
+        }
+        if self.fps == 0 || self.fps > 1000 {
+            return Err(invalid("Frame rate must be in range 1-1000"));
+        }
+        Ok(())
+    }
+
+    /// Options of the API call:
+    /// {"from": <date>, "to": <date>, "interval": "10m", "fps": 25, "daylight": true,
+    ///  "latitude": <lat>, "longitude": <lon>}
+    /// Dates are YYYY-MM-DD HH:MM UTC or ms, interval could be given in ms. Daylight frames are
+    /// selected by the coordinates of the schedule unless they are in the arguments.
+    pub fn from_json(args: &JsonValue, location: Option<(f64, f64)>) -> Result<Options> {
+        let interval = match web::optional_arg(args, "interval") {
+            Some(JsonValue::String(s)) => parse_interval(s)?,
+            Some(_) => web::number_arg(args, "interval")?.max(0.0) as u64,
+            None => parse_interval(DEFAULT_INTERVAL)?,
+        };
+        let fps = match web::optional_arg(args, "fps") {
+            Some(_) => web::number_arg(args, "fps")?.max(0.0) as u32,
+            None => DEFAULT_FPS,
+        };
+
+        let daylight = match web::optional_arg(args, "daylight") {
+            Some(JsonValue::Boolean(true)) => {
+                let location = match web::optional_arg(args, "latitude") {
+                    Some(_) => Some((
+                        web::number_arg(args, "latitude")?,
+                        web::number_arg(args, "longitude")?,
+                    )),
+                    None => location,
+                };
+                match location {
+                    Some(location) => Some(location),
+                    None => return Err(invalid("Location is required for daylight frames")),
+                }
+            }
+            Some(JsonValue::Boolean(false)) | None => None,
+            Some(_) => return Err(invalid("Argument 'daylight' must be boolean")),
+        };
+
+        let options = Options {
+            from: time_arg(args, "from")?,
+            to: time_arg(args, "to")?,
+            interval,
+            fps,
+            daylight,
+        };
+        options.validate()?;
+
+        Ok(options)
+    }
+
+    /// Default file name: timelapse-YYYYMMDD-HHMM-YYYYMMDD-HHMM.avi
+    pub fn file_name(&self) -> String {
+        let compact = |t: u64| {
+            let dt = DateTime::from_ms(t);
+            format!(
+                "{:04}{:02}{:02}-{:02}{:02}",
+                dt.year, dt.month, dt.day, dt.hour, dt.minute
+            )
+        };
+        format!("timelapse-{}-{}.avi", compact(self.from), compact(self.to))
+    }
+}
+
+/// Select the first frame of every interval, frames must be sorted by time
+pub fn select(frames: &[ArchivedFrame], options: &Options) -> Vec<ArchivedFrame> {
+    let mut res: Vec<ArchivedFrame> = vec![];
+    let mut next = options.from;
+
+    for frame in frames {
+        if frame.time < next || frame.time > options.to {
+            continue;
+        }
+        if let Some((lat, lon)) = options.daylight {
+            if !is_daylight(frame.time, lat, lon) {
+                continue;
+            }
+        }
+
+        res.push(frame.clone());
+        next = (frame.time - (frame.time - options.from) % options.interval)
+            .saturating_add(options.interval);
+    }
+
+    res
+}
+
+/// Build the timelapse, progress is called with numbers of written and selected frames,
+/// building is cancelled if it returns false. Returns number of frames in the video.
+pub fn build(
+    playback: &Playback,
+    options: &Options,
+    path: &Path,
+    progress: &mut dyn FnMut(usize, usize) -> bool,
+) -> Result<usize> {
+    options.validate()?;
+
+    let frames = select(&playback.frames(options.from, options.to)?, options);
+    if frames.is_empty() {
+        return Err(Box::new(ApiError::new(
+            ERR_NOT_FOUND,
+            "No frames in the range",
+        )));
+    }
+
+    let total = frames.len();
+    if !progress(0, total) {
+        return Err("Cancelled".into());
+    }
+    archive::write_avi_progress(&frames, path, options.fps, &mut |done| {
+        progress(done, total)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+struct Progress {
+    state: State,
+    done: usize,
+    total: usize,
+}
+
+/// Timelapse built in the background
+pub struct Job {
+    pub id: u32,
+    pub file: String,
+    // URL the file is served at when it is done
+    url: String,
+    options: Options,
+    progress: Mutex<Progress>,
+    cancel: AtomicBool,
+}
+
+impl Job {
+    pub fn state(&self) -> State {
+        self.progress.lock().unwrap().state.clone()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let progress = self.progress.lock().unwrap();
+        let mut res = HashMap::<String, JsonValue>::new();
+        let (state, error) = match progress.state {
+            State::Running => ("running", None),
+            State::Done => ("done", None),
+            State::Failed(ref err) => ("failed", Some(err.clone())),
+            State::Cancelled => ("cancelled", None),
+        };
+
+        res.insert(String::from("id"), JsonValue::Number(self.id as f64));
+        res.insert(
+            String::from("state"),
+            JsonValue::String(String::from(state)),
+        );
+        if let Some(error) = error {
+            res.insert(String::from("error"), JsonValue::String(error));
+        }
+        res.insert(
+            String::from("done"),
+            JsonValue::Number(progress.done as f64),
+        );
+        res.insert(
+            String::from("total"),
+            JsonValue::Number(progress.total as f64),
+        );
+        res.insert(String::from("file"), JsonValue::String(self.file.clone()));
+        if progress.state == State::Done {
+            res.insert(String::from("url"), JsonValue::String(self.url.clone()));
+        }
+        res.insert(
+            String::from("from"),
+            JsonValue::Number(self.options.from as f64),
+        );
+        res.insert(
+            String::from("to"),
+            JsonValue::Number(self.options.to as f64),
+        );
+
+        JsonValue::Object(res)
+    }
+}
+
+/// Check name of an AVI file served from the archive: letters, digits, '-', '_' and '.' only with .avi extension
+pub fn check_file_name(name: &str) -> Result<()> {
+    let valid = name.ends_with(".avi")
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err(invalid(&format!("Invalid timelapse file name '{}'", name)));
+    }
+    Ok(())
+}
+
+/// Background timelapse jobs of the camera, finished jobs are kept to report their results
+pub struct Jobs {
+    // Camera whose archive serves the files
+    camera: String,
+    jobs: Vec<Arc<Job>>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl Jobs {
+    pub fn new(camera: &str) -> Jobs {
+        Jobs {
+            camera: String::from(camera),
+            jobs: vec![],
+            threads: vec![],
+        }
+    }
+
+    /// Start building the timelapse into the file of the timelapse directory of the archive
+    pub fn start(
+        &mut self,
+        playback: Playback,
+        options: Options,
+        file: Option<&str>,
+    ) -> Result<Arc<Job>> {
+        let file = match file {
+            Some(file) => String::from(file),
+            None => options.file_name(),
+        };
+        check_file_name(&file)?;
+        let busy = self
+            .jobs
+            .iter()
+            .any(|j| j.file == file && j.state() == State::Running);
+        if busy {
+            return Err(invalid(&format!("Timelapse {} is being built", file)));
+        }
+        let running = self.jobs.iter().filter(|j| j.state() == State::Running);
+        if running.count() >= MAX_RUNNING {
+            return Err(Box::new(ApiError::new(
+                ERR_BUSY,
+                "Too many timelapses are being built",
+            )));
+        }
+        self.threads.retain(|t| !t.is_finished());
+
+        let dir = playback.root().join(TIMELAPSE_DIR);
+        std::fs::create_dir_all(&dir)?;
+
+        let job = Arc::new(Job {
+            id: self.jobs.len() as u32 + 1,
+            file: file.clone(),
+            url: web::archive_url(&self.camera, &format!("{}/{}", TIMELAPSE_DIR, file)),
+            options,
+            progress: Mutex::new(Progress {
+                state: State::Running,
+                done: 0,
+                total: 0,
+            }),
+            cancel: AtomicBool::new(false),
+        });
+        self.jobs.push(job.clone());
+
+        let j = job.clone();
+        self.threads.push(std::thread::spawn(move || {
+            let path: PathBuf = dir.join(&j.file);
+            let res = build(&playback, &j.options, &path, &mut |done, total| {
+                let mut p = j.progress.lock().unwrap();
+                p.done = done;
+                p.total = total;
+                !j.cancel.load(Ordering::SeqCst)
+            });
+
+            let state = match res {
+                Ok(_) => State::Done,
+                Err(_) if j.cancel.load(Ordering::SeqCst) => State::Cancelled,
+                Err(err) => State::Failed(err.to_string()),
+            };
+            if state != State::Done {
+                let _ = std::fs::remove_file(&path);
+            }
+            println!("Timelapse {}: {:?}", j.file, state);
+            j.progress.lock().unwrap().state = state;
+        }));
+
+        Ok(job)
+    }
+
+    pub fn get(&self, id: u32) -> Result<&Arc<Job>> {
+        match self.jobs.iter().find(|j| j.id == id) {
+            Some(job) => Ok(job),
+            None => Err(Box::new(ApiError::new(
+                ERR_NOT_FOUND,
+                &format!("Timelapse job {} is not found", id),
+            ))),
+        }
+    }
+
+    pub fn cancel(&self, id: u32) -> Result<()> {
+        self.get(id)?.cancel.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::Array(self.jobs.iter().map(|j| j.to_json()).collect())
+    }
+
+    /// Cancel running jobs and wait for them
+    pub fn shutdown(&mut self) {
+        for job in &self.jobs {
+            job.cancel.store(true, Ordering::SeqCst);
+        }
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                println!("Error: timelapse thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{FrameRef, Layout};
+    use crate::mjpeg::AviReader;
+
+    // 2024-06-21 00:00 UTC
+    const DAY: u64 = 1718928000000;
+    const HOUR: u64 = 3600 * 1000;
+
+    fn frames(times: &[u64]) -> Vec<ArchivedFrame> {
+        times
+            .iter()
+            .map(|t| ArchivedFrame {
+                time: *t,
+                frame: FrameRef::File(PathBuf::from(format!("frame_{}.jpg", t))),
+            })
+            .collect()
+    }
+
+    fn options(from: u64, to: u64, interval: u64) -> Options {
+        Options {
+            from,
+            to,
+            interval,
+            fps: 10,
+            daylight: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_interval("10m").unwrap(), 600_000);
+        assert_eq!(parse_interval("2h").unwrap(), 2 * HOUR);
+        assert_eq!(parse_interval("30s").unwrap(), 30_000);
+        assert!(parse_interval("10").is_err());
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("99999999999999999d").is_err());
+
+        assert_eq!(parse_time("2024-06-21").unwrap(), DAY);
+        assert_eq!(parse_time("2024-06-21 01:00").unwrap(), DAY + HOUR);
+        assert_eq!(parse_time("1000").unwrap(), 1000);
+        assert!(parse_time("June").is_err());
+
+        let args: JsonValue =
+            r#"{"from": "2024-06-21", "to": 1718935200000, "interval": "1h", "daylight": true}"#
+                .parse()
+                .unwrap();
+        assert!(Options::from_json(&args, None).is_err());
+        let o = Options::from_json(&args, Some((52.5, 13.4))).unwrap();
+        assert_eq!(o.from, DAY);
+        assert_eq!(o.to, DAY + 2 * HOUR);
+        assert_eq!((o.interval, o.fps), (HOUR, DEFAULT_FPS));
+        assert_eq!(o.daylight, Some((52.5, 13.4)));
+        assert_eq!(o.file_name(), "timelapse-20240621-0000-20240621-0200.avi");
+
+        let args: JsonValue = r#"{"from": 2000, "to": 1000}"#.parse().unwrap();
+        assert!(Options::from_json(&args, None).is_err());
+
+        assert!(check_file_name("site-2024_06.avi").is_ok());
+        assert!(check_file_name("../x.avi").is_err());
+        assert!(check_file_name("x.jpg").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let list = frames(&[1000, 1500, 1900, 2100, 3000, 3999, 6500]);
+        let times = |o: &Options| -> Vec<u64> { select(&list, o).iter().map(|f| f.time).collect() };
+
+        assert_eq!(
+            times(&options(1000, 10000, 1000)),
+            vec![1000, 2100, 3000, 6500]
+        );
+        assert_eq!(times(&options(1200, 4000, 1000)), vec![1500, 3000, 3999]);
+
+        // Berlin: the sun rises about 2:45 UTC and sets about 19:30 UTC on the 21st of June
+        let list = frames(&[DAY + HOUR, DAY + 3 * HOUR, DAY + 12 * HOUR, DAY + 20 * HOUR]);
+        let mut o = options(DAY, DAY + 24 * HOUR, HOUR);
+        o.daylight = Some((52.52, 13.405));
+        let selected: Vec<u64> = select(&list, &o).iter().map(|f| f.time).collect();
+        assert_eq!(selected, vec![DAY + 3 * HOUR, DAY + 12 * HOUR]);
+
+        // Los Angeles: sunrise about 12:40 UTC, sunset about 03:10 UTC
+        assert!(is_daylight(DAY + 2 * HOUR, 34.05, -118.25));
+        assert!(!is_daylight(DAY + 8 * HOUR, 34.05, -118.25));
+        assert!(is_daylight(DAY + 20 * HOUR, 34.05, -118.25));
+    }
+
+    #[test]
+    fn test_jobs() {
+        let root = crate::test_util::temp_path("timelapse");
+        std::fs::create_dir_all(&root).unwrap();
+        let jpeg = crate::test_util::solid_jpeg(8, 8, 50);
+        for i in 0..20 {
+            std::fs::write(root.join(format!("frame_{}.jpg", DAY + i * 30_000)), &jpeg).unwrap();
+        }
+        let playback = Playback::new(&root, &Layout::default(), "cam");
+
+        let mut jobs = Jobs::new("back");
+        let o = options(DAY, DAY + HOUR, 60_000);
+        let wait = |job: &Job| {
+            for _ in 0..1000 {
+                if job.state() != State::Running {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            job.state()
+        };
+        let job = jobs.start(playback.clone(), o, None).unwrap();
+        assert_eq!(wait(&job), State::Done);
+        let path = root.join(TIMELAPSE_DIR).join(&job.file);
+        let ar = AviReader::open(path.to_str().unwrap()).unwrap();
+        assert_eq!((ar.frame_count(), ar.fps()), (10, 10));
+
+        let json = jobs.to_json().stringify().unwrap();
+        assert!(json.contains(r#""state":"done""#));
+        assert!(json.contains(r#""total":10"#));
+        let url = format!("/cam/back/archive/{}/{}", TIMELAPSE_DIR, job.file);
+        assert!(json.contains(&url));
+
+        // Failed jobs are reported and leave no file
+        let job = jobs
+            .start(
+                playback,
+                options(DAY + HOUR, DAY + 2 * HOUR, 60_000),
+                Some("empty.avi"),
+            )
+            .unwrap();
+        assert!(matches!(wait(&job), State::Failed(_)));
+        jobs.shutdown();
+        assert!(!root.join(TIMELAPSE_DIR).join("empty.avi").exists());
+        assert!(jobs.cancel(job.id).is_ok());
+        assert!(jobs.cancel(100).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
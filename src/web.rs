@@ -1,16 +1,140 @@
 // Web interface related stuff
 
-use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tinyjson::JsonValue;
+mod access;
+mod api;
+mod camera;
 mod default_image;
+mod events;
+mod playback;
 mod static_content;
+mod tls;
+mod ws;
+
+pub use api::{MethodInfo, Param, ParamKind};
+use camera::CameraState;
+pub use camera::{archive_url, Camera};
+pub use tls::{install_reload_signal, Tls};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + 'static>>;
 
 pub struct Server {
     srv: Arc<Impl>,
     workers: Vec<std::thread::JoinHandle<()>>,
-    receiver: std::sync::mpsc::Receiver<JsonRequest>,
+    sockets: Vec<std::path::PathBuf>,
+}
+
+/// Address to listen on: host:port (IPv6 hosts in brackets: [::1]:8080), https:host:port or
+/// unix:<path>
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(String),
+    Https(String),
+    Unix(std::path::PathBuf),
+}
+
+fn parse_tcp(s: &str) -> Result<String> {
+    if s.parse::<std::net::SocketAddr>().is_ok() {
+        return Ok(String::from(s));
+    }
+    // Host names are resolved when the listener is created
+    match s.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(String::from(s)),
+        _ => Err(Box::<dyn std::error::Error>::from(format!(
+            "Invalid listen address '{}', expected host:port, [ipv6]:port, https:host:port or unix:<path>",
+            s
+        ))),
+    }
+}
+
+impl Listen {
+    pub fn parse(s: &str) -> Result<Listen> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(Box::<dyn std::error::Error>::from(
+                    "Socket path is required",
+                ));
+            }
+            return Ok(Listen::Unix(std::path::PathBuf::from(path)));
+        }
+
+        match s.strip_prefix("https:") {
+            Some(addr) => Ok(Listen::Https(parse_tcp(addr)?)),
+            None => Ok(Listen::Tcp(parse_tcp(s)?)),
+        }
+    }
+
+    fn port(&self) -> Option<u16> {
+        match self {
+            Listen::Tcp(addr) | Listen::Https(addr) => addr.rsplit_once(':')?.1.parse().ok(),
+            Listen::Unix(_) => None,
+        }
+    }
+
+    fn bind(&self) -> Result<tiny_http::Server> {
+        let srv = match self {
+            Listen::Tcp(addr) => tiny_http::Server::http(addr.as_str()),
+            Listen::Https(_) => Err(Box::from("Certificate is required for https listeners")),
+            Listen::Unix(path) => remove_socket(path).and_then(|_| bind_unix(path)),
+        };
+
+        match srv {
+            Ok(srv) => Ok(srv),
+            Err(err) => Err(Box::<dyn std::error::Error>::from(format!(
+                "Can't listen on {}: {}",
+                self, err
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "http://{}", addr),
+            Listen::Https(addr) => write!(f, "https://{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Remove socket left by previous run, any other file at the path is an error
+#[cfg(unix)]
+fn remove_socket(
+    path: &std::path::Path,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
+        Ok(_) => Err(Box::from("the path exists and is not a socket")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+#[cfg(not(unix))]
+fn remove_socket(
+    _path: &std::path::Path,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind_unix(
+    path: &std::path::Path,
+) -> std::result::Result<tiny_http::Server, Box<dyn std::error::Error + Send + Sync>> {
+    tiny_http::Server::http_unix(path)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(
+    _path: &std::path::Path,
+) -> std::result::Result<tiny_http::Server, Box<dyn std::error::Error + Send + Sync>> {
+    Err(Box::from("Unix sockets are not supported on this platform"))
 }
 
 pub struct JsonRequest {
@@ -19,6 +143,96 @@ pub struct JsonRequest {
     pub result_sender: std::sync::mpsc::Sender<tinyjson::JsonValue>,
 }
 
+// JSON API error codes, numbering follows JSON-RPC 2.0
+pub const ERR_PARSE: i32 = -32700;
+pub const ERR_INVALID_REQUEST: i32 = -32600;
+pub const ERR_INVALID_ARGS: i32 = -32602;
+pub const ERR_UNKNOWN_METHOD: i32 = -32601;
+pub const ERR_INTERNAL: i32 = -32603;
+pub const ERR_NOT_FOUND: i32 = -32001;
+pub const ERR_OUT_OF_RANGE: i32 = -32002;
+pub const ERR_READ_ONLY: i32 = -32003;
+pub const ERR_UNAUTHORIZED: i32 = -32004;
+pub const ERR_FORBIDDEN: i32 = -32005;
+/// Too many requests are processed, they are answered by 503
+pub const ERR_BUSY: i32 = -32006;
+
+/// Error returned by JSON API methods, it is reported to the client with its code
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: i32, message: &str) -> ApiError {
+        ApiError {
+            code,
+            message: String::from(message),
+        }
+    }
+
+    /// Convert any error into ApiError, errors which are not ApiError become internal errors
+    pub fn from_error(err: &(dyn std::error::Error + 'static)) -> ApiError {
+        match err.downcast_ref::<ApiError>() {
+            Some(e) => ApiError::new(e.code, &e.message),
+            None => ApiError::new(ERR_INTERNAL, &err.to_string()),
+        }
+    }
+
+    /// Convert the error into JSON object: {"code": <code>, "message": <message>}
+    pub fn to_json(&self) -> JsonValue {
+        let mut res = std::collections::HashMap::<String, JsonValue>::new();
+        res.insert(String::from("code"), JsonValue::Number(self.code as f64));
+        res.insert(
+            String::from("message"),
+            JsonValue::String(self.message.clone()),
+        );
+        JsonValue::Object(res)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "API error {}: {}", self.code, self.message)
+    }
+}
+
+/// Get optional argument of the JSON API call
+pub fn optional_arg<'a>(args: &'a JsonValue, name: &str) -> Option<&'a JsonValue> {
+    match args {
+        JsonValue::Object(obj) => match obj.get(name) {
+            Some(JsonValue::Null) => None,
+            v => v,
+        },
+        _ => None,
+    }
+}
+
+/// Get required string argument of the JSON API call
+pub fn string_arg<'a>(args: &'a JsonValue, name: &str) -> Result<&'a String> {
+    match optional_arg(args, name).and_then(|v| v.get::<String>()) {
+        Some(s) => Ok(s),
+        None => Err(Box::new(ApiError::new(
+            ERR_INVALID_ARGS,
+            &format!("String argument '{}' is required", name),
+        ))),
+    }
+}
+
+/// Get required numeric argument of the JSON API call
+pub fn number_arg(args: &JsonValue, name: &str) -> Result<f64> {
+    match optional_arg(args, name).and_then(|v| v.get::<f64>()) {
+        Some(v) => Ok(*v),
+        None => Err(Box::new(ApiError::new(
+            ERR_INVALID_ARGS,
+            &format!("Numeric argument '{}' is required", name),
+        ))),
+    }
+}
+
 struct ResponseInfo {
     result: Vec<u8>,
     content_type: String,
@@ -28,16 +242,151 @@ struct ResponseInfo {
 pub type APICallback =
     Box<dyn Fn(&tinyjson::JsonValue) -> Result<tinyjson::JsonValue> + Send + Sync + 'static>;
 
+// Boundary between frames of MJPEG stream
+const STREAM_BOUNDARY: &str = "httpcamframe";
+// Most clients served by their own threads at once, the others get 503
+const MAX_CLIENT_THREADS: usize = 100;
+// Frame rates which clients may request for their streams
+const STREAM_FPS: std::ops::RangeInclusive<f64> = 0.01..=1000.0;
+
+/// Last frame with its sequence number, streams use the number to skip frames they have sent
+#[derive(Clone)]
+struct Frame {
+    seq: u64,
+    data: Arc<Vec<u8>>,
+}
+
+/// Threads serving clients besides the workers: streams and clients waiting for events
+struct ClientThreads {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Thread of a client, it is counted until it is dropped
+struct ClientThread(Arc<AtomicUsize>);
+
+impl ClientThreads {
+    fn new(max: usize) -> ClientThreads {
+        ClientThreads {
+            count: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Count a new thread, None if there are too many of them
+    fn start(&self) -> Option<ClientThread> {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ClientThread(Arc::clone(&self.count)))
+    }
+}
+
+impl Drop for ClientThread {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 struct Impl {
-    srv: Arc<tiny_http::Server>,
+    // Requests accepted from all listeners
+    requests: Mutex<std::sync::mpsc::Receiver<tiny_http::Request>>,
     lock: Mutex<bool>,
-    last_image: Mutex<Vec<u8>>,
+    cameras: RwLock<Vec<Arc<camera::CameraState>>>,
+    log: Mutex<Option<crate::eventlog::EventLog>>,
+    auth: crate::auth::Auth,
+    tls: Mutex<Option<tls::TlsState>>,
+    // Port plain HTTP requests are redirected to
+    https_port: Option<u16>,
+    client_threads: ClientThreads,
+}
+
+/// Get value of query parameter from URL
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = url.split_once('?')?;
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some((n, v)) if n == name => return Some(v),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Interval between frames of a stream limited by the fps query parameter, None without it
+fn stream_interval(url: &str) -> std::result::Result<Option<Duration>, String> {
+    match query_param(url, "fps").map(|fps| fps.parse::<f64>()) {
+        None => Ok(None),
+        Some(Ok(fps)) if STREAM_FPS.contains(&fps) => Ok(Some(Duration::from_secs_f64(1.0 / fps))),
+        Some(_) => Err(format!(
+            "fps must be in range {}-{}",
+            STREAM_FPS.start(),
+            STREAM_FPS.end()
+        )),
+    }
+}
+
+fn write_stream_header(out: &mut dyn Write) -> Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache, no-store\r\n\
+         Pragma: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        STREAM_BOUNDARY
+    )?;
+    Ok(())
+}
+
+fn write_stream_frame(out: &mut dyn Write, data: &[u8]) -> Result<()> {
+    write!(
+        out,
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        STREAM_BOUNDARY,
+        data.len()
+    )?;
+    out.write_all(data)?;
+    out.write_all(b"\r\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn client_address(req: &tiny_http::Request) -> String {
+    match req.remote_addr() {
+        Some(addr) => addr.to_string(),
+        None => String::from("unix"),
+    }
 }
 
 fn header(t: &str, v: &str) -> tiny_http::Header {
     tiny_http::Header::from_bytes(t.as_bytes(), v.as_bytes()).unwrap()
 }
 
+fn header_value(req: &tiny_http::Request, name: &'static str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| String::from(h.value.as_str()))
+}
+
+/// Browsers send cached credentials with requests of any page, so requests and WebSocket
+/// upgrades of pages of other sites are rejected: the host of Origin must be the requested Host.
+/// Clients which aren't browsers don't send Origin.
+fn same_origin(origin: Option<&str>, host: Option<&str>) -> bool {
+    let origin = match origin {
+        Some(origin) => origin.trim(),
+        None => return true,
+    };
+    let origin_host = match origin.split_once("://") {
+        Some((_, rest)) => rest.trim_end_matches('/'),
+        None => return false,
+    };
+    host.is_some_and(|host| origin_host.eq_ignore_ascii_case(host.trim()))
+}
+
 impl ResponseInfo {
     fn new(status: i32, content_type: &str, result: Vec<u8>) -> ResponseInfo {
         ResponseInfo {
@@ -57,84 +406,214 @@ impl ResponseInfo {
 }
 
 impl Impl {
-    fn worker(&self, sender: Box<std::sync::mpsc::Sender<JsonRequest>>) {
-        loop {
-            {
-                let stop = self.lock.lock().unwrap();
-                if *stop {
-                    break;
+    fn stopped(&self) -> bool {
+        *self.lock.lock().unwrap()
+    }
+
+    /// Response to clients which would get their own threads when there are too many of them
+    fn busy(&self, req: tiny_http::Request) {
+        let res = ResponseInfo::from_string(503, "text/plain", "Too many clients");
+        self.respond(req, Ok(res));
+    }
+
+    /// Log connection or disconnection of a streaming client
+    fn log_client(&self, state: &str, address: &str, url: &str) {
+        if let Some(ref log) = *self.log.lock().unwrap() {
+            log.log(
+                "client",
+                crate::eventlog::data(&[
+                    ("state", JsonValue::String(String::from(state))),
+                    ("address", JsonValue::String(String::from(address))),
+                    ("url", JsonValue::String(String::from(url))),
+                ]),
+            );
+        }
+    }
+
+    /// Serve MJPEG stream in its own thread, so the stream doesn't occupy a worker.
+    /// Optional query parameter fps limits the frame rate for this client, invalid rates get 400.
+    fn start_stream(self: &Arc<Self>, req: tiny_http::Request, cam: Arc<CameraState>) {
+        let interval = match stream_interval(req.url()) {
+            Ok(interval) => interval,
+            Err(err) => {
+                let res = ResponseInfo::from_string(400, "text/plain", &err);
+                return self.respond(req, Ok(res));
+            }
+        };
+        let thread = match self.client_threads.start() {
+            Some(thread) => thread,
+            None => return self.busy(req),
+        };
+        let imp = Arc::clone(self);
+        let address = client_address(&req);
+        let url = String::from(req.url());
+        self.log_client("connected", &address, &url);
+
+        std::thread::spawn(move || {
+            let mut writer = req.into_writer();
+            match imp.stream(&mut writer, &cam, interval) {
+                Ok(()) => (),
+                Err(err) => println!("Stream closed: {}", err),
+            }
+            imp.log_client("disconnected", &address, &url);
+            drop(thread);
+        });
+    }
+
+    fn stream(
+        &self,
+        out: &mut dyn Write,
+        cam: &CameraState,
+        interval: Option<Duration>,
+    ) -> Result<()> {
+        write_stream_header(out)?;
+
+        let mut last: Option<u64> = None;
+        let mut next = Instant::now();
+        while !self.stopped() {
+            let now = Instant::now();
+            if now < next {
+                std::thread::sleep(next - now);
+            }
+
+            // Only the latest frame is sent, so frames are dropped for slow clients
+            let img = match cam.wait_image(last, Duration::from_secs(1)) {
+                Some(img) => img,
+                None => continue,
+            };
+            last = Some(img.seq);
+            write_stream_frame(out, &img.data)?;
+
+            if let Some(interval) = interval {
+                next = std::cmp::max(next + interval, Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accept connections on one listener and pass requests to the workers
+    fn accept(
+        &self,
+        listen: Listen,
+        listener: tiny_http::Server,
+        generation: u64,
+        requests: std::sync::mpsc::Sender<tiny_http::Request>,
+    ) {
+        let redirect = match listen {
+            Listen::Tcp(_) => self.https_port.is_some(),
+            _ => false,
+        };
+        let (mut listener, mut generation) = (listener, generation);
+        while !self.stopped() {
+            if let Listen::Https(_) = listen {
+                match self.rebind(&listen, listener, generation) {
+                    Some(res) => (listener, generation) = res,
+                    None => break,
+                }
+            }
+
+            match listener.recv_timeout(Duration::new(1, 0)) {
+                Ok(Some(req)) if redirect => self.redirect_https(req),
+                Ok(Some(req)) => {
+                    if requests.send(req).is_err() {
+                        break;
+                    }
                 }
+                Ok(None) => (),
+                Err(err) => println!("Error: {}", err),
             }
+        }
+    }
+
+    fn next_request(&self) -> Option<tiny_http::Request> {
+        let requests = self.requests.lock().unwrap();
+        requests.recv_timeout(Duration::new(1, 0)).ok()
+    }
 
-            let raw_req = self.srv.recv_timeout(core::time::Duration::new(1, 0));
-            match raw_req {
-                Ok(req) => match req {
-                    Some(mut req) => match self.process_request(&mut req, &sender) {
-                        Ok(content) => {
-                            let mut response = tiny_http::Response::from_data(content.result);
-                            response.add_header(header("content-type", &content.content_type));
-                            match req.respond(response.with_status_code(content.status)) {
-                                Ok(_) => (),
-                                Err(err) => println!("Error: {}", err),
-                            }
-                        }
+    fn worker(self: Arc<Self>) {
+        while !self.stopped() {
+            if let Some(req) = self.next_request() {
+                let camera = self.camera(req.url());
+                match self.authorize(&req, camera.as_ref()) {
+                    Ok(role) => self.route(req, role, camera),
+                    Err(response) => match req.respond(response) {
+                        Ok(_) => (),
                         Err(err) => println!("Error: {}", err),
                     },
-                    None => (),
-                },
-                Err(err) => println!("Error: {}", err),
+                }
+            }
+        }
+    }
+
+    fn route(
+        self: &Arc<Self>,
+        mut req: tiny_http::Request,
+        role: Option<crate::auth::Role>,
+        camera: Option<(Arc<CameraState>, String)>,
+    ) {
+        let (cam, url) = match camera {
+            _ if req.url() == "/api/cameras" => (None, String::from(req.url())),
+            Some((cam, url)) => (Some(cam), url),
+            None => return self.camera_not_found(req),
+        };
+
+        match (cam, url.as_str()) {
+            (None, _) => self.respond(req, Ok(self.list_cameras())),
+            (Some(cam), url) if url.starts_with("/stream.mjpg") => self.start_stream(req, cam),
+            (Some(cam), url) if url.starts_with("/archive/") => {
+                self.archive_request(req, &cam, url)
+            }
+            (Some(cam), url) if url.starts_with("/api/events") => self.events_request(req, &cam.id),
+            (Some(cam), url) if url == "/events" || url.starts_with("/events?") => {
+                self.start_event_stream(req, &cam.id)
+            }
+            (Some(cam), url) if url == "/ws" || url.starts_with("/ws?") => {
+                self.start_websocket(req, role, cam)
+            }
+            (Some(cam), url) => {
+                let res = self.process_request(&mut req, role, &cam, url);
+                self.respond(req, res)
             }
         }
     }
 
+    fn respond(&self, req: tiny_http::Request, res: Result<ResponseInfo>) {
+        match res {
+            Ok(content) => {
+                let mut response = tiny_http::Response::from_data(content.result);
+                response.add_header(header("content-type", &content.content_type));
+                match req.respond(response.with_status_code(content.status)) {
+                    Ok(_) => (),
+                    Err(err) => println!("Error: {}", err),
+                }
+            }
+            Err(err) => println!("Error: {}", err),
+        }
+    }
+
     fn process_request(
         &self,
         req: &mut tiny_http::Request,
-        sender: &std::sync::mpsc::Sender<JsonRequest>,
+        role: Option<crate::auth::Role>,
+        cam: &CameraState,
+        url: &str,
     ) -> Result<ResponseInfo> {
-        let url = if req.url() == "/" {
+        let url = if url == "/" {
             String::from("/index.html")
         } else {
-            String::from(req.url())
+            String::from(url)
         };
 
         println!("{} {}", req.method(), req.url());
 
         if url.starts_with("/image.jpg") {
             {
-                let img = self.last_image.lock().unwrap();
-                return Ok(ResponseInfo::new(
-                    200,
-                    "image/jpeg",
-                    Vec::<u8>::from(&**img),
-                ));
+                let img = cam.image();
+                return Ok(ResponseInfo::new(200, "image/jpeg", img.data.to_vec()));
             }
-        } else if url.starts_with("/api/") {
-            let method = url[5..url.len()].to_string();
-
-            let req = if *req.method() == tiny_http::Method::Post {
-                let mut content = String::new();
-                req.as_reader().read_to_string(&mut content)?;
-                let r: JsonValue = content.parse()?;
-                r
-            } else {
-                JsonValue::Null {}
-            };
-
-            let (snd, rcv) = std::sync::mpsc::channel::<JsonValue>();
-            sender.send(JsonRequest {
-                method: method,
-                result_sender: snd,
-                args: req,
-            })?;
-
-            let resp = rcv.recv()?;
-
-            return Ok(ResponseInfo::from_string(
-                200,
-                "application/json",
-                &resp.stringify()?,
-            ));
+        } else if url == "/api" || url.starts_with("/api/") || url.starts_with("/api?") {
+            return self.api_request(req, role, cam, &url);
         } else {
             let content = static_content::get_file_content(&url);
             match content {
@@ -153,49 +632,82 @@ impl Impl {
     }
 }
 
-fn start_impl_thread(
-    srv: Arc<Impl>,
-    sender: &std::sync::mpsc::Sender<JsonRequest>,
-) -> std::thread::JoinHandle<()> {
-    let s = Box::new(sender.clone());
-    std::thread::spawn(move || srv.worker(s))
-}
-
 impl Server {
-    pub fn new(addr: &str) -> Result<Server> {
-        let srv = tiny_http::Server::http(addr);
-        let (sender, receiver) = std::sync::mpsc::channel::<JsonRequest>();
+    /// Start the server listening on all addresses, requests are handled by the pool of workers.
+    /// The certificate is required for https listeners. Requests are authenticated by auth from
+    /// the first one.
+    pub fn new(
+        listen: &[Listen],
+        workers: usize,
+        tls: Option<Tls>,
+        auth: crate::auth::Auth,
+    ) -> Result<Server> {
+        let https_port = listen.iter().find_map(|l| match l {
+            Listen::Https(_) => l.port(),
+            _ => None,
+        });
+        let tls = match tls {
+            Some(tls) => Some(tls::TlsState::new(tls)?),
+            None => None,
+        };
+        let redirect = tls.as_ref().is_some_and(|t| t.redirect());
 
-        match srv {
-            Ok(srv) => {
-                let imp = Arc::new(Impl {
-                    lock: Mutex::new(false),
-                    srv: Arc::new(srv),
-                    last_image: Mutex::new(Vec::<u8>::from(default_image::DEFAULT_IMAGE)),
-                });
-                let mut workers: Vec<std::thread::JoinHandle<()>> = vec![];
-
-                for _ in [0..4] {
-                    let r = Arc::clone(&imp);
-                    let worker = start_impl_thread(r, &sender);
-                    workers.push(worker);
-                }
+        let (req_sender, req_receiver) = std::sync::mpsc::channel::<tiny_http::Request>();
+        let imp = Arc::new(Impl {
+            requests: Mutex::new(req_receiver),
+            lock: Mutex::new(false),
+            cameras: RwLock::new(vec![]),
+            log: Mutex::new(None),
+            auth,
+            tls: Mutex::new(tls),
+            https_port: https_port.filter(|_| redirect),
+            client_threads: ClientThreads::new(MAX_CLIENT_THREADS),
+        });
 
-                Ok(Server {
-                    srv: imp,
-                    workers: workers,
-                    receiver: receiver,
-                })
-            }
-            Err(err) => return Err(err),
+        let mut listeners: Vec<(Listen, tiny_http::Server, u64)> = vec![];
+        for addr in listen {
+            let (listener, generation) = imp.bind(addr)?;
+            listeners.push((addr.clone(), listener, generation));
+            println!("Listening on {}", addr);
         }
+
+        let mut threads: Vec<std::thread::JoinHandle<()>> = vec![];
+        for (addr, listener, generation) in listeners {
+            let r = Arc::clone(&imp);
+            let s = req_sender.clone();
+            threads.push(std::thread::spawn(move || {
+                r.accept(addr, listener, generation, s)
+            }));
+        }
+
+        for _ in 0..workers {
+            let r = Arc::clone(&imp);
+            threads.push(std::thread::spawn(move || r.worker()));
+        }
+
+        let sockets = listen
+            .iter()
+            .filter_map(|l| match l {
+                Listen::Unix(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Server {
+            srv: imp,
+            workers: threads,
+            sockets,
+        })
     }
 
+    /// Stop the server, cameras have to be dropped before, so workers waiting for their API
+    /// responses get an error instead of waiting forever
     pub fn destroy(self) {
         {
             let mut stop = self.srv.lock.lock().unwrap();
             *stop = true;
         }
+        self.srv.wake_cameras();
 
         for th in self.workers {
             match th.join() {
@@ -203,24 +715,126 @@ impl Server {
                 Err(_err) => println!("Error: can't join thread"),
             }
         }
-    }
 
-    pub fn update_image(&self, data: &[u8]) -> Result<()> {
-        {
-            let mut img = self.srv.last_image.lock().unwrap();
-            *img = Vec::<u8>::from(data);
+        for path in self.sockets {
+            let _ = std::fs::remove_file(path);
         }
+    }
 
-        Ok(())
+    /// Add the camera served at /cam/<id>/, the first camera is served at / as well
+    pub fn add_camera(&self, id: &str) -> Result<Camera> {
+        self.srv.add_camera(id, &default_image::DEFAULT_IMAGE)
     }
 
-    pub fn json_request(&self) -> Option<JsonRequest> {
-        let res = self
-            .receiver
-            .recv_timeout(core::time::Duration::from_millis(10));
-        match res {
-            Ok(res) => Some(res),
-            Err(_) => None,
-        }
+    /// Serve the log on /api/events and log connections of streaming clients into it, cameras
+    /// serve their entries
+    pub fn set_event_log(&self, log: crate::eventlog::EventLog) {
+        *self.srv.log.lock().unwrap() = Some(log);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen() {
+        assert_eq!(
+            Listen::parse("0.0.0.0:8080").unwrap(),
+            Listen::Tcp(String::from("0.0.0.0:8080"))
+        );
+        assert_eq!(
+            Listen::parse("[::1]:8080").unwrap(),
+            Listen::Tcp(String::from("[::1]:8080"))
+        );
+        assert_eq!(
+            Listen::parse("localhost:8081").unwrap(),
+            Listen::Tcp(String::from("localhost:8081"))
+        );
+        assert_eq!(
+            Listen::parse("https:0.0.0.0:8443").unwrap(),
+            Listen::Https(String::from("0.0.0.0:8443"))
+        );
+        assert_eq!(Listen::parse("https:[::1]:443").unwrap().port(), Some(443));
+        assert_eq!(
+            Listen::parse("unix:/run/httpcam.sock").unwrap(),
+            Listen::Unix(std::path::PathBuf::from("/run/httpcam.sock"))
+        );
+        assert!(Listen::parse("8080").is_err());
+        assert!(Listen::parse("::1:x").is_err());
+        assert!(Listen::parse("unix:").is_err());
+        assert!(Listen::parse("https:8443").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix() {
+        let dir = crate::test_util::temp_path("listen");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        std::fs::write(&path, "{}").unwrap();
+
+        // Only sockets are replaced
+        let listen = Listen::Unix(path.clone());
+        assert!(listen.bind().is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+
+        let socket = Listen::Unix(dir.join("httpcam.sock"));
+        drop(socket.bind().unwrap());
+        drop(socket.bind().unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("/stream.mjpg?fps=5", "fps"), Some("5"));
+        assert_eq!(
+            query_param("/stream.mjpg?a=1&fps=2.5&b", "fps"),
+            Some("2.5")
+        );
+        assert_eq!(query_param("/stream.mjpg?fpsx=1", "fps"), None);
+        assert_eq!(query_param("/stream.mjpg", "fps"), None);
+    }
+
+    #[test]
+    fn test_stream_interval() {
+        assert_eq!(stream_interval("/stream.mjpg"), Ok(None));
+        assert_eq!(
+            stream_interval("/stream.mjpg?fps=4"),
+            Ok(Some(Duration::from_millis(250)))
+        );
+        assert!(stream_interval("/stream.mjpg?fps=1e-30").is_err());
+        assert!(stream_interval("/stream.mjpg?fps=0").is_err());
+        assert!(stream_interval("/stream.mjpg?fps=1e9").is_err());
+        assert!(stream_interval("/stream.mjpg?fps=NaN").is_err());
+        assert!(stream_interval("/stream.mjpg?fps=fast").is_err());
+    }
+
+    #[test]
+    fn test_same_origin() {
+        let host = Some("cam.local:8080");
+        assert!(same_origin(None, host));
+        assert!(same_origin(Some("http://cam.local:8080"), host));
+        assert!(same_origin(Some("https://CAM.local:8080/"), host));
+        // Pages of other sites, other ports and sandboxed pages are rejected
+        assert!(!same_origin(Some("https://evil.example"), host));
+        assert!(!same_origin(Some("http://cam.local"), host));
+        assert!(!same_origin(
+            Some("http://cam.local:8080.evil.example"),
+            host
+        ));
+        assert!(!same_origin(Some("null"), host));
+        assert!(!same_origin(Some("http://cam.local:8080"), None));
+    }
+
+    #[test]
+    fn test_client_threads() {
+        let threads = ClientThreads::new(2);
+        let first = threads.start().unwrap();
+        let _second = threads.start().unwrap();
+        assert!(threads.start().is_none());
+        drop(first);
+        assert!(threads.start().is_some());
     }
 }
@@ -0,0 +1,100 @@
+// Access control: while users are configured every request needs credentials of a user or
+// an API token with the role required for its URL. Missing or wrong credentials get 401 with
+// Basic challenge, so browsers ask for login, insufficient role gets 403. API clients get the
+// usual {"error": ...} object with the status.
+
+use super::{client_address, header, ApiError, CameraState, Impl, ERR_FORBIDDEN, ERR_UNAUTHORIZED};
+use crate::auth::{Identity, Role};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tinyjson::JsonValue;
+
+type Response = tiny_http::Response<std::io::Cursor<Vec<u8>>>;
+
+const CHALLENGE: &str = "Basic realm=\"httpcam\", charset=\"UTF-8\"";
+
+fn denied(url: &str, status: u16, code: i32, message: &str) -> Response {
+    let response = if url == "/api" || url.starts_with("/api/") {
+        let mut obj = HashMap::<String, JsonValue>::new();
+        obj.insert(
+            String::from("error"),
+            ApiError::new(code, message).to_json(),
+        );
+        let content = JsonValue::Object(obj).stringify().unwrap_or_default();
+        tiny_http::Response::from_string(content)
+            .with_header(header("content-type", "application/json"))
+    } else {
+        tiny_http::Response::from_string(message).with_header(header("content-type", "text/plain"))
+    };
+    response.with_status_code(status)
+}
+
+/// Address wrong passwords lock user names for: IP of the client without the port, all clients
+/// of the Unix socket (a reverse proxy) share one
+fn client_ip(req: &tiny_http::Request) -> String {
+    match req.remote_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => String::from("unix"),
+    }
+}
+
+impl Impl {
+    /// Check credentials of the request to the camera route, returns the role of the client
+    /// (None if authentication is disabled) or the response for denied requests
+    pub(super) fn authorize(
+        &self,
+        req: &tiny_http::Request,
+        camera: Option<&(Arc<CameraState>, String)>,
+    ) -> std::result::Result<Option<Role>, Response> {
+        let authorization = req
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str());
+
+        if !self.auth.enabled() {
+            return Ok(None);
+        }
+        let identity = authorization.and_then(|a| self.auth.authenticate(a, &client_ip(req)));
+        let (required, url) = match camera {
+            Some((cam, url)) => (cam.methods.read().unwrap().required_role(url), url.as_str()),
+            None => (Role::Viewer, req.url()),
+        };
+
+        match identity {
+            None => {
+                // Browsers try without credentials first, only failed logins are logged
+                if authorization.is_some() {
+                    self.log_access("unauthorized", req, None);
+                }
+                let response = denied(url, 401, ERR_UNAUTHORIZED, "Authentication required");
+                Err(response.with_header(header("WWW-Authenticate", CHALLENGE)))
+            }
+            Some(ref identity) if identity.role < required => {
+                self.log_access("forbidden", req, Some(identity));
+                let message = format!("{} role is required", required.name());
+                Err(denied(url, 403, ERR_FORBIDDEN, &message))
+            }
+            Some(identity) => Ok(Some(identity.role)),
+        }
+    }
+
+    fn log_access(&self, state: &str, req: &tiny_http::Request, identity: Option<&Identity>) {
+        println!("Access {}: {} {}", state, req.method(), req.url());
+        if let Some(ref log) = *self.log.lock().unwrap() {
+            let user = match identity {
+                Some(identity) => JsonValue::String(identity.name.clone()),
+                None => JsonValue::Null,
+            };
+            log.log(
+                "access",
+                crate::eventlog::data(&[
+                    ("state", JsonValue::String(String::from(state))),
+                    ("address", JsonValue::String(client_address(req))),
+                    ("url", JsonValue::String(String::from(req.url()))),
+                    ("user", user),
+                ]),
+            );
+        }
+    }
+}
@@ -0,0 +1,603 @@
+// JSON API methods: subsystems register them with a description, arguments and the role
+// required to call them. Methods are called by /api/<method> (arguments are the posted object,
+// errors are {"error": {"code": ..., "message": ...}}, with 503 status if the server is busy,
+// methods above the viewer role have to be posted, so links and images can't call them), by
+// JSON-RPC 2.0 requests posted to /api and by WebSocket messages. Handlers which don't need the
+// capture loop run on web workers, calls of other methods are passed to the loop by
+// Server::json_request.
+// list_methods returns descriptions of all methods.
+
+use super::{
+    header_value, optional_arg, same_origin, APICallback, ApiError, CameraState, Impl, JsonRequest,
+    ResponseInfo, Result, ERR_BUSY, ERR_FORBIDDEN, ERR_INTERNAL, ERR_INVALID_ARGS,
+    ERR_INVALID_REQUEST, ERR_PARSE, ERR_UNKNOWN_METHOD,
+};
+use crate::auth::Role;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tinyjson::JsonValue;
+
+const LIST_METHODS: &str = "list_methods";
+
+/// Type of method argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamKind {
+    String,
+    Number,
+    Boolean,
+    Object,
+    Array,
+    Any,
+}
+
+impl ParamKind {
+    fn matches(self, value: &JsonValue) -> bool {
+        matches!(
+            (self, value),
+            (ParamKind::String, JsonValue::String(_))
+                | (ParamKind::Number, JsonValue::Number(_))
+                | (ParamKind::Boolean, JsonValue::Boolean(_))
+                | (ParamKind::Object, JsonValue::Object(_))
+                | (ParamKind::Array, JsonValue::Array(_))
+                | (ParamKind::Any, _)
+        )
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ParamKind::String => "string",
+            ParamKind::Number => "number",
+            ParamKind::Boolean => "boolean",
+            ParamKind::Object => "object",
+            ParamKind::Array => "array",
+            ParamKind::Any => "any",
+        }
+    }
+}
+
+/// Argument of a method, arguments are members of the object passed to the method
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+impl Param {
+    pub const fn required(name: &'static str, kind: ParamKind, description: &'static str) -> Param {
+        Param {
+            name,
+            kind,
+            required: true,
+            description,
+        }
+    }
+
+    pub const fn optional(name: &'static str, kind: ParamKind, description: &'static str) -> Param {
+        Param {
+            name,
+            kind,
+            required: false,
+            description,
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        object(vec![
+            ("name", JsonValue::String(String::from(self.name))),
+            ("type", JsonValue::String(String::from(self.kind.name()))),
+            ("required", JsonValue::Boolean(self.required)),
+            (
+                "description",
+                JsonValue::String(String::from(self.description)),
+            ),
+        ])
+    }
+}
+
+/// Description of a method
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Role required when authentication is enabled
+    pub role: Role,
+    pub params: &'static [Param],
+}
+
+impl MethodInfo {
+    /// Check types of the given arguments and presence of the required ones, other members of
+    /// the arguments are left to the handler
+    fn check_args(&self, args: &JsonValue) -> Result<()> {
+        for param in self.params {
+            match optional_arg(args, param.name) {
+                Some(value) if !param.kind.matches(value) => {
+                    return Err(invalid(&format!(
+                        "Argument '{}' must be of type {}",
+                        param.name,
+                        param.kind.name()
+                    )))
+                }
+                None if param.required => {
+                    return Err(invalid(&format!("Argument '{}' is required", param.name)))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> JsonValue {
+        object(vec![
+            ("name", JsonValue::String(String::from(self.name))),
+            (
+                "description",
+                JsonValue::String(String::from(self.description)),
+            ),
+            ("role", JsonValue::String(String::from(self.role.name()))),
+            (
+                "params",
+                JsonValue::Array(self.params.iter().map(|p| p.to_json()).collect()),
+            ),
+        ])
+    }
+}
+
+struct Method {
+    info: MethodInfo,
+    // None for methods of the capture loop
+    handler: Option<Arc<APICallback>>,
+}
+
+pub(super) struct Registry {
+    methods: BTreeMap<&'static str, Method>,
+}
+
+impl Registry {
+    pub(super) fn new() -> Registry {
+        let mut registry = Registry {
+            methods: BTreeMap::new(),
+        };
+        registry.register(
+            MethodInfo {
+                name: LIST_METHODS,
+                description: "Descriptions of the API methods",
+                role: Role::Viewer,
+                params: &[],
+            },
+            None,
+        );
+        registry
+    }
+
+    /// Add the method or replace the one with the same name
+    pub(super) fn register(&mut self, info: MethodInfo, handler: Option<APICallback>) {
+        self.methods.insert(
+            info.name,
+            Method {
+                info,
+                handler: handler.map(Arc::new),
+            },
+        );
+    }
+
+    /// Role required for the URL: /api/<method> require the role of the method, other URLs
+    /// and unknown methods the viewer role
+    pub(super) fn required_role(&self, url: &str) -> Role {
+        let path = url.split('?').next().unwrap_or(url);
+        path.strip_prefix("/api/")
+            .and_then(|method| self.methods.get(method))
+            .map_or(Role::Viewer, |m| m.info.role)
+    }
+
+    fn to_json(&self) -> JsonValue {
+        JsonValue::Array(self.methods.values().map(|m| m.info.to_json()).collect())
+    }
+}
+
+fn object(items: Vec<(&str, JsonValue)>) -> JsonValue {
+    let obj: HashMap<String, JsonValue> = items
+        .into_iter()
+        .map(|(k, v)| (String::from(k), v))
+        .collect();
+    JsonValue::Object(obj)
+}
+
+fn invalid(message: &str) -> Box<dyn std::error::Error> {
+    Box::new(ApiError::new(ERR_INVALID_ARGS, message))
+}
+
+/// Result of the capture loop, which reports errors as {"error": {"code": ..., "message": ...}}
+fn loop_result(res: JsonValue) -> Result<JsonValue> {
+    let error = match res {
+        JsonValue::Object(ref obj) if obj.len() == 1 => match obj.get("error") {
+            Some(error) => error,
+            None => return Ok(res),
+        },
+        _ => return Ok(res),
+    };
+    let code = match optional_arg(error, "code") {
+        Some(JsonValue::Number(code)) => *code as i32,
+        _ => ERR_INTERNAL,
+    };
+    let message = match optional_arg(error, "message") {
+        Some(JsonValue::String(message)) => message.as_str(),
+        _ => "",
+    };
+    Err(Box::new(ApiError::new(code, message)))
+}
+
+/// Object of /api/<method> calls: the result or {"error": ...}
+fn api_response(res: Result<JsonValue>) -> JsonValue {
+    match res {
+        Ok(res) => res,
+        Err(err) => object(vec![(
+            "error",
+            ApiError::from_error(err.as_ref()).to_json(),
+        )]),
+    }
+}
+
+/// JSON-RPC 2.0 response
+fn rpc_response(id: JsonValue, res: Result<JsonValue>) -> JsonValue {
+    let version = ("jsonrpc", JsonValue::String(String::from("2.0")));
+    match res {
+        Ok(result) => object(vec![version, ("id", id), ("result", result)]),
+        Err(err) => object(vec![
+            version,
+            ("id", id),
+            ("error", ApiError::from_error(err.as_ref()).to_json()),
+        ]),
+    }
+}
+
+impl Impl {
+    /// Call the method of the camera for a client with the role (None if authentication is
+    /// disabled)
+    pub(super) fn call(
+        &self,
+        cam: &CameraState,
+        role: Option<Role>,
+        method: &str,
+        args: JsonValue,
+    ) -> Result<JsonValue> {
+        let handler = {
+            let methods = cam.methods.read().unwrap();
+            let m = match methods.methods.get(method) {
+                Some(m) => m,
+                None => {
+                    return Err(Box::new(ApiError::new(
+                        ERR_UNKNOWN_METHOD,
+                        &format!("Unknown method '{}'", method),
+                    )))
+                }
+            };
+            if role.is_some_and(|role| role < m.info.role) {
+                return Err(Box::new(ApiError::new(
+                    ERR_FORBIDDEN,
+                    &format!("{} role is required", m.info.role.name()),
+                )));
+            }
+            m.info.check_args(&args)?;
+            if method == LIST_METHODS {
+                return Ok(methods.to_json());
+            }
+            m.handler.clone()
+        };
+
+        match handler {
+            Some(handler) => handler(&args),
+            None => {
+                let (snd, rcv) = std::sync::mpsc::channel::<JsonValue>();
+                cam.sender.send(JsonRequest {
+                    method: String::from(method),
+                    args,
+                    result_sender: snd,
+                })?;
+                loop_result(rcv.recv()?)
+            }
+        }
+    }
+
+    /// Call of JSON-RPC 2.0 request, returns None for notifications. Invalid requests aren't
+    /// notifications, they get the error with null id if they have no id.
+    fn rpc_call(
+        &self,
+        cam: &CameraState,
+        role: Option<Role>,
+        request: &JsonValue,
+    ) -> Option<JsonValue> {
+        let obj = match request {
+            JsonValue::Object(obj) => obj,
+            _ => {
+                let err = ApiError::new(ERR_INVALID_REQUEST, "Request must be an object");
+                return Some(rpc_response(JsonValue::Null, Err(Box::new(err))));
+            }
+        };
+        let id = obj.get("id").cloned();
+
+        match (obj.get("jsonrpc"), obj.get("method")) {
+            (Some(JsonValue::String(version)), Some(JsonValue::String(method)))
+                if version == "2.0" =>
+            {
+                let res = match obj.get("params") {
+                    None => self.call(cam, role, method, JsonValue::Object(HashMap::new())),
+                    Some(params @ JsonValue::Object(_)) => {
+                        self.call(cam, role, method, params.clone())
+                    }
+                    // Methods get arrays as they are, set_control accepts the list of controls
+                    Some(params @ JsonValue::Array(_)) => {
+                        self.call(cam, role, method, params.clone())
+                    }
+                    Some(_) => Err(invalid("Params must be an object or an array")),
+                };
+                id.map(|id| rpc_response(id, res))
+            }
+            _ => {
+                let err = ApiError::new(
+                    ERR_INVALID_REQUEST,
+                    "Expected {\"jsonrpc\": \"2.0\", \"method\": <method>, \"params\": ..., \"id\": <id>}",
+                );
+                Some(rpc_response(
+                    id.unwrap_or(JsonValue::Null),
+                    Err(Box::new(err)),
+                ))
+            }
+        }
+    }
+
+    /// Serve /api/<method> and JSON-RPC 2.0 requests posted to /api, single or in a batch, the
+    /// URL is the one of the camera route. Requests of pages of other sites get 403.
+    pub(super) fn api_request(
+        &self,
+        req: &mut tiny_http::Request,
+        role: Option<Role>,
+        cam: &CameraState,
+        url: &str,
+    ) -> Result<ResponseInfo> {
+        let origin = header_value(req, "Origin");
+        if !same_origin(origin.as_deref(), header_value(req, "Host").as_deref()) {
+            println!(
+                "API request of origin {} is rejected",
+                origin.unwrap_or_default()
+            );
+            let err = ApiError::new(ERR_FORBIDDEN, "Cross-origin API requests are not allowed");
+            let resp = api_response(Err(Box::new(err)));
+            return Ok(ResponseInfo::from_string(
+                403,
+                "application/json",
+                &resp.stringify()?,
+            ));
+        }
+
+        let path = url.split('?').next().unwrap_or_default();
+        let method = path.strip_prefix("/api/").map(String::from);
+
+        // Browsers send cached credentials with GET of images and links of any page without
+        // Origin, so only methods of viewers are called by GET
+        let post = *req.method() == tiny_http::Method::Post;
+        if !post
+            && method.is_some()
+            && cam.methods.read().unwrap().required_role(url) > Role::Viewer
+        {
+            let err = ApiError::new(ERR_INVALID_REQUEST, "The method must be called by POST");
+            let resp = api_response(Err(Box::new(err)));
+            return Ok(ResponseInfo::from_string(
+                405,
+                "application/json",
+                &resp.stringify()?,
+            ));
+        }
+
+        let args = if post {
+            let mut content = String::new();
+            req.as_reader().read_to_string(&mut content)?;
+            content.parse::<JsonValue>().map_err(|err| {
+                Box::new(ApiError::new(ERR_PARSE, &err.to_string())) as Box<dyn std::error::Error>
+            })
+        } else {
+            Ok(JsonValue::Null)
+        };
+
+        let mut status = 200;
+        let resp = match (method, args) {
+            (Some(method), Ok(args)) => {
+                let res = self.call(cam, role, &method, args);
+                if let Err(ref err) = res {
+                    if ApiError::from_error(err.as_ref()).code == ERR_BUSY {
+                        status = 503;
+                    }
+                }
+                api_response(res)
+            }
+            (Some(_), Err(err)) => api_response(Err(err)),
+            (None, Err(err)) => rpc_response(JsonValue::Null, Err(err)),
+            (None, Ok(JsonValue::Array(batch))) if !batch.is_empty() => {
+                let res: Vec<JsonValue> = batch
+                    .iter()
+                    .filter_map(|r| self.rpc_call(cam, role, r))
+                    .collect();
+                if res.is_empty() {
+                    return Ok(ResponseInfo::new(204, "application/json", vec![]));
+                }
+                JsonValue::Array(res)
+            }
+            (None, Ok(request)) => match self.rpc_call(cam, role, &request) {
+                Some(res) => res,
+                None => return Ok(ResponseInfo::new(204, "application/json", vec![])),
+            },
+        };
+
+        Ok(ResponseInfo::from_string(
+            status,
+            "application/json",
+            &resp.stringify()?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: &[Param] = &[
+        Param::required("name", ParamKind::String, "Name"),
+        Param::optional("value", ParamKind::Number, "Value"),
+    ];
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register(
+            MethodInfo {
+                name: "status",
+                description: "Status",
+                role: Role::Viewer,
+                params: &[],
+            },
+            None,
+        );
+        registry.register(
+            MethodInfo {
+                name: "set",
+                description: "Set value",
+                role: Role::Admin,
+                params: PARAMS,
+            },
+            Some(Box::new(|args: &JsonValue| Ok(args.clone()))),
+        );
+        registry
+    }
+
+    #[test]
+    fn test_required_role() {
+        let registry = registry();
+        assert_eq!(registry.required_role("/image.jpg"), Role::Viewer);
+        assert_eq!(registry.required_role("/api/status"), Role::Viewer);
+        assert_eq!(registry.required_role("/api/list_methods"), Role::Viewer);
+        assert_eq!(registry.required_role("/api/events?wait=1"), Role::Viewer);
+        assert_eq!(registry.required_role("/api/set"), Role::Admin);
+        assert_eq!(registry.required_role("/api/set?x=1"), Role::Admin);
+        assert_eq!(registry.required_role("/api/unknown"), Role::Viewer);
+
+        let methods = match registry.to_json() {
+            JsonValue::Array(methods) => methods,
+            _ => panic!("Array is expected"),
+        };
+        let names: Vec<&str> = methods
+            .iter()
+            .filter_map(|m| optional_arg(m, "name").and_then(|n| n.get::<String>()))
+            .map(|n| n.as_str())
+            .collect();
+        assert_eq!(names, vec!["list_methods", "set", "status"]);
+    }
+
+    #[test]
+    fn test_check_args() {
+        let info = &registry().methods["set"].info;
+        let args = |s: &str| s.parse::<JsonValue>().unwrap();
+        assert!(info.check_args(&args(r#"{"name": "a"}"#)).is_ok());
+        assert!(info
+            .check_args(&args(r#"{"name": "a", "value": 1, "x": 2}"#))
+            .is_ok());
+        assert!(info
+            .check_args(&args(r#"{"name": "a", "value": null}"#))
+            .is_ok());
+        for invalid in [
+            r#"{"value": 1}"#,
+            r#"{"name": 1}"#,
+            r#"{"name": "a", "value": "1"}"#,
+        ] {
+            let err = info.check_args(&args(invalid)).unwrap_err();
+            assert_eq!(ApiError::from_error(err.as_ref()).code, ERR_INVALID_ARGS);
+        }
+        assert!(info.check_args(&JsonValue::Null).is_err());
+    }
+
+    #[test]
+    fn test_get_admin_method() {
+        let dir = crate::test_util::temp_path("api");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("schedule.json");
+        let schedule = crate::schedule::Schedule::load(&path).unwrap();
+        let schedule = Arc::new(std::sync::Mutex::new(schedule));
+        let args = r#"{"latitude": 52.5, "longitude": 13.4}"#;
+        schedule
+            .lock()
+            .unwrap()
+            .set(&args.parse().unwrap())
+            .unwrap();
+        let before = schedule.lock().unwrap().to_json();
+        let saved = std::fs::read_to_string(&path).unwrap();
+
+        let auth = crate::auth::Auth::open(&dir.join("users.json")).unwrap();
+        let server = super::super::Server::new(&[], 0, None, auth).unwrap();
+        let camera = server.add_camera("camera").unwrap();
+        let s = Arc::clone(&schedule);
+        camera.register(
+            MethodInfo {
+                name: "set_schedule",
+                description: "Replace the schedule",
+                role: Role::Admin,
+                params: &[],
+            },
+            Some(Box::new(move |args: &JsonValue| {
+                s.lock().unwrap().set(args)?;
+                Ok(s.lock().unwrap().to_json())
+            })),
+        );
+        let (cam, url) = server.srv.camera("/api/set_schedule").unwrap();
+
+        let mut req: tiny_http::Request = tiny_http::TestRequest::new()
+            .with_method(tiny_http::Method::Get)
+            .with_path("/api/set_schedule")
+            .into();
+        let res = server.srv.api_request(&mut req, None, &cam, &url).unwrap();
+        assert_eq!(res.status, 405);
+        assert_eq!(schedule.lock().unwrap().to_json(), before);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+
+        // Viewer methods are still called by GET, others by POST
+        let mut req: tiny_http::Request = tiny_http::TestRequest::new()
+            .with_method(tiny_http::Method::Get)
+            .with_path("/api/list_methods")
+            .into();
+        let res = server
+            .srv
+            .api_request(&mut req, None, &cam, "/api/list_methods");
+        assert_eq!(res.unwrap().status, 200);
+        let mut req: tiny_http::Request = tiny_http::TestRequest::new()
+            .with_method(tiny_http::Method::Post)
+            .with_path("/api/set_schedule")
+            .with_body("{}")
+            .into();
+        let res = server.srv.api_request(&mut req, None, &cam, &url).unwrap();
+        assert_eq!(res.status, 200);
+        assert_ne!(schedule.lock().unwrap().to_json(), before);
+
+        drop((cam, camera));
+        server.destroy();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_loop_result() {
+        let res = loop_result(JsonValue::Boolean(true)).unwrap();
+        assert_eq!(res, JsonValue::Boolean(true));
+
+        let error = object(vec![(
+            "error",
+            ApiError::new(ERR_FORBIDDEN, "denied").to_json(),
+        )]);
+        let err = loop_result(error).unwrap_err();
+        let err = ApiError::from_error(err.as_ref());
+        assert_eq!((err.code, err.message.as_str()), (ERR_FORBIDDEN, "denied"));
+
+        let res = rpc_response(JsonValue::Number(1.0), Err(err.into()));
+        assert!(optional_arg(&res, "error").is_some());
+        assert_eq!(
+            optional_arg(&res, "jsonrpc"),
+            Some(&JsonValue::String(String::from("2.0")))
+        );
+    }
+}
@@ -0,0 +1,266 @@
+// Cameras of the server: each one has its last frame, archive, API methods and the channel of
+// calls to its capture loop. Camera routes are served under /cam/<id>/ (/cam/<id>/image.jpg,
+// /cam/<id>/stream.mjpg, /cam/<id>/api/<method>, /cam/<id>/archive/... and so on), URLs without
+// the prefix are routes of the first camera. /api/cameras lists the cameras. Archive URLs returned
+// by API methods (clips, frames, timelapses) are built by archive_url, so they are routes of the
+// camera whichever camera is the first one.
+
+use super::{api, header, ApiError, Frame, Impl, JsonRequest, MethodInfo, Result, ERR_NOT_FOUND};
+use super::{APICallback, ResponseInfo};
+use crate::archive::Playback;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
+use tinyjson::JsonValue;
+
+pub(super) struct CameraState {
+    pub(super) id: String,
+    source: Mutex<String>,
+    last_image: Mutex<Frame>,
+    image_ready: Condvar,
+    pub(super) archive: Mutex<Option<Playback>>,
+    pub(super) methods: RwLock<api::Registry>,
+    // Calls of methods processed by the capture loop
+    pub(super) sender: Sender<JsonRequest>,
+}
+
+impl CameraState {
+    /// Last frame, the default image until the camera sends one
+    pub(super) fn image(&self) -> Frame {
+        self.last_image.lock().unwrap().clone()
+    }
+
+    /// Wait for a frame different from the last sent one
+    pub(super) fn wait_image(&self, last: Option<u64>, timeout: Duration) -> Option<Frame> {
+        let img = self.last_image.lock().unwrap();
+        let (img, _) = self
+            .image_ready
+            .wait_timeout_while(img, timeout, |img| Some(img.seq) == last)
+            .unwrap();
+        if Some(img.seq) == last {
+            None
+        } else {
+            Some(img.clone())
+        }
+    }
+
+    /// Wake up streams waiting for frames, so they notice that the server is stopped
+    pub(super) fn wake(&self) {
+        self.image_ready.notify_all();
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let string = |s: String| JsonValue::String(s);
+        let mut res = HashMap::<String, JsonValue>::new();
+        res.insert(String::from("id"), string(self.id.clone()));
+        res.insert(
+            String::from("source"),
+            string(self.source.lock().unwrap().clone()),
+        );
+        res.insert(String::from("url"), string(format!("/cam/{}/", self.id)));
+        res.insert(
+            String::from("archive"),
+            JsonValue::Boolean(self.archive.lock().unwrap().is_some()),
+        );
+        JsonValue::Object(res)
+    }
+}
+
+/// Camera of the server, the capture loop publishes its frames and processes calls of its
+/// methods through this handle
+pub struct Camera {
+    state: Arc<CameraState>,
+    receiver: Receiver<JsonRequest>,
+}
+
+impl Camera {
+    pub fn id(&self) -> &str {
+        &self.state.id
+    }
+
+    /// Description of the source listed by /api/cameras
+    pub fn set_source(&self, source: &str) {
+        *self.state.source.lock().unwrap() = String::from(source);
+    }
+
+    /// Set the image served by image.jpg and send it to all streams of the camera
+    pub fn update_image(&self, data: &[u8]) -> Result<()> {
+        {
+            let mut img = self.state.last_image.lock().unwrap();
+            img.seq += 1;
+            img.data = Arc::new(Vec::<u8>::from(data));
+        }
+        self.state.image_ready.notify_all();
+
+        Ok(())
+    }
+
+    /// Serve frames of the archive on archive/ routes of the camera
+    pub fn set_archive(&self, playback: Playback) {
+        *self.state.archive.lock().unwrap() = Some(playback);
+    }
+
+    /// Add the API method, methods without the handler are processed by the capture loop and
+    /// their calls are returned by json_request
+    pub fn register(&self, info: MethodInfo, handler: Option<APICallback>) {
+        self.state.methods.write().unwrap().register(info, handler);
+    }
+
+    pub fn json_request(&self) -> Option<JsonRequest> {
+        self.receiver
+            .recv_timeout(core::time::Duration::from_millis(10))
+            .ok()
+    }
+}
+
+/// URL of the path of the archive of the camera: /cam/<id>/archive/<path>
+pub fn archive_url(camera: &str, path: &str) -> String {
+    format!("/cam/{}/archive/{}", camera, path)
+}
+
+/// Split /cam/<id>/<path> into the id and /<path>
+fn camera_path(url: &str) -> Option<(&str, String)> {
+    let rest = url.strip_prefix("/cam/")?;
+    match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => Some((&rest[..i], String::from(&rest[i..]))),
+        Some(i) => Some((&rest[..i], format!("/{}", &rest[i..]))),
+        None => Some((rest, String::from("/"))),
+    }
+}
+
+impl Impl {
+    pub(super) fn add_camera(&self, id: &str, default_image: &[u8]) -> Result<Camera> {
+        let mut cameras = self.cameras.write().unwrap();
+        if cameras.iter().any(|c| c.id == id) {
+            return Err(format!("Camera {} is already added", id).into());
+        }
+
+        let (sender, receiver) = std::sync::mpsc::channel::<JsonRequest>();
+        let state = Arc::new(CameraState {
+            id: String::from(id),
+            source: Mutex::new(String::new()),
+            last_image: Mutex::new(Frame {
+                seq: 0,
+                data: Arc::new(Vec::<u8>::from(default_image)),
+            }),
+            image_ready: Condvar::new(),
+            archive: Mutex::new(None),
+            methods: RwLock::new(api::Registry::new()),
+            sender,
+        });
+        cameras.push(Arc::clone(&state));
+
+        Ok(Camera { state, receiver })
+    }
+
+    /// Camera addressed by the URL and the URL of its route: /cam/<id>/<path> is /<path> of
+    /// the camera, other URLs belong to the first camera. None if the camera is not found.
+    pub(super) fn camera(&self, url: &str) -> Option<(Arc<CameraState>, String)> {
+        let cameras = self.cameras.read().unwrap();
+        match camera_path(url) {
+            Some((id, path)) => cameras
+                .iter()
+                .find(|c| c.id == id)
+                .map(|c| (Arc::clone(c), path)),
+            None => cameras.first().map(|c| (Arc::clone(c), String::from(url))),
+        }
+    }
+
+    pub(super) fn wake_cameras(&self) {
+        for camera in self.cameras.read().unwrap().iter() {
+            camera.wake();
+        }
+    }
+
+    /// List of cameras for /api/cameras
+    pub(super) fn list_cameras(&self) -> ResponseInfo {
+        let list: Vec<JsonValue> = self
+            .cameras
+            .read()
+            .unwrap()
+            .iter()
+            .map(|c| c.to_json())
+            .collect();
+        let content = JsonValue::Array(list).stringify().unwrap_or_default();
+        ResponseInfo::from_string(200, "application/json", &content)
+    }
+
+    /// Response to requests of unknown cameras
+    pub(super) fn camera_not_found(&self, req: tiny_http::Request) {
+        let message = "Camera is not found";
+        let response = if req.url().contains("/api") {
+            let mut obj = HashMap::<String, JsonValue>::new();
+            obj.insert(
+                String::from("error"),
+                ApiError::new(ERR_NOT_FOUND, message).to_json(),
+            );
+            let content = JsonValue::Object(obj).stringify().unwrap_or_default();
+            tiny_http::Response::from_string(content)
+                .with_header(header("content-type", "application/json"))
+        } else {
+            tiny_http::Response::from_string(message)
+                .with_header(header("content-type", "text/plain"))
+        };
+        if let Err(err) = req.respond(response.with_status_code(404)) {
+            println!("Error: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_camera_path() {
+        assert_eq!(camera_path("/image.jpg"), None);
+        assert_eq!(
+            camera_path("/cam/front/image.jpg"),
+            Some(("front", String::from("/image.jpg")))
+        );
+        assert_eq!(
+            camera_path("/cam/front/api/status?x=1"),
+            Some(("front", String::from("/api/status?x=1")))
+        );
+        assert_eq!(
+            camera_path("/cam/front"),
+            Some(("front", String::from("/")))
+        );
+        assert_eq!(
+            camera_path("/cam/front?seq=1"),
+            Some(("front", String::from("/?seq=1")))
+        );
+        assert_eq!(camera_path("/camera/front"), None);
+    }
+
+    #[test]
+    fn test_archive_url() {
+        let auth =
+            crate::auth::Auth::open(&crate::test_util::temp_path("cameras-users.json")).unwrap();
+        let server = super::super::Server::new(&[], 0, None, auth).unwrap();
+        let layout = crate::archive::Layout::default();
+        let mut cameras = vec![];
+        for id in ["front", "back"] {
+            let camera = server.add_camera(id).unwrap();
+            let root = std::path::PathBuf::from(format!("/archive/{}", id));
+            camera.set_archive(Playback::new(&root, &layout, id));
+            cameras.push(camera);
+        }
+
+        // URLs of the second camera are not routes of the first one
+        for id in ["front", "back"] {
+            let url = archive_url(id, "frame.jpg?t=1");
+            assert_eq!(url, format!("/cam/{}/archive/frame.jpg?t=1", id));
+            let (cam, path) = server.srv.camera(&url).unwrap();
+            assert_eq!(cam.id, id);
+            assert_eq!(path, "/archive/frame.jpg?t=1");
+            let archive = cam.archive.lock().unwrap();
+            let root = archive.as_ref().unwrap().root().to_path_buf();
+            assert_eq!(root, std::path::PathBuf::from(format!("/archive/{}", id)));
+        }
+
+        drop(cameras);
+        server.destroy();
+    }
+}
@@ -0,0 +1,216 @@
+// Event log is served by the workers, so clients waiting for new entries don't block the main
+// loop. /api/events takes the filter of eventlog::Filter and optional "wait": <ms> to wait for
+// new entries if there are none (long poll), waiting requests get their own threads. Streams and
+// waiting requests over the limit of client threads get 503.
+// /events[?type=<type>,<type>] is Server-Sent Events stream of new entries including live ones:
+//     id: <id>\nevent: <type>\ndata: <entry JSON>\n\n
+// Live entries are sent without the id line, so Last-Event-ID is the id of the last logged entry.
+// Clients reconnecting with Last-Event-ID header (or last_event_id parameter) get the entries they
+// missed as far as they are kept in memory.
+
+use super::{client_address, header, query_param, ApiError, Impl, Result, ERR_BUSY, ERR_NOT_FOUND};
+use crate::eventlog::{EventLog, Filter};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tinyjson::JsonValue;
+
+// Comment sent to idle streams, so closed connections are noticed
+const KEEPALIVE: Duration = Duration::from_secs(15);
+// Reconnection delay for clients
+const RETRY_MS: u64 = 3000;
+
+fn query(log: &EventLog, args: &JsonValue, camera: &str) -> Result<JsonValue> {
+    let filter = Filter {
+        camera: Some(String::from(camera)),
+        ..Filter::from_json(args)?
+    };
+    let entries = match super::optional_arg(args, "wait") {
+        Some(_) => {
+            let wait = super::number_arg(args, "wait")?.max(0.0) as u64;
+            log.wait(&filter, Duration::from_millis(wait))?
+        }
+        None => log.query(&filter)?,
+    };
+
+    Ok(JsonValue::Array(
+        entries.iter().map(|e| e.to_json()).collect(),
+    ))
+}
+
+fn respond(req: tiny_http::Request, res: Result<JsonValue>) {
+    let res = res.and_then(|v| Ok(v.stringify()?));
+    let (status, content) = match res {
+        Ok(content) => (200, content),
+        Err(err) => {
+            let err = ApiError::from_error(err.as_ref());
+            let status = if err.code == ERR_BUSY { 503 } else { 200 };
+            let mut obj = HashMap::<String, JsonValue>::new();
+            obj.insert(String::from("error"), err.to_json());
+            (
+                status,
+                JsonValue::Object(obj).stringify().unwrap_or_default(),
+            )
+        }
+    };
+
+    let response = tiny_http::Response::from_string(content)
+        .with_header(header("content-type", "application/json"))
+        .with_status_code(status);
+    if let Err(err) = req.respond(response) {
+        println!("Error: {}", err);
+    }
+}
+
+fn last_event_id(req: &tiny_http::Request) -> Option<u64> {
+    let header = req
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Last-Event-ID"))
+        .map(|h| h.value.as_str());
+    header
+        .or_else(|| query_param(req.url(), "last_event_id"))
+        .and_then(|id| id.trim().parse::<u64>().ok())
+}
+
+impl Impl {
+    pub(super) fn event_log(&self) -> Option<EventLog> {
+        self.log.lock().unwrap().clone()
+    }
+
+    /// Serve SSE stream of the camera entries in its own thread
+    pub(super) fn start_event_stream(self: &Arc<Self>, req: tiny_http::Request, camera: &str) {
+        println!("{} {}", req.method(), req.url());
+
+        let log = match self.event_log() {
+            Some(log) => log,
+            None => {
+                let response = tiny_http::Response::from_string("Event log is not enabled")
+                    .with_status_code(404);
+                if let Err(err) = req.respond(response) {
+                    println!("Error: {}", err);
+                }
+                return;
+            }
+        };
+        let types = match query_param(req.url(), "type") {
+            Some(types) => types
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(String::from)
+                .collect(),
+            None => vec![],
+        };
+        let filter = Filter {
+            after: last_event_id(&req).unwrap_or_else(|| log.last_id()),
+            types,
+            live: true,
+            after_live: log.last_live_id(),
+            camera: Some(String::from(camera)),
+            ..Filter::default()
+        };
+
+        let thread = match self.client_threads.start() {
+            Some(thread) => thread,
+            None => return self.busy(req),
+        };
+        let imp = Arc::clone(self);
+        let address = client_address(&req);
+        let url = String::from(req.url());
+        self.log_client("connected", &address, &url);
+
+        std::thread::spawn(move || {
+            let mut writer = req.into_writer();
+            match imp.event_stream(&mut writer, &log, filter) {
+                Ok(()) => (),
+                Err(err) => println!("Event stream closed: {}", err),
+            }
+            imp.log_client("disconnected", &address, &url);
+            drop(thread);
+        });
+    }
+
+    fn event_stream(&self, out: &mut dyn Write, log: &EventLog, mut filter: Filter) -> Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\n\
+             Connection: close\r\n\r\n\
+             retry: {}\n\n",
+            RETRY_MS
+        )?;
+        out.flush()?;
+
+        let mut sent = Instant::now();
+        while !self.stopped() {
+            let entries = log.wait(&filter, Duration::from_secs(1))?;
+            for entry in &entries {
+                if !entry.live {
+                    writeln!(out, "id: {}", entry.id)?;
+                }
+                write!(
+                    out,
+                    "event: {}\ndata: {}\n\n",
+                    entry.kind,
+                    entry.to_json().stringify()?
+                )?;
+                filter.advance(entry);
+            }
+
+            if !entries.is_empty() {
+                out.flush()?;
+                sent = Instant::now();
+            } else if sent.elapsed() >= KEEPALIVE {
+                out.write_all(b": keepalive\n\n")?;
+                out.flush()?;
+                sent = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn events_request(self: &Arc<Self>, mut req: tiny_http::Request, camera: &str) {
+        println!("{} {}", req.method(), req.url());
+
+        let log = match self.event_log() {
+            Some(log) => log,
+            None => {
+                let err = ApiError::new(ERR_NOT_FOUND, "Event log is not enabled");
+                return respond(req, Err(Box::new(err)));
+            }
+        };
+        let args = if *req.method() == tiny_http::Method::Post {
+            let mut content = String::new();
+            match req.as_reader().read_to_string(&mut content) {
+                Ok(_) => content.parse::<JsonValue>().map_err(|err| err.into()),
+                Err(err) => Err(err.into()),
+            }
+        } else {
+            Ok(JsonValue::Null)
+        };
+        let args = match args {
+            Ok(args) => args,
+            Err(err) => return respond(req, Err(err)),
+        };
+
+        if super::optional_arg(&args, "wait").is_some() {
+            let thread = match self.client_threads.start() {
+                Some(thread) => thread,
+                None => {
+                    let err = ApiError::new(ERR_BUSY, "Too many clients are waiting");
+                    return respond(req, Err(Box::new(err)));
+                }
+            };
+            let camera = String::from(camera);
+            std::thread::spawn(move || {
+                respond(req, query(&log, &args, &camera));
+                drop(thread);
+            });
+        } else {
+            respond(req, query(&log, &args, camera));
+        }
+    }
+}
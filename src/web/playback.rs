@@ -0,0 +1,294 @@
+// Archive routes, times are in ms since the epoch:
+// /archive/frame.jpg?t=<time> - frame closest to the time
+// /archive/thumb.jpg?t=<time>[&width=<px>] - its thumbnail
+// /archive/stream.mjpg?from=<time>&to=<time>[&speed=<x>] - MJPEG playback, speed 0.01-100
+// /archive/download.avi?from=<time>&to=<time>[&fps=<n>] - AVI file with frames of the range,
+//     413 if it's too large
+// /archive/timelapse/<name> - timelapse built by a background job
+// /archive/events/<name> - clip of a recorded event
+
+use super::{
+    client_address, header, query_param, write_stream_frame, write_stream_header, CameraState,
+    Impl, Result,
+};
+use crate::archive::{self, FrameReader, Playback};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_THUMBNAIL_WIDTH: u32 = 160;
+const THUMBNAIL_QUALITY: u8 = 75;
+const DEFAULT_AVI_FPS: u32 = 10;
+// Gaps of the recording are skipped in the playback
+const MAX_FRAME_DELAY: Duration = Duration::from_secs(1);
+const SPEEDS: std::ops::RangeInclusive<f64> = 0.01..=100.0;
+// Downloads are assembled by the workers, so they are limited to an hour at 10 fps and 1 GiB
+const MAX_DOWNLOAD_FRAMES: usize = 36000;
+const MAX_DOWNLOAD_SIZE: u64 = 1 << 30;
+
+static DOWNLOAD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Error reported to the client with the HTTP status
+struct RouteError {
+    status: i32,
+    message: String,
+}
+
+impl RouteError {
+    fn new(status: i32, message: &str) -> RouteError {
+        RouteError {
+            status,
+            message: String::from(message),
+        }
+    }
+}
+
+type RouteResult<T> = std::result::Result<T, RouteError>;
+
+impl From<Box<dyn std::error::Error>> for RouteError {
+    fn from(err: Box<dyn std::error::Error>) -> RouteError {
+        RouteError::new(500, &err.to_string())
+    }
+}
+
+fn param<T: std::str::FromStr>(url: &str, name: &str, default: Option<T>) -> RouteResult<T> {
+    match query_param(url, name) {
+        Some(v) => match v.parse::<T>() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(RouteError::new(400, &format!("Invalid {}", name))),
+        },
+        None => match default {
+            Some(v) => Ok(v),
+            None => Err(RouteError::new(
+                400,
+                &format!("Parameter {} is required", name),
+            )),
+        },
+    }
+}
+
+fn respond(req: tiny_http::Request, status: i32, content_type: &str, data: Vec<u8>) {
+    let response = tiny_http::Response::from_data(data)
+        .with_header(header("content-type", content_type))
+        .with_status_code(status);
+    if let Err(err) = req.respond(response) {
+        println!("Error: {}", err);
+    }
+}
+
+fn nearest_frame(pb: &Playback, url: &str) -> RouteResult<Vec<u8>> {
+    let time: u64 = param(url, "t", None)?;
+    match pb.nearest(time)? {
+        Some(frame) => Ok(FrameReader::new().read(&frame.frame)?),
+        None => Err(RouteError::new(404, "Archive is empty")),
+    }
+}
+
+fn range(pb: &Playback, url: &str) -> RouteResult<Vec<archive::ArchivedFrame>> {
+    let from: u64 = param(url, "from", None)?;
+    let to: u64 = param(url, "to", None)?;
+    let frames = pb.frames(from, to)?;
+    if frames.is_empty() {
+        return Err(RouteError::new(404, "No frames in the range"));
+    }
+    Ok(frames)
+}
+
+/// Assemble AVI in a temporary file, it is removed once sent
+fn download(req: tiny_http::Request, frames: &[archive::ArchivedFrame], fps: u32) -> Result<()> {
+    if frames.len() > MAX_DOWNLOAD_FRAMES {
+        let message = format!("Range has more than {} frames", MAX_DOWNLOAD_FRAMES);
+        respond(req, 413, "text/plain", message.into_bytes());
+        return Ok(());
+    }
+
+    let path = std::env::temp_dir().join(format!(
+        "httpcam-{}-{}.avi",
+        std::process::id(),
+        DOWNLOAD_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let mut too_large = false;
+    let res = archive::write_avi_progress(frames, &path, fps, &mut |_| {
+        too_large = std::fs::metadata(&path).is_ok_and(|m| m.len() > MAX_DOWNLOAD_SIZE);
+        !too_large
+    });
+    let res = match res {
+        Ok(_) => std::fs::File::open(&path).map_err(|err| err.into()),
+        Err(err) => Err(err),
+    };
+    let res = match res {
+        Ok(file) => {
+            let disposition = format!(
+                "attachment; filename=\"archive-{}-{}.avi\"",
+                frames[0].time,
+                frames[frames.len() - 1].time
+            );
+            let response = tiny_http::Response::from_file(file)
+                .with_header(header("content-type", "video/x-msvideo"))
+                .with_header(header("content-disposition", &disposition));
+            req.respond(response).map_err(|err| err.into())
+        }
+        Err(_) if too_large => {
+            let message = format!("AVI is larger than {} bytes", MAX_DOWNLOAD_SIZE);
+            respond(req, 413, "text/plain", message.into_bytes());
+            Ok(())
+        }
+        Err(err) => {
+            respond(req, 500, "text/plain", err.to_string().into_bytes());
+            Err(err)
+        }
+    };
+    let _ = std::fs::remove_file(&path);
+
+    res
+}
+
+/// Send AVI file of the archive subdirectory
+fn send_avi(req: tiny_http::Request, pb: &Playback, dir: &str, name: &str) {
+    let path = pb.root().join(dir).join(name);
+    let file = match crate::timelapse::check_file_name(name) {
+        Ok(()) => std::fs::File::open(path).ok(),
+        Err(_) => None,
+    };
+    let file = match file {
+        Some(file) => file,
+        None => return respond(req, 404, "text/plain", b"File is not found".to_vec()),
+    };
+
+    let response = tiny_http::Response::from_file(file)
+        .with_header(header("content-type", "video/x-msvideo"))
+        .with_header(header(
+            "content-disposition",
+            &format!("attachment; filename=\"{}\"", name),
+        ));
+    if let Err(err) = req.respond(response) {
+        println!("Error: {}", err);
+    }
+}
+
+impl Impl {
+    /// Archive routes of the camera are served by the workers, playback streams get their own
+    /// threads
+    pub(super) fn archive_request(
+        self: &Arc<Self>,
+        req: tiny_http::Request,
+        cam: &CameraState,
+        url: &str,
+    ) {
+        println!("{} {}", req.method(), req.url());
+
+        let pb = match *cam.archive.lock().unwrap() {
+            Some(ref pb) => pb.clone(),
+            None => return respond(req, 404, "text/plain", b"Archive is not enabled".to_vec()),
+        };
+        let url = String::from(url);
+        let path = match url.split_once('?') {
+            Some((path, _)) => path,
+            None => &url,
+        };
+
+        let res = match path {
+            "/archive/frame.jpg" => nearest_frame(&pb, &url),
+            "/archive/thumb.jpg" => {
+                param(&url, "width", Some(DEFAULT_THUMBNAIL_WIDTH)).and_then(|width| {
+                    let jpeg = nearest_frame(&pb, &url)?;
+                    Ok(crate::jpeg::thumbnail(&jpeg, width, THUMBNAIL_QUALITY)?)
+                })
+            }
+            "/archive/stream.mjpg" => return self.start_playback(pb, req),
+            "/archive/download.avi" => {
+                let fps = match param(&url, "fps", Some(DEFAULT_AVI_FPS)) {
+                    Ok(fps) if fps > 0 && fps <= 1000 => fps,
+                    _ => return respond(req, 400, "text/plain", b"Invalid fps".to_vec()),
+                };
+                match range(&pb, &url) {
+                    Ok(frames) => {
+                        if let Err(err) = download(req, &frames, fps) {
+                            println!("Download failed: {}", err);
+                        }
+                        return;
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            _ => match path
+                .strip_prefix("/archive/")
+                .and_then(|p| p.split_once('/'))
+            {
+                Some((dir, name))
+                    if dir == crate::timelapse::TIMELAPSE_DIR
+                        || dir == crate::recorder::EVENTS_DIR =>
+                {
+                    return send_avi(req, &pb, dir, name)
+                }
+                _ => Err(RouteError::new(404, "Not found")),
+            },
+        };
+
+        match res {
+            Ok(jpeg) => respond(req, 200, "image/jpeg", jpeg),
+            Err(err) => respond(req, err.status, "text/plain", err.message.into_bytes()),
+        }
+    }
+
+    fn start_playback(self: &Arc<Self>, pb: Playback, req: tiny_http::Request) {
+        let frames = match range(&pb, req.url()) {
+            Ok(frames) => frames,
+            Err(err) => return respond(req, err.status, "text/plain", err.message.into_bytes()),
+        };
+        let speed: f64 = match param(req.url(), "speed", Some(1.0)) {
+            Ok(speed) if SPEEDS.contains(&speed) => speed,
+            _ => return respond(req, 400, "text/plain", b"Invalid speed".to_vec()),
+        };
+        let thread = match self.client_threads.start() {
+            Some(thread) => thread,
+            None => return self.busy(req),
+        };
+        let imp = Arc::clone(self);
+        let address = client_address(&req);
+        let url = String::from(req.url());
+        self.log_client("connected", &address, &url);
+
+        std::thread::spawn(move || {
+            let mut writer = req.into_writer();
+            match imp.playback(&mut writer, &frames, speed) {
+                Ok(()) => (),
+                Err(err) => println!("Playback closed: {}", err),
+            }
+            imp.log_client("disconnected", &address, &url);
+            drop(thread);
+        });
+    }
+
+    /// Send frames with the delays they were recorded with, divided by speed
+    fn playback(
+        &self,
+        out: &mut dyn std::io::Write,
+        frames: &[archive::ArchivedFrame],
+        speed: f64,
+    ) -> Result<()> {
+        write_stream_header(out)?;
+
+        let mut reader = FrameReader::new();
+        let mut prev: Option<u64> = None;
+        for frame in frames {
+            if self.stopped() {
+                break;
+            }
+            if let Some(prev) = prev {
+                let delay = Duration::from_secs_f64(
+                    frame.time.saturating_sub(prev) as f64 / 1000.0 / speed,
+                );
+                std::thread::sleep(std::cmp::min(delay, MAX_FRAME_DELAY));
+            }
+            prev = Some(frame.time);
+
+            match reader.read(&frame.frame) {
+                Ok(jpeg) => write_stream_frame(out, &jpeg)?,
+                Err(err) => println!("Can't read frame {}: {}", frame.time, err),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+// HTTPS: https:<addr> listeners serve TLS with the PEM certificate chain and the private key
+// (PKCS#8 or RSA). The files are loaded again on SIGHUP or when they change, https listeners
+// are bound again with the new certificate while the capture goes on. Requests on keep-alive
+// connections of the old listener aren't served after the reload, so clients reconnect.
+
+use super::{Impl, Listen, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+// Files are checked for changes at this interval
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// Old listener is closed by its thread, so the address may be busy for a moment
+const BIND_ATTEMPTS: u32 = 50;
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_reload_signal(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Reload certificates on SIGHUP, it has to be installed after the termination handler
+pub fn install_reload_signal() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            on_reload_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Certificate of https listeners
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tls {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+    /// Plain HTTP listeners redirect to the first https listener
+    pub redirect: bool,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Tls {
+    /// Generate self-signed certificate for localhost and the names if the files don't exist,
+    /// returns false if they exist
+    pub fn generate_self_signed(&self, names: &[String]) -> Result<bool> {
+        if self.cert.exists() || self.key.exists() {
+            return Ok(false);
+        }
+
+        let mut names = names.to_vec();
+        names.push(String::from("localhost"));
+        let certified = rcgen::generate_simple_self_signed(names)?;
+        crate::archive::write_file_atomic_mode(
+            &self.key,
+            certified.key_pair.serialize_pem().as_bytes(),
+            0o600,
+        )?;
+        crate::archive::write_file_atomic(&self.cert, certified.cert.pem().as_bytes())?;
+        Ok(true)
+    }
+
+    /// Load and check the certificate and the key
+    fn load(&self) -> Result<tiny_http::SslConfig> {
+        let read = |path: &Path| -> Result<Vec<u8>> {
+            std::fs::read(path)
+                .map_err(|err| format!("Can't read {}: {}", path.display(), err).into())
+        };
+        let config = tiny_http::SslConfig {
+            certificate: read(&self.cert)?,
+            private_key: read(&self.key)?,
+        };
+
+        // tiny_http panics on keys it can't parse, so they are parsed the same way first
+        let invalid = |err| format!("Invalid private key {}: {}", self.key.display(), err);
+        let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut config.private_key.as_slice())
+            .map_err(invalid)?;
+        if pkcs8.is_empty() {
+            let rsa = rustls_pemfile::rsa_private_keys(&mut config.private_key.as_slice())
+                .map_err(invalid)?;
+            if rsa.is_empty() {
+                return Err(format!(
+                    "{}: PKCS#8 or RSA private key is expected",
+                    self.key.display()
+                )
+                .into());
+            }
+        }
+        // Check that the certificate matches the key on a temporary listener
+        if let Err(err) = tiny_http::Server::https("127.0.0.1:0", config.clone()) {
+            return Err(format!("Invalid certificate {}: {}", self.cert.display(), err).into());
+        }
+        Ok(config)
+    }
+}
+
+pub(super) struct TlsState {
+    tls: Tls,
+    config: tiny_http::SslConfig,
+    // Modification times of the loaded files and of the files at the last check
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    seen: (Option<SystemTime>, Option<SystemTime>),
+    checked: Instant,
+    // Incremented when the certificate is reloaded
+    generation: u64,
+}
+
+impl TlsState {
+    pub(super) fn new(tls: Tls) -> Result<TlsState> {
+        let modified = (modified(&tls.cert), modified(&tls.key));
+        Ok(TlsState {
+            config: tls.load()?,
+            modified,
+            seen: modified,
+            checked: Instant::now(),
+            generation: 0,
+            tls,
+        })
+    }
+
+    pub(super) fn redirect(&self) -> bool {
+        self.tls.redirect
+    }
+
+    /// Certificate to load again if requested by the signal or the files changed. Files which
+    /// are still being written are loaded once their times stay the same for a check interval.
+    fn reload_needed(&mut self) -> Option<Tls> {
+        let modified = (modified(&self.tls.cert), modified(&self.tls.key));
+        if !RELOAD.swap(false, Ordering::SeqCst) {
+            if self.checked.elapsed() < CHECK_INTERVAL {
+                return None;
+            }
+            self.checked = Instant::now();
+            let stable = modified == self.seen;
+            self.seen = modified;
+            if !stable || modified == self.modified {
+                return None;
+            }
+        }
+        self.seen = modified;
+        self.modified = modified;
+        Some(self.tls.clone())
+    }
+
+    /// Take the loaded certificate, listeners are bound again with it
+    fn reload(&mut self, config: Result<tiny_http::SslConfig>) {
+        match config {
+            Ok(config) => {
+                println!("Certificate reloaded from {}", self.tls.cert.display());
+                self.config = config;
+                self.generation += 1;
+            }
+            Err(err) => println!("Error: can't reload certificate: {}", err),
+        }
+    }
+}
+
+impl Impl {
+    /// Reload the certificate if requested by the signal or the files changed, returns its
+    /// generation
+    fn check_tls(&self) -> u64 {
+        let tls = match *self.tls.lock().unwrap() {
+            Some(ref mut state) => match state.reload_needed() {
+                Some(tls) => tls,
+                None => return state.generation,
+            },
+            None => return 0,
+        };
+
+        // Other listeners go on while the files are loaded
+        let config = tls.load();
+        match *self.tls.lock().unwrap() {
+            Some(ref mut state) => {
+                state.reload(config);
+                state.generation
+            }
+            None => 0,
+        }
+    }
+
+    /// Bind the listener, https listeners with the current certificate
+    pub(super) fn bind(&self, listen: &Listen) -> Result<(tiny_http::Server, u64)> {
+        match listen {
+            Listen::Https(addr) => {
+                let (config, generation) = match *self.tls.lock().unwrap() {
+                    Some(ref state) => (state.config.clone(), state.generation),
+                    None => return Err("Certificate is required for https listeners".into()),
+                };
+                match tiny_http::Server::https(addr.as_str(), config) {
+                    Ok(srv) => Ok((srv, generation)),
+                    Err(err) => Err(format!("Can't listen on {}: {}", listen, err).into()),
+                }
+            }
+            _ => Ok((listen.bind()?, 0)),
+        }
+    }
+
+    /// Bind https listener again if the certificate was reloaded, returns None if the server
+    /// is stopped meanwhile
+    pub(super) fn rebind(
+        &self,
+        listen: &Listen,
+        listener: tiny_http::Server,
+        generation: u64,
+    ) -> Option<(tiny_http::Server, u64)> {
+        if generation == self.check_tls() {
+            return Some((listener, generation));
+        }
+
+        drop(listener);
+        let mut attempt = 0;
+        while !self.stopped() {
+            match self.bind(listen) {
+                Ok(res) => return Some(res),
+                Err(err) if attempt >= BIND_ATTEMPTS => {
+                    println!("Error: {}", err);
+                    attempt = 0;
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+                Err(_) => {
+                    attempt += 1;
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            }
+        }
+        None
+    }
+
+    /// Redirect requests of plain HTTP listeners to the first https listener
+    pub(super) fn redirect_https(&self, req: tiny_http::Request) {
+        let port = match self.https_port {
+            Some(443) => String::new(),
+            Some(port) => format!(":{}", port),
+            None => String::new(),
+        };
+        let host = req
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Host"))
+            .map(|h| h.value.as_str())
+            .unwrap_or("localhost");
+        // Strip the port of the plain listener, IPv6 hosts are in brackets
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+
+        let location = format!("https://{}{}{}", host, port, req.url());
+        let response =
+            tiny_http::Response::empty(301).with_header(super::header("Location", &location));
+        if let Err(err) = req.respond(response) {
+            println!("Error: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = crate::test_util::temp_path("tls");
+        std::fs::create_dir_all(&dir).unwrap();
+        let tls = Tls {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            redirect: false,
+        };
+        // The temporary file left by a failed write doesn't give its permissions to the key
+        std::fs::write(dir.join("key.pem.tmp"), "").unwrap();
+        assert!(tls.generate_self_signed(&[]).unwrap());
+        assert!(tls.load().is_ok());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&tls.key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            assert!(!dir.join("key.pem.tmp").exists());
+        }
+
+        // Keys tiny_http can't parse are errors, not panics
+        let key = std::fs::read_to_string(&tls.key).unwrap();
+        std::fs::write(&tls.key, key.replace("-----END PRIVATE KEY-----", "")).unwrap();
+        assert!(tls.load().is_err());
+        std::fs::write(&tls.key, "").unwrap();
+        assert!(tls.load().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,518 @@
+// WebSocket at /ws: JSON API calls, live frames and events over one connection. The upgraded
+// stream of tiny_http can't be read and written by different threads, so the connection is
+// paced by the client: the server writes only in reply to its messages.
+// Client sends text messages:
+//     {"id": <id>, "method": <method>, "params": {...}}
+// and gets {"id": <id>, "result": ...} or {"id": <id>, "error": {"code": ..., "message": ...}}.
+// Methods are the ones of /api/<method> and:
+//     subscribe {"frames": true, "events": true | false | [<type>, ...]} chooses what next
+//         waits for, by default frames and all events are sent
+//     next waits up to a second for a frame newer than the last sent one (or for events if
+//         frames are off), sends the frame as a binary message, the result is {"frame": <seq>}
+//         or {"frame": null}
+// New events are sent as {"event": <entry>} messages before the result of any call. Clients
+// draw the frame and call next again, so frames aren't queued and latency stays at one frame.
+
+use super::{
+    client_address, header, header_value, optional_arg, same_origin, string_arg, ApiError,
+    CameraState, Impl, Result, ERR_INVALID_ARGS,
+};
+use crate::auth::Role;
+use crate::eventlog::{EventLog, Filter};
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tinyjson::JsonValue;
+
+type Stream = dyn tiny_http::ReadWrite + Send;
+// Opcode and payload of a message
+type Message = (u8, Vec<u8>);
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE: usize = 1 << 20;
+const NEXT_WAIT: Duration = Duration::from_secs(1);
+// Largest payload of control frames
+const MAX_CONTROL: usize = 125;
+// Status of close frames sent for protocol errors of clients
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{}{}", key.trim(), GUID).as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+fn write_frame(out: &mut dyn Write, opcode: u8, payload: &[u8]) -> Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= 0xffff => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.write_all(&head)?;
+    out.write_all(payload)?;
+    Ok(())
+}
+
+fn write_text(out: &mut dyn Write, value: &JsonValue) -> Result<()> {
+    write_frame(out, OP_TEXT, value.stringify()?.as_bytes())
+}
+
+/// Read a frame: FIN flag, opcode and unmasked payload
+fn read_frame(inp: &mut dyn Read) -> Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    inp.read_exact(&mut head)?;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            inp.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            inp.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if head[1] & 0x80 == 0 {
+        return Err("Client frames must be masked".into());
+    }
+    if len > MAX_MESSAGE as u64 {
+        return Err(format!("Frame of {} bytes is too large", len).into());
+    }
+
+    let mut mask = [0u8; 4];
+    inp.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    inp.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((head[0] & 0x80 != 0, head[0] & 0x0f, payload))
+}
+
+/// Close the connection with status 1002 for the protocol error of the client
+fn protocol_error(stream: &mut Stream, message: &str) -> Result<Option<Message>> {
+    write_frame(stream, OP_CLOSE, &CLOSE_PROTOCOL_ERROR.to_be_bytes())?;
+    stream.flush()?;
+    Err(message.into())
+}
+
+/// Read a message joining its fragments, control frames are answered on the way. Returns None
+/// when the client closes the connection. Fragmented or large control frames and new messages
+/// inside fragmented ones are protocol errors which close the connection.
+fn read_message(stream: &mut Stream) -> Result<Option<Message>> {
+    let mut message: Option<Message> = None;
+    loop {
+        let (fin, opcode, payload) = read_frame(stream)?;
+        if opcode & 0x8 != 0 && (!fin || payload.len() > MAX_CONTROL) {
+            return protocol_error(stream, "Invalid control frame");
+        }
+        match opcode {
+            OP_PING => {
+                write_frame(stream, OP_PONG, &payload)?;
+                stream.flush()?;
+            }
+            OP_PONG => (),
+            OP_CLOSE => {
+                // Echo the status code
+                write_frame(stream, OP_CLOSE, &payload[..payload.len().min(2)])?;
+                stream.flush()?;
+                return Ok(None);
+            }
+            OP_CONTINUATION => match message {
+                Some((_, ref mut data)) if data.len() + payload.len() <= MAX_MESSAGE => {
+                    data.extend_from_slice(&payload)
+                }
+                Some(_) => return Err("Message is too large".into()),
+                None => return protocol_error(stream, "Unexpected continuation frame"),
+            },
+            OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+            OP_TEXT | OP_BINARY => {
+                return protocol_error(stream, "New message inside a fragmented one")
+            }
+            _ => return protocol_error(stream, &format!("Unknown opcode {}", opcode)),
+        }
+
+        if fin && opcode & 0x8 == 0 {
+            return Ok(message);
+        }
+    }
+}
+
+fn object(items: Vec<(&str, JsonValue)>) -> JsonValue {
+    let obj: HashMap<String, JsonValue> = items
+        .into_iter()
+        .map(|(k, v)| (String::from(k), v))
+        .collect();
+    JsonValue::Object(obj)
+}
+
+/// Reply to the call
+fn reply(id: JsonValue, res: Result<JsonValue>) -> JsonValue {
+    match res {
+        Ok(result) => object(vec![("id", id), ("result", result)]),
+        Err(err) => object(vec![
+            ("id", id),
+            ("error", ApiError::from_error(err.as_ref()).to_json()),
+        ]),
+    }
+}
+
+struct Client {
+    role: Option<Role>,
+    frames: bool,
+    last_frame: Option<u64>,
+    // None if events aren't sent
+    events: Option<Filter>,
+}
+
+impl Client {
+    fn subscribe(
+        &mut self,
+        params: &JsonValue,
+        log: Option<&EventLog>,
+        camera: &str,
+    ) -> Result<()> {
+        match optional_arg(params, "frames") {
+            Some(JsonValue::Boolean(frames)) => self.frames = *frames,
+            Some(_) => return Err(invalid("frames must be a boolean")),
+            None => (),
+        }
+
+        let types = match optional_arg(params, "events") {
+            Some(JsonValue::Boolean(true)) => Some(vec![]),
+            Some(JsonValue::Boolean(false)) => None,
+            Some(JsonValue::Array(types)) => {
+                let mut res: Vec<String> = vec![];
+                for t in types {
+                    match t {
+                        JsonValue::String(t) => res.push(t.clone()),
+                        _ => return Err(invalid("events must be strings")),
+                    }
+                }
+                Some(res)
+            }
+            Some(_) => return Err(invalid("events must be a boolean or an array")),
+            None => return Ok(()),
+        };
+        self.events = match (types, log) {
+            (Some(types), Some(log)) => Some(Filter {
+                after: match self.events {
+                    Some(ref filter) => filter.after,
+                    None => log.last_id(),
+                },
+                types,
+                live: true,
+                after_live: match self.events {
+                    Some(ref filter) => filter.after_live,
+                    None => log.last_live_id(),
+                },
+                camera: Some(String::from(camera)),
+                ..Filter::default()
+            }),
+            _ => None,
+        };
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> Box<dyn std::error::Error> {
+    Box::new(ApiError::new(ERR_INVALID_ARGS, message))
+}
+
+impl Impl {
+    /// Upgrade the connection and serve it in its own thread
+    pub(super) fn start_websocket(
+        self: &Arc<Self>,
+        req: tiny_http::Request,
+        role: Option<Role>,
+        cam: Arc<CameraState>,
+    ) {
+        println!("{} {}", req.method(), req.url());
+
+        let key = match (
+            header_value(&req, "Upgrade"),
+            header_value(&req, "Sec-WebSocket-Key"),
+        ) {
+            (Some(upgrade), Some(key)) if upgrade.eq_ignore_ascii_case("websocket") => key,
+            _ => {
+                let response = tiny_http::Response::from_string("WebSocket upgrade is expected")
+                    .with_status_code(400);
+                if let Err(err) = req.respond(response) {
+                    println!("Error: {}", err);
+                }
+                return;
+            }
+        };
+        let origin = header_value(&req, "Origin");
+        if !same_origin(origin.as_deref(), header_value(&req, "Host").as_deref()) {
+            println!(
+                "WebSocket of origin {} is rejected",
+                origin.unwrap_or_default()
+            );
+            let response =
+                tiny_http::Response::from_string("Cross-origin WebSocket is not allowed")
+                    .with_status_code(403);
+            if let Err(err) = req.respond(response) {
+                println!("Error: {}", err);
+            }
+            return;
+        }
+        let thread = match self.client_threads.start() {
+            Some(thread) => thread,
+            None => return self.busy(req),
+        };
+
+        let imp = Arc::clone(self);
+        let address = client_address(&req);
+        let url = String::from(req.url());
+        self.log_client("connected", &address, &url);
+
+        std::thread::spawn(move || {
+            let response = tiny_http::Response::empty(101)
+                .with_header(header("Sec-WebSocket-Accept", &accept_key(&key)));
+            let mut stream = req.upgrade("websocket", response);
+            if let Err(err) = imp.websocket(stream.as_mut(), role, &cam) {
+                println!("WebSocket closed: {}", err);
+            }
+            imp.log_client("disconnected", &address, &url);
+            drop(thread);
+        });
+    }
+
+    fn websocket(&self, stream: &mut Stream, role: Option<Role>, cam: &CameraState) -> Result<()> {
+        let log = self.event_log();
+        let mut client = Client {
+            role,
+            frames: true,
+            last_frame: None,
+            events: log.as_ref().map(|log| Filter {
+                after: log.last_id(),
+                live: true,
+                after_live: log.last_live_id(),
+                camera: Some(cam.id.clone()),
+                ..Filter::default()
+            }),
+        };
+
+        while !self.stopped() {
+            let (opcode, payload) = match read_message(stream)? {
+                Some(message) => message,
+                None => break,
+            };
+
+            let reply = match String::from_utf8(payload) {
+                Ok(text) if opcode == OP_TEXT => match text.parse::<JsonValue>() {
+                    Ok(call) => {
+                        let id = optional_arg(&call, "id")
+                            .cloned()
+                            .unwrap_or(JsonValue::Null);
+                        let res =
+                            self.websocket_call(stream, &mut client, log.as_ref(), &call, cam);
+                        reply(id, res)
+                    }
+                    Err(err) => reply(JsonValue::Null, Err(invalid(&err.to_string()))),
+                },
+                _ => reply(
+                    JsonValue::Null,
+                    Err(invalid("JSON text messages are expected")),
+                ),
+            };
+
+            if let (Some(log), Some(filter)) = (log.as_ref(), client.events.as_mut()) {
+                for entry in log.query(filter)? {
+                    write_text(stream, &object(vec![("event", entry.to_json())]))?;
+                    filter.advance(&entry);
+                }
+            }
+            write_text(stream, &reply)?;
+            stream.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn websocket_call(
+        &self,
+        stream: &mut Stream,
+        client: &mut Client,
+        log: Option<&EventLog>,
+        call: &JsonValue,
+        cam: &CameraState,
+    ) -> Result<JsonValue> {
+        let method = string_arg(call, "method")?;
+        let params = match optional_arg(call, "params") {
+            Some(params) => params.clone(),
+            None => JsonValue::Object(HashMap::new()),
+        };
+
+        match method.as_str() {
+            "subscribe" => {
+                client.subscribe(&params, log, &cam.id)?;
+                Ok(JsonValue::Object(HashMap::new()))
+            }
+            "next" if client.frames => match cam.wait_image(client.last_frame, NEXT_WAIT) {
+                Some(img) => {
+                    write_frame(stream, OP_BINARY, &img.data)?;
+                    client.last_frame = Some(img.seq);
+                    Ok(object(vec![("frame", JsonValue::Number(img.seq as f64))]))
+                }
+                None => Ok(object(vec![("frame", JsonValue::Null)])),
+            },
+            "next" => {
+                if let (Some(log), Some(filter)) = (log, client.events.as_ref()) {
+                    log.wait(filter, NEXT_WAIT)?;
+                }
+                Ok(object(vec![("frame", JsonValue::Null)]))
+            }
+            method => self.call(cam, client.role, method, params),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // Example of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut out: Vec<u8> = vec![];
+        write_frame(&mut out, OP_TEXT, b"Hi").unwrap();
+        assert_eq!(out, b"\x81\x02Hi");
+        out.clear();
+        write_frame(&mut out, OP_BINARY, &[7u8; 300]).unwrap();
+        assert_eq!(&out[..4], &[0x82, 126, 1, 44]);
+        assert_eq!(out.len(), 304);
+
+        // Masked "Hello" of RFC 6455
+        let masked = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (fin, opcode, payload) = read_frame(&mut &masked[..]).unwrap();
+        assert_eq!(
+            (fin, opcode, payload.as_slice()),
+            (true, OP_TEXT, &b"Hello"[..])
+        );
+        // Unmasked frames of clients are rejected
+        assert!(read_frame(&mut &b"\x81\x02Hi"[..]).is_err());
+    }
+
+    /// Connection reading the frames and keeping written ones
+    struct Connection {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut head = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            len if len < 126 => head.push(0x80 | len as u8),
+            len => {
+                head.push(0x80 | 126);
+                head.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        let mask = [1u8, 2, 3, 4];
+        head.extend_from_slice(&mask);
+        head.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        head
+    }
+
+    fn read(frames: &[Vec<u8>]) -> (Result<Option<Message>>, Vec<u8>) {
+        let mut conn = Connection {
+            input: std::io::Cursor::new(frames.concat()),
+            output: vec![],
+        };
+        let res = read_message(&mut conn);
+        (res, conn.output)
+    }
+
+    #[test]
+    fn test_read_message() {
+        // Fragments with a ping between them
+        let (res, out) = read(&[
+            frame(false, OP_TEXT, b"Hel"),
+            frame(true, OP_PING, b"p"),
+            frame(true, OP_CONTINUATION, b"lo"),
+        ]);
+        assert_eq!(res.unwrap(), Some((OP_TEXT, b"Hello".to_vec())));
+        assert_eq!(out, b"\x8a\x01p");
+
+        let (res, out) = read(&[frame(true, OP_CLOSE, &1000u16.to_be_bytes())]);
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(out, b"\x88\x02\x03\xe8");
+
+        // Protocol errors close the connection with 1002
+        for frames in [
+            vec![frame(false, OP_TEXT, b"a"), frame(true, OP_TEXT, b"b")],
+            vec![frame(false, OP_TEXT, b"a"), frame(true, OP_BINARY, b"b")],
+            vec![frame(false, OP_PING, b"")],
+            vec![frame(true, OP_PING, &[0u8; 126])],
+            vec![frame(true, OP_CONTINUATION, b"a")],
+            vec![frame(true, 0x3, b"")],
+        ] {
+            let (res, out) = read(&frames);
+            assert!(res.is_err());
+            assert_eq!(out, b"\x88\x02\x03\xea");
+        }
+    }
+
+    #[test]
+    fn test_reply() {
+        let id = JsonValue::Number(1.0);
+        let res = reply(id.clone(), Ok(JsonValue::Boolean(true)));
+        assert_eq!(optional_arg(&res, "id"), Some(&id));
+        assert_eq!(
+            optional_arg(&res, "result"),
+            Some(&JsonValue::Boolean(true))
+        );
+        assert!(optional_arg(&res, "error").is_none());
+        let res = reply(id.clone(), Err(invalid("x")));
+        assert_eq!(optional_arg(&res, "id"), Some(&id));
+        assert!(optional_arg(&res, "error").is_some());
+        assert!(optional_arg(&res, "result").is_none());
+    }
+}
@@ -30,11 +30,14 @@ function preloadImage(url) {
     });
 }
 
+// Routes of the camera opened by /cam/<id>/, the first camera is served without the prefix
+const base = (location.pathname.match(/^\/cam\/[^/]+/) || [""])[0];
+
 async function updateImage() {
     let seq = 0;
     while (true) {
         let rnd = Math.round(Math.random() * 1000000 + 1.0);
-        let src = "/image.jpg?seq=" + seq + "&rnd=" + rnd;
+        let src = base + "/image.jpg?seq=" + seq + "&rnd=" + rnd;
         ++seq;
         let img = await preloadImage(src);
         let old = document.getElementById("webcam");
@@ -44,6 +47,63 @@ async function updateImage() {
     }
 }
 
+// MJPEG stream is pushed by the server, polling of single images is used only if the
+// browser can't show the stream
+function startStream() {
+    let img = document.getElementById("webcam");
+    img.onerror = function () {
+        img.onerror = null;
+        updateImage().then(function () { console.log("Error"); });
+    }
+    img.src = base + "/stream.mjpg";
+}
+
+// Frames are pulled over the WebSocket and drawn on a canvas, the next frame is requested
+// when the previous one arrives. MJPEG stream is used if the WebSocket can't connect.
+function startWebSocket() {
+    let element = document.getElementById("webcam");
+    let canvas = document.createElement("canvas");
+    let context = canvas.getContext("2d");
+    let scheme = location.protocol == "https:" ? "wss:" : "ws:";
+    let ws = new WebSocket(scheme + "//" + location.host + base + "/ws");
+    let id = 0;
+    let opened = false;
+
+    function next() {
+        ++id;
+        ws.send(JSON.stringify({id: id, method: "next"}));
+    }
+
+    ws.onopen = function () {
+        opened = true;
+        canvas.id = "webcam";
+        element.replaceWith(canvas);
+        next();
+    }
+    ws.onmessage = async function (msg) {
+        if (msg.data instanceof Blob) {
+            let bitmap = await createImageBitmap(msg.data);
+            canvas.width = bitmap.width;
+            canvas.height = bitmap.height;
+            context.drawImage(bitmap, 0, 0);
+            bitmap.close();
+        } else if (JSON.parse(msg.data).id === id) {
+            next();
+        }
+    }
+    ws.onclose = function () {
+        if (opened) {
+            setTimeout(startWebSocket, 1000);
+        } else {
+            startStream();
+        }
+    }
+}
+
 document.body.onload = function() {
-    updateImage().then(function () { console.log("Error"); });
+    if ("WebSocket" in window && "createImageBitmap" in window) {
+        startWebSocket();
+    } else {
+        startStream();
+    }
 }